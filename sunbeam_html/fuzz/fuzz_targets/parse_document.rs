@@ -0,0 +1,11 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    // Raw bytes also go through encoding sniffing, which can reparse the document
+    sunbeam_html::parse_document_from_bytes(data);
+    if let Ok(html) = std::str::from_utf8(data) {
+        sunbeam_html::parse_document_with_diagnostics(html);
+    }
+});
@@ -0,0 +1,10 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use sunbeam_html::parser::tokenizer::{TokenTag, Tokenizer};
+
+fuzz_target!(|data: &[u8]| {
+    let html = String::from_utf8_lossy(data);
+    let mut tokenizer = Tokenizer::init(&html);
+    while !matches!(tokenizer.get_next_token().tag, TokenTag::EoF) {}
+});
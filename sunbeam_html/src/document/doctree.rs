@@ -1,5 +1,10 @@
+use std::collections::{HashMap, HashSet};
+
 use crate::display_data::display_box::DisplayBox;
 use crate::document::node;
+use crate::html_elements::{HTMLElement, HTMLElementType, Namespace};
+use crate::parser::span::SourceSpan;
+use crate::parser::tree_sink::TreeSink;
 use log;
 
 use pollster;
@@ -8,32 +13,54 @@ use pollster;
 // This allows us to have doubly-linked relations without getting into
 // complicated lifetimes or arcs
 //
-// Eventually this should be redone. Anytime a node is removed,
-// that array index is set to none for the remainder of the object.
-// This happens because we don't know who holds a doctree node, and it
-// would be better to "waste" memory than swap out a reference under
-// someone's nose.
+// The slots of removed nodes get reused. We don't know who holds a doctree
+// node, so every node also gets a generation that's never handed out again,
+// and a handle that outlived its node stops resolving instead of pointing
+// at whatever took over the slot.
 #[derive(Debug)]
 pub struct Doctree {
-    data: Vec<Option<node::Node>>,
+    data: Vec<Slot>,
+    free_slots: Vec<usize>,
+    next_generation: u64,
     root_node: Vec<DoctreeNode>,
 }
 
+#[derive(Debug)]
+struct Slot {
+    generation: u64,
+    node: Option<node::Node>,
+}
+
 impl Doctree {
     pub fn new() -> Doctree {
         Doctree {
             data: Vec::new(),
+            free_slots: Vec::new(),
+            next_generation: 0,
             root_node: Vec::new(),
         }
     }
 
     pub fn add_node(&mut self, node: node::Node) -> DoctreeNode {
         log::trace!("Inserting node {:?}", node);
-        self.data.push(Some(node));
+        let generation = self.next_generation;
+        self.next_generation += 1;
+        let slot = Slot {
+            generation,
+            node: Some(node),
+        };
+        let idx = match self.free_slots.pop() {
+            Some(idx) => {
+                self.data[idx] = slot;
+                idx
+            }
+            None => {
+                self.data.push(slot);
+                self.data.len() - 1
+            }
+        };
 
-        DoctreeNode {
-            idx: self.data.len() - 1,
-        }
+        DoctreeNode { idx, generation }
     }
 
     pub fn add_root_node(&mut self, node: node::Node) -> DoctreeNode {
@@ -45,37 +72,272 @@ impl Doctree {
 
     pub fn get_node(&self, doctree_node: &DoctreeNode) -> Option<&node::Node> {
         match self.data.get(doctree_node.idx) {
-            Some(v) => v.as_ref(),
-            None => None,
+            Some(slot) if slot.generation == doctree_node.generation => slot.node.as_ref(),
+            _ => None,
         }
     }
 
     pub fn get_mut_node(&mut self, doctree_node: &DoctreeNode) -> Option<&mut node::Node> {
         match self.data.get_mut(doctree_node.idx) {
-            Some(v) => v.as_mut(),
-            None => None,
+            Some(slot) if slot.generation == doctree_node.generation => slot.node.as_mut(),
+            _ => None,
         }
     }
 
+    // The node that was added most recently and is still around
     pub fn get_last_node(&mut self) -> Option<&mut node::Node> {
-        match self.data.last_mut() {
-            Some(v) => v.as_mut(),
-            None => None,
+        self.data
+            .iter_mut()
+            .filter(|slot| slot.node.is_some())
+            .max_by_key(|slot| slot.generation)
+            .and_then(|slot| slot.node.as_mut())
+    }
+
+    // Detaches the node and frees it along with everything under it, including
+    // template contents and shadow trees
+    pub fn remove_node(&mut self, node: &DoctreeNode) {
+        self.detach_node(node);
+        let mut pending = vec![*node];
+        while let Some(n) = pending.pop() {
+            let removed = match self.data.get_mut(n.idx) {
+                Some(slot) if slot.generation == n.generation => slot.node.take(),
+                _ => None,
+            };
+            let removed = match removed {
+                Some(removed) => removed,
+                None => continue,
+            };
+            self.free_slots.push(n.idx);
+
+            pending.extend(removed.children);
+            if let node::NodeType::Element(e) = &removed.node_type {
+                pending.extend(e.shadow_root.as_ref().map(|root| root.root));
+                if let HTMLElementType::Template(template) = &e.element_type {
+                    pending.extend(template.get_contents());
+                }
+            }
         }
     }
 
-    pub fn remove_at(&mut self, idx: usize) {
-        if self.data.len() <= idx {
+    // Moves the nodes down over the free slots, so the memory they took can be given back.
+    // Returns the new handle of every node that moved. Their old handles stop resolving, so
+    // anything holding on to them has to be updated with the remapping. Handles that aren't
+    // in it stay the same.
+    pub fn compact(&mut self) -> HashMap<DoctreeNode, DoctreeNode> {
+        let mut remapping = HashMap::new();
+        let mut data = Vec::with_capacity(self.len());
+        for (idx, slot) in std::mem::take(&mut self.data).into_iter().enumerate() {
+            if slot.node.is_none() {
+                continue;
+            }
+            if idx != data.len() {
+                let generation = slot.generation;
+                remapping.insert(
+                    DoctreeNode { idx, generation },
+                    DoctreeNode {
+                        idx: data.len(),
+                        generation,
+                    },
+                );
+            }
+            data.push(slot);
+        }
+        self.data = data;
+        self.free_slots.clear();
+
+        if !remapping.is_empty() {
+            let remap = |handle: &mut DoctreeNode| {
+                if let Some(new) = remapping.get(handle) {
+                    *handle = *new;
+                }
+            };
+            for slot in &mut self.data {
+                if let Some(n) = &mut slot.node {
+                    n.remap_handles(&remap);
+                }
+            }
+            self.root_node.iter_mut().for_each(remap);
+        }
+        remapping
+    }
+
+    // Removes a node from its parent, or from the root list, leaving it unattached
+    pub fn detach_node(&mut self, node: &DoctreeNode) {
+        let parent = match self.get_mut_node(node) {
+            Some(n) => n.parent.take(),
+            None => return,
+        };
+        match parent {
+            Some(p) => {
+                if let Some(parent) = self.get_mut_node(&p) {
+                    parent.remove_child(node);
+                }
+            }
+            None => self.root_node.retain(|n| n != node),
+        }
+    }
+
+    pub fn append_child(&mut self, parent: &DoctreeNode, child: DoctreeNode) {
+        self.detach_node(&child);
+        if let Some(p) = self.get_mut_node(parent) {
+            p.add_child(child);
+        } else {
             return;
         }
+        if let Some(c) = self.get_mut_node(&child) {
+            c.add_parent(Some(*parent));
+        }
+    }
 
-        self.data[idx] = None;
+    // Inserts child before reference, falling back to appending if reference isn't a child
+    pub fn insert_child_before(
+        &mut self,
+        parent: &DoctreeNode,
+        child: DoctreeNode,
+        reference: &DoctreeNode,
+    ) {
+        self.detach_node(&child);
+        if let Some(p) = self.get_mut_node(parent) {
+            match p.children.iter().position(|c| c == reference) {
+                Some(idx) => p.children.insert(idx, child),
+                None => p.add_child(child),
+            }
+        } else {
+            return;
+        }
+        if let Some(c) = self.get_mut_node(&child) {
+            c.add_parent(Some(*parent));
+        }
+    }
+
+    // Moves all children of one node to the end of another's
+    pub fn reparent_children(&mut self, from: &DoctreeNode, to: &DoctreeNode) {
+        if self.get_node(to).is_none() {
+            return;
+        }
+        let children = match self.get_mut_node(from) {
+            Some(n) => std::mem::take(&mut n.children),
+            None => return,
+        };
+        for c in &children {
+            if let Some(child) = self.get_mut_node(c) {
+                child.add_parent(Some(*to));
+            }
+        }
+        if let Some(n) = self.get_mut_node(to) {
+            n.children.extend(children);
+        }
+    }
+
+    pub fn get_template_contents(&self, node: &DoctreeNode) -> Option<DoctreeNode> {
+        match &self.get_node(node)?.node_type {
+            node::NodeType::Element(element) => match &element.element_type {
+                HTMLElementType::Template(template) => template.get_contents(),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    // Assigns the light tree children of every shadow host to the slots of its shadow tree
+    pub fn assign_slots(&mut self) {
+        let hosts: Vec<(DoctreeNode, DoctreeNode)> = self
+            .get_all_nodes()
+            .into_iter()
+            .filter_map(|host| match &self.get_node(&host)?.node_type {
+                node::NodeType::Element(element) => {
+                    element.shadow_root.as_ref().map(|root| (host, root.root))
+                }
+                _ => None,
+            })
+            .collect();
+
+        for (host, root) in hosts {
+            let children = match self.get_node(&host) {
+                Some(n) => n.children.clone(),
+                None => continue,
+            };
+            let mut slots = Vec::new();
+            self.collect_slots(&root, &mut slots);
+
+            // Only the first slot in tree order with a given name gets assigned nodes
+            let mut assigned_names: Vec<String> = Vec::new();
+            for slot in slots {
+                let name = match self.get_slot_name(&slot) {
+                    Some(name) => name,
+                    None => continue,
+                };
+                let assigned = if assigned_names.contains(&name) {
+                    Vec::new()
+                } else {
+                    children
+                        .iter()
+                        .filter(|c| self.get_slottable_name(c).as_deref() == Some(name.as_str()))
+                        .cloned()
+                        .collect()
+                };
+                assigned_names.push(name);
+
+                if let Some(n) = self.get_mut_node(&slot) {
+                    if let node::NodeType::Element(element) = &mut n.node_type {
+                        if let HTMLElementType::Slot(s) = &mut element.element_type {
+                            s.set_assigned_nodes(assigned);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    fn collect_slots(&self, node: &DoctreeNode, slots: &mut Vec<DoctreeNode>) {
+        let n = match self.get_node(node) {
+            Some(n) => n,
+            None => return,
+        };
+        if self.get_slot_name(node).is_some() {
+            slots.push(*node);
+        }
+        for c in &n.children {
+            self.collect_slots(c, slots);
+        }
+    }
+
+    fn get_slot_name(&self, node: &DoctreeNode) -> Option<String> {
+        match &self.get_node(node)?.node_type {
+            node::NodeType::Element(element) => match &element.element_type {
+                HTMLElementType::Slot(slot) => Some(slot.get_name().to_string()),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    // The name of the slot a node is assigned to, if it can be slotted at all
+    fn get_slottable_name(&self, node: &DoctreeNode) -> Option<String> {
+        match &self.get_node(node)?.node_type {
+            node::NodeType::Text(_) => Some(String::new()),
+            node::NodeType::Element(element) => Some(
+                element
+                    .global_attributes
+                    .get_slot()
+                    .unwrap_or("")
+                    .to_string(),
+            ),
+            _ => None,
+        }
+    }
+
+    pub fn get_element_namespace(&self, node: &DoctreeNode) -> Option<Namespace> {
+        match &self.get_node(node)?.node_type {
+            node::NodeType::Element(element) => Some(element.namespace),
+            _ => None,
+        }
     }
 
     pub fn get_element_name(&self, node: &DoctreeNode) -> Option<String> {
         if let Some(node) = self.get_node(node) {
             if let node::NodeType::Element(element) = &node.node_type {
-                Some(element.get_name().to_lowercase())
+                Some(element.get_name().to_string())
             } else {
                 None
             }
@@ -88,8 +350,260 @@ impl Doctree {
         self.root_node.clone()
     }
 
+    // The number of nodes that haven't been removed
     pub fn len(&self) -> usize {
-        self.data.len()
+        self.data.len() - self.free_slots.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // The generation the next node will get, which is higher than that of any node so far
+    pub fn next_generation(&self) -> u64 {
+        self.next_generation
+    }
+
+    // Nodes that haven't been removed, in the order they were created
+    pub fn get_all_nodes(&self) -> Vec<DoctreeNode> {
+        self.get_nodes_from(0)
+    }
+
+    // Nodes that haven't been removed and were created from the given generation onwards,
+    // in the order they were created
+    pub fn get_nodes_from(&self, generation: u64) -> Vec<DoctreeNode> {
+        let mut nodes: Vec<DoctreeNode> = self
+            .data
+            .iter()
+            .enumerate()
+            .filter(|(_, slot)| slot.node.is_some() && slot.generation >= generation)
+            .map(|(idx, slot)| DoctreeNode {
+                idx,
+                generation: slot.generation,
+            })
+            .collect();
+        nodes.sort_by_key(|n| n.generation);
+        nodes
+    }
+
+    // Every node attached to the document, in tree order
+    pub fn get_nodes_in_tree_order(&self) -> Vec<DoctreeNode> {
+        let mut nodes = Vec::new();
+        for root in &self.root_node {
+            self.collect_descendants(root, &mut nodes);
+        }
+        nodes
+    }
+
+    // Prints the tree the way the html5lib tree construction tests write it down
+    pub fn dump(&self) -> String {
+        let mut out = String::from("#document\n");
+        for root in &self.root_node {
+            self.dump_node(root, 0, &mut out);
+        }
+        out
+    }
+
+    fn dump_node(&self, node: &DoctreeNode, depth: usize, out: &mut String) {
+        let n = match self.get_node(node) {
+            Some(n) => n,
+            None => return,
+        };
+        let indent = format!("| {}", "  ".repeat(depth));
+        match &n.node_type {
+            node::NodeType::Element(e) => {
+                let prefix = match e.namespace {
+                    Namespace::Svg => "svg ",
+                    Namespace::MathMl => "math ",
+                    _ => "",
+                };
+                out.push_str(&format!("{}<{}{}>\n", indent, prefix, e.get_name()));
+
+                let mut attributes: Vec<(String, &str)> = e
+                    .get_attributes()
+                    .iter()
+                    .map(|a| {
+                        let name = match a.namespace {
+                            Some(Namespace::XLink) => format!("xlink {}", a.name),
+                            Some(Namespace::Xml) => format!("xml {}", a.name),
+                            Some(Namespace::XmlNs) => format!("xmlns {}", a.name),
+                            _ => a.name.clone(),
+                        };
+                        (name, a.value.as_str())
+                    })
+                    .collect();
+                attributes.sort();
+                for (name, value) in attributes {
+                    out.push_str(&format!("{}  {}=\"{}\"\n", indent, name, value));
+                }
+
+                if let Some(contents) = self.get_template_contents(node) {
+                    out.push_str(&format!("{}  content\n", indent));
+                    if let Some(contents) = self.get_node(&contents) {
+                        for c in &contents.children {
+                            self.dump_node(c, depth + 2, out);
+                        }
+                    }
+                }
+            }
+            node::NodeType::Text(text) => out.push_str(&format!("{}\"{}\"\n", indent, text)),
+            node::NodeType::Comment(text) => {
+                out.push_str(&format!("{}<!-- {} -->\n", indent, text))
+            }
+            node::NodeType::DocumentType(doctype) => {
+                if doctype.public_id.is_empty() && doctype.system_id.is_empty() {
+                    out.push_str(&format!("{}<!DOCTYPE {}>\n", indent, doctype.name));
+                } else {
+                    out.push_str(&format!(
+                        "{}<!DOCTYPE {} \"{}\" \"{}\">\n",
+                        indent, doctype.name, doctype.public_id, doctype.system_id
+                    ));
+                }
+            }
+            node::NodeType::DocumentFragment | node::NodeType::Unknown(_) => {}
+        }
+        for c in &n.children {
+            self.dump_node(c, depth + 1, out);
+        }
+    }
+
+    fn collect_descendants(&self, node: &DoctreeNode, nodes: &mut Vec<DoctreeNode>) {
+        let n = match self.get_node(node) {
+            Some(n) => n,
+            None => return,
+        };
+        nodes.push(*node);
+        for c in &n.children {
+            self.collect_descendants(c, nodes);
+        }
+    }
+
+    // Controls the parser didn't associate with a form belong to the closest form they're in.
+    // Listed elements with a form attribute instead belong to the first element with that id,
+    // as long as it is a form.
+    pub fn assign_form_owners(&mut self) {
+        let nodes = self.get_nodes_in_tree_order();
+        let ids = self.get_id_map(&nodes);
+
+        let mut owners = Vec::new();
+        for node in &nodes {
+            let element = match self.get_element(node) {
+                Some(e) if e.is_form_associated() => e,
+                _ => continue,
+            };
+            let form_id = match element.get_attribute("form") {
+                Some(id) if element.is_listed() => id.to_string(),
+                _ => {
+                    if element.form_owner.is_none() {
+                        owners.push((*node, self.get_ancestor_form(node)));
+                    }
+                    continue;
+                }
+            };
+            let owner = ids
+                .get(form_id.as_str())
+                .filter(|n| self.is_form(n))
+                .cloned();
+            owners.push((*node, owner));
+        }
+
+        for (node, owner) in owners {
+            if let Some(element) = self.get_mut_element(&node) {
+                element.form_owner = owner;
+            }
+        }
+    }
+
+    // Resets the form owners that could have changed after the given nodes were inserted or
+    // removed, the way the DOM does on every mutation. That's the controls under those nodes,
+    // the ones owned by a form under them, and the ones that name their form by id.
+    pub fn reset_form_owners(&mut self, nodes: &[DoctreeNode]) {
+        let mut descendants = Vec::new();
+        for node in nodes {
+            self.collect_descendants(node, &mut descendants);
+        }
+        let moved: HashSet<DoctreeNode> = descendants.into_iter().collect();
+        let ids = self.get_id_map(&self.get_nodes_in_tree_order());
+
+        let mut owners = Vec::new();
+        for node in self.get_all_nodes() {
+            let element = match self.get_element(&node) {
+                Some(e) if e.is_form_associated() => e,
+                _ => continue,
+            };
+            let form_id = element
+                .get_attribute("form")
+                .filter(|_| element.is_listed());
+            let affected = moved.contains(&node)
+                || form_id.is_some()
+                || element
+                    .form_owner
+                    .is_some_and(|owner| moved.contains(&owner));
+            if !affected {
+                continue;
+            }
+            let ancestor_form = self.get_ancestor_form(&node);
+            // Owners that are still the closest form keep the element, which includes most
+            // of the ones the parser set
+            if form_id.is_none()
+                && element.form_owner.is_some()
+                && element.form_owner == ancestor_form
+            {
+                continue;
+            }
+            let owner = match form_id {
+                Some(id) if self.is_connected(&node) => {
+                    ids.get(id).filter(|n| self.is_form(n)).cloned()
+                }
+                _ => ancestor_form,
+            };
+            owners.push((node, owner));
+        }
+
+        for (node, owner) in owners {
+            if let Some(element) = self.get_mut_element(&node) {
+                element.form_owner = owner;
+            }
+        }
+    }
+
+    // Only the first element with an id counts
+    fn get_id_map(&self, nodes: &[DoctreeNode]) -> HashMap<&str, DoctreeNode> {
+        let mut ids: HashMap<&str, DoctreeNode> = HashMap::new();
+        for node in nodes {
+            if let Some(e) = self.get_element(node) {
+                let id = e.global_attributes.get_id();
+                if !id.is_empty() {
+                    ids.entry(id).or_insert(*node);
+                }
+            }
+        }
+        ids
+    }
+
+    // Whether the node is in the document, rather than detached or in template contents
+    fn is_connected(&self, node: &DoctreeNode) -> bool {
+        let mut current = *node;
+        while let Some(parent) = self.get_node(&current).and_then(|n| n.parent) {
+            current = parent;
+        }
+        self.root_node.contains(&current)
+    }
+
+    fn get_ancestor_form(&self, node: &DoctreeNode) -> Option<DoctreeNode> {
+        let mut ancestor = self.get_node(node)?.parent;
+        while let Some(n) = ancestor {
+            if self.is_form(&n) {
+                return Some(n);
+            }
+            ancestor = self.get_node(&n)?.parent;
+        }
+        None
+    }
+
+    fn is_form(&self, node: &DoctreeNode) -> bool {
+        self.get_element(node)
+            .is_some_and(|e| matches!(e.element_type, HTMLElementType::Form(_)))
     }
 
     pub fn get_display_data(&self, doc: &super::document::Document) -> Vec<DisplayBox> {
@@ -104,17 +618,192 @@ impl Doctree {
     }
 }
 
-#[derive(Clone, Copy, Debug)]
+impl TreeSink for Doctree {
+    fn create_node(&mut self, node: node::Node) -> DoctreeNode {
+        self.add_node(node)
+    }
+
+    fn get_element(&self, node: &DoctreeNode) -> Option<&HTMLElement> {
+        match &self.get_node(node)?.node_type {
+            node::NodeType::Element(element) => Some(element),
+            _ => None,
+        }
+    }
+
+    fn get_mut_element(&mut self, node: &DoctreeNode) -> Option<&mut HTMLElement> {
+        match &mut self.get_mut_node(node)?.node_type {
+            node::NodeType::Element(element) => Some(element),
+            _ => None,
+        }
+    }
+
+    fn get_parent(&self, node: &DoctreeNode) -> Option<DoctreeNode> {
+        self.get_node(node)?.parent
+    }
+
+    fn get_children(&self, node: &DoctreeNode) -> Vec<DoctreeNode> {
+        match self.get_node(node) {
+            Some(n) => n.children.clone(),
+            None => Vec::new(),
+        }
+    }
+
+    fn append_to_document(&mut self, child: DoctreeNode) {
+        self.detach_node(&child);
+        self.root_node.push(child);
+    }
+
+    fn append(&mut self, parent: &DoctreeNode, child: DoctreeNode) {
+        self.append_child(parent, child);
+    }
+
+    fn insert_before(&mut self, parent: &DoctreeNode, child: DoctreeNode, reference: &DoctreeNode) {
+        self.insert_child_before(parent, child, reference);
+    }
+
+    fn insert_text(
+        &mut self,
+        parent: &DoctreeNode,
+        before: Option<&DoctreeNode>,
+        text: &str,
+        span: SourceSpan,
+    ) {
+        // Adjacent text is merged into the previous text node
+        let previous_sibling = self.get_node(parent).and_then(|p| match before {
+            Some(before) => p
+                .children
+                .iter()
+                .position(|c| c == before)
+                .and_then(|idx| idx.checked_sub(1))
+                .map(|idx| p.children[idx]),
+            None => p.children.last().cloned(),
+        });
+        if let Some(sibling) = previous_sibling {
+            if let Some(node) = self.get_mut_node(&sibling) {
+                if let node::NodeType::Text(ref mut existing) = node.node_type {
+                    existing.push_str(text);
+                    node.span = node.span.map(|s| s.extend_to(&span));
+                    return;
+                }
+            }
+        }
+
+        let mut node = node::Node::new(node::NodeType::Text(text.to_string()));
+        node.span = Some(span);
+        let node = self.add_node(node);
+        match before {
+            Some(before) => self.insert_child_before(parent, node, before),
+            None => self.append_child(parent, node),
+        }
+    }
+
+    fn reparent_children(&mut self, from: &DoctreeNode, to: &DoctreeNode) {
+        Doctree::reparent_children(self, from, to);
+    }
+
+    fn detach(&mut self, node: &DoctreeNode) {
+        self.detach_node(node);
+    }
+
+    fn remove(&mut self, node: &DoctreeNode) {
+        self.remove_node(node);
+    }
+
+    fn finish(&mut self) {
+        self.assign_slots();
+        self.assign_form_owners();
+    }
+}
+
+// The generation tells apart the nodes that had the same slot over time
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub struct DoctreeNode {
-    pub idx: usize,
+    idx: usize,
+    generation: u64,
 }
 
-impl PartialEq for DoctreeNode {
-    fn eq(&self, other: &Self) -> bool {
-        self.idx == other.idx
+impl DoctreeNode {
+    // Tree sinks other than Doctree hand out their own handles
+    pub fn new(idx: usize, generation: u64) -> DoctreeNode {
+        DoctreeNode { idx, generation }
+    }
+
+    pub fn idx(&self) -> usize {
+        self.idx
     }
 
-    fn ne(&self, other: &Self) -> bool {
-        self.idx != other.idx
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::parser::parser::parse_document;
+
+    fn element(name: &str) -> node::Node {
+        node::Node::new(node::NodeType::Element(HTMLElement::new(name.to_string())))
+    }
+
+    #[test]
+    fn test_slot_reuse() {
+        let mut doctree = Doctree::new();
+        let parent = doctree.add_root_node(element("div"));
+        let child = doctree.add_node(element("p"));
+        let text = doctree.add_node(node::Node::new(node::NodeType::Text("a".to_string())));
+        doctree.append_child(&parent, child);
+        doctree.append_child(&child, text);
+
+        // Removing a node frees everything under it
+        doctree.remove_node(&child);
+        assert_eq!(doctree.len(), 1);
+        assert!(doctree.get_node(&child).is_none());
+        assert!(doctree.get_node(&text).is_none());
+        assert!(doctree.get_node(&parent).unwrap().children.is_empty());
+
+        // New nodes take over the free slots, without the old handles resolving to them
+        let reused = doctree.add_node(element("span"));
+        assert!(reused.idx() == child.idx() || reused.idx() == text.idx());
+        assert!(doctree.get_node(&child).is_none());
+        assert!(doctree.get_node(&text).is_none());
+        assert_eq!(doctree.get_element_name(&reused).as_deref(), Some("span"));
+        assert_eq!(doctree.get_nodes_from(reused.generation()), vec![reused]);
+    }
+
+    #[test]
+    fn test_compact() {
+        let mut doc = parse_document("<p>a</p><template><b>b</b></template><div><i>c</i></div>");
+        let p = doc
+            .doctree
+            .get_all_nodes()
+            .into_iter()
+            .find(|n| doc.doctree.get_element_name(n).as_deref() == Some("p"))
+            .unwrap();
+        let before = doc.doctree.dump();
+        let removed = doc.doctree.get_node(&p).unwrap().children.len() + 1;
+        let len = doc.doctree.len();
+
+        // Removing the p leaves holes that compaction fills in
+        doc.doctree.remove_node(&p);
+        let remapping = doc.compact();
+        assert_eq!(doc.doctree.len(), len - removed);
+        assert_eq!(doc.doctree.data.len(), doc.doctree.len());
+        assert!(!remapping.is_empty());
+        for (old, new) in &remapping {
+            assert!(doc.doctree.get_node(old).is_none());
+            assert!(doc.doctree.get_node(new).is_some());
+        }
+
+        // Parents, children and template contents all point at the moved nodes
+        assert_eq!(
+            doc.doctree.dump(),
+            before.replace("|     <p>\n|       \"a\"\n", "")
+        );
+        for n in doc.doctree.get_all_nodes() {
+            for c in &doc.doctree.get_node(&n).unwrap().children {
+                assert_eq!(doc.doctree.get_node(c).unwrap().parent, Some(n));
+            }
+        }
     }
 }
@@ -1,17 +1,32 @@
+use std::collections::HashMap;
+
 use crate::display_data::display_box::{self, DisplayBox};
 use crate::document::doctree;
+use crate::document::node::NodeType;
+use encoding_rs::Encoding;
 
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum QuirksMode {
     Off,
     LimitedQuirks,
     Quirks,
 }
 
+// How sure the parser is about the encoding the document was decoded with
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EncodingConfidence {
+    Tentative,
+    Certain,
+    Irrelevant,
+}
+
 pub struct Document {
     pub doctree: doctree::Doctree,
     doctype: String,
     quirksmode: QuirksMode,
-    pending_parsing_blocking_script: Option<()>, // TODO
+    encoding: &'static Encoding,
+    encoding_confidence: EncodingConfidence,
+    pending_parsing_blocking_script: Option<doctree::DoctreeNode>,
 
     width: u64,
     height: u64,
@@ -23,6 +38,8 @@ impl Document {
             doctree: doctree::Doctree::new(),
             doctype: "".to_string(),
             quirksmode: QuirksMode::Off,
+            encoding: encoding_rs::UTF_8,
+            encoding_confidence: EncodingConfidence::Irrelevant,
             pending_parsing_blocking_script: None,
             width: 800,
             height: 600,
@@ -41,10 +58,68 @@ impl Document {
         self.quirksmode = new_mode;
     }
 
-    pub fn get_pending_parse_blocking(&self) -> Option<()> {
+    pub fn get_quirks_mode(&self) -> QuirksMode {
+        self.quirksmode
+    }
+
+    pub fn set_encoding(&mut self, encoding: &'static Encoding, confidence: EncodingConfidence) {
+        self.encoding = encoding;
+        self.encoding_confidence = confidence;
+    }
+
+    // The WHATWG name of the encoding, e.g. "windows-1252"
+    pub fn get_encoding(&self) -> &'static str {
+        self.encoding.name()
+    }
+
+    pub fn get_encoding_confidence(&self) -> EncodingConfidence {
+        self.encoding_confidence
+    }
+
+    // The controls owned by a form element, in tree order
+    pub fn get_form_controls(&self, form: &doctree::DoctreeNode) -> Vec<doctree::DoctreeNode> {
+        self.doctree
+            .get_nodes_in_tree_order()
+            .into_iter()
+            .filter(|n| {
+                self.doctree
+                    .get_node(n)
+                    .is_some_and(|node| match &node.node_type {
+                        NodeType::Element(e) => {
+                            e.is_listed() && e.form_owner.as_ref() == Some(form)
+                        }
+                        _ => false,
+                    })
+            })
+            .collect()
+    }
+
+    pub fn get_form_owner(&self, node: &doctree::DoctreeNode) -> Option<doctree::DoctreeNode> {
+        match &self.doctree.get_node(node)?.node_type {
+            NodeType::Element(e) => e.form_owner,
+            _ => None,
+        }
+    }
+
+    pub fn get_pending_parse_blocking(&self) -> Option<doctree::DoctreeNode> {
         self.pending_parsing_blocking_script
     }
 
+    pub fn set_pending_parse_blocking(&mut self, script: Option<doctree::DoctreeNode>) {
+        self.pending_parsing_blocking_script = script;
+    }
+
+    // Compacts the tree, and updates the handles the document holds itself
+    pub fn compact(&mut self) -> HashMap<doctree::DoctreeNode, doctree::DoctreeNode> {
+        let remapping = self.doctree.compact();
+        if let Some(script) = &mut self.pending_parsing_blocking_script {
+            if let Some(new) = remapping.get(script) {
+                *script = *new;
+            }
+        }
+        remapping
+    }
+
     pub fn set_window_dimensions(&mut self, width: u64, height: u64) {
         self.width = width;
         self.height = height;
@@ -1,3 +1,4 @@
 pub mod doctree;
 pub mod document;
+pub mod mutation;
 pub mod node;
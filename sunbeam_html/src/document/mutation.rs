@@ -0,0 +1,585 @@
+// Tree mutations with the checks the DOM does before inserting, moving or removing nodes, so
+// the parent and children of every node stay in sync and the tree never loops back on itself.
+use std::fmt;
+
+use crate::document::doctree::{Doctree, DoctreeNode};
+use crate::document::document::Document;
+use crate::document::node::{Node, NodeType};
+use crate::html_elements::HTMLElementType;
+
+// Named after the DOMException each one would be
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DomError {
+    // The node can't go there, or it would end up inside itself
+    HierarchyRequest,
+    // The node isn't a child of the given parent, or has been removed
+    NotFound,
+}
+
+impl fmt::Display for DomError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DomError::HierarchyRequest => write!(f, "HierarchyRequestError"),
+            DomError::NotFound => write!(f, "NotFoundError"),
+        }
+    }
+}
+
+impl std::error::Error for DomError {}
+
+// How much of a node SubtreeCopy takes along
+#[derive(Clone, Copy, PartialEq)]
+enum CopyMode {
+    // Cloning only the node
+    Shallow,
+    // Cloning everything under it too
+    Deep,
+    // Moving the node to another doctree, which keeps everything including the shadow roots
+    // that can't be cloned
+    Move,
+}
+
+// A copy of a subtree that isn't in any tree yet, so it can be made from one doctree and put
+// into another, or into the same one
+struct SubtreeCopy {
+    node: Node,
+    children: Vec<SubtreeCopy>,
+    template_contents: Option<Vec<SubtreeCopy>>,
+    shadow_root: Option<Vec<SubtreeCopy>>,
+}
+
+impl SubtreeCopy {
+    fn new(doctree: &Doctree, handle: &DoctreeNode, mode: CopyMode) -> Option<SubtreeCopy> {
+        let original = doctree.get_node(handle)?;
+        let mut node = original.clone();
+        node.children = Vec::new();
+        node.parent = None;
+
+        let copy_children = |parent: &DoctreeNode| -> Vec<SubtreeCopy> {
+            match doctree.get_node(parent) {
+                Some(p) => p
+                    .children
+                    .iter()
+                    .filter_map(|c| {
+                        let mode = if mode == CopyMode::Move {
+                            CopyMode::Move
+                        } else {
+                            CopyMode::Deep
+                        };
+                        SubtreeCopy::new(doctree, c, mode)
+                    })
+                    .collect(),
+                None => Vec::new(),
+            }
+        };
+        let mut template_contents = None;
+        let mut shadow_root = None;
+        if let NodeType::Element(e) = &mut node.node_type {
+            // The copy doesn't belong to the original's form, and neither does a moved node,
+            // since the handle is from the other doctree. Both are worked out again once the
+            // copy is inserted.
+            e.form_owner = None;
+            if let HTMLElementType::Slot(slot) = &mut e.element_type {
+                slot.set_assigned_nodes(Vec::new());
+            }
+            if let HTMLElementType::Template(template) = &e.element_type {
+                template_contents = Some(match (mode, template.get_contents()) {
+                    (CopyMode::Shallow, _) | (_, None) => Vec::new(),
+                    (_, Some(contents)) => copy_children(&contents),
+                });
+            }
+            // Only clonable shadow roots come along with a clone
+            if let Some(root) = e
+                .shadow_root
+                .as_ref()
+                .filter(|root| root.clonable || mode == CopyMode::Move)
+            {
+                shadow_root = Some(copy_children(&root.root));
+            } else {
+                e.shadow_root = None;
+            }
+        }
+
+        Some(SubtreeCopy {
+            node,
+            children: if mode == CopyMode::Shallow {
+                Vec::new()
+            } else {
+                copy_children(handle)
+            },
+            template_contents,
+            shadow_root,
+        })
+    }
+
+    fn insert_into(self, doctree: &mut Doctree) -> DoctreeNode {
+        let handle = doctree.add_node(self.node);
+        for child in self.children {
+            let child = child.insert_into(doctree);
+            doctree.append_child(&handle, child);
+        }
+
+        if let Some(contents) = self.template_contents {
+            let fragment = Self::insert_fragment(doctree, contents);
+            if let Some(NodeType::Element(e)) =
+                doctree.get_mut_node(&handle).map(|n| &mut n.node_type)
+            {
+                if let HTMLElementType::Template(template) = &mut e.element_type {
+                    template.set_contents(fragment);
+                }
+            }
+        }
+        if let Some(shadow_root) = self.shadow_root {
+            let fragment = Self::insert_fragment(doctree, shadow_root);
+            if let Some(NodeType::Element(e)) =
+                doctree.get_mut_node(&handle).map(|n| &mut n.node_type)
+            {
+                if let Some(root) = &mut e.shadow_root {
+                    root.root = fragment;
+                }
+            }
+        }
+        handle
+    }
+
+    fn insert_fragment(doctree: &mut Doctree, children: Vec<SubtreeCopy>) -> DoctreeNode {
+        let fragment = doctree.add_node(Node::new(NodeType::DocumentFragment));
+        for child in children {
+            let child = child.insert_into(doctree);
+            doctree.append_child(&fragment, child);
+        }
+        fragment
+    }
+}
+
+impl Document {
+    pub fn append_child(
+        &mut self,
+        parent: &DoctreeNode,
+        node: &DoctreeNode,
+    ) -> Result<DoctreeNode, DomError> {
+        self.insert_before(parent, node, None)
+    }
+
+    // Inserts the node before reference, or at the end without one. Nodes that are already in
+    // the tree get moved, and inserting a document fragment moves its children instead.
+    pub fn insert_before(
+        &mut self,
+        parent: &DoctreeNode,
+        node: &DoctreeNode,
+        reference: Option<&DoctreeNode>,
+    ) -> Result<DoctreeNode, DomError> {
+        self.check_insertion(parent, node)?;
+        if let Some(reference) = reference {
+            if self.doctree.get_node(reference).and_then(|n| n.parent) != Some(*parent) {
+                return Err(DomError::NotFound);
+            }
+        }
+
+        // The node can't go before itself, so it goes before whatever comes after it
+        let reference = match reference {
+            Some(reference) if reference == node => self.next_sibling(node),
+            reference => reference.copied(),
+        };
+        self.insert(parent, node, reference.as_ref());
+        Ok(*node)
+    }
+
+    // Puts node where child was, and returns child, which is left detached
+    pub fn replace_child(
+        &mut self,
+        parent: &DoctreeNode,
+        node: &DoctreeNode,
+        child: &DoctreeNode,
+    ) -> Result<DoctreeNode, DomError> {
+        self.check_insertion(parent, node)?;
+        if self.doctree.get_node(child).and_then(|n| n.parent) != Some(*parent) {
+            return Err(DomError::NotFound);
+        }
+
+        let mut reference = self.next_sibling(child);
+        if reference.as_ref() == Some(node) {
+            reference = self.next_sibling(node);
+        }
+        if child != node {
+            self.doctree.detach_node(child);
+        }
+        self.insert(parent, node, reference.as_ref());
+        self.update_after_mutation(&[*child]);
+        Ok(*child)
+    }
+
+    // Detaches the child, which stays around so it can be inserted again. Use
+    // Doctree::remove_node to get rid of it for good.
+    pub fn remove_child(
+        &mut self,
+        parent: &DoctreeNode,
+        child: &DoctreeNode,
+    ) -> Result<DoctreeNode, DomError> {
+        if self.doctree.get_node(child).and_then(|n| n.parent) != Some(*parent) {
+            return Err(DomError::NotFound);
+        }
+        self.doctree.detach_node(child);
+        self.update_after_mutation(&[*child]);
+        Ok(*child)
+    }
+
+    // Makes a detached copy of the node, and with deep of everything under it too
+    pub fn clone_node(&mut self, node: &DoctreeNode, deep: bool) -> Option<DoctreeNode> {
+        let mode = if deep {
+            CopyMode::Deep
+        } else {
+            CopyMode::Shallow
+        };
+        let copy = SubtreeCopy::new(&self.doctree, node, mode)?;
+        Some(copy.insert_into(&mut self.doctree))
+    }
+
+    // Moves a node and everything under it out of another document and into this one,
+    // detached. Shadow roots and template contents come along as they are. The node gets a
+    // new handle here, and the old one stops resolving.
+    pub fn adopt_node(
+        &mut self,
+        from: &mut Document,
+        node: &DoctreeNode,
+    ) -> Result<DoctreeNode, DomError> {
+        if from.is_shadow_root(node) {
+            return Err(DomError::HierarchyRequest);
+        }
+        let moved =
+            SubtreeCopy::new(&from.doctree, node, CopyMode::Move).ok_or(DomError::NotFound)?;
+        from.doctree.detach_node(node);
+        from.update_after_mutation(&[*node]);
+        from.doctree.remove_node(node);
+        let moved = moved.insert_into(&mut self.doctree);
+        self.update_after_mutation(&[moved]);
+        Ok(moved)
+    }
+
+    // Ensures the node can be inserted into parent, the way the DOM does before pre-insertion
+    fn check_insertion(&self, parent: &DoctreeNode, node: &DoctreeNode) -> Result<(), DomError> {
+        let parent_node = self.doctree.get_node(parent).ok_or(DomError::NotFound)?;
+        let inserted = self.doctree.get_node(node).ok_or(DomError::NotFound)?;
+        if !matches!(
+            parent_node.node_type,
+            NodeType::Element(_) | NodeType::DocumentFragment
+        ) {
+            return Err(DomError::HierarchyRequest);
+        }
+        // Doctypes only go in the document itself
+        if matches!(
+            inserted.node_type,
+            NodeType::DocumentType(_) | NodeType::Unknown(_)
+        ) || self.is_shadow_root(node)
+        {
+            return Err(DomError::HierarchyRequest);
+        }
+        if self.is_host_including_inclusive_ancestor(node, parent) {
+            return Err(DomError::HierarchyRequest);
+        }
+        Ok(())
+    }
+
+    // Ancestors carry on from template contents and shadow trees to the element holding them
+    fn is_host_including_inclusive_ancestor(
+        &self,
+        ancestor: &DoctreeNode,
+        node: &DoctreeNode,
+    ) -> bool {
+        let mut current = Some(*node);
+        while let Some(n) = current {
+            if n == *ancestor {
+                return true;
+            }
+            current = match self.doctree.get_node(&n) {
+                Some(Node {
+                    parent: Some(parent),
+                    ..
+                }) => Some(*parent),
+                Some(Node {
+                    node_type: NodeType::DocumentFragment,
+                    ..
+                }) => self.fragment_host(&n),
+                _ => None,
+            };
+        }
+        false
+    }
+
+    // Fragments don't know what they belong to, so this looks through every element
+    fn fragment_host(&self, fragment: &DoctreeNode) -> Option<DoctreeNode> {
+        self.doctree.get_all_nodes().into_iter().find(|n| {
+            match self.doctree.get_node(n).map(|n| &n.node_type) {
+                Some(NodeType::Element(e)) => {
+                    e.shadow_root.as_ref().is_some_and(|r| r.root == *fragment)
+                        || matches!(&e.element_type, HTMLElementType::Template(t)
+                            if t.get_contents() == Some(*fragment))
+                }
+                _ => false,
+            }
+        })
+    }
+
+    fn is_shadow_root(&self, node: &DoctreeNode) -> bool {
+        match self.doctree.get_node(node).map(|n| &n.node_type) {
+            Some(NodeType::DocumentFragment) => {}
+            _ => return false,
+        }
+        self.doctree.get_all_nodes().iter().any(|n| {
+            matches!(self.doctree.get_node(n).map(|n| &n.node_type),
+                Some(NodeType::Element(e)) if e.shadow_root.as_ref().is_some_and(|r| r.root == *node))
+        })
+    }
+
+    fn next_sibling(&self, node: &DoctreeNode) -> Option<DoctreeNode> {
+        let parent = self.doctree.get_node(node)?.parent?;
+        let siblings = &self.doctree.get_node(&parent)?.children;
+        let position = siblings.iter().position(|c| c == node)?;
+        siblings.get(position + 1).copied()
+    }
+
+    // Form owners and slot assignments follow the nodes around, like the DOM keeps them up to
+    // date on every insertion and removal
+    fn update_after_mutation(&mut self, nodes: &[DoctreeNode]) {
+        self.doctree.reset_form_owners(nodes);
+        self.doctree.assign_slots();
+    }
+
+    // Moves the node, or the children of a fragment, into parent before reference
+    fn insert(
+        &mut self,
+        parent: &DoctreeNode,
+        node: &DoctreeNode,
+        reference: Option<&DoctreeNode>,
+    ) {
+        let nodes = match self.doctree.get_node(node) {
+            Some(Node {
+                node_type: NodeType::DocumentFragment,
+                children,
+                ..
+            }) => children.clone(),
+            Some(_) => vec![*node],
+            None => return,
+        };
+        for n in &nodes {
+            match reference {
+                Some(reference) => self.doctree.insert_child_before(parent, *n, reference),
+                None => self.doctree.append_child(parent, *n),
+            }
+        }
+        self.update_after_mutation(&nodes);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::parser::parser::parse_document;
+    use crate::parser::tree_sink::TreeSink;
+
+    fn find(doc: &Document, name: &str) -> DoctreeNode {
+        doc.doctree
+            .get_all_nodes()
+            .into_iter()
+            .find(|n| doc.doctree.get_element_name(n).as_deref() == Some(name))
+            .unwrap()
+    }
+
+    fn body_dump(doc: &Document) -> String {
+        let dump = doc.doctree.dump();
+        let start = dump.find("|   <body>\n").unwrap() + "|   <body>\n".len();
+        dump[start..].to_string()
+    }
+
+    #[test]
+    fn test_insertion() {
+        let mut doc = parse_document("<div><p>a</p><i>b</i></div><span></span>");
+        let (div, p, i, span) = (
+            find(&doc, "div"),
+            find(&doc, "p"),
+            find(&doc, "i"),
+            find(&doc, "span"),
+        );
+
+        // Moving a node takes it out of its old parent
+        assert_eq!(doc.insert_before(&div, &span, Some(&p)), Ok(span));
+        assert_eq!(doc.append_child(&div, &p), Ok(p));
+        assert_eq!(
+            body_dump(&doc),
+            "|     <div>\n|       <span>\n|       <i>\n|         \"b\"\n|       <p>\n|         \"a\"\n"
+        );
+        assert_eq!(doc.doctree.get_node(&p).unwrap().parent, Some(div));
+
+        // Inserting before itself leaves the node where it is
+        assert_eq!(doc.insert_before(&div, &i, Some(&i)), Ok(i));
+        assert_eq!(
+            doc.doctree.get_node(&div).unwrap().children,
+            vec![span, i, p]
+        );
+
+        assert_eq!(doc.replace_child(&div, &p, &span), Ok(span));
+        assert_eq!(doc.doctree.get_node(&div).unwrap().children, vec![p, i]);
+        assert_eq!(doc.doctree.get_node(&span).unwrap().parent, None);
+
+        assert_eq!(doc.remove_child(&div, &i), Ok(i));
+        assert_eq!(doc.remove_child(&div, &i), Err(DomError::NotFound));
+        assert_eq!(doc.doctree.get_node(&div).unwrap().children, vec![p]);
+
+        // The reference has to be a child of the parent
+        assert_eq!(
+            doc.insert_before(&div, &i, Some(&span)),
+            Err(DomError::NotFound)
+        );
+        // Text can't have children
+        let text = doc.doctree.get_node(&p).unwrap().children[0];
+        assert_eq!(doc.append_child(&text, &i), Err(DomError::HierarchyRequest));
+    }
+
+    #[test]
+    fn test_cycles_are_rejected() {
+        let mut doc = parse_document("<div><p><b></b></p></div><template><i></i></template>");
+        let (div, p, b, i) = (
+            find(&doc, "div"),
+            find(&doc, "p"),
+            find(&doc, "b"),
+            find(&doc, "i"),
+        );
+        let template = find(&doc, "template");
+
+        assert_eq!(doc.append_child(&b, &div), Err(DomError::HierarchyRequest));
+        assert_eq!(doc.append_child(&p, &p), Err(DomError::HierarchyRequest));
+        assert_eq!(
+            doc.replace_child(&p, &div, &b),
+            Err(DomError::HierarchyRequest)
+        );
+        // Template contents lead back to the template
+        assert_eq!(
+            doc.append_child(&i, &template),
+            Err(DomError::HierarchyRequest)
+        );
+        assert_eq!(doc.doctree.get_node(&p).unwrap().children, vec![b]);
+
+        // Inserting a fragment moves its children over
+        let contents = doc.doctree.get_template_contents(&template).unwrap();
+        assert_eq!(doc.append_child(&b, &contents), Ok(contents));
+        assert_eq!(doc.doctree.get_node(&b).unwrap().children, vec![i]);
+        assert!(doc.doctree.get_node(&contents).unwrap().children.is_empty());
+    }
+
+    #[test]
+    fn test_clone_and_adopt() {
+        let mut doc = parse_document("<div id=a><p>x</p><template><b>y</b></template></div>");
+        let div = find(&doc, "div");
+
+        let shallow = doc.clone_node(&div, false).unwrap();
+        assert!(doc.doctree.get_node(&shallow).unwrap().children.is_empty());
+        assert_eq!(
+            doc.doctree
+                .get_element(&shallow)
+                .unwrap()
+                .get_attribute("id"),
+            Some("a")
+        );
+
+        let deep = doc.clone_node(&div, true).unwrap();
+        let body = find(&doc, "body");
+        doc.append_child(&body, &deep).unwrap();
+        let dump = body_dump(&doc);
+        let (original, copy) = dump.split_at(dump.len() / 2);
+        assert_eq!(original, copy);
+        // Template contents are copied rather than shared
+        let template = doc.doctree.get_node(&deep).unwrap().children[1];
+        assert_ne!(
+            doc.doctree.get_template_contents(&template),
+            doc.doctree
+                .get_template_contents(&doc.doctree.get_node(&div).unwrap().children[1])
+        );
+
+        let mut other = parse_document("<p>z</p>");
+        let p = find(&other, "p");
+        let adopted = doc.adopt_node(&mut other, &p).unwrap();
+        assert!(other.doctree.get_node(&p).is_none());
+        assert!(other.doctree.get_all_nodes().iter().all(|n| other
+            .doctree
+            .get_element_name(n)
+            .as_deref()
+            != Some("p")));
+        assert_eq!(doc.doctree.get_node(&adopted).unwrap().parent, None);
+        assert_eq!(doc.doctree.get_node(&adopted).unwrap().children.len(), 1);
+
+        // Adopting keeps shadow roots that a clone would leave behind, and the form it holds
+        let mut other = parse_document(
+            "<div><template shadowrootmode=open><slot></slot></template>x</div>\
+            <form><input></form>",
+        );
+        let (host, form) = (find(&other, "div"), find(&other, "form"));
+        let host = doc.adopt_node(&mut other, &host).unwrap();
+        let root = match doc.doctree.get_element(&host).unwrap().shadow_root.as_ref() {
+            Some(root) => root.root,
+            None => panic!("The shadow root was dropped"),
+        };
+        let slot = doc.doctree.get_node(&root).unwrap().children[0];
+        let text = doc.doctree.get_node(&host).unwrap().children[0];
+        assert_eq!(assigned_nodes(&doc, &slot), vec![text]);
+        let form = doc.adopt_node(&mut other, &form).unwrap();
+        let input = doc.doctree.get_node(&form).unwrap().children[0];
+        assert_eq!(form_owner(&doc, &input), Some(form));
+    }
+
+    fn form_owner(doc: &Document, node: &DoctreeNode) -> Option<DoctreeNode> {
+        doc.doctree.get_element(node).unwrap().form_owner
+    }
+
+    fn assigned_nodes(doc: &Document, slot: &DoctreeNode) -> Vec<DoctreeNode> {
+        match &doc.doctree.get_element(slot).unwrap().element_type {
+            HTMLElementType::Slot(s) => s.get_assigned_nodes().clone(),
+            _ => panic!("Not a slot"),
+        }
+    }
+
+    #[test]
+    fn test_mutations_update_owners_and_slots() {
+        let mut doc = parse_document("<form id=f><input></form><div></div><input form=f>");
+        let (form, div, body) = (find(&doc, "form"), find(&doc, "div"), find(&doc, "body"));
+        let inner = doc.doctree.get_node(&form).unwrap().children[0];
+        let named = *doc
+            .doctree
+            .get_node(&body)
+            .unwrap()
+            .children
+            .last()
+            .unwrap();
+        assert_eq!(form_owner(&doc, &inner), Some(form));
+        assert_eq!(form_owner(&doc, &named), Some(form));
+
+        // A control leaves its form along with it
+        doc.append_child(&div, &inner).unwrap();
+        assert_eq!(form_owner(&doc, &inner), None);
+        doc.append_child(&form, &inner).unwrap();
+        assert_eq!(form_owner(&doc, &inner), Some(form));
+
+        // Controls that name their form lose it while it's out of the document
+        doc.remove_child(&body, &form).unwrap();
+        assert_eq!(form_owner(&doc, &named), None);
+        assert_eq!(form_owner(&doc, &inner), Some(form));
+        doc.insert_before(&body, &form, Some(&div)).unwrap();
+        assert_eq!(form_owner(&doc, &named), Some(form));
+
+        // Slotted children are assigned to wherever they are now
+        let mut doc = parse_document(
+            "<body><my-element><template shadowrootmode=open><slot name=a></slot></template>\
+            <span slot=a>x</span></my-element><div></div>",
+        );
+        let (host, span, div) = (
+            find(&doc, "my-element"),
+            find(&doc, "span"),
+            find(&doc, "div"),
+        );
+        let slot = find(&doc, "slot");
+        assert_eq!(assigned_nodes(&doc, &slot), vec![span]);
+        doc.append_child(&div, &span).unwrap();
+        assert!(assigned_nodes(&doc, &slot).is_empty());
+        doc.append_child(&host, &span).unwrap();
+        assert_eq!(assigned_nodes(&doc, &slot), vec![span]);
+    }
+}
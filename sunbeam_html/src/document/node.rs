@@ -11,6 +11,7 @@ use crate::{
 };
 
 use super::document;
+use crate::parser::span::SourceSpan;
 
 #[derive(Clone, Debug)]
 pub struct DocumentType {
@@ -25,6 +26,8 @@ pub enum NodeType {
     Element(html_elements::HTMLElement),
     Comment(String),
     DocumentType(DocumentType),
+    // Not part of the main tree, used for template contents
+    DocumentFragment,
     Unknown(String),
 }
 
@@ -41,6 +44,9 @@ pub struct Node {
     pub children: Vec<doctree::DoctreeNode>,
     pub parent: Option<doctree::DoctreeNode>,
     pub node_type: NodeType,
+    // Where the node came from in the source, if it was parsed
+    pub span: Option<SourceSpan>,
+    pub attribute_spans: Vec<(String, SourceSpan)>,
 }
 
 impl Node {
@@ -49,6 +55,8 @@ impl Node {
             children: Vec::new(),
             parent: None,
             node_type,
+            span: None,
+            attribute_spans: Vec::new(),
         }
     }
 
@@ -71,10 +79,62 @@ impl Node {
         self.parent = parent;
     }
 
+    // Updates every handle the node holds, for when the doctree moves nodes around
+    pub fn remap_handles(&mut self, remap: &dyn Fn(&mut doctree::DoctreeNode)) {
+        self.children.iter_mut().for_each(remap);
+        if let Some(parent) = &mut self.parent {
+            remap(parent);
+        }
+        let element = match &mut self.node_type {
+            NodeType::Element(e) => e,
+            _ => return,
+        };
+        if let Some(owner) = &mut element.form_owner {
+            remap(owner);
+        }
+        if let Some(shadow_root) = &mut element.shadow_root {
+            remap(&mut shadow_root.root);
+        }
+        match &mut element.element_type {
+            HTMLElementType::Template(template) => {
+                if let Some(mut contents) = template.get_contents() {
+                    remap(&mut contents);
+                    template.set_contents(contents);
+                }
+            }
+            HTMLElementType::Slot(slot) => {
+                let mut assigned = slot.get_assigned_nodes().clone();
+                assigned.iter_mut().for_each(remap);
+                slot.set_assigned_nodes(assigned);
+            }
+            _ => {}
+        }
+    }
+
     pub fn add_attribute(&mut self, name: String, value: String) {
         self.node_type.add_attribute(name, value);
     }
 
+    // Shadow hosts render their shadow tree, and slots render the nodes assigned to them
+    fn get_rendered_children<'a>(
+        &'a self,
+        doc: &'a document::Document,
+    ) -> &'a Vec<doctree::DoctreeNode> {
+        if let NodeType::Element(e) = &self.node_type {
+            if let Some(shadow_root) = &e.shadow_root {
+                if let Some(root) = doc.doctree.get_node(&shadow_root.root) {
+                    return &root.children;
+                }
+            }
+            if let HTMLElementType::Slot(slot) = &e.element_type {
+                if !slot.get_assigned_nodes().is_empty() {
+                    return slot.get_assigned_nodes();
+                }
+            }
+        }
+        &self.children
+    }
+
     pub async fn get_node_displaybox(&self, doc: &document::Document) -> DisplayBox {
         let mut res = DisplayBox::new();
 
@@ -86,8 +146,11 @@ impl Node {
                 });
             }
             NodeType::Element(e) => {
-                if matches!(e.element_type, HTMLElementType::Head(_)) {
-                    // Don't render anything under head
+                if matches!(
+                    e.element_type,
+                    HTMLElementType::Head(_) | HTMLElementType::Template(_)
+                ) {
+                    // Don't render anything under head, and template contents are inert
                     return res;
                 }
                 res = e.get_display_box();
@@ -101,7 +164,7 @@ impl Node {
         };
 
         let mut futures = Vec::new();
-        for n in &self.children {
+        for n in self.get_rendered_children(doc) {
             if let Some(node) = doc.doctree.get_node(&n) {
                 futures.push(node.get_node_displaybox(&doc));
             }
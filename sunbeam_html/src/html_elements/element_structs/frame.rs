@@ -0,0 +1,65 @@
+use crate::html_elements::common_attributes;
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum FrameScrollingOption {
+    #[default]
+    Auto,
+    Yes,
+    No,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame {
+    frameborder: bool,
+    marginheight: Option<usize>,
+    marginwidth: Option<usize>,
+    name: Option<String>,
+    noresize: bool,
+    scrolling: FrameScrollingOption,
+    src: Option<String>,
+}
+
+impl Default for Frame {
+    fn default() -> Self {
+        Frame {
+            frameborder: true,
+            marginheight: None,
+            marginwidth: None,
+            name: None,
+            noresize: false,
+            scrolling: FrameScrollingOption::default(),
+            src: None,
+        }
+    }
+}
+
+impl Frame {
+    pub fn get_name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    pub fn get_src(&self) -> Option<&str> {
+        self.src.as_deref()
+    }
+}
+
+impl common_attributes::Element for Frame {
+    fn add_attribute(&mut self, name: String, value: String) {
+        match name.as_str() {
+            "frameborder" => self.frameborder = value != "0",
+            "marginheight" => self.marginheight = value.parse().ok(),
+            "marginwidth" => self.marginwidth = value.parse().ok(),
+            "name" => self.name = Some(value),
+            "noresize" => self.noresize = true,
+            "scrolling" => {
+                self.scrolling = match value.to_ascii_lowercase().as_str() {
+                    "yes" => FrameScrollingOption::Yes,
+                    "no" => FrameScrollingOption::No,
+                    _ => FrameScrollingOption::Auto,
+                }
+            }
+            "src" => self.src = Some(value),
+            _ => {}
+        }
+    }
+}
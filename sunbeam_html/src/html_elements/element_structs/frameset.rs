@@ -0,0 +1,36 @@
+use crate::html_elements::common_attributes;
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Frameset {
+    // Kept as written, e.g. "20%,*,2*"
+    rows: Vec<String>,
+    cols: Vec<String>,
+}
+
+impl Frameset {
+    pub fn derive_lengths(value: &str) -> Vec<String> {
+        value
+            .split(',')
+            .map(|s| s.trim().to_string())
+            .filter(|s| !s.is_empty())
+            .collect()
+    }
+
+    pub fn get_rows(&self) -> &Vec<String> {
+        &self.rows
+    }
+
+    pub fn get_cols(&self) -> &Vec<String> {
+        &self.cols
+    }
+}
+
+impl common_attributes::Element for Frameset {
+    fn add_attribute(&mut self, name: String, value: String) {
+        match name.as_str() {
+            "rows" => self.rows = Frameset::derive_lengths(&value),
+            "cols" => self.cols = Frameset::derive_lengths(&value),
+            _ => {}
+        }
+    }
+}
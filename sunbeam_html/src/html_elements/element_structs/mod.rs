@@ -36,6 +36,8 @@ pub mod figcaption;
 pub mod figure;
 pub mod footer;
 pub mod form;
+pub mod frame;
+pub mod frameset;
 pub mod h1;
 pub mod h2;
 pub mod h3;
@@ -13,10 +13,10 @@ pub enum ScriptTypeOption {
 impl ScriptTypeOption {
     pub fn derive_type(value: &str) -> ScriptTypeOption {
         use ScriptTypeOption::*;
-        match value {
+        match value.trim().to_ascii_lowercase().as_str() {
             "importmap" => ImportMap,
             "module" => Module,
-            "javascript" | "" => Javascript,
+            "" | "javascript" | "text/javascript" | "application/javascript" => Javascript,
             _ => Other,
         }
     }
@@ -45,6 +45,23 @@ impl Script {
     pub fn enable_already_started(&mut self) {
         self.already_started = true;
     }
+
+    pub fn is_already_started(&self) -> bool {
+        self.already_started
+    }
+
+    pub fn get_src(&self) -> Option<&str> {
+        self.src.as_deref()
+    }
+
+    pub fn get_script_type(&self) -> &ScriptTypeOption {
+        &self.script_type
+    }
+
+    // Scripts that are fetched in parallel rather than blocking the parser
+    pub fn is_async_or_deferred(&self) -> bool {
+        self.async_script || self.defer
+    }
 }
 
 impl Default for Script {
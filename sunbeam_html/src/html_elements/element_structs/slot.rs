@@ -1,13 +1,33 @@
+use crate::document::doctree::DoctreeNode;
 use crate::html_elements::common_attributes;
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct Slot {
     name: Option<String>,
+    // Light tree nodes of the shadow host that render in this slot
+    assigned_nodes: Vec<DoctreeNode>,
+}
+
+impl Slot {
+    pub fn get_name(&self) -> &str {
+        self.name.as_deref().unwrap_or("")
+    }
+
+    pub fn get_assigned_nodes(&self) -> &Vec<DoctreeNode> {
+        &self.assigned_nodes
+    }
+
+    pub fn set_assigned_nodes(&mut self, nodes: Vec<DoctreeNode>) {
+        self.assigned_nodes = nodes;
+    }
 }
 
 impl Default for Slot {
     fn default() -> Self {
-        Slot { name: None }
+        Slot {
+            name: None,
+            assigned_nodes: Vec::new(),
+        }
     }
 }
 
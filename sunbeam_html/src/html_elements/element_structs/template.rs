@@ -1,3 +1,4 @@
+use crate::document::doctree::DoctreeNode;
 use crate::html_elements::common_attributes;
 
 #[derive(Debug, Clone, Default, PartialEq)]
@@ -8,27 +9,54 @@ pub enum ShadowrootModeOption {
 }
 
 impl ShadowrootModeOption {
-    pub fn derive_mode(value: &str) -> ShadowrootModeOption {
-        match value {
-            "closed" => Self::Closed,
-            "open" | _ => Self::Open,
+    // Invalid values leave the attribute in the none state
+    pub fn derive_mode(value: &str) -> Option<ShadowrootModeOption> {
+        match value.to_ascii_lowercase().as_str() {
+            "open" => Some(Self::Open),
+            "closed" => Some(Self::Closed),
+            _ => None,
         }
     }
 }
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct Template {
-    shadowrootmode: ShadowrootModeOption,
+    shadowrootmode: Option<ShadowrootModeOption>,
     shadowrootclonable: bool,
     shadowrootdelegatesfocus: bool,
+    // Document fragment holding the inert template contents, outside the main tree
+    contents: Option<DoctreeNode>,
+}
+
+impl Template {
+    pub fn get_shadowrootmode(&self) -> Option<ShadowrootModeOption> {
+        self.shadowrootmode.clone()
+    }
+
+    pub fn is_shadowrootclonable(&self) -> bool {
+        self.shadowrootclonable
+    }
+
+    pub fn is_shadowrootdelegatesfocus(&self) -> bool {
+        self.shadowrootdelegatesfocus
+    }
+
+    pub fn get_contents(&self) -> Option<DoctreeNode> {
+        self.contents
+    }
+
+    pub fn set_contents(&mut self, contents: DoctreeNode) {
+        self.contents = Some(contents);
+    }
 }
 
 impl Default for Template {
     fn default() -> Self {
         Template {
-            shadowrootmode: ShadowrootModeOption::default(),
+            shadowrootmode: None,
             shadowrootclonable: false,
             shadowrootdelegatesfocus: false,
+            contents: None,
         }
     }
 }
@@ -40,7 +68,7 @@ impl common_attributes::Element for Template {
                 self.shadowrootmode = ShadowrootModeOption::derive_mode(value.as_str())
             }
             "shadowrootclonable" => self.shadowrootclonable = true,
-            "shadowrootdelegatedfocus" => self.shadowrootdelegatesfocus = true,
+            "shadowrootdelegatesfocus" => self.shadowrootdelegatesfocus = true,
             _ => {}
         }
     }
@@ -337,4 +337,12 @@ impl GlobalAttributes {
     pub fn get_classes(&self) -> &str {
         &self.class
     }
+
+    pub fn get_slot(&self) -> Option<&str> {
+        self.slot.as_deref()
+    }
+
+    pub fn get_id(&self) -> &str {
+        &self.id
+    }
 }
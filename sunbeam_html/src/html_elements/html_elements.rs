@@ -2,6 +2,7 @@ use crate::display_data::{
     self,
     display_box::{self, DisplayBox},
 };
+use crate::document::doctree::DoctreeNode;
 use crate::html_elements::common_attributes::Element;
 use crate::html_elements::element_structs::*;
 use crate::html_elements::global_attr::GlobalAttributes;
@@ -10,7 +11,14 @@ use sunbeam_macros;
 use log;
 
 #[derive(Debug, Clone)]
-pub struct ShadowRoot {}
+pub struct ShadowRoot {
+    pub mode: template::ShadowrootModeOption,
+    pub clonable: bool,
+    pub delegates_focus: bool,
+    pub declarative: bool,
+    // Document fragment holding the shadow tree
+    pub root: DoctreeNode,
+}
 
 #[derive(Default, Clone, Debug, sunbeam_macros::ElementTypeUtils, PartialEq)]
 pub enum HTMLElementType {
@@ -53,6 +61,8 @@ pub enum HTMLElementType {
     Figure(figure::Figure),
     Footer(footer::Footer),
     Form(form::Form),
+    Frame(frame::Frame),
+    Frameset(frameset::Frameset),
     Head(head::Head),
     Header(header::Header),
     HGroup(hgroup::HGroup),
@@ -176,6 +186,8 @@ impl HTMLElementType {
             Figure(val) => val.add_attribute(name, value),
             Footer(val) => val.add_attribute(name, value),
             Form(val) => val.add_attribute(name, value),
+            Frame(val) => val.add_attribute(name, value),
+            Frameset(val) => val.add_attribute(name, value),
             Head(val) => val.add_attribute(name, value),
             Header(val) => val.add_attribute(name, value),
             HGroup(val) => val.add_attribute(name, value),
@@ -256,12 +268,48 @@ impl HTMLElementType {
     }
 }
 
+#[derive(Default, Clone, Copy, Debug, PartialEq)]
+pub enum Namespace {
+    #[default]
+    Html,
+    MathMl,
+    Svg,
+    XLink,
+    Xml,
+    XmlNs,
+}
+
+impl Namespace {
+    pub fn get_url(&self) -> &'static str {
+        match self {
+            Namespace::Html => "http://www.w3.org/1999/xhtml",
+            Namespace::MathMl => "http://www.w3.org/1998/Math/MathML",
+            Namespace::Svg => "http://www.w3.org/2000/svg",
+            Namespace::XLink => "http://www.w3.org/1999/xlink",
+            Namespace::Xml => "http://www.w3.org/XML/1998/namespace",
+            Namespace::XmlNs => "http://www.w3.org/2000/xmlns/",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ElementAttribute {
+    pub name: String,
+    pub value: String,
+    // Only set for the adjusted foreign attributes, such as xlink:href
+    pub namespace: Option<Namespace>,
+}
+
 #[derive(Clone, Debug)]
 pub struct HTMLElement {
     pub element_type: HTMLElementType,
     pub global_attributes: GlobalAttributes,
     pub local_name: String,
+    pub namespace: Namespace,
     pub shadow_root: Option<ShadowRoot>,
+    // The form a control belongs to, set while parsing
+    pub form_owner: Option<DoctreeNode>,
+    attributes: Vec<ElementAttribute>,
 }
 
 impl HTMLElement {
@@ -307,6 +355,8 @@ impl HTMLElement {
             "figure" => Figure(figure::Figure::default()),
             "footer" => Footer(footer::Footer::default()),
             "form" => Form(form::Form::default()),
+            "frame" => Frame(frame::Frame::default()),
+            "frameset" => Frameset(frameset::Frameset::default()),
             "head" => Head(head::Head::default()),
             "header" => Header(header::Header::default()),
             "hgroup" => HGroup(hgroup::HGroup::default()),
@@ -344,7 +394,7 @@ impl HTMLElement {
             "p" => P(p::P::default()),
             "picture" => Picture(picture::Picture::default()),
             // "" => Portal(::default()),
-            "pre" | "plaintext" => Pre(pre::Pre::default()),
+            "pre" => Pre(pre::Pre::default()),
             "progress" => Progress(progress::Progress::default()),
             "q" => Q(q::Q::default()),
             "rp" => Rp(rp::Rp::default()),
@@ -391,28 +441,109 @@ impl HTMLElement {
         HTMLElement {
             element_type,
             global_attributes: GlobalAttributes::default(),
-            local_name: String::new(),
+            local_name: name,
+            namespace: Namespace::Html,
+            shadow_root: None,
+            form_owner: None,
+            attributes: Vec::new(),
+        }
+    }
+
+    // SVG and MathML elements, which never map to an HTML element type
+    pub fn new_foreign(name: String, namespace: Namespace) -> HTMLElement {
+        HTMLElement {
+            element_type: HTMLElementType::Unknown,
+            global_attributes: GlobalAttributes::default(),
+            local_name: name,
+            namespace,
             shadow_root: None,
+            form_owner: None,
+            attributes: Vec::new(),
         }
     }
 
     pub fn from_element_type(element: HTMLElementType) -> HTMLElement {
         HTMLElement {
+            local_name: element.get_name().to_lowercase(),
             element_type: element,
             global_attributes: GlobalAttributes::default(),
-            local_name: String::new(),
+            namespace: Namespace::Html,
             shadow_root: None,
+            form_owner: None,
+            attributes: Vec::new(),
         }
     }
 
     pub fn add_attribute(&mut self, name: String, value: String) {
-        if !self.global_attributes.add_attribute(name, value) {
-            // TODO: Insert to local attribute
+        self.add_namespaced_attribute(name, value, None);
+    }
+
+    // Duplicate attributes are ignored, the first one set wins
+    pub fn add_namespaced_attribute(
+        &mut self,
+        name: String,
+        value: String,
+        namespace: Option<Namespace>,
+    ) {
+        if self
+            .attributes
+            .iter()
+            .any(|a| a.name == name && a.namespace == namespace)
+        {
+            return;
+        }
+        self.attributes.push(ElementAttribute {
+            name: name.clone(),
+            value: value.clone(),
+            namespace,
+        });
+
+        if namespace.is_some() {
+            return;
         }
+        if !self
+            .global_attributes
+            .add_attribute(name.clone(), value.clone())
+            && self.namespace == Namespace::Html
+        {
+            self.element_type.add_attribute(name, value);
+        }
+    }
+
+    pub fn has_attribute(&self, name: &str) -> bool {
+        self.attributes.iter().any(|a| a.name == name)
+    }
+
+    pub fn get_attribute(&self, name: &str) -> Option<&str> {
+        self.attributes
+            .iter()
+            .find(|a| a.name == name)
+            .map(|a| a.value.as_str())
+    }
+
+    pub fn get_attributes(&self) -> &Vec<ElementAttribute> {
+        &self.attributes
+    }
+
+    // Elements that can have a form owner
+    pub fn is_form_associated(&self) -> bool {
+        self.is_listed() || matches!(self.element_type, HTMLElementType::Img(_))
+    }
+
+    // Form associated elements that are listed in the form's controls, and can name
+    // their form with the form attribute
+    pub fn is_listed(&self) -> bool {
+        use HTMLElementType::*;
+        matches!(
+            self.element_type,
+            Button(_) | Fieldset(_) | Input(_) | Object(_) | Output(_) | Select(_) | Textarea(_)
+        )
     }
 
+    // The tag name the element was created with, which unlike the element type
+    // is also known for elements we don't support
     pub fn get_name(&self) -> &str {
-        self.element_type.get_name()
+        &self.local_name
     }
 
     pub fn get_display_box(&self) -> DisplayBox {
@@ -3,5 +3,6 @@ pub mod element_structs;
 mod global_attr;
 mod html_elements;
 
+pub use common_attributes::FetchPriorityOption;
 pub use global_attr::GlobalAttributes;
 pub use html_elements::*;
@@ -5,7 +5,18 @@ pub mod parser;
 pub mod types;
 
 pub use document::document::Document;
-pub use parser::parser::parse_document;
+pub use document::mutation::DomError;
+pub use parser::errors::{ParseError, ParseErrorKind};
+pub use parser::parser::{
+    parse_document, parse_document_from_bytes, parse_document_into,
+    parse_document_with_diagnostics, parse_document_with_options, parse_fragment, ParserOptions,
+    StreamingParser,
+};
+pub use parser::preload::{scan_for_preloads, PreloadKind, PreloadRequest, PreloadScanner};
+pub use parser::script::{ParserScript, ScriptHandler, ScriptStatus, ScriptWriter};
+pub use parser::span::{SourcePosition, SourceSpan};
+pub use parser::tokenizer::{FsmState, HtmlAttribute, HtmlToken, TokenTag, Tokenizer};
+pub use parser::tree_sink::TreeSink;
 
 mod lang_system;
 
@@ -0,0 +1,424 @@
+// Runs the html5lib tokenizer and tree construction tests and reports how many of them pass.
+// test_resources/html5lib holds the upstream suite, and test_resources/html5lib_local holds
+// cases written for this parser in the same format. Not all of the upstream tests pass yet, so
+// those suites only fail when their pass rate drops below what it was last raised to, while
+// every local case has to pass.
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+
+use serde_json::Value;
+
+use super::parser::{parse_document_with_options, ParserOptions};
+use super::tokenizer::{FsmState, TokenTag, Tokenizer};
+
+// Measured on the vendored files, raise these as the parser gets better
+const TOKENIZER_MIN_PASS_PERCENT: usize = 99;
+const TOKENIZER_ERRORS_MIN_PASS_PERCENT: usize = 99;
+const TREE_CONSTRUCTION_MIN_PASS_PERCENT: usize = 97;
+
+#[derive(Default)]
+struct Results {
+    passed: usize,
+    failed: Vec<String>,
+    skipped: usize,
+}
+
+impl Results {
+    fn check(&mut self, name: String, expected: &str, actual: Option<String>) {
+        match actual {
+            Some(actual) if actual == expected => self.passed += 1,
+            Some(actual) => self.failed.push(format!(
+                "{}\nexpected:\n{}\ngot:\n{}",
+                name, expected, actual
+            )),
+            None => self.failed.push(format!("{}\npanicked", name)),
+        }
+    }
+
+    fn percent(&self) -> usize {
+        let total = self.passed + self.failed.len();
+        if total == 0 {
+            return 100;
+        }
+        self.passed * 100 / total
+    }
+
+    fn report(&self, suite: &str) {
+        for failure in &self.failed {
+            println!("{}\n", failure);
+        }
+        println!(
+            "{}: {} of {} passed ({}%), {} skipped",
+            suite,
+            self.passed,
+            self.passed + self.failed.len(),
+            self.percent(),
+            self.skipped
+        );
+    }
+}
+
+fn fixture_files(suite: &str, dir: &str, extension: &str) -> Vec<PathBuf> {
+    let mut d = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    d.push("test_resources");
+    d.push(suite);
+    d.push(dir);
+    let mut files: Vec<PathBuf> = match fs::read_dir(d) {
+        Ok(entries) => entries
+            .filter_map(|e| e.ok().map(|e| e.path()))
+            .filter(|p| p.extension().is_some_and(|e| e == extension))
+            .collect(),
+        Err(_) => Vec::new(),
+    };
+    files.sort();
+    files
+}
+
+// Tree construction
+
+struct TreeTest {
+    data: String,
+    fragment_context: Option<String>,
+    scripting: Option<bool>,
+    document: String,
+}
+
+// Tests start with #data, and every section runs until the next line starting with #. The
+// expected tree runs until the blank line before the next test.
+fn parse_dat_file(contents: &str) -> Vec<TreeTest> {
+    let contents = contents.strip_prefix("#data\n").unwrap_or(contents);
+    contents
+        .split("\n\n#data\n")
+        .filter_map(|test| {
+            let (sections, document) = test.split_once("\n#document\n")?;
+            let mut lines = sections.lines().peekable();
+            let mut data = Vec::new();
+            while let Some(line) = lines.next_if(|line| !line.starts_with('#')) {
+                data.push(line);
+            }
+
+            let mut fragment_context = None;
+            let mut scripting = None;
+            while let Some(line) = lines.next() {
+                match line {
+                    "#document-fragment" => fragment_context = lines.next().map(str::to_string),
+                    "#script-on" => scripting = Some(true),
+                    "#script-off" => scripting = Some(false),
+                    _ => {}
+                }
+            }
+            Some(TreeTest {
+                data: data.join("\n"),
+                fragment_context,
+                scripting,
+                document: format!("#document\n{}\n", document.trim_end_matches('\n')),
+            })
+        })
+        .collect()
+}
+
+fn run_tree_test(test: &TreeTest) -> Option<String> {
+    let options = ParserOptions {
+        scripting: test.scripting.unwrap_or(true),
+        fragment_context: test.fragment_context.clone(),
+        collect_errors: false,
+        ..ParserOptions::default()
+    };
+    std::panic::catch_unwind(|| {
+        let (doc, _) = parse_document_with_options(&test.data, options);
+        doc.doctree.dump()
+    })
+    .ok()
+}
+
+// Tokenizer
+
+// Double escaped tests write their strings with the \uXXXX escapes left in. Lone surrogates
+// can't be put in a String, so the tests that have them can't be run.
+fn unescape(text: &str) -> Option<String> {
+    let read_unit = |rest: &str| -> Option<u32> {
+        let hex = rest.strip_prefix("\\u")?.get(..4)?;
+        u32::from_str_radix(hex, 16).ok()
+    };
+    let mut out = String::new();
+    let mut rest = text;
+    while let Some(idx) = rest.find("\\u") {
+        out.push_str(&rest[..idx]);
+        rest = &rest[idx..];
+        let unit = read_unit(rest)?;
+        rest = &rest[6..];
+        let code_point = if (0xD800..0xDC00).contains(&unit) {
+            let low = read_unit(rest).filter(|low| (0xDC00..0xE000).contains(low))?;
+            rest = &rest[6..];
+            0x10000 + ((unit - 0xD800) << 10) + (low - 0xDC00)
+        } else {
+            unit
+        };
+        out.push(char::from_u32(code_point)?);
+    }
+    out.push_str(rest);
+    Some(out)
+}
+
+fn initial_state(name: &str) -> Option<FsmState> {
+    match name {
+        "Data state" => Some(FsmState::Data),
+        "PLAINTEXT state" => Some(FsmState::Plaintext),
+        "RCDATA state" => Some(FsmState::RCData),
+        "RAWTEXT state" => Some(FsmState::RawText),
+        "Script data state" => Some(FsmState::ScriptData),
+        "CDATA section state" => Some(FsmState::CDataSection),
+        _ => None,
+    }
+}
+
+// Tokens are compared as one line each, with adjacent characters merged
+fn push_token_line(lines: &mut Vec<String>, line: String) {
+    if let (Some(last), Some(text)) = (lines.last_mut(), line.strip_prefix("Character ")) {
+        if last.starts_with("Character ") {
+            last.push_str(text);
+            return;
+        }
+    }
+    lines.push(line);
+}
+
+// Gives None when the expected tokens can't be represented
+fn describe_expected_tokens(output: &[Value], double_escaped: bool) -> Option<String> {
+    let text = |value: Option<&Value>| -> Option<String> {
+        match value.and_then(Value::as_str) {
+            Some(text) if double_escaped => Some(format!("{:?}", unescape(text)?)),
+            Some(text) => Some(format!("{:?}", text)),
+            None => Some("null".to_string()),
+        }
+    };
+    let mut lines = Vec::new();
+    for token in output {
+        // Older files put ParseError entries between the tokens
+        let parts = match token.as_array() {
+            Some(parts) => parts,
+            None => continue,
+        };
+        let line = match parts.first().and_then(Value::as_str) {
+            Some("Character") => match parts.get(1).and_then(Value::as_str) {
+                Some(data) if double_escaped => format!("Character {}", unescape(data)?),
+                Some(data) => format!("Character {}", data),
+                None => continue,
+            },
+            Some("Comment") => format!("Comment {}", text(parts.get(1))?),
+            Some("StartTag") => {
+                let mut attributes = Vec::new();
+                if let Some(map) = parts.get(2).and_then(Value::as_object) {
+                    // Sorted the same way as the actual attributes
+                    let map: BTreeMap<&String, &Value> = map.iter().collect();
+                    for (name, value) in map {
+                        attributes.push(format!("{}={}", name, text(Some(value))?));
+                    }
+                }
+                let self_closing = parts.get(3) == Some(&Value::Bool(true));
+                format!(
+                    "StartTag {} [{}] {}",
+                    text(parts.get(1))?,
+                    attributes.join(" "),
+                    self_closing
+                )
+            }
+            Some("EndTag") => format!("EndTag {}", text(parts.get(1))?),
+            Some("DOCTYPE") => format!(
+                "DOCTYPE {} {} {} {}",
+                text(parts.get(1))?,
+                text(parts.get(2))?,
+                text(parts.get(3))?,
+                parts.get(4) == Some(&Value::Bool(true))
+            ),
+            _ => continue,
+        };
+        push_token_line(&mut lines, line);
+    }
+    Some(lines.join("\n"))
+}
+
+// Older files list errors as ParseError entries in the output, newer ones in an errors array
+fn expected_error_count(test: &Value) -> usize {
+    let inline = test["output"].as_array().map_or(0, |output| {
+        output.iter().filter(|t| *t == "ParseError").count()
+    });
+    inline + test["errors"].as_array().map_or(0, Vec::len)
+}
+
+// Gives the tokens and how many errors there were
+fn run_tokenizer_test(
+    input: &str,
+    state: FsmState,
+    last_start_tag: Option<&str>,
+) -> (String, usize) {
+    let quoted = |text: &str| format!("{:?}", text);
+    let mut tokenizer = Tokenizer::init(input);
+    tokenizer.set_switch_states(false);
+    tokenizer.set_state(state);
+    if let Some(tag) = last_start_tag {
+        tokenizer.set_last_start_tag(tag);
+    }
+
+    let mut lines = Vec::new();
+    for token in tokenizer.by_ref() {
+        let line = match &token.tag {
+            TokenTag::Character => format!("Character {}", token.data),
+            TokenTag::Comment => format!("Comment {}", quoted(&token.data)),
+            TokenTag::StartTag => {
+                // Only the first of a duplicated attribute counts
+                let mut attributes: BTreeMap<&str, &str> = BTreeMap::new();
+                for a in &token.attributes {
+                    attributes.entry(&a.name).or_insert(&a.value);
+                }
+                let attributes: Vec<String> = attributes
+                    .iter()
+                    .map(|(name, value)| format!("{}={}", name, quoted(value)))
+                    .collect();
+                format!(
+                    "StartTag {} [{}] {}",
+                    quoted(&token.data),
+                    attributes.join(" "),
+                    token.flags.self_closing
+                )
+            }
+            TokenTag::EndTag => format!("EndTag {}", quoted(&token.data)),
+            TokenTag::Doctype(data) => {
+                let optional = |value: Option<&String>| match value {
+                    Some(value) => quoted(value),
+                    None => "null".to_string(),
+                };
+                let name = if token.data.is_empty() {
+                    "null".to_string()
+                } else {
+                    quoted(&token.data)
+                };
+                format!(
+                    "DOCTYPE {} {} {} {}",
+                    name,
+                    optional(data.public_identifier.as_ref()),
+                    optional(data.system.as_ref()),
+                    !token.flags.force_quirks
+                )
+            }
+            TokenTag::EoF => break,
+        };
+        push_token_line(&mut lines, line);
+    }
+    (lines.join("\n"), tokenizer.take_errors().len())
+}
+
+// Runs every tokenizer test in the suite, and with check_errors also compares how many errors
+// each one reports
+fn run_tokenizer_suite(suite: &str, check_errors: bool) -> (Results, Results) {
+    let mut results = Results::default();
+    let mut error_results = Results::default();
+    for file in fixture_files(suite, "tokenizer", "test") {
+        let contents = fs::read_to_string(&file).unwrap();
+        let json: Value = match serde_json::from_str(&contents) {
+            Ok(json) => json,
+            Err(e) => panic!("Couldn't read {}: {}", file.display(), e),
+        };
+        let tests = json["tests"].as_array().map_or(&[][..], Vec::as_slice);
+        for test in tests {
+            let description = test["description"].as_str().unwrap_or("");
+            let double_escaped = test["doubleEscaped"] == Value::Bool(true);
+            let input = test["input"].as_str().unwrap_or("");
+            let input = if double_escaped {
+                unescape(input)
+            } else {
+                Some(input.to_string())
+            };
+            let output = test["output"].as_array().map_or(&[][..], Vec::as_slice);
+            let expected = describe_expected_tokens(output, double_escaped);
+            let (input, expected) = match (input, expected) {
+                (Some(input), Some(expected)) => (input, expected),
+                _ => {
+                    results.skipped += 1;
+                    continue;
+                }
+            };
+            let last_start_tag = test["lastStartTag"].as_str();
+
+            let states: Vec<&str> = match test["initialStates"].as_array() {
+                Some(states) => states.iter().filter_map(Value::as_str).collect(),
+                None => vec!["Data state"],
+            };
+            for name in states {
+                let state = match initial_state(name) {
+                    Some(state) => state,
+                    None => {
+                        results.skipped += 1;
+                        continue;
+                    }
+                };
+                let actual =
+                    std::panic::catch_unwind(|| run_tokenizer_test(&input, state, last_start_tag))
+                        .ok();
+                let name = format!("{}: {} ({})", file.display(), description, name);
+                if check_errors {
+                    error_results.check(
+                        name.clone(),
+                        &expected_error_count(test).to_string(),
+                        actual.as_ref().map(|(_, errors)| errors.to_string()),
+                    );
+                }
+                results.check(name, &expected, actual.map(|(tokens, _)| tokens));
+            }
+        }
+    }
+    (results, error_results)
+}
+
+fn run_tree_construction_suite(suite: &str) -> Results {
+    let mut results = Results::default();
+    for file in fixture_files(suite, "tree-construction", "dat") {
+        let contents = fs::read_to_string(&file).unwrap();
+        for test in parse_dat_file(&contents) {
+            // Fragment contexts can only name HTML elements
+            if test
+                .fragment_context
+                .as_deref()
+                .is_some_and(|context| context.contains(' '))
+            {
+                results.skipped += 1;
+                continue;
+            }
+            results.check(
+                format!("{}:\n{}", file.display(), test.data),
+                &test.document,
+                run_tree_test(&test),
+            );
+        }
+    }
+    results
+}
+
+#[test]
+fn test_html5lib_tokenizer() {
+    let (results, error_results) = run_tokenizer_suite("html5lib", true);
+    results.report("html5lib tokenizer");
+    error_results.report("html5lib tokenizer error counts");
+    assert!(results.percent() >= TOKENIZER_MIN_PASS_PERCENT);
+    assert!(error_results.percent() >= TOKENIZER_ERRORS_MIN_PASS_PERCENT);
+}
+
+#[test]
+fn test_html5lib_tree_construction() {
+    let results = run_tree_construction_suite("html5lib");
+    results.report("html5lib tree construction");
+    assert!(results.percent() >= TREE_CONSTRUCTION_MIN_PASS_PERCENT);
+}
+
+// The local cases don't list their errors
+#[test]
+fn test_local_html5lib_fixtures() {
+    let (results, _) = run_tokenizer_suite("html5lib_local", false);
+    results.report("local tokenizer");
+    assert!(results.failed.is_empty());
+
+    let results = run_tree_construction_suite("html5lib_local");
+    results.report("local tree construction");
+    assert!(results.failed.is_empty());
+}
@@ -0,0 +1,303 @@
+// Determines the character encoding of a byte stream before it reaches the tokenizer.
+// Labels are resolved and bytes decoded with encoding_rs; the sniffing itself follows
+// the WHATWG encoding sniffing and prescan algorithms.
+use encoding_rs::{Encoding, UTF_16BE, UTF_16LE, UTF_8, WINDOWS_1252, X_USER_DEFINED};
+
+use crate::document::document::EncodingConfidence;
+
+// The prescan only looks at the start of the stream
+pub const PRESCAN_LIMIT: usize = 1024;
+
+// Returns the encoding, its confidence and the length of any byte order mark.
+// The data is only the start of the stream when it isn't complete.
+pub fn sniff_encoding(
+    data: &[u8],
+    complete: bool,
+) -> (&'static Encoding, EncodingConfidence, usize) {
+    if let Some((encoding, bom_len)) = Encoding::for_bom(data) {
+        return (encoding, EncodingConfidence::Certain, bom_len);
+    }
+
+    // TODO: Use the encoding given by the transport layer
+    if let Some(encoding) = prescan(&data[..data.len().min(PRESCAN_LIMIT)]) {
+        return (encoding, EncodingConfidence::Tentative, 0);
+    }
+
+    // Without any declaration, fall back to UTF-8 when the bytes are valid for it and
+    // to the legacy windows-1252 default otherwise. A sequence cut off at the end of an
+    // incomplete stream may continue in the bytes that haven't arrived yet.
+    match std::str::from_utf8(data) {
+        Ok(_) => (UTF_8, EncodingConfidence::Tentative, 0),
+        Err(e) if !complete && e.error_len().is_none() => (UTF_8, EncodingConfidence::Tentative, 0),
+        Err(_) => (WINDOWS_1252, EncodingConfidence::Tentative, 0),
+    }
+}
+
+pub fn decode(data: &[u8], encoding: &'static Encoding) -> String {
+    let (text, had_errors) = encoding.decode_without_bom_handling(data);
+    if had_errors {
+        log::debug!("Replaced invalid {} byte sequences", encoding.name());
+    }
+    text.into_owned()
+}
+
+// A document can't declare itself to be UTF-16, since the declaration was readable as ASCII
+fn adjust_declared_encoding(encoding: &'static Encoding) -> &'static Encoding {
+    if encoding == UTF_16BE || encoding == UTF_16LE {
+        UTF_8
+    } else if encoding == X_USER_DEFINED {
+        WINDOWS_1252
+    } else {
+        encoding
+    }
+}
+
+// Gets the encoding declared by the attributes of a meta element, if any
+pub fn encoding_from_meta_attributes<'a>(
+    attributes: impl Iterator<Item = (&'a str, &'a str)>,
+) -> Option<&'static Encoding> {
+    let mut got_pragma = false;
+    let mut need_pragma = None;
+    let mut charset = None;
+
+    for (name, value) in attributes {
+        match name {
+            "http-equiv" if value.eq_ignore_ascii_case("content-type") => {
+                got_pragma = true;
+            }
+            "content" if charset.is_none() => {
+                if let Some(encoding) = extract_encoding_from_content(value.as_bytes()) {
+                    charset = Some(encoding);
+                    need_pragma = Some(true);
+                }
+            }
+            "charset" => {
+                charset = Encoding::for_label(value.as_bytes());
+                need_pragma = Some(false);
+            }
+            _ => {}
+        }
+    }
+
+    match need_pragma {
+        None => None,
+        Some(true) if !got_pragma => None,
+        _ => charset.map(adjust_declared_encoding),
+    }
+}
+
+// Finds the charset parameter in the content attribute of a http-equiv meta element
+fn extract_encoding_from_content(content: &[u8]) -> Option<&'static Encoding> {
+    let mut pos = 0;
+    loop {
+        let start = content[pos..]
+            .windows(7)
+            .position(|w| w.eq_ignore_ascii_case(b"charset"))?;
+        pos += start + 7;
+        while pos < content.len() && is_whitespace(content[pos]) {
+            pos += 1;
+        }
+        if content.get(pos) == Some(&b'=') {
+            pos += 1;
+            break;
+        }
+    }
+    while pos < content.len() && is_whitespace(content[pos]) {
+        pos += 1;
+    }
+
+    let value = match content.get(pos)? {
+        quote @ (b'"' | b'\'') => {
+            let rest = &content[pos + 1..];
+            let end = rest.iter().position(|b| b == quote)?;
+            &rest[..end]
+        }
+        _ => {
+            let rest = &content[pos..];
+            let end = rest
+                .iter()
+                .position(|b| is_whitespace(*b) || *b == b';')
+                .unwrap_or(rest.len());
+            &rest[..end]
+        }
+    };
+    Encoding::for_label(value)
+}
+
+fn is_whitespace(byte: u8) -> bool {
+    matches!(byte, 0x09 | 0x0A | 0x0C | 0x0D | 0x20)
+}
+
+fn find(data: &[u8], pos: usize, needle: &[u8]) -> Option<usize> {
+    data.get(pos..)?
+        .windows(needle.len())
+        .position(|w| w == needle)
+        .map(|idx| pos + idx)
+}
+
+// Looks for a meta element declaring the encoding without running the full tokenizer
+pub fn prescan(data: &[u8]) -> Option<&'static Encoding> {
+    let mut pos = 0;
+    while pos < data.len() {
+        let rest = &data[pos..];
+        if rest.starts_with(b"<!--") {
+            // The dashes of the opening sequence can also close the comment
+            pos = find(data, pos + 2, b"-->")? + 2;
+        } else if rest.len() >= 6
+            && rest[..5].eq_ignore_ascii_case(b"<meta")
+            && (is_whitespace(rest[5]) || rest[5] == b'/')
+        {
+            pos += 6;
+            let mut attributes: Vec<(String, String)> = Vec::new();
+            while let Some((name, value)) = get_attribute(data, &mut pos) {
+                if !attributes.iter().any(|(n, _)| *n == name) {
+                    attributes.push((name, value));
+                }
+            }
+            let declared = encoding_from_meta_attributes(
+                attributes.iter().map(|(n, v)| (n.as_str(), v.as_str())),
+            );
+            if declared.is_some() {
+                return declared;
+            }
+        } else if rest.len() >= 2
+            && rest[0] == b'<'
+            && (rest[1].is_ascii_alphabetic()
+                || (rest[1] == b'/' && rest.get(2).is_some_and(|b| b.is_ascii_alphabetic())))
+        {
+            while pos < data.len() && !is_whitespace(data[pos]) && data[pos] != b'>' {
+                pos += 1;
+            }
+            while get_attribute(data, &mut pos).is_some() {}
+        } else if rest.starts_with(b"<!") || rest.starts_with(b"</") || rest.starts_with(b"<?") {
+            pos = find(data, pos, b">")?;
+        }
+        pos += 1;
+    }
+    None
+}
+
+// Reads the next attribute of a tag for the prescan. Returns None at the end of the tag,
+// leaving the position on the '>'
+fn get_attribute(data: &[u8], pos: &mut usize) -> Option<(String, String)> {
+    while *pos < data.len() && (is_whitespace(data[*pos]) || data[*pos] == b'/') {
+        *pos += 1;
+    }
+    if *data.get(*pos)? == b'>' {
+        return None;
+    }
+
+    let mut name = Vec::new();
+    let mut value = Vec::new();
+    loop {
+        let byte = *data.get(*pos)?;
+        match byte {
+            b'=' if !name.is_empty() => {
+                *pos += 1;
+                break;
+            }
+            b'/' | b'>' => return Some((bytes_to_string(&name), String::new())),
+            _ if is_whitespace(byte) => {
+                while *pos < data.len() && is_whitespace(data[*pos]) {
+                    *pos += 1;
+                }
+                if *data.get(*pos)? != b'=' {
+                    return Some((bytes_to_string(&name), String::new()));
+                }
+                *pos += 1;
+                break;
+            }
+            _ => {
+                name.push(byte.to_ascii_lowercase());
+                *pos += 1;
+            }
+        }
+    }
+
+    while *pos < data.len() && is_whitespace(data[*pos]) {
+        *pos += 1;
+    }
+    match *data.get(*pos)? {
+        quote @ (b'"' | b'\'') => {
+            *pos += 1;
+            loop {
+                let byte = *data.get(*pos)?;
+                *pos += 1;
+                if byte == quote {
+                    return Some((bytes_to_string(&name), bytes_to_string(&value)));
+                }
+                value.push(byte.to_ascii_lowercase());
+            }
+        }
+        b'>' => return Some((bytes_to_string(&name), String::new())),
+        _ => {}
+    }
+    loop {
+        let byte = *data.get(*pos)?;
+        if is_whitespace(byte) || byte == b'>' {
+            return Some((bytes_to_string(&name), bytes_to_string(&value)));
+        }
+        value.push(byte.to_ascii_lowercase());
+        *pos += 1;
+    }
+}
+
+fn bytes_to_string(bytes: &[u8]) -> String {
+    String::from_utf8_lossy(bytes).into_owned()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use encoding_rs::{ISO_8859_2, SHIFT_JIS};
+
+    #[test]
+    fn test_prescan_meta() {
+        assert_eq!(prescan(b"<meta charset=shift_jis>"), Some(SHIFT_JIS));
+        assert_eq!(
+            prescan(b"<html><head><META CHARSET='iso-8859-2'>"),
+            Some(ISO_8859_2)
+        );
+        assert_eq!(
+            prescan(b"<meta http-equiv=\"Content-Type\" content=\"text/html; charset=latin1\">"),
+            Some(WINDOWS_1252)
+        );
+        // Content without the pragma is ignored
+        assert_eq!(
+            prescan(b"<meta content=\"text/html; charset=latin2\">"),
+            None
+        );
+        // Declaring UTF-16 in an ASCII compatible stream means UTF-8
+        assert_eq!(prescan(b"<meta charset=utf-16le>"), Some(UTF_8));
+    }
+
+    #[test]
+    fn test_prescan_skips_comments_and_attributes() {
+        assert_eq!(prescan(b"<!-- <meta charset=shift_jis> -->"), None);
+        assert_eq!(prescan(b"<!--><meta charset=shift_jis>"), Some(SHIFT_JIS));
+        assert_eq!(
+            prescan(b"<div title='<meta charset=shift_jis>'><meta charset=koi8-r>"),
+            Encoding::for_label(b"koi8-r")
+        );
+    }
+
+    #[test]
+    fn test_sniff_bom() {
+        assert_eq!(
+            sniff_encoding(b"\xFF\xFE<\x00", true),
+            (UTF_16LE, EncodingConfidence::Certain, 2)
+        );
+        assert_eq!(
+            sniff_encoding(b"\xEF\xBB\xBF<meta charset=shift_jis>", true),
+            (UTF_8, EncodingConfidence::Certain, 3)
+        );
+        assert_eq!(
+            sniff_encoding(b"caf\xE9", true),
+            (WINDOWS_1252, EncodingConfidence::Tentative, 0)
+        );
+        assert_eq!(
+            sniff_encoding(b"caf\xC3", false),
+            (UTF_8, EncodingConfidence::Tentative, 0)
+        );
+    }
+}
@@ -0,0 +1,181 @@
+// Parse errors reported while tokenizing and building the tree.
+// Tokenizer errors use the names from the WHATWG spec. Tree construction errors aren't
+// named by the spec, so those are described by the token that caused them.
+use std::fmt;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ParseErrorKind {
+    AbruptClosingOfEmptyComment,
+    AbruptDoctypePublicIdentifier,
+    AbruptDoctypeSystemIdentifier,
+    AbsenceOfDigitsInNumericCharacterReference,
+    CdataInHtmlContent,
+    CharacterReferenceOutsideUnicodeRange,
+    ControlCharacterInInputStream,
+    ControlCharacterReference,
+    DuplicateAttribute,
+    EndTagWithAttributes,
+    EndTagWithTrailingSolidus,
+    EofBeforeTagName,
+    EofInCdata,
+    EofInComment,
+    EofInDoctype,
+    EofInScriptHtmlCommentLikeText,
+    EofInTag,
+    IncorrectlyClosedComment,
+    IncorrectlyOpenedComment,
+    InvalidCharacterSequenceAfterDoctypeName,
+    InvalidFirstCharacterOfTagName,
+    MissingAttributeValue,
+    MissingDoctypeName,
+    MissingDoctypePublicIdentifier,
+    MissingDoctypeSystemIdentifier,
+    MissingEndTagName,
+    MissingQuoteBeforeDoctypePublicIdentifier,
+    MissingQuoteBeforeDoctypeSystemIdentifier,
+    MissingSemicolonAfterCharacterReference,
+    MissingWhitespaceAfterDoctypePublicKeyword,
+    MissingWhitespaceAfterDoctypeSystemKeyword,
+    MissingWhitespaceBeforeDoctypeName,
+    MissingWhitespaceBetweenAttributes,
+    MissingWhitespaceBetweenDoctypePublicAndSystemIdentifiers,
+    NestedComment,
+    NoncharacterCharacterReference,
+    NoncharacterInInputStream,
+    NonVoidHtmlElementStartTagWithTrailingSolidus,
+    NullCharacterReference,
+    SurrogateCharacterReference,
+    // Never reported, a str can't hold surrogates so decoding already replaced them
+    SurrogateInInputStream,
+    UnexpectedCharacterAfterDoctypeSystemIdentifier,
+    UnexpectedCharacterInAttributeName,
+    UnexpectedCharacterInUnquotedAttributeValue,
+    UnexpectedEqualsSignBeforeAttributeName,
+    UnexpectedNullCharacter,
+    UnexpectedQuestionMarkInsteadOfTagName,
+    UnexpectedSolidusInTag,
+    UnknownNamedCharacterReference,
+
+    // Tree construction
+    MissingDoctype,
+    NonConformingDoctype,
+    UnexpectedDoctype,
+    UnexpectedStartTag,
+    UnexpectedEndTag,
+    UnexpectedCharacter,
+    UnexpectedEof,
+    // An end tag or the end of the file closed elements that were still open
+    UnclosedElements,
+}
+
+impl ParseErrorKind {
+    pub fn spec_name(&self) -> &'static str {
+        use ParseErrorKind::*;
+        match self {
+            AbruptClosingOfEmptyComment => "abrupt-closing-of-empty-comment",
+            AbruptDoctypePublicIdentifier => "abrupt-doctype-public-identifier",
+            AbruptDoctypeSystemIdentifier => "abrupt-doctype-system-identifier",
+            AbsenceOfDigitsInNumericCharacterReference => {
+                "absence-of-digits-in-numeric-character-reference"
+            }
+            CdataInHtmlContent => "cdata-in-html-content",
+            CharacterReferenceOutsideUnicodeRange => "character-reference-outside-unicode-range",
+            ControlCharacterInInputStream => "control-character-in-input-stream",
+            ControlCharacterReference => "control-character-reference",
+            DuplicateAttribute => "duplicate-attribute",
+            EndTagWithAttributes => "end-tag-with-attributes",
+            EndTagWithTrailingSolidus => "end-tag-with-trailing-solidus",
+            EofBeforeTagName => "eof-before-tag-name",
+            EofInCdata => "eof-in-cdata",
+            EofInComment => "eof-in-comment",
+            EofInDoctype => "eof-in-doctype",
+            EofInScriptHtmlCommentLikeText => "eof-in-script-html-comment-like-text",
+            EofInTag => "eof-in-tag",
+            IncorrectlyClosedComment => "incorrectly-closed-comment",
+            IncorrectlyOpenedComment => "incorrectly-opened-comment",
+            InvalidCharacterSequenceAfterDoctypeName => {
+                "invalid-character-sequence-after-doctype-name"
+            }
+            InvalidFirstCharacterOfTagName => "invalid-first-character-of-tag-name",
+            MissingAttributeValue => "missing-attribute-value",
+            MissingDoctypeName => "missing-doctype-name",
+            MissingDoctypePublicIdentifier => "missing-doctype-public-identifier",
+            MissingDoctypeSystemIdentifier => "missing-doctype-system-identifier",
+            MissingEndTagName => "missing-end-tag-name",
+            MissingQuoteBeforeDoctypePublicIdentifier => {
+                "missing-quote-before-doctype-public-identifier"
+            }
+            MissingQuoteBeforeDoctypeSystemIdentifier => {
+                "missing-quote-before-doctype-system-identifier"
+            }
+            MissingSemicolonAfterCharacterReference => {
+                "missing-semicolon-after-character-reference"
+            }
+            MissingWhitespaceAfterDoctypePublicKeyword => {
+                "missing-whitespace-after-doctype-public-keyword"
+            }
+            MissingWhitespaceAfterDoctypeSystemKeyword => {
+                "missing-whitespace-after-doctype-system-keyword"
+            }
+            MissingWhitespaceBeforeDoctypeName => "missing-whitespace-before-doctype-name",
+            MissingWhitespaceBetweenAttributes => "missing-whitespace-between-attributes",
+            MissingWhitespaceBetweenDoctypePublicAndSystemIdentifiers => {
+                "missing-whitespace-between-doctype-public-and-system-identifiers"
+            }
+            NestedComment => "nested-comment",
+            NoncharacterCharacterReference => "noncharacter-character-reference",
+            NoncharacterInInputStream => "noncharacter-in-input-stream",
+            NonVoidHtmlElementStartTagWithTrailingSolidus => {
+                "non-void-html-element-start-tag-with-trailing-solidus"
+            }
+            NullCharacterReference => "null-character-reference",
+            SurrogateCharacterReference => "surrogate-character-reference",
+            SurrogateInInputStream => "surrogate-in-input-stream",
+            UnexpectedCharacterAfterDoctypeSystemIdentifier => {
+                "unexpected-character-after-doctype-system-identifier"
+            }
+            UnexpectedCharacterInAttributeName => "unexpected-character-in-attribute-name",
+            UnexpectedCharacterInUnquotedAttributeValue => {
+                "unexpected-character-in-unquoted-attribute-value"
+            }
+            UnexpectedEqualsSignBeforeAttributeName => {
+                "unexpected-equals-sign-before-attribute-name"
+            }
+            UnexpectedNullCharacter => "unexpected-null-character",
+            UnexpectedQuestionMarkInsteadOfTagName => {
+                "unexpected-question-mark-instead-of-tag-name"
+            }
+            UnexpectedSolidusInTag => "unexpected-solidus-in-tag",
+            UnknownNamedCharacterReference => "unknown-named-character-reference",
+
+            MissingDoctype => "missing-doctype",
+            NonConformingDoctype => "non-conforming-doctype",
+            UnexpectedDoctype => "unexpected-doctype",
+            UnexpectedStartTag => "unexpected-start-tag",
+            UnexpectedEndTag => "unexpected-end-tag",
+            UnexpectedCharacter => "unexpected-character",
+            UnexpectedEof => "unexpected-eof",
+            UnclosedElements => "unclosed-elements",
+        }
+    }
+}
+
+// Lines and columns both start at 1, and columns count characters
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParseError {
+    pub kind: ParseErrorKind,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}: {}",
+            self.line,
+            self.column,
+            self.kind.spec_name()
+        )
+    }
+}
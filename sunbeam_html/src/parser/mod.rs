@@ -1,2 +1,13 @@
+#[cfg(test)]
+mod conformance;
+mod encoding;
+pub mod errors;
+mod foreign;
+mod named_character_references;
 pub mod parser;
-mod tokenizer;
+pub mod preload;
+mod quirks;
+pub mod script;
+pub mod span;
+pub mod tokenizer;
+pub mod tree_sink;
@@ -6,9 +6,17 @@ use crate::document::node;
 use crate::document::node::NodeType;
 use crate::html_elements;
 use crate::html_elements::element_structs;
+use crate::html_elements::element_structs::script::ScriptTypeOption;
 use crate::html_elements::HTMLElement;
 use crate::html_elements::HTMLElementType;
+use crate::html_elements::Namespace;
+use crate::parser::errors::{ParseError, ParseErrorKind};
+use crate::parser::preload::{PreloadRequest, PreloadScanner};
+use crate::parser::script::{ParserScript, ScriptHandler, ScriptStatus, ScriptWriter};
+use crate::parser::span::SourcePosition;
 use crate::parser::tokenizer;
+use crate::parser::tree_sink::TreeSink;
+use crate::parser::{encoding, foreign, quirks};
 
 use tokenizer::TokenTag;
 
@@ -16,8 +24,28 @@ use super::tokenizer::HtmlToken;
 
 use log;
 
-pub enum DocumentParseError {
-    UnparsableDocument,
+// Settings that change how a document is parsed
+#[derive(Debug, Clone)]
+pub struct ParserOptions {
+    // Whether scripts would run, which decides whether noscript contents are parsed as markup
+    pub scripting: bool,
+    // An iframe srcdoc document is never put in quirks mode, even without a doctype
+    pub iframe_srcdoc: bool,
+    // Parses the input as the contents of an HTML element with this name, the way innerHTML
+    // would. The resulting nodes become the top level nodes of the document.
+    pub fragment_context: Option<String>,
+    pub collect_errors: bool,
+}
+
+impl Default for ParserOptions {
+    fn default() -> Self {
+        ParserOptions {
+            scripting: true,
+            iframe_srcdoc: false,
+            fragment_context: None,
+            collect_errors: true,
+        }
+    }
 }
 
 pub fn parse_document(data: &str) -> Document {
@@ -25,11 +53,102 @@ pub fn parse_document(data: &str) -> Document {
     parser.parse_html()
 }
 
+// Parses a document and returns every parse error found along the way, in source order
+pub fn parse_document_with_diagnostics(data: &str) -> (Document, Vec<ParseError>) {
+    let mut parser = Parser::new(data);
+    let doc = parser.parse_html();
+    (doc, parser.take_errors())
+}
+
+// Parses a document into any tree builder instead of a Document, and returns the parse errors
+pub fn parse_document_into(data: &str, sink: &mut dyn TreeSink) -> Vec<ParseError> {
+    let mut parser = Parser::new(data);
+    parser.parse_html_into(sink);
+    parser.take_errors()
+}
+
+pub fn parse_document_with_options(
+    data: &str,
+    options: ParserOptions,
+) -> (Document, Vec<ParseError>) {
+    let context_name = match &options.fragment_context {
+        Some(name) => name.clone(),
+        None => {
+            let mut parser = Parser::with_options(data, &options);
+            let doc = parser.parse_html();
+            return (doc, parser.take_errors());
+        }
+    };
+
+    let mut doc = Document::new();
+    let context = doc
+        .doctree
+        .add_node(node::Node::new(node::NodeType::Element(HTMLElement::new(
+            context_name,
+        ))));
+    let mut parser = Parser::with_options(data, &options);
+    parser.fragment_parser_ctx = Some(context);
+    let nodes = parser.parse_html_fragment(&mut doc);
+    doc.doctree.remove(&context);
+    for node in nodes {
+        doc.doctree.append_to_document(node);
+    }
+    (doc, parser.take_errors())
+}
+
+// Parses a snippet of HTML the way innerHTML would, as the contents of the context element.
+// The nodes are created in the context element's document but aren't attached to anything,
+// so they can be spliced into the tree wherever they're needed.
+pub fn parse_fragment(
+    doc: &mut Document,
+    context: &doctree::DoctreeNode,
+    data: &str,
+) -> Vec<doctree::DoctreeNode> {
+    let mut parser = Parser::new_fragment_parser(data, *context);
+    parser.parse_html_fragment(doc)
+}
+
+// Parses a document from raw bytes, sniffing the encoding to decode them with
+pub fn parse_document_from_bytes(data: &[u8]) -> Document {
+    let (encoding, confidence, bom_len) = encoding::sniff_encoding(data, true);
+    let data = &data[bom_len..];
+
+    let mut parser = Parser::new(&encoding::decode(data, encoding));
+    parser.encoding = encoding;
+    parser.encoding_confidence = confidence;
+    let doc = parser.parse_html();
+
+    // A meta element past the prescan window declared a different encoding, so start over
+    match parser.changed_encoding {
+        Some(changed) => {
+            let mut parser = Parser::new(&encoding::decode(data, changed));
+            parser.encoding = changed;
+            parser.encoding_confidence = document::EncodingConfidence::Certain;
+            parser.parse_html()
+        }
+        None => doc,
+    }
+}
+
+// Formatting entries keep the token they were created from, so they can be recreated
+// when reconstructing the active formatting elements or running the adoption agency
 enum FormattingNode {
-    Node(doctree::DoctreeNode),
+    Node(doctree::DoctreeNode, HtmlToken),
     Marker,
 }
 
+impl FormattingNode {
+    fn is_marker(&self) -> bool {
+        matches!(self, FormattingNode::Marker)
+    }
+}
+
+// Where a new node should go, appended to the parent or inserted before a sibling
+struct InsertionLocation {
+    parent: doctree::DoctreeNode,
+    before: Option<doctree::DoctreeNode>,
+}
+
 #[derive(Default, Clone, Copy, Debug)]
 enum InsertionMode {
     #[default]
@@ -60,51 +179,52 @@ enum InsertionMode {
 
 struct OpenNodeStack {
     pub stack: Vec<doctree::DoctreeNode>,
-    tokenizer_tracker: Vec<bool>, // This is a terrible hack, should be removed in a refactor
 }
 
 impl OpenNodeStack {
     pub fn new() -> OpenNodeStack {
-        OpenNodeStack {
-            stack: Vec::new(),
-            tokenizer_tracker: Vec::new(),
-        }
+        OpenNodeStack { stack: Vec::new() }
     }
 
-    pub fn push(
-        &mut self,
-        tokenizer: &mut tokenizer::Tokenizer,
-        node: doctree::DoctreeNode,
-        node_name: String,
-    ) {
+    pub fn push(&mut self, node: doctree::DoctreeNode) {
         self.stack.push(node);
-        self.tokenizer_tracker.push(true);
-        tokenizer.push_open_tag(node_name);
     }
 
-    pub fn push_self_only(&mut self, node: doctree::DoctreeNode) -> usize {
-        self.stack.push(node);
-        self.tokenizer_tracker.push(false);
+    pub fn pop(&mut self) -> Option<DoctreeNode> {
+        self.stack.pop()
+    }
 
-        self.stack.len() - 1
+    pub fn remove_at(&mut self, idx: usize) {
+        self.stack.remove(idx);
     }
 
-    pub fn pop(&mut self, tokenizer: &mut tokenizer::Tokenizer) -> Option<DoctreeNode> {
-        if let Some(true) = self.tokenizer_tracker.pop() {
-            tokenizer.pop_open_tag();
+    pub fn remove_node(&mut self, node: &doctree::DoctreeNode) {
+        if let Some(idx) = self.index_of(node) {
+            self.stack.remove(idx);
         }
-        self.stack.pop()
     }
 
-    pub fn remove_at(&mut self, idx: usize) {
-        self.stack.remove(idx);
+    pub fn insert_at(&mut self, idx: usize, node: doctree::DoctreeNode) {
+        self.stack.insert(idx, node);
+    }
+
+    pub fn replace_at(&mut self, idx: usize, node: doctree::DoctreeNode) {
+        self.stack[idx] = node;
+    }
+
+    pub fn index_of(&self, node: &doctree::DoctreeNode) -> Option<usize> {
+        self.stack.iter().position(|n| n == node)
+    }
+
+    pub fn contains(&self, node: &doctree::DoctreeNode) -> bool {
+        self.stack.contains(node)
     }
 
-    pub fn first(&mut self) -> Option<&doctree::DoctreeNode> {
+    pub fn first(&self) -> Option<&doctree::DoctreeNode> {
         self.stack.first()
     }
 
-    pub fn last(&mut self) -> Option<&doctree::DoctreeNode> {
+    pub fn last(&self) -> Option<&doctree::DoctreeNode> {
         self.stack.last()
     }
 
@@ -115,16 +235,168 @@ impl OpenNodeStack {
     pub fn at(&self, index: usize) -> Option<&doctree::DoctreeNode> {
         self.stack.get(index)
     }
+
+    // Walks down from the current node until it finds a target, or hits an element that
+    // bounds the scope
+    fn has_in_specific_scope(
+        &self,
+        sink: &dyn TreeSink,
+        scope: Scope,
+        is_target: impl Fn(&doctree::DoctreeNode) -> bool,
+    ) -> bool {
+        for node in self.stack.iter().rev() {
+            if is_target(node) {
+                return true;
+            }
+            if scope.is_boundary(sink, node) {
+                return false;
+            }
+        }
+        false
+    }
+
+    pub fn has_element_in_scope(&self, sink: &dyn TreeSink, name: &str) -> bool {
+        self.has_in_specific_scope(sink, Scope::Default, |n| {
+            element_name_matches(sink, n, name)
+        })
+    }
+
+    pub fn has_element_in_list_item_scope(&self, sink: &dyn TreeSink, name: &str) -> bool {
+        self.has_in_specific_scope(sink, Scope::ListItem, |n| {
+            element_name_matches(sink, n, name)
+        })
+    }
+
+    pub fn has_element_in_button_scope(&self, sink: &dyn TreeSink, name: &str) -> bool {
+        self.has_in_specific_scope(sink, Scope::Button, |n| element_name_matches(sink, n, name))
+    }
+
+    pub fn has_element_in_table_scope(&self, sink: &dyn TreeSink, name: &str) -> bool {
+        self.has_in_specific_scope(sink, Scope::Table, |n| element_name_matches(sink, n, name))
+    }
+
+    pub fn has_element_in_select_scope(&self, sink: &dyn TreeSink, name: &str) -> bool {
+        self.has_in_specific_scope(sink, Scope::Select, |n| element_name_matches(sink, n, name))
+    }
+
+    // For the end tags that close any of a group of elements, like h1 to h6
+    pub fn has_any_element_in_scope(&self, sink: &dyn TreeSink, names: &[&str]) -> bool {
+        self.has_in_specific_scope(sink, Scope::Default, |n| {
+            names.iter().any(|name| element_name_matches(sink, n, name))
+        })
+    }
+
+    pub fn has_any_element_in_table_scope(&self, sink: &dyn TreeSink, names: &[&str]) -> bool {
+        self.has_in_specific_scope(sink, Scope::Table, |n| {
+            names.iter().any(|name| element_name_matches(sink, n, name))
+        })
+    }
+
+    pub fn has_node_in_scope(&self, sink: &dyn TreeSink, target: &doctree::DoctreeNode) -> bool {
+        self.has_in_specific_scope(sink, Scope::Default, |n| n == target)
+    }
+
+    // Pops up to and including the closest HTML element with one of the names
+    pub fn pop_until_one_of(&mut self, sink: &dyn TreeSink, names: &[&str]) {
+        while let Some(node) = self.pop() {
+            if names
+                .iter()
+                .any(|name| element_name_matches(sink, &node, name))
+            {
+                return;
+            }
+        }
+    }
+
+    pub fn pop_until(&mut self, sink: &dyn TreeSink, name: &str) {
+        self.pop_until_one_of(sink, &[name]);
+    }
+
+    // Pops elements that are allowed to be closed without an end tag, except for the
+    // element named by except
+    pub fn generate_implied_end_tags(&mut self, sink: &dyn TreeSink, except: Option<&str>) {
+        while let Some(node) = self.last() {
+            let name = match get_element_name_and_namespace(sink, node) {
+                Some((name, Namespace::Html)) => name,
+                _ => return,
+            };
+            if except == Some(name.as_str()) || !has_implied_end_tag(&name) {
+                return;
+            }
+            self.pop();
+        }
+    }
+
+    // The same, but also pops the table parts, as when a template is closed
+    pub fn generate_all_implied_end_tags_thoroughly(&mut self, sink: &dyn TreeSink) {
+        while let Some(node) = self.last() {
+            let name = match get_element_name_and_namespace(sink, node) {
+                Some((name, Namespace::Html)) => name,
+                _ => return,
+            };
+            let table_part = matches!(
+                name.as_str(),
+                "caption" | "colgroup" | "tbody" | "td" | "tfoot" | "th" | "thead" | "tr"
+            );
+            if !table_part && !has_implied_end_tag(&name) {
+                return;
+            }
+            self.pop();
+        }
+    }
+}
+
+// The kinds of scope the stack of open elements can be searched in
+#[derive(Clone, Copy, PartialEq)]
+enum Scope {
+    Default,
+    ListItem,
+    Button,
+    Table,
+    Select,
+}
+
+impl Scope {
+    fn is_boundary(&self, sink: &dyn TreeSink, entry: &doctree::DoctreeNode) -> bool {
+        match self {
+            Scope::Default => is_element_scope_boundary(sink, entry),
+            Scope::ListItem => {
+                is_element_scope_boundary(sink, entry)
+                    || element_name_matches(sink, entry, "ol")
+                    || element_name_matches(sink, entry, "ul")
+            }
+            Scope::Button => {
+                is_element_scope_boundary(sink, entry)
+                    || element_name_matches(sink, entry, "button")
+            }
+            Scope::Table => {
+                element_name_matches(sink, entry, "html")
+                    || element_name_matches(sink, entry, "table")
+                    || element_name_matches(sink, entry, "template")
+            }
+            // Everything but optgroup and option bounds select scope
+            Scope::Select => {
+                !element_name_matches(sink, entry, "optgroup")
+                    && !element_name_matches(sink, entry, "option")
+            }
+        }
+    }
+}
+
+const HEADING_ELEMENTS: [&str; 6] = ["h1", "h2", "h3", "h4", "h5", "h6"];
+
+fn has_implied_end_tag(name: &str) -> bool {
+    matches!(
+        name,
+        "dd" | "dt" | "li" | "optgroup" | "option" | "p" | "rb" | "rp" | "rt" | "rtc"
+    )
 }
 
 macro_rules! is_entry_element {
     ($doc:expr, $entry:expr, $pattern:pat) => {
-        if let Some(entry) = $doc.doctree.get_node($entry) {
-            match &entry.node_type {
-                NodeType::Element(element) => match element.element_type {
-                    $pattern => true,
-                    _ => false,
-                },
+        if let Some(element) = $doc.get_element($entry) {
+            match element.element_type {
+                $pattern => true,
                 _ => false,
             }
         } else {
@@ -133,67 +405,165 @@ macro_rules! is_entry_element {
     };
 }
 
-macro_rules! is_entry_marker {
-    ($doc:expr, $entry:expr) => {
-        match $entry {
-            FormattingNode::Node(n) => is_entry_element!(
-                $doc,
-                n,
-                |HTMLElementType::Object(_)| HTMLElementType::Template(_)
-                    | HTMLElementType::Td(_)
-                    | HTMLElementType::Th(_)
-                    | HTMLElementType::Caption(_) // | HTMLElementType::Applet(_)
-                                                  // | HTMLElementType::Marquee(_)
-            ),
-            FormattingNode::Marker => true,
-        }
-    };
+// Only matches HTML elements, SVG and MathML elements can share names with them
+fn element_name_matches(doc: &dyn TreeSink, entry: &doctree::DoctreeNode, name: &str) -> bool {
+    match doc.get_element(entry) {
+        Some(element) => element.namespace == Namespace::Html && element.get_name() == name,
+        None => false,
+    }
 }
 
-fn element_name_matches(doc: &Document, entry: &doctree::DoctreeNode, name: &str) -> bool {
-    if let Some(node) = doc.doctree.get_node(entry) {
-        if let NodeType::Element(element) = &node.node_type {
-            return element.get_name() == name;
-        }
+// The node at the top of the tree the node is in
+fn tree_root(sink: &dyn TreeSink, node: &doctree::DoctreeNode) -> doctree::DoctreeNode {
+    let mut root = *node;
+    while let Some(parent) = sink.get_parent(&root) {
+        root = parent;
     }
-    false
+    root
 }
 
-fn is_element_special(
-    doctree: &doctree::Doctree,
+fn get_element_name_and_namespace(
+    sink: &dyn TreeSink,
     entry: &doctree::DoctreeNode,
-    restrict: bool,
-) -> bool {
-    if let Some(entry) = doctree.get_node(entry) {
-        use html_elements::HTMLElementType::*;
-        match &entry.node_type {
-            NodeType::Element(element) => match element.element_type {
-                // Applet(_) | Basefont(_) | Bgsound(_) | Center(_) | Dir(_) |
-                // Frame(_) | Frameset(_) | Param(_) | Plaintext(_) | Xmp(_) |
-                // MathMls | SVGs
-                Area(_) | Article(_) | Aside(_) | Base(_) | Blockquote(_) | Body(_) | Br(_)
-                | Button(_) | Caption(_) | Col(_) | Colgroup(_) | Dd(_) | Details(_) | Dl(_)
-                | Dt(_) | Fieldset(_) | Figcaption(_) | Figure(_) | Footer(_) | Form(_)
-                | Noscript(_) | Object(_) | Ol(_) | Pre(_) | Script(_) | Search(_) | Section(_)
-                | Select(_) | Source(_) | Style(_) | Summary(_) | Table(_) | Tbody(_) | Td(_)
-                | Template(_) | Textarea(_) | Tfoot(_) | Th(_) | THead(_) | Title(_) | Tr(_)
-                | Track(_) | Ul(_) | Wbr(_) => true,
-                Address(_) | Div(_) | P(_) => {
-                    if restrict {
-                        true
-                    } else {
-                        false
-                    }
-                }
-                _ => false,
-            },
-            _ => false,
+) -> Option<(String, Namespace)> {
+    Some((
+        sink.get_element_name(entry)?,
+        sink.get_element_namespace(entry)?,
+    ))
+}
+
+fn is_mathml_text_integration_point(name: &str, namespace: Namespace) -> bool {
+    namespace == Namespace::MathMl && matches!(name, "mi" | "mo" | "mn" | "ms" | "mtext")
+}
+
+fn is_html_integration_point(sink: &dyn TreeSink, entry: &doctree::DoctreeNode) -> bool {
+    let element = match sink.get_element(entry) {
+        Some(element) => element,
+        None => return false,
+    };
+    match element.namespace {
+        Namespace::MathMl => {
+            element.get_name() == "annotation-xml"
+                && element.get_attribute("encoding").is_some_and(|encoding| {
+                    encoding.eq_ignore_ascii_case("text/html")
+                        || encoding.eq_ignore_ascii_case("application/xhtml+xml")
+                })
         }
-    } else {
-        false
+        Namespace::Svg => matches!(element.get_name(), "foreignObject" | "desc" | "title"),
+        _ => false,
+    }
+}
+
+// When restrict is set address, div and p are not considered special, as required
+// by the li, dd and dt start tag handling
+fn is_element_special(sink: &dyn TreeSink, entry: &doctree::DoctreeNode, restrict: bool) -> bool {
+    let (name, namespace) = match get_element_name_and_namespace(sink, entry) {
+        Some(element) => element,
+        None => return false,
+    };
+    match namespace {
+        Namespace::MathMl => {
+            return matches!(
+                name.as_str(),
+                "mi" | "mo" | "mn" | "ms" | "mtext" | "annotation-xml"
+            )
+        }
+        Namespace::Svg => return matches!(name.as_str(), "foreignObject" | "desc" | "title"),
+        _ => {}
+    }
+    match name.as_str() {
+        "address" | "div" | "p" => !restrict,
+        "applet" | "area" | "article" | "aside" | "base" | "basefont" | "bgsound"
+        | "blockquote" | "body" | "br" | "button" | "caption" | "center" | "col" | "colgroup"
+        | "dd" | "details" | "dir" | "dl" | "dt" | "embed" | "fieldset" | "figcaption"
+        | "figure" | "footer" | "form" | "frame" | "frameset" | "h1" | "h2" | "h3" | "h4"
+        | "h5" | "h6" | "head" | "header" | "hgroup" | "hr" | "html" | "iframe" | "img"
+        | "input" | "keygen" | "li" | "link" | "listing" | "main" | "marquee" | "menu" | "meta"
+        | "nav" | "noembed" | "noframes" | "noscript" | "object" | "ol" | "param" | "plaintext"
+        | "pre" | "script" | "search" | "section" | "select" | "source" | "style" | "summary"
+        | "table" | "tbody" | "td" | "template" | "textarea" | "tfoot" | "th" | "thead"
+        | "title" | "tr" | "track" | "ul" | "wbr" | "xmp" => true,
+        _ => false,
+    }
+}
+
+// Elements that bound the default "has an element in scope" search
+fn is_element_scope_boundary(sink: &dyn TreeSink, entry: &doctree::DoctreeNode) -> bool {
+    let (name, namespace) = match get_element_name_and_namespace(sink, entry) {
+        Some(element) => element,
+        None => return false,
+    };
+    match namespace {
+        Namespace::Html => matches!(
+            name.as_str(),
+            "applet"
+                | "caption"
+                | "html"
+                | "table"
+                | "td"
+                | "th"
+                | "marquee"
+                | "object"
+                | "template"
+        ),
+        Namespace::MathMl => matches!(
+            name.as_str(),
+            "mi" | "mo" | "mn" | "ms" | "mtext" | "annotation-xml"
+        ),
+        Namespace::Svg => matches!(name.as_str(), "foreignObject" | "desc" | "title"),
+        _ => false,
+    }
+}
+
+// Formatting elements match if they share a tag name and attribute set, regardless of order
+fn formatting_tokens_match(a: &HtmlToken, b: &HtmlToken) -> bool {
+    if a.data != b.data || a.attributes.len() != b.attributes.len() {
+        return false;
+    }
+    a.attributes.iter().all(|attr| {
+        b.attributes
+            .iter()
+            .any(|other| other.name == attr.name && other.value == attr.value)
+    })
+}
+
+// Elements that a shadow root can be attached to
+fn is_valid_shadow_host(name: &str) -> bool {
+    match name {
+        "article" | "aside" | "blockquote" | "body" | "div" | "footer" | "h1" | "h2" | "h3"
+        | "h4" | "h5" | "h6" | "header" | "main" | "nav" | "p" | "section" | "span" => true,
+        // Custom elements must contain a hyphen and can't use a reserved name
+        "annotation-xml" | "color-profile" | "font-face" | "font-face-src" | "font-face-uri"
+        | "font-face-format" | "font-face-name" | "missing-glyph" => false,
+        _ => name.starts_with(|c: char| c.is_ascii_lowercase()) && name.contains('-'),
     }
 }
 
+fn is_void_element(name: &str) -> bool {
+    matches!(
+        name,
+        "area"
+            | "base"
+            | "basefont"
+            | "bgsound"
+            | "br"
+            | "col"
+            | "embed"
+            | "frame"
+            | "hr"
+            | "image"
+            | "img"
+            | "input"
+            | "keygen"
+            | "link"
+            | "meta"
+            | "param"
+            | "source"
+            | "track"
+            | "wbr"
+    )
+}
+
 fn is_token_whitespace(token: &HtmlToken) -> bool {
     token
         .data
@@ -201,14 +571,23 @@ fn is_token_whitespace(token: &HtmlToken) -> bool {
 }
 
 pub struct Parser {
-    fragment_parser_ctx: Option<node::Node>,
+    // The context element, when parsing a fragment
+    fragment_parser_ctx: Option<doctree::DoctreeNode>,
     tokenizer: tokenizer::Tokenizer,
     insertion_mode: InsertionMode,
     original_insertion_mode: InsertionMode,
+    template_insertion_modes: Vec<InsertionMode>,
     cannot_change_mode: bool,
+    // Cleared once something shows up that can't be part of a frameset document
+    frameset_ok: bool,
+    // Set after pre, listing and textarea start tags, whose first newline is dropped
+    skip_next_newline: bool,
+    iframe_srcdoc: bool,
     scripting: bool,
-    speculative_parser: Option<()>,
+    // Looks ahead for resources to fetch while a script holds up the parser
+    speculative_parser: Option<PreloadScanner>,
     open_node_stack: OpenNodeStack,
+    foster_parenting: bool,
 
     head_element: Option<doctree::DoctreeNode>,
     form_element: Option<doctree::DoctreeNode>,
@@ -217,23 +596,46 @@ pub struct Parser {
 
     parser_pause_flag: bool,
     script_nesting_level: u32,
+    pending_parsing_blocking_script: Option<doctree::DoctreeNode>,
+    script_handler: Option<Box<dyn ScriptHandler>>,
+    // Source of the script element being parsed
+    script_text: String,
 
     pending_character_tokens: Vec<HtmlToken>,
 
     active_formatting_elements: Vec<FormattingNode>,
+
+    quirks_mode: document::QuirksMode,
+    encoding: &'static encoding_rs::Encoding,
+    encoding_confidence: document::EncodingConfidence,
+    changed_encoding: Option<&'static encoding_rs::Encoding>,
+
+    errors: Vec<ParseError>,
+    collect_errors: bool,
 }
 
 impl Parser {
     pub fn new(data: &str) -> Parser {
+        Parser::with_options(data, &ParserOptions::default())
+    }
+
+    // The fragment context in the options is ignored, since it names an element rather than
+    // pointing at one. Use new_fragment_parser for that.
+    pub fn with_options(data: &str, options: &ParserOptions) -> Parser {
         Parser {
             fragment_parser_ctx: None,
             tokenizer: tokenizer::Tokenizer::init(data),
             insertion_mode: InsertionMode::Initial,
             original_insertion_mode: InsertionMode::Initial,
+            template_insertion_modes: Vec::new(),
             cannot_change_mode: false,
-            scripting: true,
+            frameset_ok: true,
+            skip_next_newline: false,
+            iframe_srcdoc: options.iframe_srcdoc,
+            scripting: options.scripting,
             speculative_parser: None,
             open_node_stack: OpenNodeStack::new(),
+            foster_parenting: false,
 
             head_element: None,
             form_element: None,
@@ -242,54 +644,187 @@ impl Parser {
 
             parser_pause_flag: false,
             script_nesting_level: 0,
+            pending_parsing_blocking_script: None,
+            script_handler: None,
+            script_text: String::new(),
 
             pending_character_tokens: Vec::new(),
 
             active_formatting_elements: Vec::new(),
+
+            quirks_mode: document::QuirksMode::Off,
+            encoding: encoding_rs::UTF_8,
+            encoding_confidence: document::EncodingConfidence::Irrelevant,
+            changed_encoding: None,
+
+            errors: Vec::new(),
+            collect_errors: options.collect_errors,
         }
     }
 
-    pub fn new_fragment_parser(data: &str, ctx: node::Node) -> Parser {
-        Parser {
-            fragment_parser_ctx: Some(ctx),
-            tokenizer: tokenizer::Tokenizer::init(data),
-            insertion_mode: InsertionMode::Initial,
-            original_insertion_mode: InsertionMode::Initial,
-            cannot_change_mode: false,
-            scripting: true,
-            speculative_parser: None,
-            open_node_stack: OpenNodeStack::new(),
+    pub fn new_fragment_parser(data: &str, ctx: doctree::DoctreeNode) -> Parser {
+        let mut parser = Parser::new(data);
+        parser.fragment_parser_ctx = Some(ctx);
+        parser
+    }
 
-            head_element: None,
-            form_element: None,
-            reconsume_token: None,
-            should_exit: false,
+    pub fn take_errors(&mut self) -> Vec<ParseError> {
+        std::mem::take(&mut self.errors)
+    }
 
-            parser_pause_flag: false,
-            script_nesting_level: 0,
+    pub fn set_script_handler(&mut self, handler: Box<dyn ScriptHandler>) {
+        self.script_handler = Some(handler);
+    }
 
-            pending_character_tokens: Vec::new(),
+    // The script the parser is paused at, waiting for the handler to be able to run it
+    pub fn get_pending_script(&self) -> Option<doctree::DoctreeNode> {
+        self.pending_parsing_blocking_script
+    }
 
-            active_formatting_elements: Vec::new(),
+    pub fn is_paused(&self) -> bool {
+        self.pending_parsing_blocking_script.is_some()
+    }
+
+    // Markup written while the parser is paused at a script goes right after the script's
+    // end tag. Otherwise there's no insertion point, and writing would need the document
+    // to be reopened, so the markup is dropped and false is returned.
+    pub fn document_write(&mut self, markup: &str) -> bool {
+        if self.pending_parsing_blocking_script.is_none() {
+            return false;
+        }
+        self.tokenizer.insert_at_insertion_point(markup);
+        true
+    }
+
+    // Resources found ahead of a pending script since the last call, most urgent first
+    pub fn take_preload_requests(&mut self) -> Vec<PreloadRequest> {
+        match &mut self.speculative_parser {
+            Some(scanner) => scanner.take_requests(),
+            None => Vec::new(),
+        }
+    }
+
+    // Carries on parsing once the pending script has run
+    pub fn resume(&mut self, sink: &mut dyn TreeSink) {
+        if self.pending_parsing_blocking_script.take().is_none() {
+            return;
+        }
+        self.speculative_parser = None;
+        self.parser_pause_flag = false;
+        self.run(sink);
+        if self.should_exit {
+            self.finish_document(sink);
         }
     }
 
     // Utilities functions
 
+    fn set_quirks_mode(&mut self, doc: &mut dyn TreeSink, mode: document::QuirksMode) {
+        self.quirks_mode = mode;
+        doc.set_quirks_mode(mode);
+    }
+
+    // Errors are reported where the token they're about starts
+    fn parse_error(&mut self, kind: ParseErrorKind, token: &HtmlToken) {
+        if !self.collect_errors {
+            return;
+        }
+        let SourcePosition { line, column, .. } = token.span.start;
+        self.errors.push(ParseError { kind, line, column });
+    }
+
+    // Tree construction errors are named after the token the current insertion mode rejected
+    fn token_error(&mut self, token: &HtmlToken) {
+        let kind = match token.tag {
+            TokenTag::Doctype(_) => ParseErrorKind::UnexpectedDoctype,
+            TokenTag::StartTag => ParseErrorKind::UnexpectedStartTag,
+            TokenTag::EndTag => ParseErrorKind::UnexpectedEndTag,
+            TokenTag::Character if token.data == "\u{0000}" => {
+                ParseErrorKind::UnexpectedNullCharacter
+            }
+            TokenTag::Character | TokenTag::Comment => ParseErrorKind::UnexpectedCharacter,
+            TokenTag::EoF => ParseErrorKind::UnexpectedEof,
+        };
+        self.parse_error(kind, token);
+    }
+
+    // Elements whose end tag may be left out don't count as unclosed
+    fn check_unclosed_elements(&mut self, doc: &dyn TreeSink, token: &HtmlToken) {
+        let unclosed = self.open_node_stack.stack.iter().any(|entry| {
+            match get_element_name_and_namespace(doc, entry) {
+                Some((name, Namespace::Html)) => !matches!(
+                    name.as_str(),
+                    "dd" | "dt"
+                        | "li"
+                        | "optgroup"
+                        | "option"
+                        | "p"
+                        | "rb"
+                        | "rp"
+                        | "rt"
+                        | "rtc"
+                        | "tbody"
+                        | "td"
+                        | "tfoot"
+                        | "th"
+                        | "thead"
+                        | "tr"
+                        | "body"
+                        | "html"
+                ),
+                Some(_) => true,
+                None => false,
+            }
+        });
+        if unclosed {
+            self.parse_error(ParseErrorKind::UnclosedElements, token);
+        }
+    }
+
     fn create_element_from_token(&self, token: HtmlToken) -> node::Node {
-        if self.speculative_parser.is_some() {}
         // TODO: Lookup custom element, for now we skip
 
         let mut element = HTMLElement::new(token.data);
+        let mut attribute_spans = Vec::new();
 
         token.attributes.into_iter().for_each(|val| {
+            attribute_spans.push((val.name.clone(), val.span));
             element.add_attribute(val.name, val.value);
         });
 
-        node::Node::new(node::NodeType::Element(element))
+        let mut node = node::Node::new(node::NodeType::Element(element));
+        node.span = Some(token.span);
+        node.attribute_spans = attribute_spans;
+        node
+    }
+
+    // Meta elements can declare the encoding when it was only guessed at
+    fn check_meta_encoding(&mut self, token: &HtmlToken) {
+        if self.encoding_confidence != document::EncodingConfidence::Tentative
+            || self.speculative_parser.is_some()
+        {
+            return;
+        }
+        let declared = match encoding::encoding_from_meta_attributes(
+            token
+                .attributes
+                .iter()
+                .map(|a| (a.name.as_str(), a.value.as_str())),
+        ) {
+            Some(e) => e,
+            None => return,
+        };
+
+        if declared == self.encoding {
+            self.encoding_confidence = document::EncodingConfidence::Certain;
+        } else {
+            // TODO: Change the encoding in place when the new one decodes the parsed
+            //       bytes the same way, instead of reparsing
+            self.changed_encoding = Some(declared);
+        }
     }
 
-    fn clear_stack_back_to_table(&mut self, doc: &mut Document) {
+    fn clear_stack_back_to_table(&mut self, doc: &mut dyn TreeSink) {
         while match self.open_node_stack.last() {
             Some(n) => !is_entry_element!(
                 doc,
@@ -298,115 +833,127 @@ impl Parser {
             ),
             None => false,
         } {
-            self.open_node_stack.pop(&mut self.tokenizer);
+            self.open_node_stack.pop();
         }
     }
 
-    fn reset_insertion_mode(&mut self, doc: &Document) {
+    fn reset_insertion_mode(&mut self, doc: &dyn TreeSink) {
         let mut last = false;
-        let mut node_idx = self.open_node_stack.len() - 1;
+        let mut node_idx = match self.open_node_stack.len().checked_sub(1) {
+            Some(idx) => idx,
+            None => {
+                self.insertion_mode = InsertionMode::InBody;
+                return;
+            }
+        };
 
         loop {
             last = if node_idx == 0 { true } else { false };
-            let node = match self.fragment_parser_ctx.as_ref() {
-                Some(ctx_node) => ctx_node,
-                None => match self.open_node_stack.at(node_idx) {
-                    Some(n) => match doc.doctree.get_node(n) {
-                        Some(node) => node,
-                        None => {
-                            // ERROR: This is a confusing, off spec error that we should never hit
-                            self.insertion_mode = InsertionMode::InBody;
-                            return;
-                        }
-                    },
+            // The bottom of the stack stands in for the context element of a fragment
+            let entry = match self.fragment_parser_ctx {
+                Some(ctx) if last => Some(ctx),
+                _ => self.open_node_stack.at(node_idx).cloned(),
+            };
+            let element = match entry {
+                Some(n) => match doc.get_element(&n) {
+                    Some(element) => element,
                     None => {
                         // ERROR: This is a confusing, off spec error that we should never hit
                         self.insertion_mode = InsertionMode::InBody;
                         return;
                     }
                 },
+                None => {
+                    // ERROR: This is a confusing, off spec error that we should never hit
+                    self.insertion_mode = InsertionMode::InBody;
+                    return;
+                }
             };
 
-            if let NodeType::Element(e) = &node.node_type {
-                use HTMLElementType::*;
-                match e.element_type {
-                    Select(_) => {
-                        let mut ancestor_idx = node_idx;
-                        loop {
-                            if last || ancestor_idx == 0 {
-                                self.insertion_mode = InsertionMode::InTable;
-                                return;
-                            }
-                            match self.open_node_stack.at(ancestor_idx) {
-                                Some(n) => {
-                                    if is_entry_element!(doc, n, Template(_)) {
-                                        self.insertion_mode = InsertionMode::InTable;
-                                        return;
-                                    }
-                                    if is_entry_element!(doc, n, Table(_)) {
-                                        self.insertion_mode = InsertionMode::InSelectTable;
-                                        return;
-                                    }
-                                }
-                                None => {
-                                    // This is an absolutely confusing error
-                                }
-                            };
-                            ancestor_idx -= 1;
-                        }
-                    }
-                    Td(_) | Th(_) => {
-                        if !last {
-                            self.insertion_mode = InsertionMode::InCell;
+            use HTMLElementType::*;
+            match element.element_type {
+                Select(_) => {
+                    let mut ancestor_idx = node_idx;
+                    loop {
+                        if last || ancestor_idx == 0 {
+                            self.insertion_mode = InsertionMode::InTable;
                             return;
                         }
+                        match self.open_node_stack.at(ancestor_idx) {
+                            Some(n) => {
+                                if is_entry_element!(doc, n, Template(_)) {
+                                    self.insertion_mode = InsertionMode::InTable;
+                                    return;
+                                }
+                                if is_entry_element!(doc, n, Table(_)) {
+                                    self.insertion_mode = InsertionMode::InSelectTable;
+                                    return;
+                                }
+                            }
+                            None => {
+                                // This is an absolutely confusing error
+                            }
+                        };
+                        ancestor_idx -= 1;
                     }
-                    Tr(_) => {
-                        self.insertion_mode = InsertionMode::InRow;
-                        return;
-                    }
-                    Tbody(_) | THead(_) | Tfoot(_) => {
-                        self.insertion_mode = InsertionMode::InTableBody;
-                        return;
-                    }
-                    Caption(_) => {
-                        self.insertion_mode = InsertionMode::InCaption;
+                }
+                Td(_) | Th(_) => {
+                    if !last {
+                        self.insertion_mode = InsertionMode::InCell;
                         return;
                     }
-                    Colgroup(_) => {
-                        self.insertion_mode = InsertionMode::InColumnGroup;
+                }
+                Tr(_) => {
+                    self.insertion_mode = InsertionMode::InRow;
+                    return;
+                }
+                Tbody(_) | THead(_) | Tfoot(_) => {
+                    self.insertion_mode = InsertionMode::InTableBody;
+                    return;
+                }
+                Caption(_) => {
+                    self.insertion_mode = InsertionMode::InCaption;
+                    return;
+                }
+                Colgroup(_) => {
+                    self.insertion_mode = InsertionMode::InColumnGroup;
+                    return;
+                }
+                Table(_) => {
+                    self.insertion_mode = InsertionMode::InTable;
+                    return;
+                }
+                Template(_) => {
+                    self.insertion_mode = self
+                        .template_insertion_modes
+                        .last()
+                        .cloned()
+                        .unwrap_or(InsertionMode::InTemplate);
+                    return;
+                }
+                Head(_) => {
+                    if !last {
+                        self.insertion_mode = InsertionMode::InHead;
                         return;
                     }
-                    Table(_) => {
-                        self.insertion_mode = InsertionMode::InTable;
-                        return;
-                    }
-                    Template(_) => {
-                        // TODO: Reassess when template support is added
-                        self.insertion_mode = InsertionMode::InTemplate;
-                        return;
-                    }
-                    Head(_) => {
-                        if !last {
-                            self.insertion_mode = InsertionMode::InHead;
-                            return;
-                        }
-                    }
-                    Body(_) => {
-                        self.insertion_mode = InsertionMode::InBody;
-                        return;
-                    }
-                    // TODO: Frameset
-                    Html(_) => {
-                        if self.head_element.is_none() {
-                            self.insertion_mode = InsertionMode::BeforeHead;
-                        } else {
-                            self.insertion_mode = InsertionMode::AfterHead;
-                        }
-                        return;
+                }
+                Body(_) => {
+                    self.insertion_mode = InsertionMode::InBody;
+                    return;
+                }
+                Frameset(_) => {
+                    self.insertion_mode = InsertionMode::InFrameset;
+                    return;
+                }
+                Html(_) => {
+                    if self.head_element.is_none() {
+                        self.insertion_mode = InsertionMode::BeforeHead;
+                    } else {
+                        self.insertion_mode = InsertionMode::AfterHead;
                     }
-                    _ => {}
+                    return;
                 }
+                _ => {}
             }
 
             if last {
@@ -417,7 +964,7 @@ impl Parser {
         }
     }
 
-    fn clear_stack_back_to_table_body(&mut self, doc: &Document) {
+    fn clear_stack_back_to_table_body(&mut self, doc: &dyn TreeSink) {
         while let Some(n) = self.open_node_stack.last() {
             if is_entry_element!(
                 doc,
@@ -430,11 +977,11 @@ impl Parser {
             ) {
                 return;
             }
-            self.open_node_stack.pop(&mut self.tokenizer);
+            self.open_node_stack.pop();
         }
     }
 
-    fn clear_stack_back_to_table_row(&mut self, doc: &Document) {
+    fn clear_stack_back_to_table_row(&mut self, doc: &dyn TreeSink) {
         while let Some(n) = self.open_node_stack.last() {
             if is_entry_element!(
                 doc,
@@ -443,300 +990,718 @@ impl Parser {
             ) {
                 return;
             }
-            self.open_node_stack.pop(&mut self.tokenizer);
+            self.open_node_stack.pop();
+        }
+    }
+
+    fn close_caption(&mut self, doc: &dyn TreeSink, token: &HtmlToken) {
+        self.open_node_stack.generate_implied_end_tags(doc, None);
+        self.check_current_node(doc, token, &["caption"]);
+        self.open_node_stack.pop_until(doc, "caption");
+        self.clear_active_formatting_to_marker();
+        self.insertion_mode = InsertionMode::InTable;
+    }
+
+    fn close_cell(&mut self, doc: &mut dyn TreeSink, token: &HtmlToken) {
+        self.open_node_stack.generate_implied_end_tags(doc, None);
+        self.check_current_node(doc, token, &["td", "th"]);
+        self.open_node_stack.pop_until_one_of(doc, &["td", "th"]);
+        self.clear_active_formatting_to_marker();
+        self.insertion_mode = InsertionMode::InRow;
+    }
+
+    fn active_formatting_index_of(&self, node: &doctree::DoctreeNode) -> Option<usize> {
+        self.active_formatting_elements
+            .iter()
+            .position(|e| matches!(e, FormattingNode::Node(n, _) if n == node))
+    }
+
+    // Finds the last formatting element with a tag name after the last marker
+    fn find_active_formatting_element(
+        &self,
+        name: &str,
+    ) -> Option<(usize, doctree::DoctreeNode, HtmlToken)> {
+        for (idx, entry) in self.active_formatting_elements.iter().enumerate().rev() {
+            match entry {
+                FormattingNode::Marker => return None,
+                FormattingNode::Node(node, token) => {
+                    if token.data == name {
+                        return Some((idx, *node, token.clone()));
+                    }
+                }
+            }
         }
+        None
     }
 
-    fn close_cell(&mut self, doc: &mut Document) {
-        // TODO: Generate implied end tags
-        self.open_node_stack.pop(&mut self.tokenizer);
-        if let Some(node) = self.open_node_stack.last() {
-            if !is_entry_element!(doc, node, HTMLElementType::Td(_) | HTMLElementType::Th(_)) {
-                // ERROR
+    fn push_to_active_formatting_elements(&mut self, node: doctree::DoctreeNode, token: HtmlToken) {
+        // Noah's Ark clause, only allow three identical elements after the last marker
+        let mut matching = Vec::new();
+        for (idx, entry) in self.active_formatting_elements.iter().enumerate().rev() {
+            match entry {
+                FormattingNode::Marker => break,
+                FormattingNode::Node(_, entry_token) => {
+                    // TODO: Check the namespace
+                    if formatting_tokens_match(entry_token, &token) {
+                        matching.push(idx);
+                    }
+                }
             }
         }
-        while let Some(node) = self.open_node_stack.pop(&mut self.tokenizer) {
-            if !is_entry_element!(doc, &node, HTMLElementType::Td(_) | HTMLElementType::Th(_)) {
-                break;
+        if matching.len() >= 3 {
+            if let Some(earliest) = matching.last() {
+                self.active_formatting_elements.remove(*earliest);
             }
         }
-        self.clear_active_formatting_to_marker(doc);
-        self.insertion_mode = InsertionMode::InRow;
+
+        self.active_formatting_elements
+            .push(FormattingNode::Node(node, token));
     }
 
-    fn is_in_table_scope(&self, doc: &mut Document) -> bool {
-        self.open_node_stack.stack.iter().any(|n| {
-            is_entry_element!(
-                doc,
-                n,
-                HTMLElementType::Html(_) | HTMLElementType::Table(_) | HTMLElementType::Template(_)
-            )
-        })
+    fn push_marker_to_active_formatting_elements(&mut self) {
+        self.active_formatting_elements.push(FormattingNode::Marker);
     }
 
-    fn is_element_in_table_scope(&self, doc: &Document, element_name: &str) -> bool {
-        let mut idx = self.open_node_stack.len() - 1;
-        let mut found = false;
+    fn reconstruct_active_formatting_elements(&mut self, doc: &mut dyn TreeSink) {
+        let len = self.active_formatting_elements.len();
+        match self.active_formatting_elements.last() {
+            None | Some(FormattingNode::Marker) => return,
+            Some(FormattingNode::Node(n, _)) => {
+                if self.open_node_stack.contains(n) {
+                    return;
+                }
+            }
+        }
 
-        while let Some(node) = self.open_node_stack.at(idx) {
-            if !found {
-                if let Some(n) = doc.doctree.get_node(node) {
-                    if let NodeType::Element(element) = &n.node_type {
-                        found = element.get_name() == element_name;
+        // Rewind to the entry after the last marker or open element
+        let mut pos = len - 1;
+        while pos > 0 {
+            match &self.active_formatting_elements[pos - 1] {
+                FormattingNode::Marker => break,
+                FormattingNode::Node(n, _) => {
+                    if self.open_node_stack.contains(n) {
+                        break;
                     }
                 }
-            } else if is_entry_element!(
-                doc,
-                node,
-                HTMLElementType::Html(_) | HTMLElementType::Table(_) | HTMLElementType::Template(_)
-            ) {
-                return true;
             }
+            pos -= 1;
         }
-        false
-    }
 
-    fn push_to_active_formatting_elements(&mut self, doc: &Document, node: doctree::DoctreeNode) {
-        let cur_element = match doc.doctree.get_node(&node) {
-            Some(en) => match &en.node_type {
-                NodeType::Element(element_data) => element_data,
-                _ => return,
-            },
-            // This is a strange case, maybe log in the future...
-            None => return,
-        };
+        // Advance, recreating every element from its token
+        for idx in pos..len {
+            let token = match &self.active_formatting_elements[idx] {
+                FormattingNode::Node(_, token) => token.clone(),
+                FormattingNode::Marker => continue,
+            };
+            let node = self.insert_element_from_token(doc, false, false, token.clone());
+            self.active_formatting_elements[idx] = FormattingNode::Node(node, token);
+        }
+    }
 
-        let mut found = 0;
-        let mut position = self.active_formatting_elements.len() - 1;
-        for e in self.active_formatting_elements.iter().rev() {
-            if is_entry_marker!(doc, e) {
+    fn clear_active_formatting_to_marker(&mut self) {
+        while let Some(e) = self.active_formatting_elements.pop() {
+            if e.is_marker() {
                 break;
             }
-            let ent = match e {
-                FormattingNode::Node(e) => e,
-                FormattingNode::Marker => return, // ERROR
-            };
-            let entry = match doc.doctree.get_node(ent) {
-                Some(en) => match &en.node_type {
-                    NodeType::Element(element_data) => element_data,
-                    _ => return,
-                },
-                // This is a strange case, maybe log in the future...
-                None => continue,
+        }
+    }
+
+    // Returns false if there is no formatting element for the token, in which case
+    // it should be handled like any other end tag
+    fn run_adoption_agency(&mut self, doc: &mut dyn TreeSink, token: &HtmlToken) -> bool {
+        let subject = token.data.as_str();
+        if let Some(current) = self.open_node_stack.last().cloned() {
+            if element_name_matches(doc, &current, subject)
+                && self.active_formatting_index_of(&current).is_none()
+            {
+                self.open_node_stack.pop();
+                return true;
+            }
+        }
+
+        for _ in 0..8 {
+            let (formatting_idx, formatting_element, formatting_token) =
+                match self.find_active_formatting_element(subject) {
+                    Some(entry) => entry,
+                    None => return false,
+                };
+
+            let stack_idx = match self.open_node_stack.index_of(&formatting_element) {
+                Some(idx) => idx,
+                None => {
+                    self.token_error(token);
+                    self.active_formatting_elements.remove(formatting_idx);
+                    return true;
+                }
             };
-            if std::mem::discriminant(&cur_element.element_type)
-                != std::mem::discriminant(&entry.element_type)
+            if !self
+                .open_node_stack
+                .has_node_in_scope(doc, &formatting_element)
             {
-                continue;
+                self.token_error(token);
+                return true;
+            }
+            if self.open_node_stack.last() != Some(&formatting_element) {
+                self.parse_error(ParseErrorKind::UnclosedElements, token);
             }
-            // TODO: Check the namespace
-            // TODO: Check the attributes
 
-            found += 1;
-            if found == 3 {
-                self.active_formatting_elements.remove(position);
-                break;
+            let furthest_block_idx = match self.open_node_stack.stack[stack_idx + 1..]
+                .iter()
+                .position(|n| is_element_special(doc, n, false))
+            {
+                Some(idx) => stack_idx + 1 + idx,
+                None => {
+                    while let Some(n) = self.open_node_stack.pop() {
+                        if n == formatting_element {
+                            break;
+                        }
+                    }
+                    self.active_formatting_elements.remove(formatting_idx);
+                    return true;
+                }
+            };
+            let furthest_block = self.open_node_stack.stack[furthest_block_idx];
+            let common_ancestor = match stack_idx.checked_sub(1) {
+                Some(idx) => self.open_node_stack.stack[idx],
+                None => return true,
+            };
+
+            let mut bookmark = formatting_idx;
+            let mut node_idx = furthest_block_idx;
+            let mut last_node = furthest_block;
+            let mut inner_loop_counter = 0;
+            loop {
+                inner_loop_counter += 1;
+                // Removing entries only shifts the ones below, so the next node up is always
+                // at the previous index
+                node_idx -= 1;
+                let node = self.open_node_stack.stack[node_idx];
+                if node == formatting_element {
+                    break;
+                }
+
+                let mut formatting_entry = self.active_formatting_index_of(&node);
+                if inner_loop_counter > 3 {
+                    if let Some(idx) = formatting_entry.take() {
+                        self.active_formatting_elements.remove(idx);
+                        if idx < bookmark {
+                            bookmark -= 1;
+                        }
+                    }
+                }
+                let entry_idx = match formatting_entry {
+                    Some(idx) => idx,
+                    None => {
+                        self.open_node_stack.remove_at(node_idx);
+                        continue;
+                    }
+                };
+
+                let node_token = match &self.active_formatting_elements[entry_idx] {
+                    FormattingNode::Node(_, t) => t.clone(),
+                    FormattingNode::Marker => break,
+                };
+                let new_element =
+                    doc.create_node(self.create_element_from_token(node_token.clone()));
+                self.active_formatting_elements[entry_idx] =
+                    FormattingNode::Node(new_element, node_token);
+                self.open_node_stack.replace_at(node_idx, new_element);
+
+                if last_node == furthest_block {
+                    bookmark = entry_idx + 1;
+                }
+                doc.append(&new_element, last_node);
+                last_node = new_element;
+            }
+
+            let location = self.appropriate_insertion_location(doc, Some(common_ancestor));
+            if let Some(location) = location {
+                self.insert_existing_node_at(doc, &location, last_node);
+            }
+
+            let new_element =
+                doc.create_node(self.create_element_from_token(formatting_token.clone()));
+            doc.reparent_children(&furthest_block, &new_element);
+            doc.append(&furthest_block, new_element);
+
+            if let Some(idx) = self.active_formatting_index_of(&formatting_element) {
+                self.active_formatting_elements.remove(idx);
+                if idx < bookmark {
+                    bookmark -= 1;
+                }
+            }
+            let bookmark = bookmark.min(self.active_formatting_elements.len());
+            self.active_formatting_elements.insert(
+                bookmark,
+                FormattingNode::Node(new_element, formatting_token),
+            );
+
+            self.open_node_stack.remove_node(&formatting_element);
+            if let Some(idx) = self.open_node_stack.index_of(&furthest_block) {
+                self.open_node_stack.insert_at(idx + 1, new_element);
             }
-            position -= 1;
         }
+        true
+    }
 
-        self.active_formatting_elements
-            .push(FormattingNode::Node(node));
+    // Reports an error when the current node isn't the HTML element that's about to be closed
+    fn check_current_node(&mut self, doc: &dyn TreeSink, token: &HtmlToken, names: &[&str]) {
+        if !self
+            .open_node_stack
+            .last()
+            .is_some_and(|n| names.iter().any(|name| element_name_matches(doc, n, name)))
+        {
+            self.parse_error(ParseErrorKind::UnclosedElements, token);
+        }
     }
 
-    fn push_marker_to_active_formatting_elements(&mut self, doc: &Document) {
-        self.active_formatting_elements.push(FormattingNode::Marker);
+    fn close_p_element(&mut self, doc: &dyn TreeSink, token: &HtmlToken) {
+        self.open_node_stack
+            .generate_implied_end_tags(doc, Some("p"));
+        self.check_current_node(doc, token, &["p"]);
+        self.open_node_stack.pop_until(doc, "p");
     }
 
-    fn reconstruct_active_formatting_elements(&mut self, doc: &Document) {
-        // TODO: This is incomplete
-        //     let mut e = match self.active_formatting_elements.last() {
-        //         Some(e) => e,
-        //         None => return,
-        //     };
-        //     if is_entry_marker!(doc, e) || self.open_node_stack.stack.contains(e) {
-        //         return;
-        //     }
-        //     let mut pos = self.active_formatting_elements.len() - 1;
-
-        //     while pos != 0 {
-        //         pos -= 1;
-        //         e = match self.active_formatting_elements.get(pos) {
-        //             Some(e) => e,
-        //             None => return, // TODO: Not sure what the best way to handle this is
-        //         };
-
-        //         if !is_entry_marker!(doc, e) && !self.open_node_stack.stack.contains(e) {
-        //             continue;
-        //         }
-
-        //         pos += 1;
-        //         e = match self.active_formatting_elements.get(pos) {
-        //             Some(e) => e,
-        //             None => return, // TODO: Not sure what the best way to handle this is
-        //         };
-        //         break;
-        //     }
-
-        //     loop {
-        //         if pos != 0 {
-        //             break;
-        //         }
-
-        //         // Insert for the element entry created
-        //         let new_entry = match doc.doctree.get_node(e) {
-        //             Some(e) => e.clone(),
-        //             None => {
-        //                 // ERROR
-        //                 return;
-        //             }
-        //         };
-        //         // Replace the entry in with list with new entry for element
-
-        //         // Advance
-        //         pos += 1;
-        //         e = match self.active_formatting_elements.get(pos) {
-        //             Some(e) => e,
-        //             None => return, // TODO: Not sure what the best way to handle this is
-        //         };
-        //         if pos == self.active_formatting_elements.len() - 1 {
-        //             break;
-        //         }
-        //     }
-    }
-
-    fn clear_active_formatting_to_marker(&mut self, doc: &Document) {
-        while let Some(e) = self.active_formatting_elements.last() {
-            if is_entry_marker!(doc, e) {
-                break;
+    fn close_p_in_button_scope(&mut self, doc: &dyn TreeSink, token: &HtmlToken) {
+        if self.open_node_stack.has_element_in_button_scope(doc, "p") {
+            self.close_p_element(doc, token);
+        }
+    }
+
+    fn any_other_end_tag(&mut self, doc: &mut dyn TreeSink, token: &HtmlToken) {
+        let mut idx = self.open_node_stack.len();
+        while idx > 0 {
+            idx -= 1;
+            let node = match self.open_node_stack.at(idx) {
+                Some(n) => *n,
+                None => return,
+            };
+            if element_name_matches(doc, &node, &token.data) {
+                self.open_node_stack
+                    .generate_implied_end_tags(doc, Some(&token.data));
+                if self.open_node_stack.last() != Some(&node) {
+                    self.parse_error(ParseErrorKind::UnclosedElements, token);
+                }
+                while let Some(n) = self.open_node_stack.pop() {
+                    if n == node {
+                        break;
+                    }
+                }
+                return;
+            }
+            if is_element_special(doc, &node, false) {
+                self.token_error(token);
+                return;
             }
-            self.active_formatting_elements.pop();
         }
     }
 
     // Node Insertion functions
+    fn appropriate_insertion_location(
+        &self,
+        sink: &dyn TreeSink,
+        override_target: Option<doctree::DoctreeNode>,
+    ) -> Option<InsertionLocation> {
+        let target = override_target.or_else(|| self.open_node_stack.last().cloned())?;
+
+        let is_table_target = matches!(
+            sink.get_element_name(&target).as_deref(),
+            Some("table" | "tbody" | "tfoot" | "thead" | "tr")
+        );
+        let location = if self.foster_parenting && is_table_target {
+            self.foster_parent_location(sink)?
+        } else {
+            InsertionLocation {
+                parent: target,
+                before: None,
+            }
+        };
+
+        // Anything inserted into a template goes into its contents instead
+        match sink.get_template_contents(&location.parent) {
+            Some(contents) => Some(InsertionLocation {
+                parent: contents,
+                before: None,
+            }),
+            None => Some(location),
+        }
+    }
+
+    // Content misplaced in a table is inserted before the table instead of inside it
+    fn foster_parent_location(&self, sink: &dyn TreeSink) -> Option<InsertionLocation> {
+        let last_index_of = |name: &str| {
+            self.open_node_stack
+                .stack
+                .iter()
+                .rposition(|n| sink.get_element_name(n).as_deref() == Some(name))
+        };
+        let last_template = last_index_of("template");
+        let last_table = last_index_of("table");
+
+        if let Some(template_idx) = last_template {
+            if last_table.is_none_or(|table_idx| template_idx > table_idx) {
+                return Some(InsertionLocation {
+                    parent: self.open_node_stack.stack[template_idx],
+                    before: None,
+                });
+            }
+        }
+
+        let table_idx = match last_table {
+            Some(idx) => idx,
+            // Only possible when parsing a fragment
+            None => {
+                return self.open_node_stack.first().map(|n| InsertionLocation {
+                    parent: *n,
+                    before: None,
+                })
+            }
+        };
+        let table = self.open_node_stack.stack[table_idx];
+        if let Some(parent) = sink.get_parent(&table) {
+            return Some(InsertionLocation {
+                parent,
+                before: Some(table),
+            });
+        }
+
+        let previous = table_idx.checked_sub(1)?;
+        Some(InsertionLocation {
+            parent: self.open_node_stack.stack[previous],
+            before: None,
+        })
+    }
+
+    fn insert_existing_node_at(
+        &mut self,
+        sink: &mut dyn TreeSink,
+        location: &InsertionLocation,
+        node: DoctreeNode,
+    ) {
+        match location.before {
+            Some(before) => sink.insert_before(&location.parent, node, &before),
+            None => sink.append(&location.parent, node),
+        }
+    }
+
+    fn insert_node_at(
+        &mut self,
+        sink: &mut dyn TreeSink,
+        location: &InsertionLocation,
+        node: node::Node,
+    ) -> DoctreeNode {
+        let n = sink.create_node(node);
+        self.insert_existing_node_at(sink, location, n);
+        n
+    }
+
     fn insert_element_from_token(
         &mut self,
-        doctree: &mut doctree::Doctree,
+        sink: &mut dyn TreeSink,
         is_root: bool,
         nostackpush: bool,
         token: HtmlToken,
     ) -> DoctreeNode {
-        let node_name = token.data.clone();
-        log::trace!("Inserting token: {}", node_name);
-        let is_self_closing = token.flags.self_closing;
-
-        let mut element = self.create_element_from_token(token);
-        let node = if is_root || self.open_node_stack.len() == 0 {
-            doctree.add_root_node(element)
-        } else {
-            let parent = self.open_node_stack.last();
-            element.parent = parent.cloned();
-
-            let n = doctree.add_node(element);
-            if let Some(p) = parent {
-                log::trace!("Adding child to parent {}", p.idx);
-                if let Some(parent_node) = doctree.get_mut_node(p) {
-                    parent_node.add_child(n);
-                }
-            };
+        log::trace!("Inserting token: {}", token.data);
 
-            n
-        };
+        let element = self.create_element_from_token(token);
+        let node = self.insert_element_node(sink, is_root, element);
 
-        if !is_self_closing && !nostackpush {
-            self.open_node_stack
-                .push(&mut self.tokenizer, node, node_name);
+        if !nostackpush {
+            self.open_node_stack.push(node);
         }
         node
     }
 
-    fn insert_element(&mut self, doctree: &mut doctree::Doctree, is_root: bool, e: node::Node) {
-        let mut element = e;
-        let node = if is_root || self.open_node_stack.len() == 0 {
-            doctree.add_root_node(element)
-        } else {
-            let parent = self.open_node_stack.last();
-            element.parent = parent.cloned();
+    fn insert_element(
+        &mut self,
+        sink: &mut dyn TreeSink,
+        is_root: bool,
+        e: node::Node,
+    ) -> DoctreeNode {
+        let node = self.insert_element_node(sink, is_root, e);
+        self.open_node_stack.push(node);
+        node
+    }
 
-            let n = doctree.add_node(element);
-            if let Some(p) = parent {
-                if let Some(parent_node) = doctree.get_mut_node(p) {
-                    parent_node.add_child(n);
-                }
+    fn insert_element_node(
+        &mut self,
+        sink: &mut dyn TreeSink,
+        is_root: bool,
+        element: node::Node,
+    ) -> DoctreeNode {
+        let node = match self.appropriate_insertion_location(sink, None) {
+            Some(location) if !is_root => {
+                let node = self.insert_node_at(sink, &location, element);
+                self.associate_with_form(sink, &node, &location.parent);
+                node
+            }
+            _ => {
+                let node = sink.create_node(element);
+                sink.append_to_document(node);
+                node
             }
-
-            n
         };
+        self.create_template_contents(sink, &node);
+        node
+    }
 
-        self.open_node_stack.push_self_only(node);
+    // Controls created while a form is open belong to it, unless they name their form
+    // with the form attribute. Those are resolved once the whole tree is known.
+    fn associate_with_form(
+        &self,
+        sink: &mut dyn TreeSink,
+        node: &DoctreeNode,
+        parent: &DoctreeNode,
+    ) {
+        let form = match self.form_element {
+            Some(form) => form,
+            None => return,
+        };
+        if self.has_template_on_stack(sink) {
+            return;
+        }
+        let element = match sink.get_element(node) {
+            Some(e) => e,
+            None => return,
+        };
+        if !element.is_form_associated()
+            || (element.is_listed() && element.get_attribute("form").is_some())
+        {
+            return;
+        }
+        // The form pointer of a fragment parser points into another tree
+        if tree_root(sink, parent) != tree_root(sink, &form) {
+            return;
+        }
+        if let Some(e) = sink.get_mut_element(node) {
+            e.form_owner = Some(form);
+        }
     }
 
-    fn insert_comment_token(&mut self, doc: &mut Document, is_root: bool, str: &str) {
-        if is_root || self.open_node_stack.len() == 0 {
-            doc.doctree
-                .add_root_node(node::Node::new(node::NodeType::Comment(str.to_string())));
+    // Template elements get a document fragment to hold their contents
+    fn create_template_contents(&self, sink: &mut dyn TreeSink, node: &DoctreeNode) {
+        if !is_entry_element!(sink, node, HTMLElementType::Template(_)) {
             return;
         }
 
-        let parent = self.open_node_stack.last();
-        let node = doc
-            .doctree
-            .add_node(node::Node::new(node::NodeType::Comment(str.to_string())));
+        let contents = sink.create_node(node::Node::new(NodeType::DocumentFragment));
+        self.set_template_contents(sink, node, contents);
+    }
 
-        if let Some(idx) = parent {
-            if let Some(parent) = doc.doctree.get_mut_node(idx) {
-                parent.add_child(node);
-                doc.doctree
-                    .get_mut_node(&node)
-                    .unwrap()
-                    .add_parent(Some(idx.clone()));
+    fn set_template_contents(
+        &self,
+        sink: &mut dyn TreeSink,
+        node: &DoctreeNode,
+        contents: DoctreeNode,
+    ) {
+        if let Some(element) = sink.get_mut_element(node) {
+            if let HTMLElementType::Template(template) = &mut element.element_type {
+                template.set_contents(contents);
             }
         }
     }
 
-    fn insert_character_token(&mut self, doc: &mut Document, token: HtmlToken) {
-        match doc.doctree.get_last_node() {
-            Some(n) => match n.node_type {
-                node::NodeType::Text(ref mut text) => {
-                    text.push_str(token.data.as_str());
-                    return;
-                }
-                _ => {}
+    // Templates with a valid shadowrootmode attach a shadow root to the current node and
+    // parse their contents into it. Returns false if the template should be inserted normally
+    fn attach_declarative_shadow_root(
+        &mut self,
+        doc: &mut dyn TreeSink,
+        token: &HtmlToken,
+    ) -> bool {
+        if self.open_node_stack.len() <= 1 {
+            return false;
+        }
+        let host = match self.open_node_stack.last() {
+            Some(n) => *n,
+            None => return false,
+        };
+
+        let element = self.create_element_from_token(token.clone());
+        let (mode, clonable, delegates_focus) = match &element.node_type {
+            NodeType::Element(e) => match &e.element_type {
+                HTMLElementType::Template(t) => match t.get_shadowrootmode() {
+                    Some(mode) => (
+                        mode,
+                        t.is_shadowrootclonable(),
+                        t.is_shadowrootdelegatesfocus(),
+                    ),
+                    None => return false,
+                },
+                _ => return false,
             },
-            None => {}
+            _ => return false,
+        };
+
+        let location = self.appropriate_insertion_location(doc, None);
+        // The template is only added to the stack of open elements, not the tree
+        let template = doc.create_node(element);
+        self.open_node_stack.push(template);
+
+        let can_attach = match doc.get_element(&host) {
+            Some(e) => e.shadow_root.is_none() && is_valid_shadow_host(e.get_name()),
+            None => false,
+        };
+        if !can_attach {
+            self.token_error(token);
+            if let Some(location) = location {
+                self.insert_existing_node_at(doc, &location, template);
+            }
+            self.create_template_contents(doc, &template);
+            return true;
         }
 
-        let parent = self.open_node_stack.last();
-        let mut node = node::Node::new(node::NodeType::Text(token.data));
-        node.parent = parent.cloned();
-        let n = doc.doctree.add_node(node);
+        let root = doc.create_node(node::Node::new(NodeType::DocumentFragment));
+        self.set_template_contents(doc, &template, root);
+        if let Some(e) = doc.get_mut_element(&host) {
+            e.shadow_root = Some(html_elements::ShadowRoot {
+                mode,
+                clonable,
+                delegates_focus,
+                declarative: true,
+                root,
+            });
+        }
+        true
+    }
 
-        match parent {
-            Some(p) => match doc.doctree.get_mut_node(p) {
-                Some(parent) => parent.add_child(n),
-                None => {}
-            },
-            None => {}
+    fn insert_comment_token(&mut self, doc: &mut dyn TreeSink, is_root: bool, token: &HtmlToken) {
+        let mut node = node::Node::new(node::NodeType::Comment(token.data.clone()));
+        node.span = Some(token.span);
+        match self.appropriate_insertion_location(doc, None) {
+            Some(location) if !is_root => {
+                self.insert_node_at(doc, &location, node);
+            }
+            _ => {
+                let node = doc.create_node(node);
+                doc.append_to_document(node);
+            }
         }
     }
 
-    fn generic_raw_text_parsing(&mut self, doc: &mut Document, token: HtmlToken) {
-        self.insert_element_from_token(&mut doc.doctree, false, false, token);
+    fn insert_character_token(&mut self, doc: &mut dyn TreeSink, token: HtmlToken) {
+        // Text can't be inserted directly into the document
+        let location = match self.appropriate_insertion_location(doc, None) {
+            Some(location) => location,
+            None => return,
+        };
+
+        doc.insert_text(
+            &location.parent,
+            location.before.as_ref(),
+            &token.data,
+            token.span,
+        );
+    }
+
+    fn generic_raw_text_parsing(&mut self, doc: &mut dyn TreeSink, token: HtmlToken) {
+        self.insert_element_from_token(doc, false, false, token);
         self.tokenizer.set_state(tokenizer::FsmState::RawText);
         self.original_insertion_mode = self.insertion_mode;
         self.insertion_mode = InsertionMode::Text;
     }
 
-    fn generic_rcdata_parsing(&mut self, doc: &mut Document, token: HtmlToken) {
-        self.insert_element_from_token(&mut doc.doctree, false, false, token);
+    fn generic_rcdata_parsing(&mut self, doc: &mut dyn TreeSink, token: HtmlToken) {
+        self.insert_element_from_token(doc, false, false, token);
         self.tokenizer.set_state(tokenizer::FsmState::RCData);
         self.original_insertion_mode = self.insertion_mode;
         self.insertion_mode = InsertionMode::Text;
     }
 
+    fn start_speculative_parser(&mut self) {
+        let mut scanner = PreloadScanner::new();
+        scanner.feed(&self.tokenizer.remaining_input());
+        if self.tokenizer.is_input_finished() {
+            scanner.finish();
+        }
+        self.speculative_parser = Some(scanner);
+    }
+
+    // Only classic scripts that block the parser are run. Async, deferred and module scripts
+    // are left to the embedder.
+    fn prepare_script(&mut self, doc: &mut dyn TreeSink, node: DoctreeNode, text: String) {
+        // Template contents aren't connected to the document, so their scripts stay inert
+        if self.has_template_on_stack(doc) {
+            return;
+        }
+        let script = match doc.get_mut_element(&node).map(|e| &mut e.element_type) {
+            Some(HTMLElementType::Script(s)) => s,
+            _ => return,
+        };
+        if script.is_already_started() {
+            return;
+        }
+        let src = script.get_src().map(|src| src.to_string());
+        if src.is_none() && text.is_empty() {
+            return;
+        }
+        let script_type = script.get_script_type().clone();
+        if script_type == ScriptTypeOption::Other {
+            return;
+        }
+        script.enable_already_started();
+        if !self.scripting {
+            return;
+        }
+        // TODO: Run module scripts, import maps and async and deferred scripts
+        let blocks_parser = src.is_none() || !script.is_async_or_deferred();
+        if script_type != ScriptTypeOption::Javascript || !blocks_parser {
+            return;
+        }
+
+        let handler = match self.script_handler.as_mut() {
+            Some(handler) => handler,
+            None => return,
+        };
+        let text = if src.is_some() { String::new() } else { text };
+        let script = ParserScript { node, src, text };
+        let mut writer = ScriptWriter::default();
+        if handler.run_script(&script, &mut writer) == ScriptStatus::Pending {
+            self.pending_parsing_blocking_script = Some(node);
+        }
+        self.tokenizer.insert_at_insertion_point(&writer.take());
+    }
+
+    fn has_template_on_stack(&self, doc: &dyn TreeSink) -> bool {
+        self.open_node_stack
+            .stack
+            .iter()
+            .any(|n| is_entry_element!(doc, n, HTMLElementType::Template(_)))
+    }
+
+    fn switch_template_insertion_mode(&mut self, mode: InsertionMode) {
+        self.template_insertion_modes.pop();
+        self.template_insertion_modes.push(mode);
+        self.insertion_mode = mode;
+    }
+
+    fn close_template(&mut self, doc: &mut dyn TreeSink, token: &HtmlToken) {
+        if !self.has_template_on_stack(doc) {
+            self.parse_error(ParseErrorKind::UnexpectedEndTag, token);
+            return;
+        }
+
+        self.open_node_stack
+            .generate_all_implied_end_tags_thoroughly(doc);
+        if !self
+            .open_node_stack
+            .last()
+            .is_some_and(|n| is_entry_element!(doc, n, HTMLElementType::Template(_)))
+        {
+            self.parse_error(ParseErrorKind::UnclosedElements, token);
+        }
+        while let Some(n) = self.open_node_stack.pop() {
+            if is_entry_element!(doc, &n, HTMLElementType::Template(_)) {
+                break;
+            }
+        }
+        self.clear_active_formatting_to_marker();
+        self.template_insertion_modes.pop();
+        self.reset_insertion_mode(doc);
+    }
+
     // Insertion mode functions
-    fn parse_initial(&mut self, doc: &mut Document, token: HtmlToken) {
+    fn parse_initial(&mut self, doc: &mut dyn TreeSink, token: HtmlToken) {
         let mut anything_else = true;
         match token.tag {
             TokenTag::Character => {
@@ -748,19 +1713,39 @@ impl Parser {
                 };
             }
             TokenTag::Comment => {
-                self.insert_comment_token(doc, true, token.data.as_str());
+                self.insert_comment_token(doc, true, &token);
+                anything_else = false;
             }
             TokenTag::Doctype(ref data) => {
-                doc.doctree
-                    .add_root_node(node::Node::new(node::NodeType::DocumentType(
-                        node::DocumentType {
-                            name: token.data.clone(),
-                            public_id: data.public_identifier.clone().unwrap_or(String::new()),
-                            system_id: data.system.clone().unwrap_or(String::new()),
-                        },
-                    )));
-                // TODO: Check if the document should be set to quirks mode
-                // TODO: Check if limited quirks mode
+                if token.data != "html"
+                    || data.public_identifier.is_some()
+                    || data
+                        .system
+                        .as_deref()
+                        .is_some_and(|s| s != "about:legacy-compat")
+                {
+                    self.parse_error(ParseErrorKind::NonConformingDoctype, &token);
+                }
+                let mut doctype =
+                    node::Node::new(node::NodeType::DocumentType(node::DocumentType {
+                        name: token.data.clone(),
+                        public_id: data.public_identifier.clone().unwrap_or(String::new()),
+                        system_id: data.system.clone().unwrap_or(String::new()),
+                    }));
+                doctype.span = Some(token.span);
+                let doctype = doc.create_node(doctype);
+                doc.append_to_document(doctype);
+                if !self.iframe_srcdoc && !self.cannot_change_mode {
+                    self.set_quirks_mode(
+                        doc,
+                        quirks::quirks_mode_from_doctype(
+                            &token.data,
+                            data.public_identifier.as_deref(),
+                            data.system.as_deref(),
+                            token.flags.force_quirks,
+                        ),
+                    );
+                }
                 self.insertion_mode = InsertionMode::BeforeHtml;
                 anything_else = false;
             }
@@ -768,21 +1753,26 @@ impl Parser {
         };
 
         if anything_else {
-            if !self.cannot_change_mode {
-                doc.set_quirks_mode(document::QuirksMode::Quirks);
+            // An iframe srcdoc document doesn't need a doctype
+            if !self.iframe_srcdoc {
+                self.parse_error(ParseErrorKind::MissingDoctype, &token);
+            }
+            if !self.iframe_srcdoc && !self.cannot_change_mode {
+                self.set_quirks_mode(doc, document::QuirksMode::Quirks);
             }
             self.insertion_mode = InsertionMode::BeforeHtml;
             self.reconsume_token = Some(token);
         }
     }
 
-    fn parse_before_html(&mut self, doc: &mut Document, token: HtmlToken) {
+    fn parse_before_html(&mut self, doc: &mut dyn TreeSink, token: HtmlToken) {
         match token.tag {
             TokenTag::Doctype(_) => {
+                self.token_error(&token);
                 return;
             }
             TokenTag::Comment => {
-                self.insert_comment_token(doc, true, token.data.as_str());
+                self.insert_comment_token(doc, true, &token);
                 return;
             }
             TokenTag::Character => {
@@ -796,35 +1786,35 @@ impl Parser {
             TokenTag::StartTag => {
                 if token.data == "html" {
                     // Create a new token and push it onto the stack of open nodes
-                    self.insert_element_from_token(&mut doc.doctree, true, false, token);
+                    self.insert_element_from_token(doc, true, false, token);
                     self.insertion_mode = InsertionMode::BeforeHead;
                     return;
                 }
             }
             TokenTag::EndTag => match token.data.as_str() {
                 "head" | "body" | "html" | "br" => {}
-                _ => return,
+                _ => {
+                    self.token_error(&token);
+                    return;
+                }
             },
             _ => {}
         };
         log::trace!("In anything else case");
         // Anything else case
-        self.open_node_stack.push(
-            &mut self.tokenizer,
-            doc.doctree
-                .add_root_node(node::Node::new(node::NodeType::Element(
-                    HTMLElement::from_element_type(HTMLElementType::Html(
-                        element_structs::html::Html::default(),
-                    )),
-                ))),
-            token.data.clone(),
-        );
+        let html = doc.create_node(node::Node::new(node::NodeType::Element(
+            HTMLElement::from_element_type(HTMLElementType::Html(
+                element_structs::html::Html::default(),
+            )),
+        )));
+        doc.append_to_document(html);
+        self.open_node_stack.push(html);
 
         self.reconsume_token = Some(token);
         self.insertion_mode = InsertionMode::BeforeHead;
     }
 
-    fn parse_before_head(&mut self, doc: &mut Document, token: HtmlToken) {
+    fn parse_before_head(&mut self, doc: &mut dyn TreeSink, token: HtmlToken) {
         match token.tag {
             TokenTag::Character => {
                 match token.data.as_str() {
@@ -833,10 +1823,11 @@ impl Parser {
                 };
             }
             TokenTag::Comment => {
-                self.insert_comment_token(doc, false, token.data.as_str());
+                self.insert_comment_token(doc, false, &token);
                 return;
             }
             TokenTag::Doctype(_) => {
+                self.token_error(&token);
                 return;
             }
             TokenTag::StartTag => match token.data.as_str() {
@@ -845,7 +1836,8 @@ impl Parser {
                     return;
                 }
                 "head" => {
-                    self.insert_element_from_token(&mut doc.doctree, false, false, token);
+                    self.head_element =
+                        Some(self.insert_element_from_token(doc, false, false, token));
                     self.insertion_mode = InsertionMode::InHead;
                     return;
                 }
@@ -854,33 +1846,25 @@ impl Parser {
             TokenTag::EndTag => match token.data.as_str() {
                 "head" | "body" | "html" | "br" => {}
                 _ => {
+                    self.token_error(&token);
                     return;
                 }
             },
             _ => {}
         };
         // Anything else
-        let mut n = node::Node::new(node::NodeType::Element(
+        let n = node::Node::new(node::NodeType::Element(
             html_elements::HTMLElement::from_element_type(HTMLElementType::Head(
                 html_elements::element_structs::head::Head::default(),
             )),
         ));
-        n.add_parent(self.open_node_stack.last().cloned());
-        let node = doc.doctree.add_node(n);
-        self.head_element = Some(node);
-        match self.open_node_stack.last() {
-            Some(p) => match doc.doctree.get_mut_node(p) {
-                Some(parent) => parent.add_child(node),
-                None => {}
-            },
-            None => {}
-        };
+        self.head_element = Some(self.insert_element(doc, false, n));
 
         self.reconsume_token = Some(token);
         self.insertion_mode = InsertionMode::InHead;
     }
 
-    fn parse_in_head(&mut self, doc: &mut Document, token: HtmlToken) {
+    fn parse_in_head(&mut self, doc: &mut dyn TreeSink, token: HtmlToken) {
         match token.tag {
             TokenTag::Character => {
                 match token.data.as_str() {
@@ -892,10 +1876,11 @@ impl Parser {
                 };
             }
             TokenTag::Comment => {
-                self.insert_comment_token(doc, false, token.data.as_str());
+                self.insert_comment_token(doc, false, &token);
                 return;
             }
             TokenTag::Doctype(_) => {
+                self.token_error(&token);
                 return;
             }
             TokenTag::StartTag => {
@@ -905,15 +1890,16 @@ impl Parser {
                         return;
                     }
                     "base" | "basefont" | "bgsound" | "link" => {
-                        self.insert_element_from_token(&mut doc.doctree, false, true, token);
+                        self.insert_element_from_token(doc, false, true, token);
                         return;
                     }
                     "meta" => {
-                        self.insert_element_from_token(&mut doc.doctree, false, true, token);
+                        self.check_meta_encoding(&token);
+                        self.insert_element_from_token(doc, false, true, token);
                         return;
                     }
                     "title" => {
-                        self.insert_element_from_token(&mut doc.doctree, false, false, token);
+                        self.insert_element_from_token(doc, false, false, token);
                         self.original_insertion_mode = self.insertion_mode;
                         self.insertion_mode = InsertionMode::Text;
                         return;
@@ -922,7 +1908,7 @@ impl Parser {
                         if self.scripting {
                             self.generic_raw_text_parsing(doc, token);
                         } else {
-                            self.insert_element_from_token(&mut doc.doctree, false, false, token);
+                            self.insert_element_from_token(doc, false, false, token);
                             self.insertion_mode = InsertionMode::InHeadNoscript;
                         }
                         return;
@@ -932,15 +1918,34 @@ impl Parser {
                         return;
                     }
                     "script" => {
-                        // TODO: Implement script parsing
+                        let node = self.insert_element_from_token(doc, false, false, token);
+                        // Scripts in fragments never run
+                        if self.fragment_parser_ctx.is_some() {
+                            if let Some(element) = doc.get_mut_element(&node) {
+                                if let HTMLElementType::Script(s) = &mut element.element_type {
+                                    s.enable_already_started();
+                                }
+                            }
+                        }
+                        self.script_text.clear();
+                        self.tokenizer.set_state(tokenizer::FsmState::ScriptData);
+                        self.original_insertion_mode = self.insertion_mode;
+                        self.insertion_mode = InsertionMode::Text;
                         return;
                     }
                     "template" => {
-                        // TODO: Implement templating
+                        self.push_marker_to_active_formatting_elements();
+                        self.frameset_ok = false;
+                        self.insertion_mode = InsertionMode::InTemplate;
+                        self.template_insertion_modes
+                            .push(InsertionMode::InTemplate);
+                        if !self.attach_declarative_shadow_root(doc, &token) {
+                            self.insert_element_from_token(doc, false, false, token);
+                        }
                         return;
                     }
                     "head" => {
-                        // Error, ignore
+                        self.token_error(&token);
                         return;
                     }
                     _ => {}
@@ -949,7 +1954,7 @@ impl Parser {
             TokenTag::EndTag => {
                 match token.data.as_str() {
                     "head" => {
-                        self.open_node_stack.pop(&mut self.tokenizer);
+                        self.open_node_stack.pop();
                         self.insertion_mode = InsertionMode::AfterHead;
                         return;
                     }
@@ -957,11 +1962,11 @@ impl Parser {
                         // Anything else
                     }
                     "template" => {
-                        // TODO: Implement template
+                        self.close_template(doc, &token);
                         return;
                     }
                     _ => {
-                        // Error: Ignore
+                        self.token_error(&token);
                         return;
                     }
                 }
@@ -970,19 +1975,19 @@ impl Parser {
         };
 
         // Anything else
-        self.open_node_stack.pop(&mut self.tokenizer);
+        self.open_node_stack.pop();
         self.insertion_mode = InsertionMode::AfterHead;
         self.reconsume_token = Some(token);
     }
 
-    fn parse_in_head_noscript(&mut self, doc: &mut Document, token: HtmlToken) {
+    fn parse_in_head_noscript(&mut self, doc: &mut dyn TreeSink, token: HtmlToken) {
         match token.tag {
             TokenTag::Doctype(_) => {
-                // ERROR: Ignore
+                self.token_error(&token);
                 return;
             }
             TokenTag::Comment => {
-                self.insert_comment_token(doc, false, token.data.as_str());
+                self.insert_comment_token(doc, false, &token);
                 return;
             }
             TokenTag::Character => match token.data.as_str() {
@@ -997,44 +2002,47 @@ impl Parser {
                     return;
                 }
                 "basefont" | "bgsound" | "link" | "meta" | "noframes" | "style" => {
-                    self.insert_element_from_token(&mut doc.doctree, false, true, token);
+                    if token.data == "meta" {
+                        self.check_meta_encoding(&token);
+                    }
+                    self.insert_element_from_token(doc, false, true, token);
                     return;
                 }
                 "head" | "noscript" => {
-                    // Error: Ignore
+                    self.token_error(&token);
                     return;
                 }
                 _ => {}
             },
             TokenTag::EndTag => match token.data.as_str() {
                 "noscript" => {
-                    self.open_node_stack.pop(&mut self.tokenizer);
+                    self.open_node_stack.pop();
                     self.insertion_mode = InsertionMode::InHead;
                     return;
                 }
                 "br" => {}
                 _ => {
-                    // ERROR: Ignore
+                    self.token_error(&token);
                     return;
                 }
             },
             _ => {}
         };
 
-        // ERROR
-        self.open_node_stack.pop(&mut self.tokenizer);
+        self.token_error(&token);
+        self.open_node_stack.pop();
         self.insertion_mode = InsertionMode::InHead;
         self.reconsume_token = Some(token);
     }
 
-    fn parse_after_head(&mut self, doc: &mut Document, token: HtmlToken) {
+    fn parse_after_head(&mut self, doc: &mut dyn TreeSink, token: HtmlToken) {
         match token.tag {
             TokenTag::Doctype(_) => {
-                // ERROR: Ignore
+                self.token_error(&token);
                 return;
             }
             TokenTag::Comment => {
-                self.insert_comment_token(doc, false, token.data.as_str());
+                self.insert_comment_token(doc, false, &token);
                 return;
             }
             TokenTag::Character => match token.data.as_str() {
@@ -1051,32 +2059,32 @@ impl Parser {
                         return;
                     }
                     "body" => {
-                        self.insert_element_from_token(&mut doc.doctree, false, false, token);
-                        // TODO: Set frameset-ok flag to not ok
+                        self.insert_element_from_token(doc, false, false, token);
+                        self.frameset_ok = false;
                         self.insertion_mode = InsertionMode::InBody;
                         return;
                     }
                     "frameset" => {
-                        self.insert_element_from_token(&mut doc.doctree, false, false, token);
+                        self.insert_element_from_token(doc, false, false, token);
                         self.insertion_mode = InsertionMode::InFrameset;
                         return;
                     }
                     "base" | "basefont" | "bgsound" | "link" | "meta" | "noframes" | "script"
                     | "style" | "template" | "title" => {
-                        // ERROR: Parse error
-                        if self.head_element.is_none() {
-                            return;
-                        }
+                        self.token_error(&token);
+                        let head = match self.head_element {
+                            Some(head) => head,
+                            None => return,
+                        };
 
-                        let head_idx = self
-                            .open_node_stack
-                            .push_self_only(self.head_element.unwrap());
+                        self.open_node_stack.push(head);
                         self.parse_in_head(doc, token);
-                        self.open_node_stack.remove_at(head_idx);
+                        // The head might not be the current node anymore
+                        self.open_node_stack.remove_node(&head);
                         return;
                     }
                     "head" => {
-                        // Error: Parse error
+                        self.token_error(&token);
                         return;
                     }
                     _ => {}
@@ -1085,12 +2093,12 @@ impl Parser {
             TokenTag::EndTag => {
                 match token.data.as_str() {
                     "template" => {
-                        // TODO: Implement templates
+                        self.parse_in_head(doc, token);
                         return;
                     }
                     "body" | "html" | "br" => {}
                     _ => {
-                        // ERROR: Parse error
+                        self.token_error(&token);
                         return;
                     }
                 };
@@ -1099,61 +2107,51 @@ impl Parser {
         };
 
         // Anything else
-        let mut n = node::Node::new(node::NodeType::Element(
+        let n = node::Node::new(node::NodeType::Element(
             html_elements::HTMLElement::from_element_type(HTMLElementType::Body(
                 html_elements::element_structs::body::Body::default(),
             )),
         ));
-        n.add_parent(self.open_node_stack.last().cloned());
-        let node = doc.doctree.add_node(n);
-        if let Some(p) = self.open_node_stack.last() {
-            if let Some(parent) = doc.doctree.get_mut_node(p) {
-                parent.add_child(node);
-            }
-        }
+        self.insert_element(doc, false, n);
 
         self.insertion_mode = InsertionMode::InBody;
         self.reconsume_token = Some(token);
     }
 
-    fn parse_in_body(&mut self, doc: &mut Document, token: HtmlToken) {
+    fn parse_in_body(&mut self, doc: &mut dyn TreeSink, token: HtmlToken) {
         match token.tag {
-            TokenTag::Character => {
-                match token.data.as_str() {
-                    "\u{0000}" => {
-                        // ERROR: Parse error
-                        return;
-                    }
-                    "\u{0009}" | "\u{000A}" | "\u{000C}" | "\u{000D}" | "\u{0020}" => {
-                        self.reconstruct_active_formatting_elements(doc);
-                        self.insert_character_token(doc, token);
-                        return;
-                    }
-                    _ => {
-                        self.reconstruct_active_formatting_elements(doc);
-                        self.insert_character_token(doc, token);
-                        // TODO: Set the frameset ok flag to not okay
-                        return;
-                    }
+            TokenTag::Character => match token.data.as_str() {
+                "\u{0000}" => {
+                    self.token_error(&token);
+                    return;
                 }
-            }
+                "\u{0009}" | "\u{000A}" | "\u{000C}" | "\u{000D}" | "\u{0020}" => {
+                    self.reconstruct_active_formatting_elements(doc);
+                    self.insert_character_token(doc, token);
+                    return;
+                }
+                _ => {
+                    self.reconstruct_active_formatting_elements(doc);
+                    self.insert_character_token(doc, token);
+                    self.frameset_ok = false;
+                    return;
+                }
+            },
             TokenTag::Comment => {
-                self.insert_comment_token(doc, false, token.data.as_str());
+                self.insert_comment_token(doc, false, &token);
                 return;
             }
             TokenTag::Doctype(_) => {
-                // ERROR: Parse error
+                self.token_error(&token);
                 return;
             }
             TokenTag::StartTag => match token.data.as_str() {
                 "html" => {
-                    // ERROR: Parse error
-                    // TODO: Check if there's a template in the stack of open nodes
-                    if false {
+                    self.token_error(&token);
+                    if self.has_template_on_stack(doc) {
                     } else if let Some(r) = self.open_node_stack.first() {
-                        if let Some(html) = doc.doctree.get_mut_node(r) {
+                        if let Some(html) = doc.get_mut_element(r) {
                             token.attributes.into_iter().for_each(|val| {
-                                // TODO: This should only affect attributes that don't already exist
                                 html.add_attribute(val.name, val.value);
                             });
                         }
@@ -1166,9 +2164,9 @@ impl Parser {
                     return;
                 }
                 "body" => {
-                    // ERROR: Parse error
-                    // TODO: This also applies if template is on the stack
+                    self.token_error(&token);
                     if self.open_node_stack.len() <= 1
+                        || self.has_template_on_stack(doc)
                         || if let Some(e) = self.open_node_stack.at(1) {
                             !is_entry_element!(doc, e, html_elements::HTMLElementType::Body(_))
                         } else {
@@ -1177,11 +2175,10 @@ impl Parser {
                     {
                         // Ignore
                     } else {
-                        // TODO: Set frameset to not okay
+                        self.frameset_ok = false;
                         if let Some(r) = self.open_node_stack.at(1) {
-                            if let Some(body) = doc.doctree.get_mut_node(r) {
+                            if let Some(body) = doc.get_mut_element(r) {
                                 token.attributes.into_iter().for_each(|val| {
-                                    // TODO: This should only affect attributes that don't already exist
                                     body.add_attribute(val.name, val.value);
                                 });
                             }
@@ -1190,9 +2187,9 @@ impl Parser {
                     return;
                 }
                 "frameset" => {
-                    // ERROR: Parse error
-                    // TODO: This also applies if template is on the stack
+                    self.token_error(&token);
                     if self.open_node_stack.len() <= 1
+                        || self.has_template_on_stack(doc)
                         || if let Some(e) = self.open_node_stack.at(1) {
                             !is_entry_element!(doc, e, html_elements::HTMLElementType::Body(_))
                         } else {
@@ -1200,24 +2197,18 @@ impl Parser {
                         }
                     {
                         // Ignore
-                    } else {
-                        // TODO: Set frameset to not okay
+                    } else if self.frameset_ok {
                         if let Some(r) = self.open_node_stack.at(1) {
                             if !is_entry_element!(doc, r, html_elements::HTMLElementType::Body(_)) {
                                 return;
                             }
-                            if let Some(p) = self.open_node_stack.last() {
-                                if let Some(parent) = doc.doctree.get_mut_node(p) {
-                                    if let Some(c) = self.open_node_stack.at(1) {
-                                        parent.remove_child(c);
-                                    }
-                                }
-                            }
+                            doc.detach(r);
 
                             while self.open_node_stack.len() > 1 {
-                                self.open_node_stack.pop(&mut self.tokenizer);
+                                self.open_node_stack.pop();
                             }
                         }
+                        self.insert_element_from_token(doc, false, false, token);
                         self.insertion_mode = InsertionMode::InFrameset;
                     }
                     return;
@@ -1226,60 +2217,58 @@ impl Parser {
                 | "dialog" | "dir" | "div" | "dl" | "fieldset" | "figcaption" | "figure"
                 | "footer" | "header" | "hgroup" | "main" | "menu" | "nav" | "ol" | "p"
                 | "search" | "section" | "summary" | "ul" => {
-                    // TODO: If the stack has a p element in button scope, close it
-                    self.insert_element_from_token(&mut doc.doctree, false, false, token);
+                    self.close_p_in_button_scope(doc, &token);
+                    self.insert_element_from_token(doc, false, false, token);
                     return;
                 }
                 "h1" | "h2" | "h3" | "h4" | "h5" | "h6" => {
-                    if let Some(cur_node) = self.open_node_stack.last() {
-                        if let Some(n) = doc.doctree.get_node(cur_node) {
-                            if let NodeType::Element(e) = &n.node_type {
-                                if e.get_name().to_lowercase() == token.data {
-                                    // ERROR: Parse error
-                                    self.open_node_stack.pop(&mut self.tokenizer);
-                                }
-                            }
-                        }
+                    self.close_p_in_button_scope(doc, &token);
+                    // Headings don't nest
+                    if self.open_node_stack.last().is_some_and(|n| {
+                        HEADING_ELEMENTS
+                            .iter()
+                            .any(|name| element_name_matches(doc, n, name))
+                    }) {
+                        self.token_error(&token);
+                        self.open_node_stack.pop();
                     }
 
-                    self.insert_element_from_token(&mut doc.doctree, false, false, token);
+                    self.insert_element_from_token(doc, false, false, token);
                     return;
                 }
                 "pre" | "listing" => {
-                    // TODO: If the stack has a p element in button scope, close it
-                    self.insert_element_from_token(&mut doc.doctree, false, false, token);
-                    // TODO: Implement system for handling the next token
-                    // TODO: Set frameset ok flag to not okay
+                    self.close_p_in_button_scope(doc, &token);
+                    self.insert_element_from_token(doc, false, false, token);
+                    self.skip_next_newline = true;
+                    self.frameset_ok = false;
                     return;
                 }
                 "form" => {
-                    if self.form_element.is_some() {
-                        // ERROR: Parse error
+                    if self.form_element.is_some() && !self.has_template_on_stack(doc) {
+                        self.token_error(&token);
                         return;
                     }
-                    // TODO: Check if there is a template on the open stack
-                    // TODO: If the stack has a p element in button scope, close it
-                    let node =
-                        self.insert_element_from_token(&mut doc.doctree, false, false, token);
+                    self.close_p_in_button_scope(doc, &token);
+                    let node = self.insert_element_from_token(doc, false, false, token);
 
-                    if true {
-                        // TODO: Check if there is a template on the open stack
+                    if !self.has_template_on_stack(doc) {
                         self.form_element = Some(node);
                     }
                     return;
                 }
                 "li" => {
-                    // TODO: Set frameset ok flag to not okay
+                    self.frameset_ok = false;
                     let mut node_idx = self.open_node_stack.len() - 1;
 
                     while let Some(node) = self.open_node_stack.at(node_idx) {
                         if is_entry_element!(doc, node, html_elements::HTMLElementType::Li(_)) {
-                            while self.open_node_stack.len() > node_idx {
-                                self.open_node_stack.pop(&mut self.tokenizer);
-                            }
+                            self.open_node_stack
+                                .generate_implied_end_tags(doc, Some("li"));
+                            self.check_current_node(doc, &token, &["li"]);
+                            self.open_node_stack.pop_until(doc, "li");
                             break;
                         }
-                        if is_element_special(&doc.doctree, node, true) {
+                        if is_element_special(doc, node, true) {
                             break;
                         }
 
@@ -1289,12 +2278,12 @@ impl Parser {
                         node_idx -= 1;
                     }
 
-                    // TODO: If the stack has a p element in button scope, close it
-                    self.insert_element_from_token(&mut doc.doctree, false, false, token);
+                    self.close_p_in_button_scope(doc, &token);
+                    self.insert_element_from_token(doc, false, false, token);
                     return;
                 }
                 "dd" | "dt" => {
-                    // TODO: Set the frameset ok flag to not okay
+                    self.frameset_ok = false;
                     let mut node_idx = self.open_node_stack.len() - 1;
 
                     while let Some(node) = self.open_node_stack.at(node_idx) {
@@ -1304,13 +2293,15 @@ impl Parser {
                             html_elements::HTMLElementType::Dd(_)
                                 | html_elements::HTMLElementType::Dt(_)
                         ) {
-                            while self.open_node_stack.len() > node_idx {
-                                self.open_node_stack.pop(&mut self.tokenizer);
-                            }
+                            let name = doc.get_element_name(node).unwrap_or_default();
+                            self.open_node_stack
+                                .generate_implied_end_tags(doc, Some(&name));
+                            self.check_current_node(doc, &token, &[&name]);
+                            self.open_node_stack.pop_until(doc, &name);
                             break;
                         }
 
-                        if is_element_special(&doc.doctree, node, true) {
+                        if is_element_special(doc, node, true) {
                             break;
                         }
 
@@ -1320,111 +2311,125 @@ impl Parser {
                         node_idx -= 1;
                     }
 
-                    // TODO: If the stack has a p element in button scope, close it
-                    self.insert_element_from_token(&mut doc.doctree, false, false, token);
+                    self.close_p_in_button_scope(doc, &token);
+                    self.insert_element_from_token(doc, false, false, token);
                     return;
                 }
                 "plaintext" => {
-                    // TODO: If the stack has a p element in button scope, close it
-                    self.insert_element_from_token(&mut doc.doctree, false, false, token);
+                    self.close_p_in_button_scope(doc, &token);
+                    self.insert_element_from_token(doc, false, false, token);
                     self.tokenizer.set_state(tokenizer::FsmState::Plaintext);
                     return;
                 }
                 "button" => {
-                    if self.open_node_stack.stack.clone().into_iter().any(|i| {
-                        is_entry_element!(doc, &i, html_elements::HTMLElementType::Button(_))
-                    }) {
-                        // Parse Error
-                        loop {
-                            // NOTE: This would be much nicer as a while let ... && is_entry
-                            //       That's currently unstable so we do this instead
-                            let entry = match self.open_node_stack.last() {
-                                Some(val) => val,
-                                None => break,
-                            };
-
-                            if is_entry_element!(
-                                doc,
-                                entry,
-                                html_elements::HTMLElementType::Button(_)
-                            ) {
-                                self.open_node_stack.pop(&mut self.tokenizer);
-                                break;
-                            }
-                            self.open_node_stack.pop(&mut self.tokenizer);
-                        }
+                    if self.open_node_stack.has_element_in_scope(doc, "button") {
+                        self.token_error(&token);
+                        self.open_node_stack.generate_implied_end_tags(doc, None);
+                        self.open_node_stack.pop_until(doc, "button");
                     }
 
                     self.reconstruct_active_formatting_elements(doc);
-                    self.insert_element_from_token(&mut doc.doctree, false, false, token);
-                    // TODO: Set frameset ok flag to not okay
+                    self.insert_element_from_token(doc, false, false, token);
+                    self.frameset_ok = false;
                     return;
                 }
                 "a" => {
-                    // TODO: check for a elements in active formatting elements
+                    if let Some((_, existing, _)) = self.find_active_formatting_element("a") {
+                        self.token_error(&token);
+                        self.run_adoption_agency(doc, &token);
+                        if let Some(idx) = self.active_formatting_index_of(&existing) {
+                            self.active_formatting_elements.remove(idx);
+                        }
+                        self.open_node_stack.remove_node(&existing);
+                    }
                     self.reconstruct_active_formatting_elements(doc);
-                    let node =
-                        self.insert_element_from_token(&mut doc.doctree, false, false, token);
-                    self.push_to_active_formatting_elements(doc, node);
+                    let node = self.insert_element_from_token(doc, false, false, token.clone());
+                    self.push_to_active_formatting_elements(node, token);
                     return;
                 }
                 "b" | "big" | "code" | "em" | "font" | "i" | "s" | "small" | "strike"
                 | "strong" | "tt" | "u" => {
                     self.reconstruct_active_formatting_elements(doc);
-                    let node =
-                        self.insert_element_from_token(&mut doc.doctree, false, false, token);
-                    self.push_to_active_formatting_elements(doc, node);
+                    let node = self.insert_element_from_token(doc, false, false, token.clone());
+                    self.push_to_active_formatting_elements(node, token);
+                    return;
+                }
+                "nobr" => {
+                    self.reconstruct_active_formatting_elements(doc);
+                    if self.open_node_stack.has_element_in_scope(doc, "nobr") {
+                        self.token_error(&token);
+                        self.run_adoption_agency(doc, &token);
+                        self.reconstruct_active_formatting_elements(doc);
+                    }
+                    let node = self.insert_element_from_token(doc, false, false, token.clone());
+                    self.push_to_active_formatting_elements(node, token);
+                    return;
+                }
+                "applet" | "marquee" | "object" => {
+                    self.reconstruct_active_formatting_elements(doc);
+                    self.insert_element_from_token(doc, false, false, token);
+                    self.push_marker_to_active_formatting_elements();
+                    self.frameset_ok = false;
                     return;
                 }
-                // "nobr" => {}
-                // "applet" | "marquee" | "object" => {}
                 "table" => {
-                    // TODO: check if the document is set to quirks mode
-                    self.insert_element_from_token(&mut doc.doctree, false, false, token);
-                    // TODO: Set frameset-ok flag to not okay
+                    if self.quirks_mode != document::QuirksMode::Quirks {
+                        self.close_p_in_button_scope(doc, &token);
+                    }
+                    self.insert_element_from_token(doc, false, false, token);
+                    self.frameset_ok = false;
                     self.insertion_mode = InsertionMode::InTable;
                     return;
                 }
                 "area" | "br" | "embed" | "img" | "image" | "keygen" | "wbr" => {
+                    let mut token = token;
+                    // image is an error that's read as img
+                    if token.data == "image" {
+                        self.token_error(&token);
+                        token.data = "img".to_string();
+                    }
                     self.reconstruct_active_formatting_elements(doc);
-                    self.insert_element_from_token(&mut doc.doctree, false, true, token);
-                    // TODO: Set the frameset-ok flag to not ok
+                    self.insert_element_from_token(doc, false, true, token);
+                    self.frameset_ok = false;
                     return;
                 }
                 "input" => {
                     self.reconstruct_active_formatting_elements(doc);
-                    self.insert_element_from_token(&mut doc.doctree, false, true, token.clone());
-                    if token
-                        .attributes
-                        .into_iter()
-                        .any(|attr| attr.name == "type" && attr.value.to_lowercase() == "hidden")
-                    {
-                        // TODO: Set frameset not ok
+                    self.insert_element_from_token(doc, false, true, token.clone());
+                    if !token.attributes.iter().any(|attr| {
+                        attr.name == "type" && attr.value.eq_ignore_ascii_case("hidden")
+                    }) {
+                        self.frameset_ok = false;
                     }
                     return;
                 }
                 "param" | "source" | "track" => {
-                    self.insert_element_from_token(&mut doc.doctree, false, true, token);
+                    self.insert_element_from_token(doc, false, true, token);
                     return;
                 }
                 "hr" => {
-                    // TODO: If the stack has a p element in button scope, close it
-                    self.insert_element_from_token(&mut doc.doctree, false, true, token);
-                    // TODO: Set frameset not okay
+                    self.close_p_in_button_scope(doc, &token);
+                    self.insert_element_from_token(doc, false, true, token);
+                    self.frameset_ok = false;
                     return;
                 }
                 "textarea" => {
-                    self.insert_element_from_token(&mut doc.doctree, false, false, token);
-                    // TODO: Check if the next token is a linefeed
+                    self.insert_element_from_token(doc, false, false, token);
+                    self.skip_next_newline = true;
                     self.tokenizer.set_state(tokenizer::FsmState::RCData);
                     self.original_insertion_mode = self.insertion_mode;
-                    // TODO: Set frameset-ok not ok
+                    self.frameset_ok = false;
                     self.insertion_mode = InsertionMode::Text;
                     return;
                 }
-                // "xmp" => {}
+                "xmp" => {
+                    self.close_p_in_button_scope(doc, &token);
+                    self.reconstruct_active_formatting_elements(doc);
+                    self.frameset_ok = false;
+                    self.generic_raw_text_parsing(doc, token);
+                }
                 "iframe" => {
-                    // TODO: Set frameset-ok to not ok
+                    self.frameset_ok = false;
                     self.generic_raw_text_parsing(doc, token);
                     return;
                 }
@@ -1437,11 +2442,15 @@ impl Parser {
                         self.generic_raw_text_parsing(doc, token);
                         return;
                     }
+                    // Without scripting it's an ordinary element
+                    self.reconstruct_active_formatting_elements(doc);
+                    self.insert_element_from_token(doc, false, false, token);
+                    return;
                 }
                 "select" => {
                     self.reconstruct_active_formatting_elements(doc);
-                    self.insert_element_from_token(&mut doc.doctree, false, false, token);
-                    // TODO: Set frameset no  ok
+                    self.insert_element_from_token(doc, false, false, token);
+                    self.frameset_ok = false;
                     self.insertion_mode = if matches!(
                         self.insertion_mode,
                         InsertionMode::InTable
@@ -1459,41 +2468,65 @@ impl Parser {
                 "optgroup" | "option" => {
                     if let Some(item) = self.open_node_stack.last() {
                         if is_entry_element!(doc, item, html_elements::HTMLElementType::Option(_)) {
-                            self.open_node_stack.pop(&mut self.tokenizer);
+                            self.open_node_stack.pop();
                         }
                     }
                     self.reconstruct_active_formatting_elements(doc);
-                    self.insert_element_from_token(&mut doc.doctree, false, false, token);
+                    self.insert_element_from_token(doc, false, false, token);
                     return;
                 }
                 "rb" | "rtc" => {
-                    // TODO
+                    if self.open_node_stack.has_element_in_scope(doc, "ruby") {
+                        self.open_node_stack.generate_implied_end_tags(doc, None);
+                        if !self
+                            .open_node_stack
+                            .last()
+                            .is_some_and(|n| element_name_matches(doc, n, "ruby"))
+                        {
+                            self.token_error(&token);
+                        }
+                    }
+                    self.insert_element_from_token(doc, false, false, token);
                 }
                 "rp" | "rt" => {
-                    // TODO
+                    if self.open_node_stack.has_element_in_scope(doc, "ruby") {
+                        self.open_node_stack
+                            .generate_implied_end_tags(doc, Some("rtc"));
+                        if !self.open_node_stack.last().is_some_and(|n| {
+                            element_name_matches(doc, n, "rtc")
+                                || element_name_matches(doc, n, "ruby")
+                        }) {
+                            self.token_error(&token);
+                        }
+                    }
+                    self.insert_element_from_token(doc, false, false, token);
                 }
                 "math" => {
-                    // TODO: Implement MathML
+                    self.reconstruct_active_formatting_elements(doc);
+                    self.insert_foreign_element_from_token(doc, token, Namespace::MathMl);
+                    return;
                 }
                 "svg" => {
                     self.reconstruct_active_formatting_elements(doc);
-                    // TODO: Adjust svg attributes
-                    // TODO: Adjust foreign attributes
-                    // TODO: Insert a foreign element for the token
+                    self.insert_foreign_element_from_token(doc, token, Namespace::Svg);
+                    return;
                 }
                 "caption" | "col" | "colgroup" | "frame" | "head" | "tbody" | "td" | "tfoot"
                 | "th" | "thead" | "tr" => {
-                    // ERROR: Parse error
+                    self.token_error(&token);
                     return;
                 }
                 _ => {
                     self.reconstruct_active_formatting_elements(doc);
-                    self.insert_element_from_token(&mut doc.doctree, false, false, token);
+                    self.insert_element_from_token(doc, false, false, token);
                 }
             },
             TokenTag::EoF => {
-                // TODO: Check if the stack of template insertion modes is not empty
-                // TODO: Check the stack of open elements non-exempt nodes for parse errors
+                if !self.template_insertion_modes.is_empty() {
+                    self.parse_in_template(doc, token);
+                    return;
+                }
+                self.check_unclosed_elements(doc, &token);
                 self.should_exit = true;
                 return;
             }
@@ -1504,27 +2537,20 @@ impl Parser {
                         return;
                     }
                     "body" => {
-                        // TODO: Check if the stack of template insertion modes is not empty
-                        // TODO: Check the stack of open elements non-exempt nodes for parse errors
+                        if !self.open_node_stack.has_element_in_scope(doc, "body") {
+                            self.token_error(&token);
+                            return;
+                        }
+                        self.check_unclosed_elements(doc, &token);
                         self.insertion_mode = InsertionMode::AfterBody;
                         return;
                     }
                     "html" => {
-                        // Check if there's a body element in the open stack
-                        let mut found = false;
-                        for item in self.open_node_stack.stack.iter() {
-                            if is_entry_element!(doc, item, html_elements::HTMLElementType::Body(_))
-                            {
-                                found = true;
-                                break;
-                            }
-                        }
-                        if !found {
-                            // ERROR: Parse error
+                        if !self.open_node_stack.has_element_in_scope(doc, "body") {
+                            self.token_error(&token);
                             return;
                         }
-
-                        // TODO: Check if there's invalid nodes in the open stack to determine error status
+                        self.check_unclosed_elements(doc, &token);
                         self.insertion_mode = InsertionMode::AfterBody;
                         self.reconsume_token = Some(token);
                     }
@@ -1532,219 +2558,146 @@ impl Parser {
                     | "details" | "dialog" | "dir" | "div" | "dl" | "fieldset" | "figcaption"
                     | "figure" | "footer" | "header" | "hgroup" | "listing" | "main" | "menu"
                     | "nav" | "ol" | "pre" | "search" | "section" | "summary" | "ul" => {
-                        if let Some(entry) = self.open_node_stack.last() {
-                            if let Some(node) = doc.doctree.get_node(entry) {
-                                if let NodeType::Element(e) = &node.node_type {
-                                    if e.get_name().to_lowercase() != token.data {
-                                        // Parse error
-                                    }
-                                }
-                            }
-                        }
-                        while let Some(entry) = self.open_node_stack.last() {
-                            if let Some(node) = doc.doctree.get_node(entry) {
-                                if let NodeType::Element(e) = &node.node_type {
-                                    if e.get_name().to_lowercase() == token.data {
-                                        self.open_node_stack.pop(&mut self.tokenizer);
-                                        return;
-                                    }
-                                }
-                            }
-                            self.open_node_stack.pop(&mut self.tokenizer);
+                        if !self.open_node_stack.has_element_in_scope(doc, &token.data) {
+                            self.token_error(&token);
+                            return;
                         }
-                        // If we get here, something's very wrong
+                        self.open_node_stack.generate_implied_end_tags(doc, None);
+                        self.check_current_node(doc, &token, &[&token.data]);
+                        self.open_node_stack.pop_until(doc, &token.data);
                         return;
                     }
                     "form" => {
-                        // TODO: Check if there's a template
-                        let n = self.form_element;
-                        if n != self.open_node_stack.last().cloned() {
-                            // Error
+                        if self.has_template_on_stack(doc) {
+                            if !self.open_node_stack.has_element_in_scope(doc, "form") {
+                                self.token_error(&token);
+                                return;
+                            }
+                            self.open_node_stack.generate_implied_end_tags(doc, None);
+                            self.check_current_node(doc, &token, &["form"]);
+                            self.open_node_stack.pop_until(doc, "form");
+                            return;
                         }
 
-                        self.form_element = None;
-                        if let Some(_) = n.clone() {
-                            if self.open_node_stack.stack.clone().into_iter().any(|i| {
-                                is_entry_element!(
-                                    doc,
-                                    &i,
-                                    html_elements::HTMLElementType::Button(_)
-                                )
-                            }) {
-                                self.open_node_stack.pop(&mut self.tokenizer);
-                            } else {
-                                // Parse error
+                        let node = match self.form_element.take() {
+                            Some(n) if self.open_node_stack.has_node_in_scope(doc, &n) => n,
+                            _ => {
+                                self.token_error(&token);
+                                return;
                             }
-                        } else {
-                            // Parse error
+                        };
+                        self.open_node_stack.generate_implied_end_tags(doc, None);
+                        if self.open_node_stack.last() != Some(&node) {
+                            self.parse_error(ParseErrorKind::UnclosedElements, &token);
                         }
+                        // The form is taken out of the stack wherever it is
+                        self.open_node_stack.remove_node(&node);
                         return;
                     }
                     "p" => {
-                        if !self.open_node_stack.stack.clone().into_iter().any(|i| {
-                            is_entry_element!(doc, &i, html_elements::HTMLElementType::P(_))
-                        }) {
-                            // Parse Error
+                        if !self.open_node_stack.has_element_in_button_scope(doc, "p") {
+                            self.token_error(&token);
                             let node = node::Node::new(NodeType::Element(
                                 html_elements::HTMLElement::from_element_type(HTMLElementType::P(
                                     html_elements::element_structs::p::P::default(),
                                 )),
                             ));
-                            self.insert_element(&mut doc.doctree, false, node);
+                            self.insert_element(doc, false, node);
                         }
-                        self.open_node_stack.pop(&mut self.tokenizer);
+                        self.close_p_element(doc, &token);
                         return;
                     }
                     "li" => {
-                        if !self.open_node_stack.stack.clone().into_iter().any(|i| {
-                            is_entry_element!(doc, &i, html_elements::HTMLElementType::Li(_))
-                        }) {
-                            // ERROR: Parse Error
+                        if !self
+                            .open_node_stack
+                            .has_element_in_list_item_scope(doc, "li")
+                        {
+                            self.token_error(&token);
                             return;
                         }
-                        loop {
-                            // NOTE: This would be much nicer as a while let ... && is_entry
-                            //       That's currently unstable so we do this instead
-                            let entry = match self.open_node_stack.last() {
-                                Some(val) => val,
-                                None => break,
-                            };
-
-                            if is_entry_element!(doc, entry, html_elements::HTMLElementType::Li(_))
-                            {
-                                self.open_node_stack.pop(&mut self.tokenizer);
-                                break;
-                            }
-                            self.open_node_stack.pop(&mut self.tokenizer);
-                        }
+                        self.open_node_stack
+                            .generate_implied_end_tags(doc, Some("li"));
+                        self.check_current_node(doc, &token, &["li"]);
+                        self.open_node_stack.pop_until(doc, "li");
                     }
                     "dd" | "dt" => {
-                        if !self.open_node_stack.stack.clone().into_iter().any(|i| {
-                            is_entry_element!(
-                                doc,
-                                &i,
-                                html_elements::HTMLElementType::Dd(_)
-                                    | html_elements::HTMLElementType::Dt(_)
-                            )
-                        }) {
-                            // ERROR: Parse error
+                        if !self.open_node_stack.has_element_in_scope(doc, &token.data) {
+                            self.token_error(&token);
                             return;
                         }
-
-                        loop {
-                            // NOTE: This would be much nicer as a while let ... && is_entry
-                            //       That's currently unstable so we do this instead
-                            let entry = match self.open_node_stack.last() {
-                                Some(val) => val,
-                                None => break,
-                            };
-
-                            if is_entry_element!(
-                                doc,
-                                entry,
-                                html_elements::HTMLElementType::Dd(_)
-                                    | html_elements::HTMLElementType::Dt(_)
-                            ) {
-                                self.open_node_stack.pop(&mut self.tokenizer);
-                                break;
-                            }
-                            self.open_node_stack.pop(&mut self.tokenizer);
-                        }
+                        self.open_node_stack
+                            .generate_implied_end_tags(doc, Some(&token.data));
+                        self.check_current_node(doc, &token, &[&token.data]);
+                        self.open_node_stack.pop_until(doc, &token.data);
                     }
                     "h1" | "h2" | "h3" | "h4" | "h5" | "h6" => {
-                        if !self.open_node_stack.stack.clone().into_iter().any(|node| {
-                            if let Some(n) = doc.doctree.get_node(&node) {
-                                if let NodeType::Element(e) = &n.node_type {
-                                    if e.get_name().to_lowercase() == token.data {
-                                        true
-                                    } else {
-                                        false
-                                    }
-                                } else {
-                                    false
-                                }
-                            } else {
-                                false
-                            }
-                        }) {
-                            // ERROR: Parse error
+                        // Any heading closes any other
+                        if !self
+                            .open_node_stack
+                            .has_any_element_in_scope(doc, &HEADING_ELEMENTS)
+                        {
+                            self.token_error(&token);
                             return;
                         }
-
-                        while let Some(node) = self.open_node_stack.last() {
-                            if let Some(n) = doc.doctree.get_node(&node) {
-                                if let NodeType::Element(e) = &n.node_type {
-                                    if e.get_name().to_lowercase().starts_with("h") {
-                                        self.open_node_stack.pop(&mut self.tokenizer);
-                                        return;
-                                    }
-                                }
-                            }
-
-                            self.open_node_stack.pop(&mut self.tokenizer);
+                        self.open_node_stack.generate_implied_end_tags(doc, None);
+                        self.check_current_node(doc, &token, &[&token.data]);
+                        self.open_node_stack
+                            .pop_until_one_of(doc, &HEADING_ELEMENTS);
+                        return;
+                    }
+                    "a" | "b" | "big" | "code" | "em" | "font" | "i" | "nobr" | "s" | "small"
+                    | "strike" | "strong" | "tt" | "u" => {
+                        if !self.run_adoption_agency(doc, &token) {
+                            self.any_other_end_tag(doc, &token);
                         }
                         return;
                     }
-                    "a" | "b" | "big" | "code" | "em" | "font" | "i" | "s" | "small" | "strike"
-                    | "strong" | "tt" | "u" => {
-                        // TODO: Run the adoption agency algorithm
+                    "applet" | "marquee" | "object" => {
+                        if !self.open_node_stack.has_element_in_scope(doc, &token.data) {
+                            self.token_error(&token);
+                            return;
+                        }
+                        self.open_node_stack.generate_implied_end_tags(doc, None);
+                        self.check_current_node(doc, &token, &[&token.data]);
+                        self.open_node_stack.pop_until(doc, &token.data);
+                        self.clear_active_formatting_to_marker();
                         return;
                     }
-                    // "applet" | "marquee" | "object" => {}
                     "br" => {
-                        // Error: Parse error
+                        self.token_error(&token);
                         self.reconstruct_active_formatting_elements(doc);
-                        self.insert_element_from_token(&mut doc.doctree, false, true, token);
-                        // TODO: Set the frameset-ok flag to not ok
+                        self.insert_element_from_token(doc, false, true, token);
+                        self.frameset_ok = false;
                         return;
                     }
                     _ => {
-                        if token.data == "" {
-                            return;
-                        }
-
-                        if !self.open_node_stack.stack.clone().into_iter().any(|i| {
-                            doc.doctree.get_element_name(&i).unwrap_or("".to_string()) == token.data
-                        }) {
-                            // Error
-                            return;
-                        }
-                        while let Some(n) = self.open_node_stack.last() {
-                            if doc.doctree.get_element_name(n).unwrap_or("".to_string())
-                                == token.data
-                            {
-                                self.open_node_stack.pop(&mut self.tokenizer);
-                                return;
-                            }
-                            if is_element_special(&doc.doctree, n, false) {
-                                // Error
-                                return;
-                            }
-                            self.open_node_stack.pop(&mut self.tokenizer);
-                        }
+                        self.any_other_end_tag(doc, &token);
                     }
                 };
             }
         }
     }
 
-    fn parse_text(&mut self, doc: &mut Document, token: HtmlToken) {
+    fn parse_text(&mut self, doc: &mut dyn TreeSink, token: HtmlToken) {
         match token.tag {
             TokenTag::Character => {
+                if let Some(n) = self.open_node_stack.last() {
+                    if is_entry_element!(doc, n, HTMLElementType::Script(_)) {
+                        self.script_text.push_str(&token.data);
+                    }
+                }
                 self.insert_character_token(doc, token);
             }
             TokenTag::EoF => {
-                // ERROR
+                self.token_error(&token);
                 if let Some(n) = self.open_node_stack.last() {
-                    if let Some(node) = doc.doctree.get_mut_node(n) {
-                        if let NodeType::Element(element) = &mut node.node_type {
-                            if let HTMLElementType::Script(s) = &mut element.element_type {
-                                s.enable_already_started();
-                            }
+                    if let Some(element) = doc.get_mut_element(n) {
+                        if let HTMLElementType::Script(s) = &mut element.element_type {
+                            s.enable_already_started();
                         }
                     }
                 }
-                self.open_node_stack.pop(&mut self.tokenizer);
+                self.open_node_stack.pop();
                 self.insertion_mode = self.original_insertion_mode;
                 self.reconsume_token = Some(token);
                 return;
@@ -1757,32 +2710,33 @@ impl Parser {
                         //     Perform microtask checkpoint
                         // }
 
-                        let script = self.open_node_stack.pop(&mut self.tokenizer);
+                        let script = self.open_node_stack.pop();
                         self.insertion_mode = self.original_insertion_mode;
-                        // TODO: let the old insertion point have the same value as the current insertion point
-                        //       let the insertion point be just before the next input character
+                        let text = std::mem::take(&mut self.script_text);
+                        // The insertion point is just before the next input character, which
+                        // is where the tokenizer stopped after the end tag
                         self.script_nesting_level += 1;
                         if self.speculative_parser.is_none() {
-                            // TODO: Prepare the script element
+                            if let Some(script) = script {
+                                self.prepare_script(doc, script, text);
+                            }
                         }
 
                         self.script_nesting_level -= 1;
                         if self.script_nesting_level == 0 {
                             self.parser_pause_flag = false;
                         }
-                        // TODO: Set the insertion point to the old insertion point
-                        if doc.get_pending_parse_blocking().is_none() {
-                            if self.script_nesting_level != 0 {
-                                self.parser_pause_flag = true;
-                                // TODO: Abort any nested invokations of tokenizer
-                            } else {
-                                // TODO: Finish this as I add JS support
-                            }
+                        // Written markup is parsed by this same tokenizer once the script is
+                        // done, so there are never nested invocations to abort. Rather than
+                        // spinning the event loop, the parser stops until it's resumed.
+                        if self.pending_parsing_blocking_script.is_some() {
+                            self.parser_pause_flag = true;
+                            self.start_speculative_parser();
                         }
                         return;
                     }
                     _ => {
-                        self.open_node_stack.pop(&mut self.tokenizer);
+                        self.open_node_stack.pop();
                         self.insertion_mode = self.original_insertion_mode;
                         return;
                     }
@@ -1792,7 +2746,7 @@ impl Parser {
         }
     }
 
-    fn parse_in_table(&mut self, doc: &mut Document, token: HtmlToken) {
+    fn parse_in_table(&mut self, doc: &mut dyn TreeSink, token: HtmlToken) {
         match token.tag {
             TokenTag::Character => {
                 if let Some(n) = self.open_node_stack.last() {
@@ -1809,29 +2763,30 @@ impl Parser {
                         self.pending_character_tokens = Vec::new();
                         self.original_insertion_mode = self.insertion_mode;
                         self.insertion_mode = InsertionMode::InTableText;
+                        self.reconsume_token = Some(token);
                         return;
                     }
                 }
             }
             TokenTag::Comment => {
-                self.insert_comment_token(doc, false, &token.data);
+                self.insert_comment_token(doc, false, &token);
                 return;
             }
             TokenTag::Doctype(_) => {
-                // ERROR: Parse error
+                self.token_error(&token);
                 return;
             }
             TokenTag::StartTag => match token.data.as_str() {
                 "caption" => {
                     self.clear_stack_back_to_table(doc);
-                    self.push_marker_to_active_formatting_elements(doc);
-                    self.insert_character_token(doc, token);
+                    self.push_marker_to_active_formatting_elements();
+                    self.insert_element_from_token(doc, false, false, token);
                     self.insertion_mode = InsertionMode::InCaption;
                     return;
                 }
                 "colgroup" => {
                     self.clear_stack_back_to_table(doc);
-                    self.insert_element_from_token(&mut doc.doctree, false, false, token);
+                    self.insert_element_from_token(doc, false, false, token);
                     self.insertion_mode = InsertionMode::InColumnGroup;
                     return;
                 }
@@ -1842,14 +2797,14 @@ impl Parser {
                             html_elements::element_structs::colgroup::ColGroup::default(),
                         )),
                     ));
-                    self.insert_element(&mut doc.doctree, false, node);
+                    self.insert_element(doc, false, node);
                     self.insertion_mode = InsertionMode::InColumnGroup;
                     self.reconsume_token = Some(token);
                     return;
                 }
                 "tbody" | "tfoot" | "thead" => {
                     self.clear_stack_back_to_table(doc);
-                    self.insert_element_from_token(&mut doc.doctree, false, false, token);
+                    self.insert_element_from_token(doc, false, false, token);
                     self.insertion_mode = InsertionMode::InTableBody;
                     return;
                 }
@@ -1860,7 +2815,7 @@ impl Parser {
                             html_elements::element_structs::tbody::TBody::default(),
                         )),
                     ));
-                    self.insert_element(&mut doc.doctree, false, node);
+                    self.insert_element(doc, false, node);
                     self.insertion_mode = InsertionMode::InTableBody;
                     self.reconsume_token = Some(token);
                     return;
@@ -1868,16 +2823,13 @@ impl Parser {
                 "table" => {
                     if !self
                         .open_node_stack
-                        .stack
-                        .clone()
-                        .into_iter()
-                        .any(|n| is_entry_element!(doc, &n, HTMLElementType::Table(_)))
+                        .has_element_in_table_scope(doc, "table")
                     {
-                        // Parse error
+                        self.token_error(&token);
                         return;
                     }
 
-                    while let Some(n) = self.open_node_stack.pop(&mut self.tokenizer) {
+                    while let Some(n) = self.open_node_stack.pop() {
                         if is_entry_element!(doc, &n, HTMLElementType::Table(_)) {
                             break;
                         }
@@ -1891,29 +2843,23 @@ impl Parser {
                     return;
                 }
                 "input" => {
-                    if !token
+                    if token
                         .attributes
                         .iter()
-                        .any(|val| val.name == "type" && val.value == "hidden")
+                        .any(|val| val.name == "type" && val.value.eq_ignore_ascii_case("hidden"))
                     {
-                        // ERROR
-                        self.insert_element_from_token(&mut doc.doctree, false, true, token);
+                        self.token_error(&token);
+                        self.insert_element_from_token(doc, false, true, token);
                         return;
                     }
                 }
                 "form" => {
-                    // ERROR
-                    if self.form_element.is_none()
-                        || self
-                            .open_node_stack
-                            .stack
-                            .iter()
-                            .any(|n| is_entry_element!(doc, n, HTMLElementType::Template(_)))
-                    {
+                    self.token_error(&token);
+                    if self.form_element.is_some() || self.has_template_on_stack(doc) {
                         return;
                     }
                     self.form_element =
-                        Some(self.insert_element_from_token(&mut doc.doctree, false, true, token));
+                        Some(self.insert_element_from_token(doc, false, true, token));
                     return;
                 }
                 _ => {}
@@ -1922,16 +2868,13 @@ impl Parser {
                 "table" => {
                     if !self
                         .open_node_stack
-                        .stack
-                        .clone()
-                        .into_iter()
-                        .any(|n| is_entry_element!(doc, &n, HTMLElementType::Table(_)))
+                        .has_element_in_table_scope(doc, "table")
                     {
-                        // Parse error
+                        self.token_error(&token);
                         return;
                     }
 
-                    while let Some(n) = self.open_node_stack.pop(&mut self.tokenizer) {
+                    while let Some(n) = self.open_node_stack.pop() {
                         if is_entry_element!(doc, &n, HTMLElementType::Table(_)) {
                             break;
                         }
@@ -1954,34 +2897,37 @@ impl Parser {
                 return;
             }
         };
-        // ERROR
-        // TODO: Enable foster parenting
+        self.token_error(&token);
+        self.foster_parenting = true;
         self.parse_in_body(doc, token);
-        // TODO: Disable foster parenting
+        self.foster_parenting = false;
     }
 
-    fn parse_in_table_text(&mut self, doc: &mut Document, token: HtmlToken) {
+    fn parse_in_table_text(&mut self, doc: &mut dyn TreeSink, token: HtmlToken) {
         match token.tag {
             TokenTag::Character => {
                 if token.data == "\u{0000}" {
-                    // ERROR
+                    self.token_error(&token);
                     return;
                 }
                 self.pending_character_tokens.push(token);
                 return;
             }
             _ => {
-                if self
+                if let Some(text) = self
                     .pending_character_tokens
                     .iter()
-                    .any(|t| !is_token_whitespace(t))
+                    .find(|t| !is_token_whitespace(t))
+                    .cloned()
                 {
-                    // ERROR
-                    for t in self.pending_character_tokens.clone() {
-                        self.parse_in_table(doc, t);
+                    self.parse_error(ParseErrorKind::UnexpectedCharacter, &text);
+                    self.foster_parenting = true;
+                    for t in std::mem::take(&mut self.pending_character_tokens) {
+                        self.parse_in_body(doc, t);
                     }
+                    self.foster_parenting = false;
                 } else {
-                    for t in self.pending_character_tokens.clone() {
+                    for t in std::mem::take(&mut self.pending_character_tokens) {
                         self.insert_character_token(doc, t);
                     }
                 }
@@ -1993,102 +2939,62 @@ impl Parser {
         }
     }
 
-    fn parse_in_caption(&mut self, doc: &mut Document, token: HtmlToken) {
+    fn parse_in_caption(&mut self, doc: &mut dyn TreeSink, token: HtmlToken) {
         match token.tag {
-            TokenTag::EndTag => {
-                match token.data.as_str() {
-                    "caption" => {
-                        if !self
-                            .open_node_stack
-                            .stack
-                            .iter()
-                            .any(|n| is_entry_element!(doc, n, HTMLElementType::Caption(_)))
-                        {
-                            // ERROR
-                            return;
-                        }
-
-                        // TODO: Generate implied end tags
-                        while let Some(n) = self.open_node_stack.last().cloned() {
-                            self.open_node_stack.pop(&mut self.tokenizer);
-
-                            if is_entry_element!(doc, &n, HTMLElementType::Caption(_)) {
-                                break;
-                            }
-                        }
-                        self.clear_active_formatting_to_marker(doc);
-                        self.insertion_mode = InsertionMode::InTable;
-                        return;
-                    }
-                    "table" => {
-                        if !self
-                            .open_node_stack
-                            .stack
-                            .iter()
-                            .any(|n| is_entry_element!(doc, n, HTMLElementType::Caption(_)))
-                        {
-                            // ERROR
-                            return;
-                        }
-
-                        // TODO: Generate implied end tags
-                        while let Some(n) = self.open_node_stack.last().cloned() {
-                            self.open_node_stack.pop(&mut self.tokenizer);
-
-                            if is_entry_element!(doc, &n, HTMLElementType::Caption(_)) {
-                                break;
-                            }
-                        }
-                        self.clear_active_formatting_to_marker(doc);
-                        self.insertion_mode = InsertionMode::InTable;
-                        self.reconsume_token = Some(token);
+            TokenTag::EndTag => match token.data.as_str() {
+                "caption" => {
+                    if !self
+                        .open_node_stack
+                        .has_element_in_table_scope(doc, "caption")
+                    {
+                        self.token_error(&token);
                         return;
                     }
-                    "body" | "col" | "colgroup" | "html" | "tbody" | "td" | "tfoot" | "th"
-                    | "thead" | "tr" => {
-                        // ERROR
+                    self.close_caption(doc, &token);
+                    return;
+                }
+                "table" => {
+                    if !self
+                        .open_node_stack
+                        .has_element_in_table_scope(doc, "caption")
+                    {
+                        self.token_error(&token);
                         return;
                     }
-                    _ => {}
+                    self.close_caption(doc, &token);
+                    self.reconsume_token = Some(token);
+                    return;
                 }
-            }
-            TokenTag::StartTag => {
-                match token.data.as_str() {
-                    "caption" | "col" | "colgroup" | "tbody" | "td" | "tfoot" | "th" | "thead"
-                    | "tr" => {
-                        if !self
-                            .open_node_stack
-                            .stack
-                            .iter()
-                            .any(|n| is_entry_element!(doc, n, HTMLElementType::Caption(_)))
-                        {
-                            // ERROR
-                            return;
-                        }
-
-                        // TODO: Generate implied end tags
-                        while let Some(n) = self.open_node_stack.last().cloned() {
-                            self.open_node_stack.pop(&mut self.tokenizer);
-
-                            if is_entry_element!(doc, &n, HTMLElementType::Caption(_)) {
-                                break;
-                            }
-                        }
-                        self.clear_active_formatting_to_marker(doc);
-                        self.insertion_mode = InsertionMode::InTable;
-                        self.reconsume_token = Some(token);
+                "body" | "col" | "colgroup" | "html" | "tbody" | "td" | "tfoot" | "th"
+                | "thead" | "tr" => {
+                    self.token_error(&token);
+                    return;
+                }
+                _ => {}
+            },
+            TokenTag::StartTag => match token.data.as_str() {
+                "caption" | "col" | "colgroup" | "tbody" | "td" | "tfoot" | "th" | "thead"
+                | "tr" => {
+                    if !self
+                        .open_node_stack
+                        .has_element_in_table_scope(doc, "caption")
+                    {
+                        self.token_error(&token);
                         return;
                     }
-                    _ => {}
+                    self.close_caption(doc, &token);
+                    self.reconsume_token = Some(token);
+                    return;
                 }
-            }
+                _ => {}
+            },
             _ => {}
         }
         self.parse_in_body(doc, token);
         return;
     }
 
-    fn parse_in_column_group(&mut self, doc: &mut Document, token: HtmlToken) {
+    fn parse_in_column_group(&mut self, doc: &mut dyn TreeSink, token: HtmlToken) {
         match token.tag {
             TokenTag::Character => match token.data.as_str() {
                 "\u{0009}" | "\u{000a}" | "\u{000c}" | "\u{000d}" | "\u{0020}" => {
@@ -2098,11 +3004,11 @@ impl Parser {
                 _ => {}
             },
             TokenTag::Comment => {
-                self.insert_comment_token(doc, false, token.data.as_str());
+                self.insert_comment_token(doc, false, &token);
                 return;
             }
             TokenTag::Doctype(_) => {
-                // ERROR
+                self.token_error(&token);
                 return;
             }
             TokenTag::StartTag => match token.data.as_str() {
@@ -2111,7 +3017,7 @@ impl Parser {
                     return;
                 }
                 "col" => {
-                    self.insert_element_from_token(&mut doc.doctree, false, true, token);
+                    self.insert_element_from_token(doc, false, true, token);
                     return;
                 }
                 "template" => {
@@ -2124,10 +3030,10 @@ impl Parser {
                 "colgroup" => {
                     if let Some(n) = self.open_node_stack.last() {
                         if !is_entry_element!(doc, n, HTMLElementType::Colgroup(_)) {
-                            // ERROR
+                            self.token_error(&token);
                             return;
                         } else {
-                            self.open_node_stack.pop(&mut self.tokenizer);
+                            self.open_node_stack.pop();
                             self.insertion_mode = InsertionMode::InTable;
                             return;
                         }
@@ -2136,7 +3042,7 @@ impl Parser {
                     return;
                 }
                 "col" => {
-                    // ERROR
+                    self.token_error(&token);
                     return;
                 }
                 "template" => {
@@ -2152,33 +3058,33 @@ impl Parser {
         };
         if let Some(n) = self.open_node_stack.last() {
             if !is_entry_element!(doc, n, HTMLElementType::Colgroup(_)) {
-                // ERROR: Parse error
+                self.token_error(&token);
                 return;
             }
         } else {
             // ERROR: Internal
             return;
         }
-        self.open_node_stack.pop(&mut self.tokenizer);
+        self.open_node_stack.pop();
         self.insertion_mode = InsertionMode::InTable;
         self.reconsume_token = Some(token);
         return;
     }
 
-    fn parse_in_table_body(&mut self, doc: &mut Document, token: HtmlToken) {
+    fn parse_in_table_body(&mut self, doc: &mut dyn TreeSink, token: HtmlToken) {
         match token.tag {
             TokenTag::StartTag => match token.data.as_str() {
                 "tr" => {
                     self.clear_stack_back_to_table_body(doc);
-                    self.insert_element_from_token(&mut doc.doctree, false, false, token);
+                    self.insert_element_from_token(doc, false, false, token);
                     self.insertion_mode = InsertionMode::InRow;
                     return;
                 }
                 "th" | "td" => {
-                    // ERROR
+                    self.token_error(&token);
                     self.clear_stack_back_to_table_body(doc);
                     self.insert_element(
-                        &mut doc.doctree,
+                        doc,
                         false,
                         node::Node::new(NodeType::Element(HTMLElement::from_element_type(
                             HTMLElementType::Tr(element_structs::tr::Tr::default()),
@@ -2189,10 +3095,15 @@ impl Parser {
                     return;
                 }
                 "caption" | "col" | "colgroup" | "tbody" | "tfoot" | "thead" => {
-                    // TODO: Check if stack has tbody, thead, or tfoot in table scope, if not, error and ignore
-
+                    if !self
+                        .open_node_stack
+                        .has_any_element_in_table_scope(doc, &["tbody", "thead", "tfoot"])
+                    {
+                        self.token_error(&token);
+                        return;
+                    }
                     self.clear_stack_back_to_table_body(doc);
-                    self.open_node_stack.pop(&mut self.tokenizer);
+                    self.open_node_stack.pop();
                     self.insertion_mode = InsertionMode::InTable;
                     self.reconsume_token = Some(token);
                     return;
@@ -2201,24 +3112,34 @@ impl Parser {
             },
             TokenTag::EndTag => match token.data.as_str() {
                 "tbody" | "tfoot" | "thead" => {
-                    // TODO: Check if the stack of open elements has element in table scope
-
+                    if !self
+                        .open_node_stack
+                        .has_element_in_table_scope(doc, &token.data)
+                    {
+                        self.token_error(&token);
+                        return;
+                    }
                     self.clear_stack_back_to_table_body(doc);
-                    self.open_node_stack.pop(&mut self.tokenizer);
+                    self.open_node_stack.pop();
                     self.insertion_mode = InsertionMode::InTable;
                     return;
                 }
                 "table" => {
-                    // TODO: Check if stack has tbody, thead, or tfoot in table scope, if not, error and ignore
-
-                    self.clear_stack_back_to_table_body(doc);
-                    self.open_node_stack.pop(&mut self.tokenizer);
-                    self.insertion_mode = InsertionMode::InTable;
+                    if !self
+                        .open_node_stack
+                        .has_any_element_in_table_scope(doc, &["tbody", "thead", "tfoot"])
+                    {
+                        self.token_error(&token);
+                        return;
+                    }
+                    self.clear_stack_back_to_table_body(doc);
+                    self.open_node_stack.pop();
+                    self.insertion_mode = InsertionMode::InTable;
                     self.reconsume_token = Some(token);
                     return;
                 }
                 "body" | "caption" | "col" | "colgroup" | "html" | "td" | "th" | "tr" => {
-                    // ERROR
+                    self.token_error(&token);
                     return;
                 }
                 _ => {}
@@ -2229,29 +3150,24 @@ impl Parser {
         return;
     }
 
-    fn parse_in_row(&mut self, doc: &mut Document, token: HtmlToken) {
+    fn parse_in_row(&mut self, doc: &mut dyn TreeSink, token: HtmlToken) {
         match token.tag {
             TokenTag::StartTag => match token.data.as_str() {
                 "th" | "td" => {
                     self.clear_stack_back_to_table_row(doc);
-                    self.insert_element_from_token(&mut doc.doctree, false, false, token);
+                    self.insert_element_from_token(doc, false, false, token);
                     self.insertion_mode = InsertionMode::InCell;
-                    self.push_marker_to_active_formatting_elements(doc);
+                    self.push_marker_to_active_formatting_elements();
                     return;
                 }
                 "caption" | "col" | "colgroup" | "tbody" | "tfoot" | "thead" | "tr" => {
-                    if !self
-                        .open_node_stack
-                        .stack
-                        .iter()
-                        .any(|n| is_entry_element!(doc, n, HTMLElementType::Tr(_)))
-                    {
-                        // ERROR
+                    if !self.open_node_stack.has_element_in_table_scope(doc, "tr") {
+                        self.token_error(&token);
                         return;
                     }
 
                     self.clear_stack_back_to_table_row(doc);
-                    self.open_node_stack.pop(&mut self.tokenizer);
+                    self.open_node_stack.pop();
                     self.insertion_mode = InsertionMode::InTableBody;
                     self.reconsume_token = Some(token);
                     return;
@@ -2260,60 +3176,48 @@ impl Parser {
             },
             TokenTag::EndTag => match token.data.as_str() {
                 "tr" => {
-                    if !self
-                        .open_node_stack
-                        .stack
-                        .iter()
-                        .any(|n| is_entry_element!(doc, n, HTMLElementType::Tr(_)))
-                    {
-                        // ERROR
+                    if !self.open_node_stack.has_element_in_table_scope(doc, "tr") {
+                        self.token_error(&token);
                         return;
                     }
 
                     self.clear_stack_back_to_table_row(doc);
-                    self.open_node_stack.pop(&mut self.tokenizer);
+                    self.open_node_stack.pop();
                     self.insertion_mode = InsertionMode::InTableBody;
                     return;
                 }
                 "table" => {
-                    if !self
-                        .open_node_stack
-                        .stack
-                        .iter()
-                        .any(|n| is_entry_element!(doc, n, HTMLElementType::Tr(_)))
-                    {
-                        // ERROR
+                    if !self.open_node_stack.has_element_in_table_scope(doc, "tr") {
+                        self.token_error(&token);
                         return;
                     }
 
                     self.clear_stack_back_to_table_row(doc);
-                    self.open_node_stack.pop(&mut self.tokenizer);
+                    self.open_node_stack.pop();
                     self.insertion_mode = InsertionMode::InTableBody;
                     self.reconsume_token = Some(token);
                     return;
                 }
                 "tbody" | "tfoot" | "thead" => {
-                    if !self.is_element_in_table_scope(doc, token.data.as_str()) {
-                        // ERROR
-                        return;
-                    }
                     if !self
                         .open_node_stack
-                        .stack
-                        .iter()
-                        .any(|n| is_entry_element!(doc, n, HTMLElementType::Tr(_)))
+                        .has_element_in_table_scope(doc, token.data.as_str())
                     {
+                        self.token_error(&token);
+                        return;
+                    }
+                    if !self.open_node_stack.has_element_in_table_scope(doc, "tr") {
                         return;
                     }
 
                     self.clear_stack_back_to_table_row(doc);
-                    self.open_node_stack.pop(&mut self.tokenizer);
+                    self.open_node_stack.pop();
                     self.insertion_mode = InsertionMode::InTable;
                     self.reconsume_token = Some(token);
                     return;
                 }
                 "body" | "caption" | "col" | "colgroup" | "html" | "td" | "th" => {
-                    // ERROR
+                    self.token_error(&token);
                     return;
                 }
                 _ => {}
@@ -2324,49 +3228,38 @@ impl Parser {
         return;
     }
 
-    fn parse_in_cell(&mut self, doc: &mut Document, token: HtmlToken) {
+    fn parse_in_cell(&mut self, doc: &mut dyn TreeSink, token: HtmlToken) {
         match token.tag {
             TokenTag::EndTag => match token.data.as_str() {
                 "td" | "th" => {
-                    if !self.is_element_in_table_scope(doc, token.data.as_str()) {
-                        // ERROR
+                    if !self
+                        .open_node_stack
+                        .has_element_in_table_scope(doc, token.data.as_str())
+                    {
+                        self.token_error(&token);
                         return;
                     }
 
-                    // TODO: Generate end tags
-                    if let Some(n) = self.open_node_stack.last() {
-                        if let Some(node) = doc.doctree.get_node(n) {
-                            if let NodeType::Element(e) = &node.node_type {
-                                if e.get_name() != token.data {
-                                    // ERROR
-                                }
-                            }
-                        }
-                    }
-                    while let Some(n) = self.open_node_stack.pop(&mut self.tokenizer) {
-                        if let Some(node) = doc.doctree.get_node(&n) {
-                            if let NodeType::Element(e) = &node.node_type {
-                                if e.get_name() == token.data {
-                                    break;
-                                }
-                            }
-                        }
-                    }
-
-                    self.clear_active_formatting_to_marker(doc);
+                    self.open_node_stack.generate_implied_end_tags(doc, None);
+                    self.check_current_node(doc, &token, &[&token.data]);
+                    self.open_node_stack.pop_until(doc, &token.data);
+                    self.clear_active_formatting_to_marker();
                     self.insertion_mode = InsertionMode::InRow;
                     return;
                 }
                 "body" | "caption" | "col" | "colgroup" | "html" => {
-                    // ERROR
+                    self.token_error(&token);
                     return;
                 }
                 "table" | "tbody" | "tfoot" | "thead" | "tr" => {
-                    if !self.is_element_in_table_scope(doc, token.data.as_str()) {
-                        // ERROR
+                    if !self
+                        .open_node_stack
+                        .has_element_in_table_scope(doc, token.data.as_str())
+                    {
+                        self.token_error(&token);
                         return;
                     }
-                    self.close_cell(doc);
+                    self.close_cell(doc, &token);
                     self.reconsume_token = Some(token);
                     return;
                 }
@@ -2375,8 +3268,15 @@ impl Parser {
             TokenTag::StartTag => match token.data.as_str() {
                 "caption" | "col" | "colgroup" | "tbody" | "td" | "tfoot" | "th" | "thead"
                 | "tr" => {
-                    // TODO: Assert open stack has td or th
-                    self.close_cell(doc);
+                    if !self
+                        .open_node_stack
+                        .has_any_element_in_table_scope(doc, &["td", "th"])
+                    {
+                        self.token_error(&token);
+                        return;
+                    }
+                    self.close_cell(doc, &token);
+                    self.reconsume_token = Some(token);
                     return;
                 }
                 _ => {}
@@ -2387,11 +3287,11 @@ impl Parser {
         return;
     }
 
-    fn parse_in_select(&mut self, doc: &mut Document, token: HtmlToken) {
+    fn parse_in_select(&mut self, doc: &mut dyn TreeSink, token: HtmlToken) {
         match token.tag {
             TokenTag::Character => match token.data.as_str() {
                 "\u{0000}" => {
-                    // ERROR
+                    self.token_error(&token);
                     return;
                 }
                 _ => {
@@ -2400,11 +3300,11 @@ impl Parser {
                 }
             },
             TokenTag::Comment => {
-                self.insert_comment_token(doc, false, token.data.as_str());
+                self.insert_comment_token(doc, false, &token);
                 return;
             }
             TokenTag::Doctype(_) => {
-                // ERROR
+                self.token_error(&token);
                 return;
             }
             TokenTag::StartTag => match token.data.as_str() {
@@ -2415,10 +3315,10 @@ impl Parser {
                 "option" => {
                     if let Some(n) = self.open_node_stack.last() {
                         if is_entry_element!(doc, n, HTMLElementType::Option(_)) {
-                            self.open_node_stack.pop(&mut self.tokenizer);
+                            self.open_node_stack.pop();
                         }
                     }
-                    self.insert_element_from_token(&mut doc.doctree, false, false, token);
+                    self.insert_element_from_token(doc, false, false, token);
                     return;
                 }
                 "optgroup" => {
@@ -2428,10 +3328,10 @@ impl Parser {
                             n,
                             HTMLElementType::Option(_) | HTMLElementType::Optgroup(_)
                         ) {
-                            self.open_node_stack.pop(&mut self.tokenizer);
+                            self.open_node_stack.pop();
                         }
                     }
-                    self.insert_element_from_token(&mut doc.doctree, false, false, token);
+                    self.insert_element_from_token(doc, false, false, token);
                     return;
                 }
                 "hr" => {
@@ -2441,24 +3341,22 @@ impl Parser {
                             n,
                             HTMLElementType::Option(_) | HTMLElementType::Optgroup(_)
                         ) {
-                            self.open_node_stack.pop(&mut self.tokenizer);
+                            self.open_node_stack.pop();
                         }
                     }
-                    self.insert_element_from_token(&mut doc.doctree, false, false, token);
+                    self.insert_element_from_token(doc, false, false, token);
                     // TODO: Acknowledge self closing
                     return;
                 }
                 "select" => {
-                    // ERROR
+                    self.token_error(&token);
                     if !self
                         .open_node_stack
-                        .stack
-                        .iter()
-                        .any(|n| is_entry_element!(doc, n, HTMLElementType::Select(_)))
+                        .has_element_in_select_scope(doc, "select")
                     {
                         return;
                     }
-                    while let Some(n) = self.open_node_stack.pop(&mut self.tokenizer) {
+                    while let Some(n) = self.open_node_stack.pop() {
                         if is_entry_element!(doc, &n, HTMLElementType::Select(_)) {
                             break;
                         }
@@ -2467,16 +3365,14 @@ impl Parser {
                     return;
                 }
                 "input" | "keygen" | "textarea" => {
-                    // ERROR
+                    self.token_error(&token);
                     if !self
                         .open_node_stack
-                        .stack
-                        .iter()
-                        .any(|n| is_entry_element!(doc, n, HTMLElementType::Select(_)))
+                        .has_element_in_select_scope(doc, "select")
                     {
                         return;
                     }
-                    while let Some(n) = self.open_node_stack.pop(&mut self.tokenizer) {
+                    while let Some(n) = self.open_node_stack.pop() {
                         if is_entry_element!(doc, &n, HTMLElementType::Select(_)) {
                             break;
                         }
@@ -2500,8 +3396,8 @@ impl Parser {
                             if let Some(n) = self.open_node_stack.at(self.open_node_stack.len() - 2)
                             {
                                 if is_entry_element!(doc, n, HTMLElementType::Optgroup(_)) {
-                                    self.open_node_stack.pop(&mut self.tokenizer);
-                                    self.open_node_stack.pop(&mut self.tokenizer);
+                                    self.open_node_stack.pop();
+                                    self.open_node_stack.pop();
                                     return;
                                 }
                             }
@@ -2510,35 +3406,33 @@ impl Parser {
 
                     if let Some(n) = self.open_node_stack.last() {
                         if is_entry_element!(doc, n, HTMLElementType::Optgroup(_)) {
-                            self.open_node_stack.pop(&mut self.tokenizer);
+                            self.open_node_stack.pop();
                             return;
                         }
                     }
-                    // ERROR
+                    self.token_error(&token);
                     return;
                 }
                 "option" => {
                     if let Some(n) = self.open_node_stack.last() {
                         if is_entry_element!(doc, n, HTMLElementType::Option(_)) {
-                            self.open_node_stack.pop(&mut self.tokenizer);
+                            self.open_node_stack.pop();
                             return;
                         }
                     }
-                    // ERROR
+                    self.token_error(&token);
                     return;
                 }
                 "select" => {
                     if !self
                         .open_node_stack
-                        .stack
-                        .iter()
-                        .any(|n| is_entry_element!(doc, n, HTMLElementType::Select(_)))
+                        .has_element_in_select_scope(doc, "select")
                     {
-                        // ERROR
+                        self.token_error(&token);
                         return;
                     }
 
-                    while let Some(n) = self.open_node_stack.pop(&mut self.tokenizer) {
+                    while let Some(n) = self.open_node_stack.pop() {
                         if is_entry_element!(doc, &n, HTMLElementType::Select(_)) {
                             break;
                         }
@@ -2557,16 +3451,16 @@ impl Parser {
                 return;
             }
         }
-        // ERROR
+        self.token_error(&token);
         return;
     }
 
-    fn parse_in_select_table(&mut self, doc: &mut Document, token: HtmlToken) {
+    fn parse_in_select_table(&mut self, doc: &mut dyn TreeSink, token: HtmlToken) {
         match token.tag {
             TokenTag::StartTag => match token.data.as_str() {
                 "caption" | "table" | "tbody" | "tfoot" | "thead" | "tr" | "td" | "th" => {
-                    // ERROR
-                    while let Some(n) = self.open_node_stack.pop(&mut self.tokenizer) {
+                    self.token_error(&token);
+                    while let Some(n) = self.open_node_stack.pop() {
                         if is_entry_element!(doc, &n, HTMLElementType::Select(_)) {
                             break;
                         }
@@ -2579,11 +3473,14 @@ impl Parser {
             },
             TokenTag::EndTag => match token.data.as_str() {
                 "caption" | "table" | "tbody" | "tfoot" | "thead" | "tr" | "td" | "th" => {
-                    // ERROR
-                    if !self.is_element_in_table_scope(doc, &token.data) {
+                    self.token_error(&token);
+                    if !self
+                        .open_node_stack
+                        .has_element_in_table_scope(doc, &token.data)
+                    {
                         return;
                     }
-                    while let Some(n) = self.open_node_stack.pop(&mut self.tokenizer) {
+                    while let Some(n) = self.open_node_stack.pop() {
                         if is_entry_element!(doc, &n, HTMLElementType::Select(_)) {
                             break;
                         }
@@ -2599,7 +3496,7 @@ impl Parser {
         self.parse_in_select(doc, token);
     }
 
-    fn parse_in_template(&mut self, doc: &mut Document, token: HtmlToken) {
+    fn parse_in_template(&mut self, doc: &mut dyn TreeSink, token: HtmlToken) {
         match token.tag {
             TokenTag::Character => {
                 self.parse_in_body(doc, token);
@@ -2620,37 +3517,27 @@ impl Parser {
                     return;
                 }
                 "caption" | "colgroup" | "tbody" | "tfoot" | "thead" => {
-                    // TODO: pop current template insertion mode
-                    // TODO: Push in table onto stack of template insertion modes
-                    self.insertion_mode = InsertionMode::InTable;
+                    self.switch_template_insertion_mode(InsertionMode::InTable);
                     self.reconsume_token = Some(token);
                     return;
                 }
                 "col" => {
-                    // TODO: pop current template insertion mode
-                    // TODO: Push in column group onto the template insertion stack
-                    self.insertion_mode = InsertionMode::InColumnGroup;
+                    self.switch_template_insertion_mode(InsertionMode::InColumnGroup);
                     self.reconsume_token = Some(token);
                     return;
                 }
                 "tr" => {
-                    // TODO: pop current template insertion mode
-                    // TODO: Push in table body onto the template insertion stack
-                    self.insertion_mode = InsertionMode::InTableBody;
+                    self.switch_template_insertion_mode(InsertionMode::InTableBody);
                     self.reconsume_token = Some(token);
                     return;
                 }
                 "td" | "th" => {
-                    // TODO: pop current template insertion mode
-                    // TODO: Push in row onto the template insertion stack
-                    self.insertion_mode = InsertionMode::InRow;
+                    self.switch_template_insertion_mode(InsertionMode::InRow);
                     self.reconsume_token = Some(token);
                     return;
                 }
                 _ => {
-                    // TODO: pop current template insertion mode
-                    // TODO: Push in body the template insertion stack
-                    self.insertion_mode = InsertionMode::InBody;
+                    self.switch_template_insertion_mode(InsertionMode::InBody);
                     self.reconsume_token = Some(token);
                     return;
                 }
@@ -2660,34 +3547,243 @@ impl Parser {
                     self.parse_in_head(doc, token);
                 }
                 _ => {
-                    // ERROR
+                    self.token_error(&token);
                     return;
                 }
             },
             TokenTag::EoF => {
-                if !self
-                    .open_node_stack
-                    .stack
-                    .iter()
-                    .any(|n| is_entry_element!(doc, n, HTMLElementType::Template(_)))
-                {
+                if !self.has_template_on_stack(doc) {
                     self.should_exit = true;
+                    return;
                 }
-                // ERROR
-                while let Some(n) = self.open_node_stack.pop(&mut self.tokenizer) {
+                self.token_error(&token);
+                while let Some(n) = self.open_node_stack.pop() {
                     if is_entry_element!(doc, &n, HTMLElementType::Template(_)) {
                         break;
                     }
                 }
-                self.clear_active_formatting_to_marker(doc);
-                // TODO: Pop last entry in template insertion mode stack
+                self.clear_active_formatting_to_marker();
+                self.template_insertion_modes.pop();
+                self.reset_insertion_mode(doc);
                 self.reconsume_token = Some(token);
                 return;
             }
         }
     }
 
-    fn parse_after_body(&mut self, doc: &mut Document, token: HtmlToken) {
+    fn adjusted_current_node(&self) -> Option<DoctreeNode> {
+        match self.fragment_parser_ctx {
+            Some(ctx) if self.open_node_stack.len() == 1 => Some(ctx),
+            _ => self.open_node_stack.last().cloned(),
+        }
+    }
+
+    fn adjusted_current_node_namespace(&self, doc: &dyn TreeSink) -> Namespace {
+        self.adjusted_current_node()
+            .and_then(|n| doc.get_element_namespace(&n))
+            .unwrap_or(Namespace::Html)
+    }
+
+    // Decides if a token goes to the foreign content rules instead of the insertion mode
+    fn should_parse_as_foreign(&self, doc: &dyn TreeSink, token: &HtmlToken) -> bool {
+        let node = match self.adjusted_current_node() {
+            Some(n) => n,
+            None => return false,
+        };
+        let (name, namespace) = match get_element_name_and_namespace(doc, &node) {
+            Some(element) => element,
+            None => return false,
+        };
+        if namespace == Namespace::Html {
+            return false;
+        }
+
+        let is_start_tag = matches!(token.tag, TokenTag::StartTag);
+        let is_character = matches!(token.tag, TokenTag::Character);
+        if is_mathml_text_integration_point(&name, namespace)
+            && (is_character
+                || (is_start_tag && token.data != "mglyph" && token.data != "malignmark"))
+        {
+            return false;
+        }
+        if namespace == Namespace::MathMl
+            && name == "annotation-xml"
+            && is_start_tag
+            && token.data == "svg"
+        {
+            return false;
+        }
+        if is_html_integration_point(doc, &node) && (is_start_tag || is_character) {
+            return false;
+        }
+        !matches!(token.tag, TokenTag::EoF)
+    }
+
+    fn create_foreign_element_from_token(
+        &self,
+        token: HtmlToken,
+        namespace: Namespace,
+    ) -> node::Node {
+        let name = match namespace {
+            Namespace::Svg => foreign::adjust_svg_tag_name(&token.data)
+                .map(|name| name.to_string())
+                .unwrap_or(token.data),
+            _ => token.data,
+        };
+        let mut element = HTMLElement::new_foreign(name, namespace);
+        let mut attribute_spans = Vec::new();
+
+        for attribute in token.attributes {
+            let adjusted = match namespace {
+                Namespace::Svg => foreign::adjust_svg_attribute_name(&attribute.name),
+                Namespace::MathMl => foreign::adjust_mathml_attribute_name(&attribute.name),
+                _ => None,
+            };
+            let name = adjusted
+                .map(|name| name.to_string())
+                .unwrap_or(attribute.name);
+            attribute_spans.push((name.clone(), attribute.span));
+            match foreign::adjust_foreign_attribute(&name) {
+                Some((attribute_namespace, local_name)) => element.add_namespaced_attribute(
+                    local_name.to_string(),
+                    attribute.value,
+                    Some(attribute_namespace),
+                ),
+                None => element.add_attribute(name, attribute.value),
+            }
+        }
+
+        let mut node = node::Node::new(NodeType::Element(element));
+        node.span = Some(token.span);
+        node.attribute_spans = attribute_spans;
+        node
+    }
+
+    fn insert_foreign_element_from_token(
+        &mut self,
+        sink: &mut dyn TreeSink,
+        token: HtmlToken,
+        namespace: Namespace,
+    ) -> DoctreeNode {
+        let self_closing = token.flags.self_closing;
+        let element = self.create_foreign_element_from_token(token, namespace);
+        let node = self.insert_element_node(sink, false, element);
+        // Self closing foreign elements are acknowledged by never leaving them open
+        if !self_closing {
+            self.open_node_stack.push(node);
+        }
+        node
+    }
+
+    fn parse_foreign_content(&mut self, doc: &mut dyn TreeSink, token: HtmlToken) {
+        match token.tag {
+            TokenTag::Character => {
+                if token.data == "\u{0000}" {
+                    self.token_error(&token);
+                    let mut replacement = token;
+                    replacement.data = "\u{FFFD}".to_string();
+                    self.insert_character_token(doc, replacement);
+                    return;
+                }
+                if !is_token_whitespace(&token) {
+                    self.frameset_ok = false;
+                }
+                self.insert_character_token(doc, token);
+            }
+            TokenTag::Comment => {
+                self.insert_comment_token(doc, false, &token);
+            }
+            TokenTag::Doctype(_) => {
+                self.token_error(&token);
+            }
+            TokenTag::StartTag => {
+                let breaks_out = match token.data.as_str() {
+                    "b" | "big" | "blockquote" | "body" | "br" | "center" | "code" | "dd"
+                    | "div" | "dl" | "dt" | "em" | "embed" | "h1" | "h2" | "h3" | "h4" | "h5"
+                    | "h6" | "head" | "hr" | "i" | "img" | "li" | "listing" | "menu" | "meta"
+                    | "nobr" | "ol" | "p" | "pre" | "ruby" | "s" | "small" | "span" | "strong"
+                    | "strike" | "sub" | "sup" | "table" | "tt" | "u" | "ul" | "var" => true,
+                    "font" => token
+                        .attributes
+                        .iter()
+                        .any(|a| matches!(a.name.as_str(), "color" | "face" | "size")),
+                    _ => false,
+                };
+                if breaks_out {
+                    self.token_error(&token);
+                    self.break_out_of_foreign_content(doc, token);
+                    return;
+                }
+
+                let namespace = self.adjusted_current_node_namespace(doc);
+                self.insert_foreign_element_from_token(doc, token, namespace);
+            }
+            TokenTag::EndTag => {
+                if token.data == "br" || token.data == "p" {
+                    self.token_error(&token);
+                    self.break_out_of_foreign_content(doc, token);
+                    return;
+                }
+                // TODO: Process SVG script elements
+                self.foreign_end_tag(doc, token);
+            }
+            TokenTag::EoF => self.process_token(doc, token),
+        }
+    }
+
+    // HTML elements close any open foreign elements before being processed normally
+    fn break_out_of_foreign_content(&mut self, doc: &mut dyn TreeSink, token: HtmlToken) {
+        while let Some(n) = self.open_node_stack.last() {
+            let is_html = doc.get_element_namespace(n) == Some(Namespace::Html);
+            let name = doc.get_element_name(n).unwrap_or_default();
+            let namespace = doc.get_element_namespace(n).unwrap_or(Namespace::Html);
+            if is_html
+                || is_mathml_text_integration_point(&name, namespace)
+                || is_html_integration_point(doc, n)
+            {
+                break;
+            }
+            self.open_node_stack.pop();
+        }
+        self.process_token(doc, token);
+    }
+
+    fn foreign_end_tag(&mut self, doc: &mut dyn TreeSink, token: HtmlToken) {
+        let mut idx = match self.open_node_stack.len().checked_sub(1) {
+            Some(idx) => idx,
+            None => return,
+        };
+        let matches_token = |doc: &dyn TreeSink, node: &DoctreeNode| {
+            doc.get_element_name(node)
+                .is_some_and(|name| name.to_ascii_lowercase() == token.data)
+        };
+        if !matches_token(doc, &self.open_node_stack.stack[idx]) {
+            self.parse_error(ParseErrorKind::UnclosedElements, &token);
+        }
+
+        loop {
+            if idx == 0 {
+                return;
+            }
+            let node = self.open_node_stack.stack[idx];
+            if matches_token(doc, &node) {
+                while let Some(n) = self.open_node_stack.pop() {
+                    if n == node {
+                        break;
+                    }
+                }
+                return;
+            }
+            idx -= 1;
+            if doc.get_element_namespace(&self.open_node_stack.stack[idx]) == Some(Namespace::Html)
+            {
+                self.process_token(doc, token);
+                return;
+            }
+        }
+    }
+
+    fn parse_after_body(&mut self, doc: &mut dyn TreeSink, token: HtmlToken) {
         match token.tag {
             TokenTag::Character => match token.data.as_str() {
                 "\u{0009}" | "\u{000A}" | "\u{000C}" | "\u{000D}" | "\u{0020}" => {
@@ -2697,11 +3793,11 @@ impl Parser {
                 _ => {}
             },
             TokenTag::Comment => {
-                self.insert_comment_token(doc, false, &token.data);
+                self.insert_comment_token(doc, false, &token);
                 return;
             }
             TokenTag::Doctype(_) => {
-                // ERROR
+                self.token_error(&token);
                 return;
             }
             TokenTag::StartTag => match token.data.as_str() {
@@ -2714,7 +3810,7 @@ impl Parser {
             TokenTag::EndTag => match token.data.as_str() {
                 "html" => {
                     if self.fragment_parser_ctx.is_some() {
-                        // ERROR
+                        self.token_error(&token);
                         return;
                     }
                     self.insertion_mode = InsertionMode::AfterAfterBody;
@@ -2727,13 +3823,13 @@ impl Parser {
                 return;
             }
         }
-        // ERROR
+        self.token_error(&token);
         self.insertion_mode = InsertionMode::InBody;
         self.reconsume_token = Some(token);
         return;
     }
 
-    fn parse_in_frameset(&mut self, doc: &mut Document, token: HtmlToken) {
+    fn parse_in_frameset(&mut self, doc: &mut dyn TreeSink, token: HtmlToken) {
         match token.tag {
             TokenTag::Character => match token.data.as_str() {
                 "\u{0009}" | "\u{000A}" | "\u{000C}" | "\u{000D}" | "\u{0020}" => {
@@ -2743,11 +3839,11 @@ impl Parser {
                 _ => {}
             },
             TokenTag::Comment => {
-                self.insert_comment_token(doc, false, &token.data);
+                self.insert_comment_token(doc, false, &token);
                 return;
             }
             TokenTag::Doctype(_) => {
-                // ERROR
+                self.token_error(&token);
                 return;
             }
             TokenTag::StartTag => match token.data.as_str() {
@@ -2756,12 +3852,11 @@ impl Parser {
                     return;
                 }
                 "frameset" => {
-                    self.insert_element_from_token(&mut doc.doctree, false, false, token);
+                    self.insert_element_from_token(doc, false, false, token);
                     return;
                 }
                 "frame" => {
-                    self.insert_element_from_token(&mut doc.doctree, false, true, token);
-                    // TODO: Acknowledge self closing tags
+                    self.insert_element_from_token(doc, false, true, token);
                     return;
                 }
                 "noframes" => {
@@ -2775,29 +3870,30 @@ impl Parser {
                     if self.open_node_stack.len() == 1 {
                         if let Some(n) = self.open_node_stack.last() {
                             if is_entry_element!(doc, n, HTMLElementType::Html(_)) {
-                                // ERROR
+                                self.token_error(&token);
                                 return;
                             }
                         }
                     }
-                    self.open_node_stack.pop(&mut self.tokenizer);
-                    // TODO: Add frameset
-                    // if self.fragment_parser_ctx.is_none() {
-                    //     if let Some(n) = self.open_node_stack.last() {
-                    //         // if is_entry_element!(doc, n, HTMLElementType::Frame)
-                    //     }
-                    // }
-                    self.insertion_mode = InsertionMode::AfterFrameset;
+                    self.open_node_stack.pop();
+                    // Nested framesets keep us in frameset
+                    if self.fragment_parser_ctx.is_none()
+                        && !self.open_node_stack.last().is_some_and(|n| {
+                            is_entry_element!(doc, n, HTMLElementType::Frameset(_))
+                        })
+                    {
+                        self.insertion_mode = InsertionMode::AfterFrameset;
+                    }
                     return;
                 }
                 _ => {}
             },
             TokenTag::EoF => {
                 if self.open_node_stack.len() != 1 {
-                    // ERROR
+                    self.token_error(&token);
                 } else if let Some(n) = self.open_node_stack.last() {
                     if !is_entry_element!(doc, n, HTMLElementType::Html(_)) {
-                        // ERROR
+                        self.token_error(&token);
                     }
                 }
                 self.should_exit = true;
@@ -2805,11 +3901,11 @@ impl Parser {
             }
             _ => {}
         }
-        // ERROR
+        self.token_error(&token);
         return;
     }
 
-    fn parse_after_frameset(&mut self, doc: &mut Document, token: HtmlToken) {
+    fn parse_after_frameset(&mut self, doc: &mut dyn TreeSink, token: HtmlToken) {
         match token.tag {
             TokenTag::Character => match token.data.as_str() {
                 "\u{0009}" | "\u{000A}" | "\u{000C}" | "\u{000D}" | "\u{0020}" => {
@@ -2819,11 +3915,11 @@ impl Parser {
                 _ => {}
             },
             TokenTag::Comment => {
-                self.insert_comment_token(doc, false, &token.data);
+                self.insert_comment_token(doc, false, &token);
                 return;
             }
             TokenTag::Doctype(_) => {
-                // ERROR
+                self.token_error(&token);
                 return;
             }
             TokenTag::StartTag => match token.data.as_str() {
@@ -2849,14 +3945,14 @@ impl Parser {
                 return;
             }
         };
-        // ERROR
+        self.token_error(&token);
         return;
     }
 
-    fn parse_after_after_body(&mut self, doc: &mut Document, token: HtmlToken) {
+    fn parse_after_after_body(&mut self, doc: &mut dyn TreeSink, token: HtmlToken) {
         match token.tag {
             TokenTag::Comment => {
-                self.insert_comment_token(doc, true, &token.data);
+                self.insert_comment_token(doc, true, &token);
                 return;
             }
             TokenTag::Doctype(_) => {
@@ -2883,16 +3979,16 @@ impl Parser {
             }
             _ => {}
         };
-        // ERROR
+        self.token_error(&token);
         self.insertion_mode = InsertionMode::InBody;
         self.reconsume_token = Some(token);
         return;
     }
 
-    fn parse_after_after_frameset(&mut self, doc: &mut Document, token: HtmlToken) {
+    fn parse_after_after_frameset(&mut self, doc: &mut dyn TreeSink, token: HtmlToken) {
         match token.tag {
             TokenTag::Comment => {
-                self.insert_comment_token(doc, true, &token.data);
+                self.insert_comment_token(doc, true, &token);
                 return;
             }
             TokenTag::Doctype(_) => {
@@ -2923,130 +4019,1668 @@ impl Parser {
             }
             _ => {}
         };
-        // ERROR
+        self.token_error(&token);
         return;
     }
 
+    fn process_token(&mut self, doc: &mut dyn TreeSink, token: HtmlToken) {
+        match self.insertion_mode {
+            InsertionMode::Initial => self.parse_initial(doc, token),
+            InsertionMode::BeforeHtml => self.parse_before_html(doc, token),
+            InsertionMode::BeforeHead => self.parse_before_head(doc, token),
+            InsertionMode::InHead => self.parse_in_head(doc, token),
+            InsertionMode::InHeadNoscript => self.parse_in_head_noscript(doc, token),
+            InsertionMode::AfterHead => self.parse_after_head(doc, token),
+            InsertionMode::InBody => self.parse_in_body(doc, token),
+            InsertionMode::Text => self.parse_text(doc, token),
+            InsertionMode::InTable => self.parse_in_table(doc, token),
+            InsertionMode::InTableText => self.parse_in_table_text(doc, token),
+            InsertionMode::InCaption => self.parse_in_caption(doc, token),
+            InsertionMode::InColumnGroup => self.parse_in_column_group(doc, token),
+            InsertionMode::InTableBody => self.parse_in_table_body(doc, token),
+            InsertionMode::InRow => self.parse_in_row(doc, token),
+            InsertionMode::InCell => self.parse_in_cell(doc, token),
+            InsertionMode::InSelect => self.parse_in_select(doc, token),
+            InsertionMode::InSelectTable => self.parse_in_select_table(doc, token),
+            InsertionMode::InTemplate => self.parse_in_template(doc, token),
+            InsertionMode::AfterBody => self.parse_after_body(doc, token),
+            InsertionMode::InFrameset => self.parse_in_frameset(doc, token),
+            InsertionMode::AfterFrameset => self.parse_after_frameset(doc, token),
+            InsertionMode::AfterAfterBody => self.parse_after_after_body(doc, token),
+            InsertionMode::AfterAfterFrameset => self.parse_after_after_frameset(doc, token),
+        };
+    }
+
     pub fn parse_html(&mut self) -> document::Document {
-        // TODO: Determine encoding of document
-        //       For now we just assume UTF-8
-        // TODO: Normalize Newlines
-        log::debug!("Starting parser");
         let mut doc = document::Document::new();
+        self.parse_html_into(&mut doc.doctree);
+        self.update_document(&mut doc);
+        return doc;
+    }
+
+    pub fn parse_html_into(&mut self, sink: &mut dyn TreeSink) {
+        log::debug!("Starting parser");
+        self.reconsume_token = None;
+        self.should_exit = false;
+        self.run(sink);
+        // A pending script leaves the rest of the input for resume
+        if self.should_exit {
+            self.finish_document(sink);
+        }
+    }
+
+    pub fn parse_html_fragment(&mut self, doc: &mut Document) -> Vec<doctree::DoctreeNode> {
+        let context = match self.fragment_parser_ctx {
+            Some(ctx) => ctx,
+            None => return Vec::new(),
+        };
+        log::debug!("Starting fragment parser");
+        self.quirks_mode = doc.get_quirks_mode();
+        let sink: &mut dyn TreeSink = &mut doc.doctree;
+
+        // The context element decides how its contents get tokenized
+        if let Some((name, Namespace::Html)) = get_element_name_and_namespace(sink, &context) {
+            let state = match name.as_str() {
+                "title" | "textarea" => Some(tokenizer::FsmState::RCData),
+                "style" | "xmp" | "iframe" | "noembed" | "noframes" => {
+                    Some(tokenizer::FsmState::RawText)
+                }
+                "script" => Some(tokenizer::FsmState::ScriptData),
+                "noscript" if self.scripting => Some(tokenizer::FsmState::RawText),
+                "plaintext" => Some(tokenizer::FsmState::Plaintext),
+                _ => None,
+            };
+            if let Some(state) = state {
+                self.tokenizer.set_state(state);
+                self.tokenizer.set_last_start_tag(&name);
+            }
+            if name == "template" {
+                self.template_insertion_modes
+                    .push(InsertionMode::InTemplate);
+            }
+        }
+
+        // A detached html element stands in for the root of a new document
+        let root = sink.create_node(node::Node::new(node::NodeType::Element(
+            HTMLElement::from_element_type(HTMLElementType::Html(
+                element_structs::html::Html::default(),
+            )),
+        )));
+        self.open_node_stack.push(root);
+        self.reset_insertion_mode(sink);
+
+        // Controls in the fragment belong to the form the context element is in
+        let mut ancestor = Some(context);
+        while let Some(n) = ancestor {
+            if element_name_matches(sink, &n, "form") {
+                self.form_element = Some(n);
+                break;
+            }
+            ancestor = sink.get_parent(&n);
+        }
 
+        self.tokenizer
+            .set_allow_cdata(self.adjusted_current_node_namespace(sink) != Namespace::Html);
         self.reconsume_token = None;
         self.should_exit = false;
+        self.run(sink);
+        self.finish_document(sink);
+
+        let children = sink.get_children(&root);
+        for child in &children {
+            sink.detach(child);
+        }
+        sink.remove(&root);
+        children
+    }
+
+    // Processes tokens until the document ends, the tokenizer needs more input or a script
+    // pauses the parser
+    fn run(&mut self, doc: &mut dyn TreeSink) {
         loop {
-            if self.should_exit {
+            if self.should_exit || self.parser_pause_flag {
                 break;
             }
 
-            let token = match self.reconsume_token.clone() {
+            let reprocessed = self.reconsume_token.is_some();
+            let mut token = match self.reconsume_token.clone() {
                 Some(t) => {
                     self.reconsume_token = None;
                     t
                 }
-                None => self.tokenizer.get_next_token(),
+                None => {
+                    let token = self.tokenizer.next_token();
+                    self.collect_tokenizer_errors();
+                    match token {
+                        Some(token) => token,
+                        None => break,
+                    }
+                }
             };
+            if std::mem::take(&mut self.skip_next_newline)
+                && matches!(token.tag, TokenTag::Character)
+            {
+                if let Some(rest) = token.data.strip_prefix('\n') {
+                    if rest.is_empty() {
+                        continue;
+                    }
+                    token.data = rest.to_string();
+                }
+            }
             log::trace!(
                 "In fsm state {:?}, parsing tokentag {:?}",
                 self.insertion_mode,
                 token.tag
             );
-            // TODO: Check if we're in a foreign context or not
-            //       For now follow normal insertion rules
-
-            match self.insertion_mode {
-                InsertionMode::Initial => self.parse_initial(&mut doc, token),
-                InsertionMode::BeforeHtml => self.parse_before_html(&mut doc, token),
-                InsertionMode::BeforeHead => self.parse_before_head(&mut doc, token),
-                InsertionMode::InHead => self.parse_in_head(&mut doc, token),
-                InsertionMode::InHeadNoscript => self.parse_in_head_noscript(&mut doc, token),
-                InsertionMode::AfterHead => self.parse_after_head(&mut doc, token),
-                InsertionMode::InBody => self.parse_in_body(&mut doc, token),
-                InsertionMode::Text => self.parse_text(&mut doc, token),
-                InsertionMode::InTable => self.parse_in_table(&mut doc, token),
-                InsertionMode::InTableText => self.parse_in_table_text(&mut doc, token),
-                InsertionMode::InCaption => self.parse_in_caption(&mut doc, token),
-                InsertionMode::InColumnGroup => self.parse_in_column_group(&mut doc, token),
-                InsertionMode::InTableBody => self.parse_in_table_body(&mut doc, token),
-                InsertionMode::InRow => self.parse_in_row(&mut doc, token),
-                InsertionMode::InCell => self.parse_in_cell(&mut doc, token),
-                InsertionMode::InSelect => self.parse_in_select(&mut doc, token),
-                InsertionMode::InSelectTable => self.parse_in_select_table(&mut doc, token),
-                InsertionMode::InTemplate => self.parse_in_template(&mut doc, token),
-                InsertionMode::AfterBody => self.parse_after_body(&mut doc, token),
-                InsertionMode::InFrameset => self.parse_in_frameset(&mut doc, token),
-                InsertionMode::AfterFrameset => self.parse_after_frameset(&mut doc, token),
-                InsertionMode::AfterAfterBody => self.parse_after_after_body(&mut doc, token),
-                InsertionMode::AfterAfterFrameset => {
-                    self.parse_after_after_frameset(&mut doc, token)
+
+            if self.should_parse_as_foreign(doc, &token) {
+                self.parse_foreign_content(doc, token);
+            } else {
+                // Only void elements acknowledge the self-closing flag
+                if matches!(token.tag, TokenTag::StartTag)
+                    && token.flags.self_closing
+                    && !is_void_element(&token.data)
+                    && !reprocessed
+                {
+                    self.parse_error(
+                        ParseErrorKind::NonVoidHtmlElementStartTagWithTrailingSolidus,
+                        &token,
+                    );
                 }
-            };
+                self.process_token(doc, token);
+            }
+            self.tokenizer
+                .set_allow_cdata(self.adjusted_current_node_namespace(doc) != Namespace::Html);
         }
+    }
 
-        return doc;
+    fn finish_document(&mut self, doc: &mut dyn TreeSink) {
+        doc.finish();
+        self.collect_tokenizer_errors();
+        self.errors.sort_by_key(|e| (e.line, e.column));
     }
-}
 
-#[cfg(test)]
-mod test {
+    fn collect_tokenizer_errors(&mut self) {
+        let errors = self.tokenizer.take_errors();
+        if self.collect_errors {
+            self.errors.extend(errors);
+        }
+    }
 
-    use super::*;
-    use crate::document;
-    use std::fs;
-    use std::path::PathBuf;
+    // Document level state isn't part of the tree, so it's copied over separately
+    fn update_document(&self, doc: &mut Document) {
+        doc.set_quirks_mode(self.quirks_mode);
+        doc.set_encoding(self.encoding, self.encoding_confidence);
+        doc.set_pending_parse_blocking(self.pending_parsing_blocking_script);
+    }
+}
 
-    // Tests
-    #[test]
-    fn test_empty_parse_no_panic() {
-        let mut d = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
-        d.push("test_resources/empty.html");
+enum StreamDecoder {
+    // Bytes are held back until there are enough of them to sniff the encoding
+    Sniffing(Vec<u8>),
+    Decoding(encoding_rs::Decoder),
+    // The input is already text
+    Text,
+}
 
-        let html = fs::read_to_string(d).unwrap();
+// Builds a document while its input is still arriving, so the partial tree can be used
+// before the download finishes. Input is only kept until the tokenizer has consumed it.
+pub struct StreamingParser {
+    parser: Parser,
+    doc: Document,
+    decoder: StreamDecoder,
+    // Nodes from this generation onwards haven't been handed out by take_new_nodes yet
+    seen_generation: u64,
+}
 
-        parse_document(&html);
+impl Default for StreamingParser {
+    fn default() -> Self {
+        Self::new()
     }
+}
 
-    #[test]
-    fn test_basic_html() {
-        let mut d = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
-        d.push("test_resources/basic.html");
-
-        let html = fs::read_to_string(d).unwrap();
-
-        let doc = parse_document(&html);
-        let root_nodes = doc.doctree.get_root_node_list();
+impl StreamingParser {
+    pub fn new() -> StreamingParser {
+        StreamingParser {
+            parser: Parser {
+                tokenizer: tokenizer::Tokenizer::new(),
+                ..Parser::new("")
+            },
+            doc: Document::new(),
+            decoder: StreamDecoder::Sniffing(Vec::new()),
+            seen_generation: 0,
+        }
+    }
 
-        let mut root_html_found = false;
-        for r in root_nodes {
-            let node = match doc.doctree.get_node(&r) {
-                Some(n) => n,
-                None => continue,
-            };
-            let node_element = match &node.node_type {
-                NodeType::Element(e) => e,
-                _ => continue,
-            };
-            if !matches!(node_element.element_type, HTMLElementType::Html(_)) {
-                continue;
+    // Feeds the next chunk of a document that's already decoded
+    pub fn feed(&mut self, chunk: &str) {
+        if let StreamDecoder::Sniffing(ref bytes) = self.decoder {
+            if bytes.is_empty() {
+                self.decoder = StreamDecoder::Text;
             }
-            root_html_found = true;
-
-            assert!(node.children.len() >= 2);
+        }
+        match self.decoder {
+            StreamDecoder::Text => self.push_text(chunk),
+            // Text mixed into a byte stream is taken as its UTF-8 encoding
+            _ => self.feed_bytes(chunk.as_bytes()),
+        }
+    }
 
-            let mut head_found = false;
-            let mut body_found = false;
-            for c in node.children.clone() {
-                if is_entry_element!(doc, &c, HTMLElementType::Head(_)) {
-                    head_found = true;
-                }
-                if is_entry_element!(doc, &c, HTMLElementType::Body(_)) {
-                    body_found = true;
+    // Feeds the next chunk of raw bytes. The encoding is sniffed once the first
+    // kilobyte has arrived, or when the input ends.
+    pub fn feed_bytes(&mut self, chunk: &[u8]) {
+        match self.decoder {
+            StreamDecoder::Sniffing(ref mut bytes) => {
+                bytes.extend_from_slice(chunk);
+                if bytes.len() >= encoding::PRESCAN_LIMIT {
+                    self.start_decoding(false);
                 }
             }
-            assert!(head_found && body_found);
+            StreamDecoder::Decoding(_) => self.decode(chunk, false),
+            StreamDecoder::Text => {
+                self.decoder =
+                    StreamDecoder::Decoding(encoding_rs::UTF_8.new_decoder_without_bom_handling());
+                self.decode(chunk, false);
+            }
+        }
+    }
+
+    // The tree built from the input so far
+    pub fn document(&self) -> &Document {
+        &self.doc
+    }
+
+    // Nodes created since the last call, in the order they were created
+    pub fn take_new_nodes(&mut self) -> Vec<DoctreeNode> {
+        let nodes = self.doc.doctree.get_nodes_from(self.seen_generation);
+        self.seen_generation = self.doc.doctree.next_generation();
+        nodes
+    }
+
+    pub fn set_script_handler(&mut self, handler: Box<dyn ScriptHandler>) {
+        self.parser.set_script_handler(handler);
+    }
+
+    // Whether feeding is on hold until the pending script has run. Input fed in the
+    // meantime is kept for later.
+    pub fn is_paused(&self) -> bool {
+        self.parser.is_paused()
+    }
+
+    pub fn document_write(&mut self, markup: &str) -> bool {
+        self.parser.document_write(markup)
+    }
+
+    pub fn take_preload_requests(&mut self) -> Vec<PreloadRequest> {
+        self.parser.take_preload_requests()
+    }
+
+    // Carries on parsing once the pending script has run
+    pub fn resume(&mut self) {
+        self.parser.resume(&mut self.doc.doctree);
+        self.parser.update_document(&mut self.doc);
+    }
+
+    // Parse errors found since the last call
+    pub fn take_errors(&mut self) -> Vec<ParseError> {
+        self.parser.collect_tokenizer_errors();
+        self.parser.take_errors()
+    }
+
+    // Ends the input and hands over the finished document
+    pub fn finish(mut self) -> Document {
+        match self.decoder {
+            StreamDecoder::Sniffing(_) => {
+                self.start_decoding(true);
+                self.decode(&[], true);
+            }
+            StreamDecoder::Decoding(_) => self.decode(&[], true),
+            StreamDecoder::Text => {}
+        }
+        self.parser.tokenizer.finish();
+        self.parser.run(&mut self.doc.doctree);
+        // Scripts that are still pending once the input is over are skipped
+        while self.parser.pending_parsing_blocking_script.take().is_some() {
+            self.parser.speculative_parser = None;
+            self.parser.parser_pause_flag = false;
+            self.parser.run(&mut self.doc.doctree);
+        }
+        self.parser.finish_document(&mut self.doc.doctree);
+        self.parser.update_document(&mut self.doc);
+        self.doc
+    }
+
+    fn start_decoding(&mut self, complete: bool) {
+        let bytes = match std::mem::replace(&mut self.decoder, StreamDecoder::Text) {
+            StreamDecoder::Sniffing(bytes) => bytes,
+            other => {
+                self.decoder = other;
+                return;
+            }
+        };
+        let (encoding, confidence, bom_len) = encoding::sniff_encoding(&bytes, complete);
+        self.parser.encoding = encoding;
+        self.parser.encoding_confidence = confidence;
+        self.doc.set_encoding(encoding, confidence);
+        self.decoder = StreamDecoder::Decoding(encoding.new_decoder_without_bom_handling());
+        self.decode(&bytes[bom_len..], false);
+    }
+
+    fn decode(&mut self, bytes: &[u8], last: bool) {
+        let decoder = match self.decoder {
+            StreamDecoder::Decoding(ref mut decoder) => decoder,
+            _ => return,
+        };
+        let mut text = String::with_capacity(
+            decoder
+                .max_utf8_buffer_length(bytes.len())
+                .unwrap_or(bytes.len()),
+        );
+        let (_, _, had_errors) = decoder.decode_to_string(bytes, &mut text, last);
+        if had_errors {
+            log::debug!("Replaced invalid byte sequences while decoding");
+        }
+        self.push_text(&text);
+    }
+
+    fn push_text(&mut self, text: &str) {
+        self.parser.tokenizer.feed(text);
+        if let Some(scanner) = &mut self.parser.speculative_parser {
+            scanner.feed(text);
+        }
+        self.parser.run(&mut self.doc.doctree);
+
+        // The rest of the stream can't be reparsed, so a late meta element changes the
+        // encoding in place for the bytes that haven't been decoded yet
+        if let Some(changed) = self.parser.changed_encoding.take() {
+            self.parser.encoding = changed;
+            self.parser.encoding_confidence = document::EncodingConfidence::Certain;
+            if let StreamDecoder::Decoding(_) = self.decoder {
+                self.decoder = StreamDecoder::Decoding(changed.new_decoder_without_bom_handling());
+            }
+        }
+        self.parser.update_document(&mut self.doc);
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use crate::document;
+    use std::fs;
+    use std::path::PathBuf;
+
+    // Utilities
+    fn serialize_node(doc: &Document, node: &DoctreeNode, out: &mut String) {
+        let n = match doc.doctree.get_node(node) {
+            Some(n) => n,
+            None => return,
+        };
+        match &n.node_type {
+            NodeType::Text(text) => out.push_str(text),
+            NodeType::Comment(text) => out.push_str(&format!("<!--{}-->", text)),
+            NodeType::Element(e) => {
+                out.push_str(&format!("<{}>", e.get_name()));
+                for c in &n.children {
+                    serialize_node(doc, c, out);
+                }
+                out.push_str(&format!("</{}>", e.get_name()));
+            }
+            _ => {}
+        }
+    }
+
+    fn body_contents(html: &str) -> String {
+        let doc = parse_document(html);
+        let body = doc
+            .doctree
+            .get_all_nodes()
+            .into_iter()
+            .find(|n| doc.doctree.get_element_name(n).as_deref() == Some("body"))
+            .expect("No body element was created");
+
+        let mut out = String::new();
+        for c in &doc.doctree.get_node(&body).unwrap().children {
+            serialize_node(&doc, c, &mut out);
+        }
+        out
+    }
+
+    // Tests
+    #[test]
+    fn test_empty_parse_no_panic() {
+        let mut d = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        d.push("test_resources/empty.html");
+
+        let html = fs::read_to_string(d).unwrap();
+
+        parse_document(&html);
+    }
+
+    #[test]
+    fn test_basic_html() {
+        let mut d = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        d.push("test_resources/basic.html");
+
+        let html = fs::read_to_string(d).unwrap();
+
+        let doc = parse_document(&html);
+        let root_nodes = doc.doctree.get_root_node_list();
+
+        let mut root_html_found = false;
+        for r in root_nodes {
+            let node = match doc.doctree.get_node(&r) {
+                Some(n) => n,
+                None => continue,
+            };
+            let node_element = match &node.node_type {
+                NodeType::Element(e) => e,
+                _ => continue,
+            };
+            if !matches!(node_element.element_type, HTMLElementType::Html(_)) {
+                continue;
+            }
+            root_html_found = true;
+
+            assert!(node.children.len() >= 2);
+
+            let mut head_found = false;
+            let mut body_found = false;
+            for c in node.children.clone() {
+                if is_entry_element!(doc.doctree, &c, HTMLElementType::Head(_)) {
+                    head_found = true;
+                }
+                if is_entry_element!(doc.doctree, &c, HTMLElementType::Body(_)) {
+                    body_found = true;
+                }
+            }
+            assert!(head_found && body_found);
+        }
+        assert!(root_html_found);
+    }
+
+    #[test]
+    fn test_adoption_agency_misnested_formatting() {
+        assert_eq!(body_contents("<b><i>x</b>y</i>"), "<b><i>x</i></b><i>y</i>");
+        assert_eq!(
+            body_contents("<b>1<p>2</b>3</p>"),
+            "<b>1</b><p><b>2</b>3</p>"
+        );
+        assert_eq!(
+            body_contents("<a>1<div>2<div>3</a>4</div></div>"),
+            "<a>1</a><div><a>2</a><div><a>3</a>4</div></div>"
+        );
+    }
+
+    #[test]
+    fn test_nested_a_elements() {
+        assert_eq!(body_contents("<a>x<a>y</a>z"), "<a>x</a><a>y</a>z");
+    }
+
+    #[test]
+    fn test_reconstruct_active_formatting_elements() {
+        assert_eq!(
+            body_contents("<div><b><i>x</div>y"),
+            "<div><b><i>x</i></b></div><b><i>y</i></b>"
+        );
+    }
+
+    #[test]
+    fn test_noahs_ark_clause() {
+        assert_eq!(
+            body_contents("<div><b><b><b><b>x</div>y"),
+            "<div><b><b><b><b>x</b></b></b></b></div><b><b><b>y</b></b></b>"
+        );
+        // Differing attributes aren't counted as identical
+        assert_eq!(
+            body_contents("<div><b><b class=a><b><b>x</div>y"),
+            "<div><b><b><b><b>x</b></b></b></b></div><b><b><b><b>y</b></b></b></b>"
+        );
+    }
+
+    #[test]
+    fn test_active_formatting_markers() {
+        assert_eq!(
+            body_contents("<b>1<object><i>2</object>3"),
+            "<b>1<object><i>2</i></object>3</b>"
+        );
+        // The marker keeps formatting elements outside the object out of scope
+        assert_eq!(
+            body_contents("<b><object></b>x</object>"),
+            "<b><object>x</object></b>"
+        );
+    }
+
+    #[test]
+    fn test_text_nodes_are_merged() {
+        let doc = parse_document("<p>a&amp;b</p>");
+        let text_nodes = doc
+            .doctree
+            .get_all_nodes()
+            .iter()
+            .filter_map(|n| doc.doctree.get_node(n))
+            .filter(|n| matches!(n.node_type, NodeType::Text(_)))
+            .count();
+        assert_eq!(text_nodes, 1);
+    }
+
+    #[test]
+    fn test_node_spans() {
+        let html = "<!DOCTYPE html>\r\n<p class=x>a&amp;\r\nbé<!--c--></p>";
+        let doc = parse_document(html);
+        let source = |kind: fn(&NodeType) -> bool| {
+            doc.doctree
+                .get_all_nodes()
+                .iter()
+                .filter_map(|n| doc.doctree.get_node(n))
+                .find(|n| kind(&n.node_type))
+                .and_then(|n| n.span)
+                .map(|span| &html[span.range()])
+        };
+
+        assert_eq!(
+            source(|t| matches!(t, NodeType::DocumentType(_))),
+            Some("<!DOCTYPE html>")
+        );
+        assert_eq!(
+            source(|t| matches!(t, NodeType::Element(e) if e.get_name() == "p")),
+            Some("<p class=x>")
+        );
+        // Merged text covers every token it came from
+        assert_eq!(
+            source(|t| matches!(t, NodeType::Text(_))),
+            Some("a&amp;\r\nbé")
+        );
+        assert_eq!(
+            source(|t| matches!(t, NodeType::Comment(_))),
+            Some("<!--c-->")
+        );
+        // Elements the parser made up have no source
+        assert_eq!(
+            source(|t| matches!(t, NodeType::Element(e) if e.get_name() == "body")),
+            None
+        );
+
+        let p = doc
+            .doctree
+            .get_all_nodes()
+            .iter()
+            .filter_map(|n| doc.doctree.get_node(n))
+            .find(|n| matches!(&n.node_type, NodeType::Element(e) if e.get_name() == "p"))
+            .unwrap();
+        let (name, span) = &p.attribute_spans[0];
+        assert_eq!(name, "class");
+        assert_eq!(&html[span.range()], "class=x");
+        assert_eq!((span.start.line, span.start.column), (2, 4));
+    }
+
+    #[test]
+    fn test_foster_parenting() {
+        assert_eq!(
+            body_contents("<table>x<tr><td>y</td></tr></table>"),
+            "x<table><tbody><tr><td>y</td></tr></tbody></table>"
+        );
+        assert_eq!(
+            body_contents("<table><b>x</b><tr><td>y</td></tr></table>"),
+            "<b>x</b><table><tbody><tr><td>y</td></tr></tbody></table>"
+        );
+        // Whitespace is kept inside the table
+        assert_eq!(
+            body_contents("<table> <tr><td>y</td></tr></table>"),
+            "<table> <tbody><tr><td>y</td></tr></tbody></table>"
+        );
+    }
+
+    #[test]
+    fn test_text_element_end_tags() {
+        // The end tag closes the element instead of ending up in its text
+        assert_eq!(
+            body_contents("<textarea>a</textarea>b"),
+            "<textarea>a</textarea>b"
+        );
+        assert_eq!(
+            body_contents("<p><style>a</style><b>c</b>"),
+            "<p><style>a</style><b>c</b></p>"
+        );
+        assert_eq!(
+            body_contents("<p><title>a</TITLE>b<textarea>c</textarea >d"),
+            "<p><title>a</title>b<textarea>c</textarea>d</p>"
+        );
+    }
+
+    #[test]
+    fn test_implied_end_tags() {
+        assert_eq!(
+            body_contents("<p>a<p>b<div>c</div>"),
+            "<p>a</p><p>b</p><div>c</div>"
+        );
+        assert_eq!(
+            body_contents("<ul><li>a<li>b<ul><li>c</ul></ul>"),
+            "<ul><li>a</li><li>b<ul><li>c</li></ul></li></ul>"
+        );
+        assert_eq!(
+            body_contents("<dl><dt>a<dd>b<dt>c</dl>"),
+            "<dl><dt>a</dt><dd>b</dd><dt>c</dt></dl>"
+        );
+        assert_eq!(body_contents("<h1>a<h2>b</h1>c"), "<h1>a</h1><h2>b</h2>c");
+        assert_eq!(
+            body_contents("<button>a<button>b"),
+            "<button>a</button><button>b</button>"
+        );
+        // A stray end tag gets an empty p element
+        assert_eq!(body_contents("a</p>"), "a<p></p>");
+        // The p element is out of button scope, so it's left open
+        assert_eq!(
+            body_contents("<p><button><div>a</div></button>"),
+            "<p><button><div>a</div></button></p>"
+        );
+        assert_eq!(body_contents("<div><p>a</div>b"), "<div><p>a</p></div>b");
+        assert_eq!(
+            body_contents("<ruby>a<rb>b<rt>c<rp>d</ruby>"),
+            "<ruby>a<rb>b</rb><rt>c</rt><rp>d</rp></ruby>"
+        );
+        assert_eq!(
+            body_contents("<table><tr><td><p>a<td>b</table>"),
+            "<table><tbody><tr><td><p>a</p></td><td>b</td></tr></tbody></table>"
+        );
+        assert_eq!(
+            body_contents("<table><caption><p>a</caption><tr><td>b</table>"),
+            "<table><caption><p>a</p></caption><tbody><tr><td>b</td></tr></tbody></table>"
+        );
+    }
+
+    #[test]
+    fn test_scope() {
+        let doc = parse_document("<p><table><tr><td><ul><li><button>");
+        let mut parser = Parser::new("");
+        let names = [
+            "html", "body", "p", "table", "tbody", "tr", "td", "ul", "li", "button",
+        ];
+        parser.open_node_stack.stack = names.iter().map(|n| find_element(&doc, n)[0]).collect();
+        let stack = &parser.open_node_stack;
+        assert!(stack.has_element_in_scope(&doc.doctree, "li"));
+        assert!(!stack.has_element_in_scope(&doc.doctree, "p"));
+        assert!(!stack.has_element_in_button_scope(&doc.doctree, "li"));
+        assert!(stack.has_element_in_list_item_scope(&doc.doctree, "li"));
+        assert!(!stack.has_element_in_list_item_scope(&doc.doctree, "td"));
+        assert!(stack.has_element_in_table_scope(&doc.doctree, "td"));
+        assert!(!stack.has_element_in_table_scope(&doc.doctree, "p"));
+        assert!(!stack.has_element_in_select_scope(&doc.doctree, "li"));
+    }
+
+    #[test]
+    fn test_form_owner() {
+        let doc = parse_document(
+            "<div><form id=f></div><input><form id=g><select></select></form>\
+            <input form=f><input form=div><button form=missing></button>\
+            <form id=h><div></form><textarea></textarea></div><img><output></output>",
+        );
+        let forms = find_element(&doc, "form");
+        let inputs = find_element(&doc, "input");
+        // The second form start tag is ignored while the form element pointer is set, and the
+        // end tag clears the pointer even though the form is no longer open
+        assert_eq!(forms.len(), 2);
+        assert_eq!(
+            doc.get_form_controls(&forms[0]),
+            vec![inputs[0], find_element(&doc, "select")[0], inputs[1]]
+        );
+        // A control in a form that was closed early still belongs to it
+        assert_eq!(
+            doc.get_form_controls(&forms[1]),
+            find_element(&doc, "textarea")
+        );
+        assert_eq!(doc.get_form_owner(&inputs[2]), None);
+        assert_eq!(doc.get_form_owner(&find_element(&doc, "button")[0]), None);
+        assert_eq!(doc.get_form_owner(&find_element(&doc, "output")[0]), None);
+
+        let doc =
+            parse_document("<form id=a></form><form id=b><img></form><table><form><tr><td><input>");
+        let forms = find_element(&doc, "form");
+        assert_eq!(forms.len(), 3);
+        // Images are owned but aren't listed among the controls
+        assert_eq!(
+            doc.get_form_owner(&find_element(&doc, "img")[0]),
+            Some(forms[1])
+        );
+        assert!(doc.get_form_controls(&forms[1]).is_empty());
+        assert_eq!(
+            doc.get_form_controls(&forms[2]),
+            find_element(&doc, "input")
+        );
+
+        // Only the first element with the id is looked at
+        let doc = parse_document(
+            "<form id=a></form><form id=a></form><input form=a><p id=b><form id=b></form><input form=b>",
+        );
+        let forms = find_element(&doc, "form");
+        let inputs = find_element(&doc, "input");
+        assert_eq!(doc.get_form_owner(&inputs[0]), Some(forms[0]));
+        assert_eq!(doc.get_form_owner(&inputs[1]), None);
+    }
+
+    fn template_contents(html: &str) -> Vec<String> {
+        let doc = parse_document(html);
+        doc.doctree
+            .get_all_nodes()
+            .iter()
+            .filter_map(|n| doc.doctree.get_template_contents(n))
+            .map(|contents| {
+                let mut out = String::new();
+                for c in &doc.doctree.get_node(&contents).unwrap().children {
+                    serialize_node(&doc, c, &mut out);
+                }
+                out
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_template_contents() {
+        assert_eq!(
+            body_contents("<body><template><b>x</b></template>y"),
+            "<template></template>y"
+        );
+        assert_eq!(
+            template_contents("<body><template><b>x</b></template>y"),
+            vec!["<b>x</b>"]
+        );
+        assert_eq!(
+            template_contents("<template><template>x</template>y</template>"),
+            vec!["<template></template>y", "x"]
+        );
+    }
+
+    #[test]
+    fn test_template_insertion_modes() {
+        assert_eq!(
+            template_contents("<body><template><tr><td>x</td></tr></template><p>y"),
+            vec!["<tr><td>x</td></tr>"]
+        );
+        assert_eq!(
+            body_contents("<body><template><td>x</td></template><p>y"),
+            "<template></template><p>y</p>"
+        );
+        // Unclosed templates are closed at the end of the document
+        assert_eq!(
+            template_contents("<body><template><col>"),
+            vec!["<col></col>"]
+        );
+    }
+
+    fn find_element(doc: &Document, name: &str) -> Vec<DoctreeNode> {
+        doc.doctree
+            .get_all_nodes()
+            .into_iter()
+            .filter(|n| doc.doctree.get_element_name(n).as_deref() == Some(name))
+            .collect()
+    }
+
+    #[test]
+    fn test_declarative_shadow_root() {
+        let doc = parse_document(
+            "<body><div><template shadowrootmode=open shadowrootdelegatesfocus>\
+            <slot></slot><slot name=a></slot></template><span slot=a>x</span>y</div>",
+        );
+        let host = find_element(&doc, "div")[0];
+        let host_node = doc.doctree.get_node(&host).unwrap();
+        let shadow_root = match &host_node.node_type {
+            NodeType::Element(e) => e.shadow_root.clone().expect("No shadow root attached"),
+            _ => panic!("Host is not an element"),
+        };
+        assert_eq!(
+            shadow_root.mode,
+            element_structs::template::ShadowrootModeOption::Open
+        );
+        assert!(shadow_root.delegates_focus);
+        assert!(!shadow_root.clonable);
+
+        // The template itself is not part of the light tree
+        let mut light_tree = String::new();
+        for c in &host_node.children {
+            serialize_node(&doc, c, &mut light_tree);
+        }
+        assert_eq!(light_tree, "<span>x</span>y");
+
+        let mut shadow_tree = String::new();
+        for c in &doc.doctree.get_node(&shadow_root.root).unwrap().children {
+            serialize_node(&doc, c, &mut shadow_tree);
+        }
+        assert_eq!(shadow_tree, "<slot></slot><slot></slot>");
+    }
+
+    #[test]
+    fn test_slot_assignment() {
+        let doc = parse_document(
+            "<body><my-element><template shadowrootmode=closed>\
+            <slot></slot><slot name=a></slot><slot name=a></slot></template>\
+            <span slot=a>x</span>y<b slot=missing>z</b></my-element>",
+        );
+        let assigned: Vec<Vec<String>> = find_element(&doc, "slot")
+            .iter()
+            .map(
+                |slot| match &doc.doctree.get_node(slot).unwrap().node_type {
+                    NodeType::Element(e) => match &e.element_type {
+                        HTMLElementType::Slot(s) => s
+                            .get_assigned_nodes()
+                            .iter()
+                            .map(|n| {
+                                let mut out = String::new();
+                                serialize_node(&doc, n, &mut out);
+                                out
+                            })
+                            .collect(),
+                        _ => panic!("Not a slot"),
+                    },
+                    _ => panic!("Not an element"),
+                },
+            )
+            .collect();
+        assert_eq!(
+            assigned,
+            vec![
+                vec!["y".to_string()],
+                vec!["<span>x</span>".to_string()],
+                vec![]
+            ]
+        );
+    }
+
+    #[test]
+    fn test_declarative_shadow_root_fallback() {
+        // Invalid modes and hosts that can't have a shadow root get a normal template
+        for html in [
+            "<body><div><template shadowrootmode=foo>x</template></div>",
+            "<body><ul><template shadowrootmode=open>x</template></ul>",
+        ] {
+            let doc = parse_document(html);
+            assert_eq!(template_contents(html), vec!["x"]);
+            assert!(find_element(&doc, "template").iter().all(|t| doc
+                .doctree
+                .get_node(t)
+                .unwrap()
+                .parent
+                .is_some()));
+        }
+    }
+
+    #[test]
+    fn test_foreign_content_namespaces() {
+        let doc = parse_document(
+            "<body><svg viewbox='0 0 1 1' xlink:href=a><foreignobject><p>x</p></foreignobject>\
+            <lineargradient></lineargradient></svg><math definitionurl=b><mi>y</mi></math>",
+        );
+        let svg = find_element(&doc, "svg")[0];
+        assert_eq!(
+            doc.doctree.get_element_namespace(&svg),
+            Some(Namespace::Svg)
+        );
+        let svg_element = match &doc.doctree.get_node(&svg).unwrap().node_type {
+            NodeType::Element(e) => e,
+            _ => panic!("svg is not an element"),
+        };
+        assert_eq!(svg_element.get_attribute("viewBox"), Some("0 0 1 1"));
+        assert!(svg_element
+            .get_attributes()
+            .iter()
+            .any(|a| a.name == "href" && a.namespace == Some(Namespace::XLink)));
+
+        assert_eq!(find_element(&doc, "foreignObject").len(), 1);
+        assert_eq!(find_element(&doc, "linearGradient").len(), 1);
+        // Integration points switch back to HTML
+        let p = find_element(&doc, "p")[0];
+        assert_eq!(doc.doctree.get_element_namespace(&p), Some(Namespace::Html));
+
+        let math = find_element(&doc, "math")[0];
+        assert_eq!(
+            doc.doctree.get_element_namespace(&math),
+            Some(Namespace::MathMl)
+        );
+        let mi = find_element(&doc, "mi")[0];
+        assert_eq!(
+            doc.doctree.get_element_namespace(&mi),
+            Some(Namespace::MathMl)
+        );
+    }
+
+    #[test]
+    fn test_foreign_content_breakout() {
+        assert_eq!(
+            body_contents("<svg><g><p>x</p></g></svg>"),
+            "<svg><g></g></svg><p>x</p>"
+        );
+        assert_eq!(
+            body_contents("<svg><g/><circle></circle></svg>y"),
+            "<svg><g></g><circle></circle></svg>y"
+        );
+    }
+
+    #[test]
+    fn test_cdata_sections() {
+        assert_eq!(
+            body_contents("<svg><![CDATA[a<b]]></svg>"),
+            "<svg>a<b</svg>"
+        );
+        assert_eq!(
+            body_contents("<div><![CDATA[a]]></div>"),
+            "<div><!--[CDATA[a]]--></div>"
+        );
+        assert_eq!(body_contents("<body><!--abc-->"), "<!--abc-->");
+    }
+
+    #[test]
+    fn test_quirks_mode_from_doctype() {
+        use document::document::QuirksMode;
+        for (html, mode) in [
+            ("<!DOCTYPE html>", QuirksMode::Off),
+            (
+                "<!doctype HTML SYSTEM \"about:legacy-compat\">",
+                QuirksMode::Off,
+            ),
+            ("<p>No doctype", QuirksMode::Quirks),
+            ("<!DOCTYPE svg>", QuirksMode::Quirks),
+            (
+                "<!DOCTYPE HTML PUBLIC \"-//W3C//DTD HTML 3.2 Final//EN\">",
+                QuirksMode::Quirks,
+            ),
+            (
+                "<!DOCTYPE html PUBLIC \"-//w3c//dtd html 4.01 transitional//en\">",
+                QuirksMode::Quirks,
+            ),
+            (
+                "<!DOCTYPE html PUBLIC \"-//W3C//DTD HTML 4.01 Transitional//EN\" \
+                \"http://www.w3.org/TR/html4/loose.dtd\">",
+                QuirksMode::LimitedQuirks,
+            ),
+            (
+                "<!DOCTYPE html PUBLIC \"-//W3C//DTD XHTML 1.0 Transitional//EN\" \
+                \"http://www.w3.org/TR/xhtml1/DTD/xhtml1-transitional.dtd\">",
+                QuirksMode::LimitedQuirks,
+            ),
+            (
+                "<!DOCTYPE html PUBLIC \"-//W3C//DTD HTML 4.01//EN\" \
+                \"http://www.w3.org/TR/html4/strict.dtd\">",
+                QuirksMode::Off,
+            ),
+            (
+                "<!DOCTYPE html SYSTEM \
+                \"http://www.ibm.com/data/dtd/v11/ibmxhtml1-transitional.dtd\">",
+                QuirksMode::Quirks,
+            ),
+        ] {
+            assert_eq!(parse_document(html).get_quirks_mode(), mode, "{}", html);
+        }
+    }
+
+    #[test]
+    fn test_parse_document_from_bytes() {
+        use document::document::EncodingConfidence;
+
+        // "日本" in Shift_JIS
+        let doc = parse_document_from_bytes(b"<meta charset=shift_jis><body>\x93\xfa\x96\x7b");
+        assert_eq!(doc.get_encoding(), "Shift_JIS");
+        assert_eq!(doc.get_encoding_confidence(), EncodingConfidence::Certain);
+        let body = find_element(&doc, "body")[0];
+        let mut text = String::new();
+        serialize_node(
+            &doc,
+            &doc.doctree.get_node(&body).unwrap().children[0],
+            &mut text,
+        );
+        assert_eq!(text, "日本");
+
+        let mut utf16 = vec![0xFE, 0xFF];
+        for c in "<p>é".encode_utf16() {
+            utf16.extend(c.to_be_bytes());
+        }
+        let doc = parse_document_from_bytes(&utf16);
+        assert_eq!(doc.get_encoding(), "UTF-16BE");
+        assert_eq!(doc.get_encoding_confidence(), EncodingConfidence::Certain);
+        assert_eq!(find_element(&doc, "p").len(), 1);
+
+        let doc = parse_document_from_bytes(b"<p>caf\xE9");
+        assert_eq!(doc.get_encoding(), "windows-1252");
+        assert_eq!(doc.get_encoding_confidence(), EncodingConfidence::Tentative);
+
+        assert_eq!(
+            parse_document("<p>").get_encoding_confidence(),
+            EncodingConfidence::Irrelevant
+        );
+    }
+
+    #[test]
+    fn test_late_meta_changes_encoding() {
+        // The declaration is past the prescan window, so the parser has to reparse
+        let mut html = format!("<!--{}--><meta charset=iso-8859-2>", " ".repeat(1100)).into_bytes();
+        html.extend(b"<p>\xB1");
+        let doc = parse_document_from_bytes(&html);
+        assert_eq!(doc.get_encoding(), "ISO-8859-2");
+        let p = find_element(&doc, "p")[0];
+        let mut text = String::new();
+        serialize_node(
+            &doc,
+            &doc.doctree.get_node(&p).unwrap().children[0],
+            &mut text,
+        );
+        assert_eq!(text, "ą");
+    }
+
+    #[test]
+    fn test_newlines_are_normalized() {
+        assert_eq!(
+            body_contents("<p title=\"a\r\nb\rc\">x\r\ny\r\rz</p>\r\n"),
+            "<p>x\ny\n\nz</p>\n"
+        );
+        let doc = parse_document("<body><p title=\"a\r\nb\rc\">");
+        let p = find_element(&doc, "p")[0];
+        match &doc.doctree.get_node(&p).unwrap().node_type {
+            NodeType::Element(e) => assert_eq!(e.get_attribute("title"), Some("a\nb\nc")),
+            _ => panic!("p is not an element"),
+        }
+
+        // A newline right after these start tags is dropped, even a normalized CRLF
+        assert_eq!(
+            body_contents("<pre>\r\na\n</pre><listing>\n\nb</listing><textarea>\nc</textarea>"),
+            "<pre>a\n</pre><listing>\nb</listing><textarea>c</textarea>"
+        );
+        assert_eq!(body_contents("<pre>a\n</pre>"), "<pre>a\n</pre>");
+    }
+
+    fn error_kinds(html: &str) -> Vec<ParseErrorKind> {
+        let (_, errors) = parse_document_with_diagnostics(html);
+        errors.into_iter().map(|e| e.kind).collect()
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(error_kinds("<!DOCTYPE html><p>a</p>"), vec![]);
+        assert_eq!(error_kinds("<p>a"), vec![ParseErrorKind::MissingDoctype]);
+        assert_eq!(
+            error_kinds("<!DOCTYPE html PUBLIC \"-//W3C//DTD HTML 4.01//EN\">"),
+            vec![ParseErrorKind::NonConformingDoctype]
+        );
+        assert_eq!(
+            error_kinds("<!DOCTYPE html><div><span></div>"),
+            vec![ParseErrorKind::UnclosedElements]
+        );
+        assert_eq!(
+            error_kinds("<!DOCTYPE html></span>"),
+            vec![ParseErrorKind::UnexpectedEndTag]
+        );
+
+        // Errors from the tokenizer and the tree builder come back in document order
+        let (_, errors) =
+            parse_document_with_diagnostics("<!DOCTYPE html>\n<div a a>\n</span><br/>");
+        let errors: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+        assert_eq!(errors[0], "2:9: duplicate-attribute");
+        assert_eq!(errors[1], "3:1: unexpected-end-tag");
+    }
+
+    fn error_positions(html: &str) -> Vec<String> {
+        let (_, errors) = parse_document_with_diagnostics(html);
+        errors.iter().map(|e| e.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse_error_positions() {
+        // Tree construction errors point at the start of the token they're about, not at how
+        // far the tokenizer had read when the tree builder got to it
+        assert_eq!(
+            error_positions("<table>A<tr>"),
+            vec![
+                "1:1: missing-doctype",
+                "1:8: unexpected-character",
+                "1:13: unclosed-elements"
+            ]
+        );
+        assert_eq!(
+            error_positions("<!DOCTYPE html><p>a</span>b"),
+            vec!["1:20: unexpected-end-tag"]
+        );
+        assert_eq!(
+            error_positions("<!DOCTYPE html>\n<div>\n  <span></div>"),
+            vec!["3:9: unclosed-elements"]
+        );
+        assert_eq!(
+            error_positions("<!DOCTYPE html><table>  <td>x</td>\n  y</table>"),
+            vec!["1:25: unexpected-start-tag", "2:3: unexpected-character"]
+        );
+        assert_eq!(
+            error_positions("<!DOCTYPE html><div/></div>"),
+            vec!["1:16: non-void-html-element-start-tag-with-trailing-solidus"]
+        );
+    }
+
+    fn contents_with_options(html: &str, options: ParserOptions) -> String {
+        let (doc, _) = parse_document_with_options(html, options);
+        let mut out = String::new();
+        match find_element(&doc, "body").first() {
+            Some(body) => {
+                for c in &doc.doctree.get_node(body).unwrap().children {
+                    serialize_node(&doc, c, &mut out);
+                }
+            }
+            None => {
+                for c in doc.doctree.get_root_node_list() {
+                    serialize_node(&doc, &c, &mut out);
+                }
+            }
+        }
+        out
+    }
+
+    #[test]
+    fn test_parser_options() {
+        // With scripting on, noscript is raw text rather than markup
+        let noscript_child = |html: &str, options: ParserOptions| {
+            let (doc, _) = parse_document_with_options(html, options);
+            let noscript = find_element(&doc, "noscript")[0];
+            let child = doc.doctree.get_node(&noscript).unwrap().children[0];
+            doc.doctree.get_element_name(&child)
+        };
+        let no_scripting = ParserOptions {
+            scripting: false,
+            ..Default::default()
+        };
+        for html in [
+            "<noscript><link></noscript>",
+            "<body><noscript><p>a</p></noscript>",
+        ] {
+            assert_eq!(noscript_child(html, ParserOptions::default()), None);
+            assert!(noscript_child(html, no_scripting.clone()).is_some());
+        }
+
+        // Without a doctype an iframe srcdoc document still isn't in quirks mode
+        let (doc, errors) = parse_document_with_options(
+            "<p>a",
+            ParserOptions {
+                iframe_srcdoc: true,
+                ..Default::default()
+            },
+        );
+        assert_eq!(doc.get_quirks_mode(), document::document::QuirksMode::Off);
+        assert!(errors.is_empty());
+
+        let (_, errors) = parse_document_with_options(
+            "<p>a</span>",
+            ParserOptions {
+                collect_errors: false,
+                ..Default::default()
+            },
+        );
+        assert!(errors.is_empty());
+
+        let in_div = ParserOptions {
+            fragment_context: Some("div".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(contents_with_options("<p>a</p>b", in_div), "<p>a</p>b");
+        let in_textarea = ParserOptions {
+            fragment_context: Some("textarea".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(contents_with_options("<b>a</b>", in_textarea), "<b>a</b>");
+    }
+
+    #[test]
+    fn test_plaintext() {
+        let doc = parse_document("<plaintext><b>a</plaintext>");
+        let plaintext = find_element(&doc, "plaintext")[0];
+        let node = doc.doctree.get_node(&plaintext).unwrap();
+        match &node.node_type {
+            NodeType::Element(e) => assert!(!matches!(e.element_type, HTMLElementType::Pre(_))),
+            _ => panic!("plaintext is not an element"),
+        }
+        match &doc.doctree.get_node(&node.children[0]).unwrap().node_type {
+            NodeType::Text(text) => assert_eq!(text, "<b>a</plaintext>"),
+            _ => panic!("plaintext contents weren't parsed as text"),
+        }
+        assert!(find_element(&doc, "b").is_empty());
+    }
+
+    #[test]
+    fn test_frameset() {
+        assert_eq!(
+            document_contents(&parse_document(
+                "<frameset cols=\"20%,*\"><frame src=a><frameset rows=*,*><frame><frame>\
+                </frameset></frameset><noframes>x</noframes>"
+            )),
+            "<html><head></head><frameset><frame></frame><frameset><frame></frame>\
+            <frame></frame></frameset></frameset><noframes>x</noframes></html>"
+        );
+
+        let doc = parse_document("<frameset cols=\"20%, *\"><frame src=a.html name=left>");
+        let frameset = find_element(&doc, "frameset")[0];
+        match &doc.doctree.get_node(&frameset).unwrap().node_type {
+            NodeType::Element(e) => match &e.element_type {
+                HTMLElementType::Frameset(f) => assert_eq!(f.get_cols(), &vec!["20%", "*"]),
+                _ => panic!("frameset has the wrong element type"),
+            },
+            _ => panic!("frameset is not an element"),
+        }
+        let frame = find_element(&doc, "frame")[0];
+        match &doc.doctree.get_node(&frame).unwrap().node_type {
+            NodeType::Element(e) => match &e.element_type {
+                HTMLElementType::Frame(f) => {
+                    assert_eq!(f.get_src(), Some("a.html"));
+                    assert_eq!(f.get_name(), Some("left"));
+                }
+                _ => panic!("frame has the wrong element type"),
+            },
+            _ => panic!("frame is not an element"),
+        }
+
+        // A frameset can replace a body that hasn't got any content yet
+        assert_eq!(
+            document_contents(&parse_document("<div></div><frameset><frame>")),
+            "<html><head></head><frameset><frame></frame></frameset></html>"
+        );
+        // Once there is content the frameset is ignored
+        for html in [
+            "<body><frameset><frame>",
+            "a<frameset><frame>",
+            "<input><frameset><frame>",
+            "<pre></pre><frameset><frame>",
+            "<svg>a</svg><frameset><frame>",
+        ] {
+            assert!(find_element(&parse_document(html), "frameset").is_empty());
+        }
+        assert!(
+            !find_element(&parse_document("<input type=HIDDEN><frameset>"), "frameset").is_empty()
+        );
+    }
+
+    // Every seed in the fuzzing corpus has to make it through without a panic
+    #[test]
+    fn test_fuzz_corpus() {
+        let mut d = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        d.push("test_resources/fuzz_corpus");
+        for entry in fs::read_dir(d).unwrap() {
+            let data = fs::read(entry.unwrap().path()).unwrap();
+
+            let html = String::from_utf8_lossy(&data);
+            let mut tokenizer = tokenizer::Tokenizer::init(&html);
+            while !matches!(tokenizer.get_next_token().tag, TokenTag::EoF) {}
+
+            parse_document_from_bytes(&data);
+            parse_document_with_diagnostics(&html);
+        }
+    }
+
+    // Writes the source of inline scripts back into the document, and leaves external ones
+    // pending
+    struct WritingHandler {
+        ran: std::rc::Rc<std::cell::RefCell<Vec<String>>>,
+    }
+
+    impl ScriptHandler for WritingHandler {
+        fn run_script(&mut self, script: &ParserScript, writer: &mut ScriptWriter) -> ScriptStatus {
+            if let Some(src) = &script.src {
+                self.ran.borrow_mut().push(src.clone());
+                return ScriptStatus::Pending;
+            }
+            self.ran.borrow_mut().push(script.text.clone());
+            writer.write(&script.text);
+            ScriptStatus::Done
+        }
+    }
+
+    #[test]
+    fn test_scripts() {
+        let ran = std::rc::Rc::new(std::cell::RefCell::new(Vec::new()));
+        let handler = || Box::new(WritingHandler { ran: ran.clone() });
+
+        // Written markup goes right after the end tag, and scripts in it run in turn
+        let mut parser = Parser::new(
+            "<body><script><i>a</i><script>b</script></script>c\
+            <script async src=x.js></script><script type=module>d</script>",
+        );
+        parser.set_script_handler(handler());
+        let doc = parser.parse_html();
+        let body = find_element(&doc, "body")[0];
+        let mut out = String::new();
+        for c in &doc.doctree.get_node(&body).unwrap().children {
+            serialize_node(&doc, c, &mut out);
+        }
+        assert_eq!(
+            out,
+            "<script><i>a</i><script>b</script><i>a</i><script>b</script>bc\
+            <script></script><script>d</script>"
+        );
+        // Only the classic scripts that block the parser are run
+        assert_eq!(*ran.borrow(), vec!["<i>a</i><script>b", "b"]);
+
+        // Without scripting nothing runs, but the scripts are still in the tree
+        ran.borrow_mut().clear();
+        let mut parser = Parser::with_options(
+            "<script>a</script>",
+            &ParserOptions {
+                scripting: false,
+                ..ParserOptions::default()
+            },
+        );
+        parser.set_script_handler(handler());
+        let doc = parser.parse_html();
+        assert!(ran.borrow().is_empty());
+        assert_eq!(find_element(&doc, "script").len(), 1);
+
+        // Scripts in template contents never run, and comment-like text reaches the handler
+        // as it was written
+        let mut parser =
+            Parser::new("<template><script>inert()</script></template><script><!--a--></script>");
+        parser.set_script_handler(handler());
+        parser.parse_html();
+        assert_eq!(*ran.borrow(), vec!["<!--a-->"]);
+
+        // A pending script holds up the rest of the input until it's resumed
+        let mut parser = StreamingParser::new();
+        parser.set_script_handler(handler());
+        parser.feed("<p>a</p><script src=x.js></script><p>c</p><img src=c.png>");
+        assert!(parser.is_paused());
+        // The input after the script is scanned for resources meanwhile
+        parser.feed("<script async src=y.js></script>");
+        let preloads: Vec<String> = parser
+            .take_preload_requests()
+            .into_iter()
+            .map(|r| r.url)
+            .collect();
+        assert_eq!(preloads, vec!["c.png", "y.js"]);
+        let script = find_element(parser.document(), "script")[0];
+        assert_eq!(parser.document().get_pending_parse_blocking(), Some(script));
+        assert_eq!(find_element(parser.document(), "p").len(), 1);
+        assert!(parser.document_write("<p>b</p>"));
+        parser.resume();
+        assert!(!parser.is_paused());
+        assert!(!parser.document_write("<p>d</p>"));
+        let doc = parser.finish();
+        assert_eq!(doc.get_pending_parse_blocking(), None);
+        let paragraphs: Vec<String> = find_element(&doc, "p")
+            .iter()
+            .map(|p| {
+                let mut out = String::new();
+                serialize_node(&doc, p, &mut out);
+                out
+            })
+            .collect();
+        assert_eq!(paragraphs, vec!["<p>a</p>", "<p>b</p>", "<p>c</p>"]);
+    }
+
+    fn document_contents(doc: &Document) -> String {
+        let mut out = String::new();
+        for node in doc.doctree.get_root_node_list() {
+            serialize_node(doc, &node, &mut out);
+        }
+        out
+    }
+
+    #[test]
+    fn test_streaming_parser() {
+        let html = "<!DOCTYPE html><title>a</title><table><tr><td>b<b>c</td></tr></table>\
+            <p>d<i>e</p>f<svg><desc>g</desc></svg>";
+        let expected = document_contents(&parse_document(html));
+
+        for chunk_size in [1, 3, 16] {
+            let mut parser = StreamingParser::new();
+            for chunk in html.as_bytes().chunks(chunk_size) {
+                parser.feed(std::str::from_utf8(chunk).unwrap());
+            }
+            assert_eq!(document_contents(&parser.finish()), expected);
+        }
+
+        // The partial tree is available before the input ends
+        let mut parser = StreamingParser::new();
+        parser.feed("<body><p>first</p><p>sec");
+        assert_eq!(find_element(parser.document(), "p").len(), 2);
+        let new_nodes = parser.take_new_nodes();
+        assert!(new_nodes
+            .iter()
+            .any(|n| parser.document().doctree.get_element_name(n).as_deref() == Some("body")));
+        parser.feed("ond</p><div></div>");
+        let new_nodes = parser.take_new_nodes();
+        assert_eq!(
+            new_nodes
+                .iter()
+                .filter_map(|n| parser.document().doctree.get_element_name(n))
+                .collect::<Vec<String>>(),
+            vec!["div".to_string()]
+        );
+        let doc = parser.finish();
+        assert!(document_contents(&doc).contains("<p>second</p><div></div>"));
+    }
+
+    #[test]
+    fn test_streaming_parser_bytes() {
+        // The encoding is sniffed from the buffered start of the stream
+        let mut parser = StreamingParser::new();
+        parser.feed_bytes(b"<meta charset=windows-1252><p>caf");
+        parser.feed_bytes(b"\xE9</p>");
+        let doc = parser.finish();
+        assert_eq!(doc.get_encoding(), "windows-1252");
+        assert!(document_contents(&doc).contains("<p>caf\u{E9}</p>"));
+
+        // A multibyte character split between chunks still decodes
+        let mut parser = StreamingParser::new();
+        let html = "\u{FEFF}<p>\u{65E5}\u{672C}</p>".as_bytes();
+        for chunk in html.chunks(2) {
+            parser.feed_bytes(chunk);
+        }
+        let doc = parser.finish();
+        assert_eq!(
+            doc.get_encoding_confidence(),
+            document::document::EncodingConfidence::Certain
+        );
+        assert!(document_contents(&doc).contains("<p>\u{65E5}\u{672C}</p>"));
+
+        // A late meta switches the decoder for the rest of the stream
+        let mut parser = StreamingParser::new();
+        parser.feed_bytes(&[b' '; 1100]);
+        parser.feed_bytes(b"<meta charset=windows-1252>");
+        parser.feed_bytes(b"<p>\xE9</p>");
+        let doc = parser.finish();
+        assert_eq!(doc.get_encoding(), "windows-1252");
+        assert!(document_contents(&doc).contains("<p>\u{E9}</p>"));
+    }
+
+    fn fragment_contents(context_html: &str, context: &str, html: &str) -> String {
+        let mut doc = parse_document(context_html);
+        let context = find_element(&doc, context)[0];
+        let nodes = parse_fragment(&mut doc, &context, html);
+        let mut out = String::new();
+        for n in &nodes {
+            assert!(doc.doctree.get_node(n).unwrap().parent.is_none());
+            serialize_node(&doc, n, &mut out);
+        }
+        out
+    }
+
+    #[test]
+    fn test_parse_fragment() {
+        assert_eq!(
+            fragment_contents("<div></div>", "div", "<p>a</p><b>b"),
+            "<p>a</p><b>b</b>"
+        );
+        // The context element sets the tokenizer state
+        assert_eq!(
+            fragment_contents("<title></title>", "title", "a<b>&amp;</title>c"),
+            "a<b>&c"
+        );
+        assert_eq!(
+            fragment_contents("<div></div>", "div", "<style>a<b></style>"),
+            "<style>a<b></style>"
+        );
+        // and the insertion mode
+        assert_eq!(
+            fragment_contents("<table></table>", "table", "<tr><td>x"),
+            "<tbody><tr><td>x</td></tr></tbody>"
+        );
+        assert_eq!(
+            fragment_contents("<select></select>", "select", "<option>a<option>b"),
+            "<option>a</option><option>b</option>"
+        );
+
+        // Foreign context elements put the fragment in their namespace
+        let mut doc = parse_document("<svg></svg>");
+        let svg = find_element(&doc, "svg")[0];
+        let nodes = parse_fragment(&mut doc, &svg, "<circle/><![CDATA[x]]>");
+        assert_eq!(nodes.len(), 2);
+        assert_eq!(
+            doc.doctree.get_element_namespace(&nodes[0]),
+            Some(Namespace::Svg)
+        );
+
+        // The nodes can be spliced into the document
+        let mut doc = parse_document("<body><div></div>");
+        let div = find_element(&doc, "div")[0];
+        for n in parse_fragment(&mut doc, &div, "<i>x</i>y") {
+            doc.doctree.append_child(&div, n);
+        }
+        let mut out = String::new();
+        serialize_node(&doc, &div, &mut out);
+        assert_eq!(out, "<div><i>x</i>y</div>");
+    }
+
+    // Only keeps the elements and their parents around, and writes everything else out as it
+    // happens
+    #[derive(Default)]
+    struct EventSink {
+        elements: Vec<Option<HTMLElement>>,
+        parents: Vec<Option<DoctreeNode>>,
+        events: Vec<String>,
+    }
+
+    impl EventSink {
+        fn describe(&self, node: &DoctreeNode) -> String {
+            match self.get_element(node) {
+                Some(e) => e.get_name().to_string(),
+                None => format!("#{}", node.idx()),
+            }
+        }
+    }
+
+    impl TreeSink for EventSink {
+        fn create_node(&mut self, node: node::Node) -> DoctreeNode {
+            match node.node_type {
+                NodeType::Element(e) => self.elements.push(Some(e)),
+                NodeType::Comment(text) => {
+                    self.events.push(format!("comment {}", text));
+                    self.elements.push(None);
+                }
+                _ => self.elements.push(None),
+            }
+            self.parents.push(None);
+            DoctreeNode::new(self.elements.len() - 1, 0)
+        }
+
+        fn get_element(&self, node: &DoctreeNode) -> Option<&HTMLElement> {
+            self.elements.get(node.idx())?.as_ref()
+        }
+
+        fn get_mut_element(&mut self, node: &DoctreeNode) -> Option<&mut HTMLElement> {
+            self.elements.get_mut(node.idx())?.as_mut()
+        }
+
+        fn get_parent(&self, node: &DoctreeNode) -> Option<DoctreeNode> {
+            self.parents.get(node.idx()).cloned().flatten()
+        }
+
+        fn get_children(&self, _node: &DoctreeNode) -> Vec<DoctreeNode> {
+            Vec::new()
+        }
+
+        fn append_to_document(&mut self, child: DoctreeNode) {
+            let event = format!("document > {}", self.describe(&child));
+            self.events.push(event);
+        }
+
+        fn append(&mut self, parent: &DoctreeNode, child: DoctreeNode) {
+            let event = format!("{} > {}", self.describe(parent), self.describe(&child));
+            self.events.push(event);
+            self.parents[child.idx()] = Some(*parent);
+        }
+
+        fn insert_before(
+            &mut self,
+            parent: &DoctreeNode,
+            child: DoctreeNode,
+            reference: &DoctreeNode,
+        ) {
+            let event = format!(
+                "{} > {} before {}",
+                self.describe(parent),
+                self.describe(&child),
+                self.describe(reference)
+            );
+            self.events.push(event);
+            self.parents[child.idx()] = Some(*parent);
+        }
+
+        fn insert_text(
+            &mut self,
+            parent: &DoctreeNode,
+            before: Option<&DoctreeNode>,
+            text: &str,
+            _span: crate::parser::span::SourceSpan,
+        ) {
+            let mut event = format!("{} > {:?}", self.describe(parent), text);
+            if let Some(before) = before {
+                event.push_str(&format!(" before {}", self.describe(before)));
+            }
+            self.events.push(event);
+        }
+
+        fn reparent_children(&mut self, from: &DoctreeNode, to: &DoctreeNode) {
+            let event = format!("{} children > {}", self.describe(from), self.describe(to));
+            self.events.push(event);
+        }
+
+        fn detach(&mut self, node: &DoctreeNode) {
+            let event = format!("detach {}", self.describe(node));
+            self.events.push(event);
+            self.parents[node.idx()] = None;
+        }
+
+        fn remove(&mut self, node: &DoctreeNode) {
+            self.elements[node.idx()] = None;
+        }
+
+        fn set_quirks_mode(&mut self, mode: document::document::QuirksMode) {
+            self.events.push(format!("quirks {:?}", mode));
+        }
+    }
+
+    #[test]
+    fn test_custom_tree_sink() {
+        let mut sink = EventSink::default();
+        let errors = parse_document_into("<!--a--><p>x<table>y", &mut sink);
+        assert!(!errors.is_empty());
+        assert_eq!(
+            sink.events,
+            vec![
+                "comment a",
+                "document > #0",
+                "quirks Quirks",
+                "document > html",
+                "html > head",
+                "html > body",
+                "body > p",
+                "p > \"x\"",
+                "p > table",
+                // Foster parented in front of the table
+                "p > \"y\" before table",
+            ]
+        );
+
+        // Parsing into a Doctree directly gives the same tree as a Document
+        let mut doctree = doctree::Doctree::new();
+        parse_document_into("<p>a<b>b<i>c</b>d", &mut doctree);
+        let doc = parse_document("<p>a<b>b<i>c</b>d");
+        assert_eq!(doctree.len(), doc.doctree.len());
+        for node in doctree.get_all_nodes() {
+            assert_eq!(
+                doctree.get_element_name(&node),
+                doc.doctree.get_element_name(&node)
+            );
+            assert_eq!(doctree.get_parent(&node), doc.doctree.get_parent(&node));
         }
-        assert!(root_html_found);
     }
 }
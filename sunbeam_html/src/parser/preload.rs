@@ -0,0 +1,290 @@
+use std::collections::HashSet;
+
+use crate::html_elements::FetchPriorityOption;
+use crate::parser::tokenizer::{HtmlToken, TokenTag, Tokenizer};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PreloadKind {
+    Image,
+    Script,
+    Style,
+    Font,
+    // Whatever a preload link's as attribute named
+    Other(String),
+}
+
+impl PreloadKind {
+    fn derive_kind(value: &str) -> PreloadKind {
+        match value.to_ascii_lowercase().as_str() {
+            "image" => PreloadKind::Image,
+            "script" => PreloadKind::Script,
+            "style" => PreloadKind::Style,
+            "font" => PreloadKind::Font,
+            other => PreloadKind::Other(other.to_string()),
+        }
+    }
+}
+
+// A resource the document is going to need. URLs are left as they were written, since the
+// scanner doesn't know the document's base URL.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PreloadRequest {
+    pub url: String,
+    pub kind: PreloadKind,
+    pub priority: FetchPriorityOption,
+}
+
+fn priority_rank(priority: &FetchPriorityOption) -> u8 {
+    match priority {
+        FetchPriorityOption::High => 0,
+        FetchPriorityOption::Auto => 1,
+        FetchPriorityOption::Low => 2,
+    }
+}
+
+// Only the URL of every candidate is needed, the descriptors are skipped
+fn srcset_urls(srcset: &str) -> Vec<String> {
+    let mut urls = Vec::new();
+    let mut rest = srcset;
+    loop {
+        rest = rest.trim_start_matches(|c: char| c.is_ascii_whitespace() || c == ',');
+        if rest.is_empty() {
+            return urls;
+        }
+        let end = rest
+            .find(|c: char| c.is_ascii_whitespace())
+            .unwrap_or(rest.len());
+        let url = &rest[..end];
+        rest = &rest[end..];
+        if let Some(url) = url.strip_suffix(',') {
+            urls.push(url.to_string());
+            continue;
+        }
+        urls.push(url.to_string());
+        rest = match rest.find(',') {
+            Some(comma) => &rest[comma..],
+            None => "",
+        };
+    }
+}
+
+// Looks ahead through input the parser can't get to yet, which is the case while it waits
+// on a script. Nothing is built from the tokens, it only picks out the URLs that are worth
+// fetching early.
+pub struct PreloadScanner {
+    tokenizer: Tokenizer,
+    seen: HashSet<String>,
+    requests: Vec<PreloadRequest>,
+    // Sources only pick images inside a picture, and template contents are never fetched
+    picture_depth: usize,
+    template_depth: usize,
+}
+
+impl Default for PreloadScanner {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PreloadScanner {
+    pub fn new() -> PreloadScanner {
+        PreloadScanner {
+            tokenizer: Tokenizer::new(),
+            seen: HashSet::new(),
+            requests: Vec::new(),
+            picture_depth: 0,
+            template_depth: 0,
+        }
+    }
+
+    pub fn feed(&mut self, chunk: &str) {
+        self.tokenizer.feed(chunk);
+        self.scan();
+    }
+
+    pub fn finish(&mut self) {
+        self.tokenizer.finish();
+        self.scan();
+    }
+
+    // The requests found since the last call, most urgent first
+    pub fn take_requests(&mut self) -> Vec<PreloadRequest> {
+        let mut requests = std::mem::take(&mut self.requests);
+        requests.sort_by_key(|r| priority_rank(&r.priority));
+        requests
+    }
+
+    fn scan(&mut self) {
+        while let Some(token) = self.tokenizer.next_ready_token() {
+            match token.tag {
+                TokenTag::StartTag => self.check_start_tag(&token),
+                TokenTag::EndTag => match token.data.as_str() {
+                    "picture" => self.picture_depth = self.picture_depth.saturating_sub(1),
+                    "template" => self.template_depth = self.template_depth.saturating_sub(1),
+                    _ => {}
+                },
+                TokenTag::EoF => return,
+                _ => {}
+            }
+        }
+    }
+
+    fn check_start_tag(&mut self, token: &HtmlToken) {
+        match token.data.as_str() {
+            "template" => self.template_depth += 1,
+            "picture" => self.picture_depth += 1,
+            _ => {}
+        }
+        if self.template_depth > 0 {
+            return;
+        }
+        let attribute = |name: &str| {
+            token
+                .attributes
+                .iter()
+                .find(|a| a.name == name)
+                .map(|a| a.value.trim())
+        };
+        let priority = attribute("fetchpriority").map(FetchPriorityOption::derive_priority);
+
+        match token.data.as_str() {
+            "img" | "source" => {
+                // Outside of a picture, sources are media for video and audio
+                if token.data == "source" && self.picture_depth == 0 {
+                    return;
+                }
+                let priority = priority.unwrap_or_default();
+                if token.data == "img" {
+                    if let Some(src) = attribute("src") {
+                        self.add(src, PreloadKind::Image, priority.clone());
+                    }
+                }
+                if let Some(srcset) = attribute("srcset") {
+                    for url in srcset_urls(srcset) {
+                        self.add(&url, PreloadKind::Image, priority.clone());
+                    }
+                }
+            }
+            "script" => {
+                let src = match attribute("src") {
+                    Some(src) => src,
+                    None => return,
+                };
+                let module = match attribute("type").map(|t| t.to_ascii_lowercase()) {
+                    None => false,
+                    Some(t) if t.is_empty() || t.ends_with("javascript") => false,
+                    Some(t) if t == "module" => true,
+                    Some(_) => return,
+                };
+                // Only scripts that block the parser are urgent
+                let deferred =
+                    module || attribute("async").is_some() || attribute("defer").is_some();
+                let priority = priority.unwrap_or(if deferred {
+                    FetchPriorityOption::Low
+                } else {
+                    FetchPriorityOption::High
+                });
+                self.add(src, PreloadKind::Script, priority);
+            }
+            "link" => {
+                let href = match attribute("href") {
+                    Some(href) => href,
+                    None => return,
+                };
+                let rel = attribute("rel").unwrap_or("").to_ascii_lowercase();
+                let rel: Vec<&str> = rel.split_ascii_whitespace().collect();
+                if rel.contains(&"stylesheet") {
+                    // Style sheets block scripts from running
+                    let priority = priority.unwrap_or(FetchPriorityOption::High);
+                    self.add(href, PreloadKind::Style, priority);
+                } else if rel.contains(&"preload") {
+                    if let Some(kind) = attribute("as") {
+                        self.add(
+                            href,
+                            PreloadKind::derive_kind(kind),
+                            priority.unwrap_or_default(),
+                        );
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn add(&mut self, url: &str, kind: PreloadKind, priority: FetchPriorityOption) {
+        if url.is_empty() || !self.seen.insert(url.to_string()) {
+            return;
+        }
+        self.requests.push(PreloadRequest {
+            url: url.to_string(),
+            kind,
+            priority,
+        });
+    }
+}
+
+// Finds the resources in a whole document, most urgent first
+pub fn scan_for_preloads(html: &str) -> Vec<PreloadRequest> {
+    let mut scanner = PreloadScanner::new();
+    scanner.feed(html);
+    scanner.finish();
+    scanner.take_requests()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn describe(requests: Vec<PreloadRequest>) -> Vec<String> {
+        requests
+            .iter()
+            .map(|r| format!("{:?} {:?} {}", r.priority, r.kind, r.url))
+            .collect()
+    }
+
+    #[test]
+    fn test_scan_for_preloads() {
+        let html = "<img src=a.png srcset='b.png 2x, c,d.png 100w,e.png'>\
+            <script>var s = '<img src=inline.png>';</script>\
+            <script async src=async.js></script><script src=sync.js></script>\
+            <link rel='Stylesheet' href=style.css><link rel=preload as=font href=font.woff>\
+            <link rel=icon href=icon.png><img src=a.png><img fetchpriority=high src=hero.png>\
+            <script type=text/plain src=data.txt></script>";
+        assert_eq!(
+            describe(scan_for_preloads(html)),
+            vec![
+                "High Script sync.js",
+                "High Style style.css",
+                "High Image hero.png",
+                "Auto Image a.png",
+                "Auto Image b.png",
+                "Auto Image c,d.png",
+                "Auto Image e.png",
+                "Auto Font font.woff",
+                "Low Script async.js",
+            ]
+        );
+
+        // Chunks are scanned as they arrive, and each URL is only reported once
+        let mut scanner = PreloadScanner::new();
+        scanner.feed("<img src=a.png><img sr");
+        assert_eq!(describe(scanner.take_requests()), vec!["Auto Image a.png"]);
+        scanner.feed("c=b.png><img src=a.png>");
+        scanner.finish();
+        assert_eq!(describe(scanner.take_requests()), vec!["Auto Image b.png"]);
+
+        // Only sources in a picture are images, and template contents are skipped
+        let html = "<video><source src=clip.mp4 srcset=poster.png></video>\
+            <picture><source src=x.png srcset=wide.png><img src=narrow.png></picture>\
+            <source srcset=stray.png><template><img src=inert.png><script src=inert.js>\
+            </script></template><img src=after.png>";
+        assert_eq!(
+            describe(scan_for_preloads(html)),
+            vec![
+                "Auto Image wide.png",
+                "Auto Image narrow.png",
+                "Auto Image after.png",
+            ]
+        );
+    }
+}
@@ -0,0 +1,48 @@
+use crate::document::doctree::DoctreeNode;
+
+// A classic script the parser reached the end tag of. Parsing waits for it, since it can
+// write markup into the input that comes next.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParserScript {
+    pub node: DoctreeNode,
+    // Set for external scripts, which the embedder has to fetch first
+    pub src: Option<String>,
+    // The inline source, which is empty for external scripts
+    pub text: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ScriptStatus {
+    // The script ran, so parsing carries on with whatever it wrote
+    Done,
+    // The script can't run yet, most likely because it's still being fetched. The parser
+    // stays paused until it's resumed.
+    Pending,
+}
+
+// Markup written by a script, which is parsed before the rest of the input
+#[derive(Debug, Default)]
+pub struct ScriptWriter {
+    markup: String,
+}
+
+impl ScriptWriter {
+    pub fn write(&mut self, markup: &str) {
+        self.markup.push_str(markup);
+    }
+
+    pub fn writeln(&mut self, markup: &str) {
+        self.markup.push_str(markup);
+        self.markup.push('\n');
+    }
+
+    pub(crate) fn take(&mut self) -> String {
+        std::mem::take(&mut self.markup)
+    }
+}
+
+// Runs the scripts that block the parser. Without one, scripts are put in the tree but
+// never run.
+pub trait ScriptHandler {
+    fn run_script(&mut self, script: &ParserScript, writer: &mut ScriptWriter) -> ScriptStatus;
+}
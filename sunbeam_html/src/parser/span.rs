@@ -0,0 +1,31 @@
+// Where a token or node came from in the source. Offsets are in bytes of the decoded text
+// and lines and columns start at 1, with columns counting characters like parse errors do.
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SourcePosition {
+    pub offset: usize,
+    pub line: usize,
+    pub column: usize,
+}
+
+// The end position is just past the last character
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SourceSpan {
+    pub start: SourcePosition,
+    pub end: SourcePosition,
+}
+
+impl SourceSpan {
+    // The span covering both of these, e.g. for text merged from several tokens
+    pub fn extend_to(&self, other: &SourceSpan) -> SourceSpan {
+        SourceSpan {
+            start: self.start,
+            end: other.end,
+        }
+    }
+
+    // Byte range of the span, for slicing the source text
+    pub fn range(&self) -> std::ops::Range<usize> {
+        self.start.offset..self.end.offset
+    }
+}
@@ -1,5 +1,9 @@
 use std::collections::VecDeque;
 
+use super::errors::{ParseError, ParseErrorKind};
+use super::named_character_references;
+use super::span::{SourcePosition, SourceSpan};
+
 #[derive(Clone, Debug)]
 pub struct DoctypeData {
     pub public_identifier: Option<String>,
@@ -25,22 +29,30 @@ pub enum TokenTag {
     EoF,
 }
 
-#[derive(Clone)]
+#[derive(Clone, Debug)]
 pub struct HtmlAttribute {
     pub name: String,
     pub value: String,
+    // From the start of the name to the end of the value
+    pub span: SourceSpan,
+    // Character positions in the input while the span is still being tracked
+    start: usize,
+    end: usize,
 }
 
 impl HtmlAttribute {
-    fn new() -> HtmlAttribute {
+    fn new(start: usize) -> HtmlAttribute {
         HtmlAttribute {
             name: String::new(),
             value: String::new(),
+            span: SourceSpan::default(),
+            start,
+            end: start,
         }
     }
 }
 
-#[derive(Clone)]
+#[derive(Clone, Debug)]
 pub struct HtmlTokenFlags {
     pub self_closing: bool,
     pub force_quirks: bool,
@@ -55,12 +67,13 @@ impl HtmlTokenFlags {
     }
 }
 
-#[derive(Clone)]
+#[derive(Clone, Debug)]
 pub struct HtmlToken {
     pub tag: TokenTag,
     pub data: String,
     pub attributes: Vec<HtmlAttribute>,
     pub flags: HtmlTokenFlags,
+    pub span: SourceSpan,
 }
 
 impl HtmlToken {
@@ -70,6 +83,7 @@ impl HtmlToken {
             data: String::new(),
             attributes: Vec::new(),
             flags: HtmlTokenFlags::new(),
+            span: SourceSpan::default(),
         }
     }
     fn data_append(&mut self, c: char) {
@@ -82,6 +96,40 @@ impl HtmlToken {
             None => {}
         };
     }
+
+    fn append_to_last_attribute_name(&mut self, c: char) {
+        if let Some(v) = self.attributes.last_mut() {
+            v.name.push(c);
+        }
+    }
+
+    // The identifiers only exist on doctype tokens. Any other token is left untouched
+    // rather than failing, since the tokenizer must not panic on its own state.
+    fn start_public_identifier(&mut self) {
+        if let TokenTag::Doctype(ref mut val) = self.tag {
+            val.public_identifier = Some(String::new());
+        }
+    }
+
+    fn append_to_public_identifier(&mut self, c: char) {
+        if let TokenTag::Doctype(ref mut val) = self.tag {
+            val.public_identifier
+                .get_or_insert_with(String::new)
+                .push(c);
+        }
+    }
+
+    fn start_system_identifier(&mut self) {
+        if let TokenTag::Doctype(ref mut val) = self.tag {
+            val.system = Some(String::new());
+        }
+    }
+
+    fn append_to_system_identifier(&mut self, c: char) {
+        if let TokenTag::Doctype(ref mut val) = self.tag {
+            val.system.get_or_insert_with(String::new).push(c);
+        }
+    }
 }
 
 fn new_character_string(c: char) -> HtmlToken {
@@ -90,6 +138,7 @@ fn new_character_string(c: char) -> HtmlToken {
         data: String::from(c),
         attributes: Vec::new(),
         flags: HtmlTokenFlags::new(),
+        span: SourceSpan::default(),
     }
 }
 
@@ -150,7 +199,6 @@ pub enum FsmState {
     Doctype,
     BeforeDoctypeName,
     DoctypeName,
-    AfterDoctype,
     AfterDoctypeName,
     AfterDoctypePublicKeyword,
     BeforeDoctypePublicIndentifier,
@@ -179,29 +227,17 @@ pub enum FsmState {
     NumericCharacterReferenceEnd,
 }
 
-// TODO: There's probably a better way of comparing case insensitive arrays of characters,
-//       but this will do for now
-fn compare_slices(a1: &[char], a2: &[char], case_sensitive: bool) -> bool {
-    if a1.len() != a2.len() {
-        return false;
-    }
-
-    let mut idx = 0;
-    while idx < a1.len() {
-        if case_sensitive {
-            if a1[idx] != a2[idx] {
-                return false;
-            }
-        } else {
-            if a1[idx].to_lowercase().next().unwrap() != a2[idx].to_lowercase().next().unwrap() {
-                return false;
-            }
-        }
+fn is_noncharacter(c: char) -> bool {
+    let code = c as u32;
+    (0xFDD0..=0xFDEF).contains(&code) || code & 0xFFFE == 0xFFFE
+}
 
-        idx += 1;
+// Controls other than ASCII whitespace and NULL, which the tokenizer handles itself
+fn is_control_error(c: char) -> bool {
+    match c {
+        '\u{0000}' | '\u{0009}' | '\u{000A}' | '\u{000C}' | '\u{000D}' | '\u{0020}' => false,
+        _ => c.is_control(),
     }
-
-    true
 }
 
 fn is_part_of_attribute(state: FsmState) -> bool {
@@ -273,9 +309,31 @@ macro_rules! reconsume {
     };
 }
 
+// How many consumed characters can pile up before the buffer gets compacted
+const DISCARD_THRESHOLD: usize = 4096;
+
 pub struct Tokenizer {
     idx: usize,
     chars: Vec<char>,
+    // Consumed characters get dropped from the buffer, so these track how much of the
+    // input came before it
+    discarded_chars: usize,
+    discarded_lines: usize,
+    // Positions of the lines starting from the buffer onwards, counted from the start of the input
+    line_starts: Vec<usize>,
+    errors: Vec<ParseError>,
+
+    // Byte offset in the source text of every character in the buffer
+    char_offsets: Vec<usize>,
+    fed_bytes: usize,
+    // A CR ending a chunk waits to swallow the LF starting the next one
+    pending_cr: bool,
+    input_finished: bool,
+
+    // Character position in the input where the token being read started, and the
+    // first token in the buffer that doesn't have its span yet
+    token_start: usize,
+    unspanned_tokens: usize,
 
     state: FsmState,
     return_state: FsmState,
@@ -284,61 +342,449 @@ pub struct Tokenizer {
     token_buffer: VecDeque<HtmlToken>,
 
     temp_buffer: String,
-    pub open_tag_stack: Vec<String>,
+    // Used to decide if an end tag is appropriate in the RCDATA, RAWTEXT and script states
+    last_start_tag: Option<String>,
+    // Set by the parser when the adjusted current node is not in the HTML namespace
+    allow_cdata: bool,
     character_reference_code: u32,
+
+    // Without a parser driving the state, iterating switches it after start tags instead
+    switch_states: bool,
+    reached_eof: bool,
+}
+
+// Iterating gives every token up to the end of the file. A tokenizer that's still being fed
+// also stops when it needs more input, which reached_eof tells apart from the real end.
+impl Iterator for Tokenizer {
+    type Item = HtmlToken;
+
+    fn next(&mut self) -> Option<HtmlToken> {
+        if self.reached_eof {
+            return None;
+        }
+        match self.next_ready_token() {
+            Some(token) if matches!(token.tag, TokenTag::EoF) => {
+                self.reached_eof = true;
+                None
+            }
+            token => token,
+        }
+    }
 }
 
-// self.chars[self.idx].to_lowercase().next().unwrap()
+// self.chars[self.idx].to_ascii_lowercase()
 // '\u{0009}' | '\u{000A}' | '\u{000C}' | '\u{0020}'
 
+impl Default for Tokenizer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Tokenizer {
+    // Creates a tokenizer for a whole document
     pub fn init(html_string: &str) -> Tokenizer {
+        let mut tokenizer = Tokenizer::new();
+        tokenizer.feed(html_string);
+        tokenizer.finish();
+        tokenizer
+    }
+
+    // Creates a tokenizer that gets its input in chunks, through feed
+    pub fn new() -> Tokenizer {
         Tokenizer {
             idx: 0,
-            chars: html_string.chars().collect(),
+            chars: Vec::new(),
+            discarded_chars: 0,
+            discarded_lines: 0,
+            line_starts: vec![0],
+            errors: Vec::new(),
+            char_offsets: Vec::new(),
+            fed_bytes: 0,
+            pending_cr: false,
+            input_finished: false,
+            token_start: 0,
+            unspanned_tokens: 0,
             state: FsmState::Data,
             return_state: FsmState::Data,
             cur_token: HtmlToken::new(TokenTag::EoF),
             token_buffer: VecDeque::new(),
             temp_buffer: String::new(),
-            open_tag_stack: Vec::new(),
+            last_start_tag: None,
+            allow_cdata: false,
             character_reference_code: 0,
+            switch_states: true,
+            reached_eof: false,
+        }
+    }
+
+    // Adds the next chunk of the input. Newlines are normalized to LF as the chunk is
+    // read in, and characters that aren't allowed in the input stream are reported.
+    pub fn feed(&mut self, chunk: &str) {
+        // The end of the file has already been decided
+        if self.input_finished {
+            log::warn!("Ignoring input fed to the tokenizer after finish");
+            return;
+        }
+        let mut input = chunk.char_indices().peekable();
+        if self.pending_cr {
+            self.pending_cr = false;
+            self.push_newline(self.fed_bytes - 1);
+            input.next_if(|(_, c)| *c == '\u{000A}');
+        }
+        while let Some((offset, c)) = input.next() {
+            if c == '\u{000D}' || c == '\u{000A}' {
+                if c == '\u{000D}' && input.next_if(|(_, c)| *c == '\u{000A}').is_none() {
+                    // Held back until it's known whether it's part of a CRLF
+                    if input.peek().is_none() {
+                        self.pending_cr = true;
+                        continue;
+                    }
+                }
+                self.push_newline(self.fed_bytes + offset);
+                continue;
+            }
+
+            if is_control_error(c) {
+                self.error_at(
+                    ParseErrorKind::ControlCharacterInInputStream,
+                    self.chars.len(),
+                );
+            } else if is_noncharacter(c) {
+                self.error_at(ParseErrorKind::NoncharacterInInputStream, self.chars.len());
+            }
+            self.char_offsets.push(self.fed_bytes + offset);
+            self.chars.push(c);
+        }
+        self.fed_bytes += chunk.len();
+    }
+
+    fn push_newline(&mut self, offset: usize) {
+        self.char_offsets.push(offset);
+        self.chars.push('\u{000A}');
+        self.line_starts
+            .push(self.discarded_chars + self.chars.len());
+    }
+
+    // Marks the end of the input. Anything left in the buffer is treated as the end of the file.
+    pub fn finish(&mut self) {
+        if self.pending_cr {
+            self.pending_cr = false;
+            self.push_newline(self.fed_bytes - 1);
+        }
+        self.input_finished = true;
+    }
+
+    // Inserts text right before the next input character, the way document.write does. The
+    // text isn't part of the source, so its characters all get the source position of the
+    // insertion point and don't start new lines.
+    pub fn insert_at_insertion_point(&mut self, text: &str) {
+        let offset = self
+            .char_offsets
+            .get(self.idx)
+            .copied()
+            .unwrap_or(self.fed_bytes - usize::from(self.pending_cr));
+        let mut chars = Vec::new();
+        let mut input = text.chars().peekable();
+        while let Some(c) = input.next() {
+            if c == '\u{000D}' {
+                input.next_if_eq(&'\u{000A}');
+                chars.push('\u{000A}');
+            } else {
+                chars.push(c);
+            }
+        }
+
+        let position = self.discarded_chars + self.idx;
+        let len = chars.len();
+        self.chars.splice(self.idx..self.idx, chars);
+        self.char_offsets
+            .splice(self.idx..self.idx, std::iter::repeat_n(offset, len));
+        for start in self.line_starts.iter_mut().filter(|s| **s > position) {
+            *start += len;
+        }
+    }
+
+    // The input that hasn't been read yet
+    pub(crate) fn remaining_input(&self) -> String {
+        self.chars[self.idx..].iter().collect()
+    }
+
+    pub(crate) fn is_input_finished(&self) -> bool {
+        self.input_finished
+    }
+
+    // Whether iterating has given out every token, rather than waiting for more input
+    pub fn reached_eof(&self) -> bool {
+        self.reached_eof
+    }
+
+    // Drops the characters that have been consumed, once they make up most of the buffer
+    fn discard_consumed_input(&mut self) {
+        // The token being read still needs its start for the span
+        let consumed = self.idx.min(self.token_start - self.discarded_chars);
+        if consumed < DISCARD_THRESHOLD || consumed < self.chars.len() / 2 {
+            return;
+        }
+        self.chars.drain(..consumed);
+        self.char_offsets.drain(..consumed);
+        self.discarded_chars += consumed;
+        self.idx -= consumed;
+
+        // Keep the start of the current line around for the column numbers
+        let current_line = self
+            .line_starts
+            .partition_point(|start| *start <= self.discarded_chars)
+            - 1;
+        self.line_starts.drain(..current_line);
+        self.discarded_lines += current_line;
+    }
+
+    // Lookahead past the end of the buffer has to wait for more input, unless there is none
+    fn needs_more_input(&self, len: usize) -> bool {
+        !self.input_finished && self.chars.len() - self.idx < len
+    }
+
+    // Line and column of a character in the buffer
+    pub fn position_of(&self, idx: usize) -> (usize, usize) {
+        let position = self.discarded_chars + idx;
+        let line = self.line_starts.partition_point(|start| *start <= position);
+        (
+            self.discarded_lines + line,
+            position - self.line_starts[line - 1] + 1,
+        )
+    }
+
+    // Checks if the input continues with the pattern, without reading past its end
+    fn next_chars_match(&self, pattern: &str, case_sensitive: bool) -> bool {
+        let len = pattern.chars().count();
+        match self.chars.get(self.idx..self.idx + len) {
+            Some(next) => next.iter().zip(pattern.chars()).all(|(a, b)| {
+                if case_sensitive {
+                    *a == b
+                } else {
+                    a.eq_ignore_ascii_case(&b)
+                }
+            }),
+            None => false,
+        }
+    }
+
+    // Source position of a character, given its position in the whole input
+    fn source_position(&self, position: usize) -> SourcePosition {
+        let idx = position.saturating_sub(self.discarded_chars);
+        let (line, column) = self.position_of(idx);
+        SourcePosition {
+            offset: self
+                .char_offsets
+                .get(idx)
+                .copied()
+                .unwrap_or(self.fed_bytes - usize::from(self.pending_cr)),
+            line,
+            column,
+        }
+    }
+
+    fn start_attribute(&mut self) {
+        let start = self.discarded_chars + self.idx;
+        self.cur_token.attributes.push(HtmlAttribute::new(start));
+    }
+
+    // Moves the end of the current token and attribute along with the character
+    // about to be consumed
+    fn track_spans(&mut self) {
+        use FsmState::*;
+        let c = self.chars[self.idx];
+        let position = self.discarded_chars + self.idx;
+        if self.unspanned_tokens < self.token_buffer.len() {
+            self.close_token_spans(position);
+        }
+        match self.state {
+            // Anything consumed in these states starts a new token
+            Data | RCData | RawText | ScriptData | Plaintext => self.token_start = position,
+            AttributeName | AttributeValueUnquoted => {
+                let ends = match c {
+                    '\u{0009}' | '\u{000A}' | '\u{000C}' | '\u{0020}' | '>' => true,
+                    '/' | '=' => matches!(self.state, AttributeName),
+                    _ => false,
+                };
+                if let Some(attribute) = self.cur_token.attributes.last_mut() {
+                    attribute.end = if ends { position } else { position + 1 };
+                }
+            }
+            AttributeValueDoubleQuoted | AttributeValueSingleQuoted => {
+                if let Some(attribute) = self.cur_token.attributes.last_mut() {
+                    attribute.end = position + 1;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // Gives the tokens emitted since the last call their spans, which together cover the
+    // input from the start of the token up to the given position
+    fn close_token_spans(&mut self, end: usize) {
+        let start = self.token_start;
+        let count = self.token_buffer.len() - self.unspanned_tokens;
+        let one_char_each = count == end - start
+            && self
+                .token_buffer
+                .iter()
+                .skip(self.unspanned_tokens)
+                .all(|t| matches!(t.tag, TokenTag::Character) && t.data.chars().count() == 1);
+        let start_position = self.source_position(start);
+        let end_position = self.source_position(end);
+
+        for i in 0..count {
+            let span = if one_char_each {
+                SourceSpan {
+                    start: self.source_position(start + i),
+                    end: self.source_position(start + i + 1),
+                }
+            } else {
+                SourceSpan {
+                    start: start_position,
+                    end: end_position,
+                }
+            };
+            let attribute_spans: Vec<SourceSpan> = self.token_buffer[self.unspanned_tokens + i]
+                .attributes
+                .iter()
+                .map(|a| SourceSpan {
+                    start: self.source_position(a.start),
+                    end: self.source_position(a.end),
+                })
+                .collect();
+            let token = &mut self.token_buffer[self.unspanned_tokens + i];
+            token.span = span;
+            for (attribute, span) in token.attributes.iter_mut().zip(attribute_spans) {
+                attribute.span = span;
+            }
+        }
+        self.unspanned_tokens = self.token_buffer.len();
+        self.token_start = end;
+    }
+
+    pub fn current_position(&self) -> (usize, usize) {
+        self.position_of(self.idx)
+    }
+
+    // Hands over the errors found since the last call
+    pub fn take_errors(&mut self) -> Vec<ParseError> {
+        std::mem::take(&mut self.errors)
+    }
+
+    fn error_at(&mut self, kind: ParseErrorKind, idx: usize) {
+        let (line, column) = self.position_of(idx);
+        self.errors.push(ParseError { kind, line, column });
+    }
+
+    fn error(&mut self, kind: ParseErrorKind) {
+        self.error_at(kind, self.idx);
+    }
+
+    // Attribute names are only complete once the tokenizer leaves the attribute name state
+    fn check_duplicate_attribute(&mut self) {
+        if let Some((last, rest)) = self.cur_token.attributes.split_last() {
+            if rest.iter().any(|a| a.name == last.name) {
+                self.error(ParseErrorKind::DuplicateAttribute);
+            }
         }
     }
 
+    // Gets the next token of a tokenizer that has all of its input, like the ones made by init.
+    // On a tokenizer that's still being fed, an EoF token only means nothing is ready yet, and
+    // the input isn't ended by it. Use next_token to tell the two apart.
     pub fn get_next_token(&mut self) -> HtmlToken {
-        match self.token_buffer.pop_front() {
+        match self.next_token() {
+            Some(token) => token,
+            None => HtmlToken::new(TokenTag::EoF),
+        }
+    }
+
+    // Gets the next token, or None when it can't be known until more input is fed
+    pub fn next_token(&mut self) -> Option<HtmlToken> {
+        let token = match self.token_buffer.pop_front() {
             Some(val) => val,
             None => {
                 self.next_token_iteration();
                 match self.token_buffer.pop_front() {
                     Some(val) => val,
-                    None => self.get_eof_token(),
+                    None if self.input_finished => self.get_eof_token(),
+                    None => return None,
+                }
+            }
+        };
+        match token.tag {
+            TokenTag::StartTag => self.last_start_tag = Some(token.data.clone()),
+            TokenTag::EndTag => {
+                // The closing '>' was the last character consumed
+                let idx = self.idx.saturating_sub(1);
+                if !token.attributes.is_empty() {
+                    self.error_at(ParseErrorKind::EndTagWithAttributes, idx);
+                }
+                if token.flags.self_closing {
+                    self.error_at(ParseErrorKind::EndTagWithTrailingSolidus, idx);
                 }
             }
+            _ => {}
         }
+        Some(token)
     }
 
-    pub fn push_open_tag(&mut self, tag_name: String) {
-        self.open_tag_stack.push(tag_name);
+    // Like next_token, but switches states after start tags the way iterating does. None means
+    // more input has to be fed first, and the end of the file comes as an EoF token.
+    pub fn next_ready_token(&mut self) -> Option<HtmlToken> {
+        let token = self.next_token()?;
+        self.switch_state_after(&token);
+        Some(token)
     }
 
-    pub fn pop_open_tag(&mut self) {
-        self.open_tag_stack.pop();
-    }
-
-    pub fn is_open_tag_stack_empty(&mut self) -> bool {
-        self.open_tag_stack.is_empty()
+    pub fn set_allow_cdata(&mut self, allow_cdata: bool) {
+        self.allow_cdata = allow_cdata;
     }
 
     pub fn set_state(&mut self, state: FsmState) {
         self.state = state;
     }
 
+    // Decides which end tag closes the RCDATA, RAWTEXT and script data states
+    pub fn set_last_start_tag(&mut self, name: &str) {
+        self.last_start_tag = Some(name.to_string());
+    }
+
+    // Whether iterating switches to the RCDATA, RAWTEXT, script data and PLAINTEXT states
+    // after the start tags of the HTML elements that need them. Without a tree the tokenizer
+    // can't tell when those tags are in SVG or MathML, so this can be turned off to leave the
+    // state to the caller.
+    pub fn set_switch_states(&mut self, switch_states: bool) {
+        self.switch_states = switch_states;
+    }
+
+    fn switch_state_after(&mut self, token: &HtmlToken) {
+        if !self.switch_states || !matches!(token.tag, TokenTag::StartTag) {
+            return;
+        }
+        self.state = match token.data.as_str() {
+            "title" | "textarea" => FsmState::RCData,
+            "style" | "xmp" | "iframe" | "noembed" | "noframes" => FsmState::RawText,
+            "script" => FsmState::ScriptData,
+            "plaintext" => FsmState::Plaintext,
+            _ => return,
+        };
+    }
+
     fn next_token_iteration(&mut self) {
-        use FsmState::*;
+        self.discard_consumed_input();
+        self.unspanned_tokens = self.token_buffer.len();
+        self.run_state_machine();
+        if self.unspanned_tokens < self.token_buffer.len() {
+            self.close_token_spans(self.discarded_chars + self.idx);
+        }
+    }
 
-        let mut temp_buf = self.temp_buffer.clone();
+    fn run_state_machine(&mut self) {
+        use FsmState::*;
 
         while self.idx < self.chars.len() {
             log::trace!(
@@ -346,6 +792,7 @@ impl Tokenizer {
                 self.state,
                 self.chars[self.idx]
             );
+            self.track_spans();
             match self.state {
                 Data => match self.chars[self.idx] {
                     '&' => {
@@ -353,6 +800,10 @@ impl Tokenizer {
                         self.state = CharacterReference;
                     }
                     '<' => self.state = TagOpen,
+                    '\u{0000}' => {
+                        self.error(ParseErrorKind::UnexpectedNullCharacter);
+                        emit_token!(self, new_character_string('\u{0000}'));
+                    }
                     _ => {
                         emit_token!(self, new_character_string(self.chars[self.idx]));
                     }
@@ -364,6 +815,7 @@ impl Tokenizer {
                     }
                     '<' => self.state = RCDataLessThan,
                     '\u{0000}' => {
+                        self.error(ParseErrorKind::UnexpectedNullCharacter);
                         emit_token!(self, new_character_string('\u{FFFD}'));
                     }
                     _ => {
@@ -373,6 +825,7 @@ impl Tokenizer {
                 RawText => match self.chars[self.idx] {
                     '<' => self.state = RawTextLessThan,
                     '\u{0000}' => {
+                        self.error(ParseErrorKind::UnexpectedNullCharacter);
                         emit_token!(self, new_character_string('\u{FFFD}'));
                     }
                     _ => {
@@ -382,6 +835,7 @@ impl Tokenizer {
                 ScriptData => match self.chars[self.idx] {
                     '<' => self.state = ScriptDataLessThan,
                     '\u{0000}' => {
+                        self.error(ParseErrorKind::UnexpectedNullCharacter);
                         emit_token!(self, new_character_string('\u{FFFD}'));
                     }
                     _ => {
@@ -390,6 +844,7 @@ impl Tokenizer {
                 },
                 Plaintext => match self.chars[self.idx] {
                     '\u{0000}' => {
+                        self.error(ParseErrorKind::UnexpectedNullCharacter);
                         emit_token!(self, new_character_string('\u{FFFD}'));
                     }
                     _ => {
@@ -400,6 +855,7 @@ impl Tokenizer {
                     '!' => self.state = MarkupDeclarationOpen,
                     '/' => self.state = EndTagOpen,
                     '?' => {
+                        self.error(ParseErrorKind::UnexpectedQuestionMarkInsteadOfTagName);
                         self.cur_token = HtmlToken::new(TokenTag::Comment);
                         self.state = BogusComment;
                         reconsume!();
@@ -410,19 +866,24 @@ impl Tokenizer {
                             self.state = TagName;
                             reconsume!();
                         } else {
+                            self.error(ParseErrorKind::InvalidFirstCharacterOfTagName);
                             self.state = Data;
                             emit_and_reconsume!(self, new_character_string('<'));
                         }
                     }
                 },
                 EndTagOpen => match self.chars[self.idx] {
-                    '>' => self.state = Data,
+                    '>' => {
+                        self.error(ParseErrorKind::MissingEndTagName);
+                        self.state = Data;
+                    }
                     _ => {
                         if self.chars[self.idx].is_alphabetic() {
                             self.cur_token = HtmlToken::new(TokenTag::EndTag);
                             self.state = TagName;
                             reconsume!();
                         } else {
+                            self.error(ParseErrorKind::InvalidFirstCharacterOfTagName);
                             self.cur_token = HtmlToken::new(TokenTag::Comment);
                             self.state = BogusComment;
                             reconsume!();
@@ -438,14 +899,16 @@ impl Tokenizer {
                         self.state = Data;
                         emit_token!(self, self.cur_token.clone());
                     }
-                    '\u{0000}' => self.cur_token.data_append('\u{FFFD}'),
+                    '\u{0000}' => {
+                        self.error(ParseErrorKind::UnexpectedNullCharacter);
+                        self.cur_token.data_append('\u{FFFD}');
+                    }
                     _ => self
                         .cur_token
-                        .data_append(self.chars[self.idx].to_lowercase().next().unwrap()),
+                        .data_append(self.chars[self.idx].to_ascii_lowercase()),
                 },
                 RCDataLessThan => match self.chars[self.idx] {
                     '/' => {
-                        temp_buf = String::new();
                         self.temp_buffer = String::new();
                         self.state = RCDataEndTagOpen;
                     }
@@ -467,7 +930,8 @@ impl Tokenizer {
                 }
                 RCDataEndTagName => {
                     let mut acted = false;
-                    match self.open_tag_stack.last() {
+                    let mut emit = false;
+                    match &self.last_start_tag {
                         Some(val) => {
                             if *val == self.cur_token.data {
                                 match self.chars[self.idx] {
@@ -482,6 +946,7 @@ impl Tokenizer {
                                     '>' => {
                                         self.state = Data;
                                         acted = true;
+                                        emit = true;
                                     }
                                     _ => {}
                                 }
@@ -489,10 +954,12 @@ impl Tokenizer {
                         }
                         None => {}
                     }
+                    if emit {
+                        emit_token!(self, self.cur_token.clone());
+                    }
                     if self.chars[self.idx].is_alphabetic() {
                         self.cur_token
-                            .data_append(self.chars[self.idx].to_lowercase().next().unwrap());
-                        temp_buf.push(self.chars[self.idx]);
+                            .data_append(self.chars[self.idx].to_ascii_lowercase());
                         self.temp_buffer.push(self.chars[self.idx]);
                         acted = true;
                     }
@@ -500,7 +967,7 @@ impl Tokenizer {
                     if !acted {
                         emit_token_no_return!(self, new_character_string('<'));
                         emit_token_no_return!(self, new_character_string('/'));
-                        temp_buf.chars().for_each(|c| {
+                        self.temp_buffer.chars().for_each(|c| {
                             emit_token_no_return!(self, new_character_string(c));
                         });
                         self.state = RCData;
@@ -509,7 +976,6 @@ impl Tokenizer {
                 }
                 RawTextLessThan => match self.chars[self.idx] {
                     '/' => {
-                        temp_buf = String::new();
                         self.temp_buffer = String::new();
                         self.state = RawTextEndTagOpen;
                     }
@@ -531,7 +997,8 @@ impl Tokenizer {
                 }
                 RawTextEndTagName => {
                     let mut acted = false;
-                    match self.open_tag_stack.last() {
+                    let mut emit = false;
+                    match &self.last_start_tag {
                         Some(val) => {
                             if *val == self.cur_token.data {
                                 match self.chars[self.idx] {
@@ -546,6 +1013,7 @@ impl Tokenizer {
                                     '>' => {
                                         self.state = Data;
                                         acted = true;
+                                        emit = true;
                                     }
                                     _ => {}
                                 }
@@ -553,10 +1021,12 @@ impl Tokenizer {
                         }
                         None => {}
                     }
+                    if emit {
+                        emit_token!(self, self.cur_token.clone());
+                    }
                     if self.chars[self.idx].is_alphabetic() {
                         self.cur_token
-                            .data_append(self.chars[self.idx].to_lowercase().next().unwrap());
-                        temp_buf.push(self.chars[self.idx]);
+                            .data_append(self.chars[self.idx].to_ascii_lowercase());
                         self.temp_buffer.push(self.chars[self.idx]);
                         acted = true;
                     }
@@ -564,7 +1034,7 @@ impl Tokenizer {
                     if !acted {
                         emit_token_no_return!(self, new_character_string('<'));
                         emit_token_no_return!(self, new_character_string('/'));
-                        temp_buf.chars().for_each(|c| {
+                        self.temp_buffer.chars().for_each(|c| {
                             emit_token_no_return!(self, new_character_string(c));
                         });
                         self.state = RawText;
@@ -573,14 +1043,13 @@ impl Tokenizer {
                 }
                 ScriptDataLessThan => match self.chars[self.idx] {
                     '/' => {
-                        temp_buf = String::new();
                         self.temp_buffer = String::new();
                         self.state = ScriptDataEndTagOpen;
                     }
                     '!' => {
                         self.state = ScriptDataEscapeStart;
                         emit_token_no_return!(self, new_character_string('<'));
-                        emit_and_reconsume!(self, new_character_string('!'));
+                        emit_token!(self, new_character_string('!'));
                     }
                     _ => {
                         self.state = ScriptData;
@@ -600,7 +1069,8 @@ impl Tokenizer {
                 }
                 ScriptDataEndTagName => {
                     let mut acted = false;
-                    match self.open_tag_stack.last() {
+                    let mut emit = false;
+                    match &self.last_start_tag {
                         Some(val) => {
                             if *val == self.cur_token.data {
                                 match self.chars[self.idx] {
@@ -615,6 +1085,7 @@ impl Tokenizer {
                                     '>' => {
                                         self.state = Data;
                                         acted = true;
+                                        emit = true;
                                     }
                                     _ => {}
                                 }
@@ -622,10 +1093,12 @@ impl Tokenizer {
                         }
                         None => {}
                     }
+                    if emit {
+                        emit_token!(self, self.cur_token.clone());
+                    }
                     if self.chars[self.idx].is_alphabetic() {
                         self.cur_token
-                            .data_append(self.chars[self.idx].to_lowercase().next().unwrap());
-                        temp_buf.push(self.chars[self.idx]);
+                            .data_append(self.chars[self.idx].to_ascii_lowercase());
                         self.temp_buffer.push(self.chars[self.idx]);
                         acted = true;
                     }
@@ -633,7 +1106,7 @@ impl Tokenizer {
                     if !acted {
                         emit_token_no_return!(self, new_character_string('<'));
                         emit_token_no_return!(self, new_character_string('/'));
-                        temp_buf.chars().for_each(|c| {
+                        self.temp_buffer.chars().for_each(|c| {
                             emit_token_no_return!(self, new_character_string(c));
                         });
                         self.state = ScriptData;
@@ -642,7 +1115,17 @@ impl Tokenizer {
                 }
                 ScriptDataEscapeStart => match self.chars[self.idx] {
                     '-' => {
-                        self.state = ScriptDataEscapedDash;
+                        self.state = ScriptDataEscapeStartDash;
+                        emit_token!(self, new_character_string('-'));
+                    }
+                    _ => {
+                        self.state = ScriptData;
+                        reconsume!();
+                    }
+                },
+                ScriptDataEscapeStartDash => match self.chars[self.idx] {
+                    '-' => {
+                        self.state = ScriptDataEscapedDashDash;
                         emit_token!(self, new_character_string('-'));
                     }
                     _ => {
@@ -657,6 +1140,7 @@ impl Tokenizer {
                     }
                     '<' => self.state = ScriptDataEscapedLessThan,
                     '\u{0000}' => {
+                        self.error(ParseErrorKind::UnexpectedNullCharacter);
                         emit_token!(self, new_character_string('\u{FFFD}'));
                     }
                     _ => {
@@ -670,6 +1154,7 @@ impl Tokenizer {
                     }
                     '<' => self.state = ScriptDataEscapedLessThan,
                     '\u{0000}' => {
+                        self.error(ParseErrorKind::UnexpectedNullCharacter);
                         self.state = ScriptDataEscaped;
                         emit_token!(self, new_character_string('\u{FFFD}'));
                     }
@@ -688,6 +1173,7 @@ impl Tokenizer {
                         emit_token!(self, new_character_string('>'));
                     }
                     '\u{0000}' => {
+                        self.error(ParseErrorKind::UnexpectedNullCharacter);
                         self.state = ScriptDataEscaped;
                         emit_token!(self, new_character_string('\u{FFFD}'));
                     }
@@ -698,13 +1184,11 @@ impl Tokenizer {
                 },
                 ScriptDataEscapedLessThan => match self.chars[self.idx] {
                     '/' => {
-                        temp_buf = String::new();
                         self.temp_buffer = String::new();
                         self.state = ScriptDataEscapedEndTagOpen;
                     }
                     _ => {
                         if self.chars[self.idx].is_alphabetic() {
-                            temp_buf = String::new();
                             self.temp_buffer = String::new();
                             self.state = ScriptDataDoubleEscapeStart;
                             emit_and_reconsume!(self, new_character_string('<'));
@@ -727,7 +1211,8 @@ impl Tokenizer {
                 }
                 ScriptDataEscapedEndTagName => {
                     let mut acted = false;
-                    match self.open_tag_stack.last() {
+                    let mut emit = false;
+                    match &self.last_start_tag {
                         Some(val) => {
                             if *val == self.cur_token.data {
                                 match self.chars[self.idx] {
@@ -742,6 +1227,7 @@ impl Tokenizer {
                                     '>' => {
                                         self.state = Data;
                                         acted = true;
+                                        emit = true;
                                     }
                                     _ => {}
                                 }
@@ -749,10 +1235,12 @@ impl Tokenizer {
                         }
                         None => {}
                     }
+                    if emit {
+                        emit_token!(self, self.cur_token.clone());
+                    }
                     if self.chars[self.idx].is_alphabetic() {
                         self.cur_token
-                            .data_append(self.chars[self.idx].to_lowercase().next().unwrap());
-                        temp_buf.push(self.chars[self.idx]);
+                            .data_append(self.chars[self.idx].to_ascii_lowercase());
                         self.temp_buffer.push(self.chars[self.idx]);
                         acted = true;
                     }
@@ -760,7 +1248,7 @@ impl Tokenizer {
                     if !acted {
                         emit_token_no_return!(self, new_character_string('<'));
                         emit_token_no_return!(self, new_character_string('/'));
-                        temp_buf.chars().for_each(|c| {
+                        self.temp_buffer.chars().for_each(|c| {
                             emit_token_no_return!(self, new_character_string(c));
                         });
                         self.state = ScriptDataEscaped;
@@ -769,7 +1257,7 @@ impl Tokenizer {
                 }
                 ScriptDataDoubleEscapeStart => match self.chars[self.idx] {
                     '\u{0009}' | '\u{000A}' | '\u{000C}' | '\u{0020}' | '/' | '>' => {
-                        if temp_buf == "script" {
+                        if self.temp_buffer == "script" {
                             self.state = ScriptDataDoubleEscaped;
                         } else {
                             self.state = ScriptDataEscaped;
@@ -778,9 +1266,8 @@ impl Tokenizer {
                     }
                     _ => {
                         if self.chars[self.idx].is_alphabetic() {
-                            temp_buf.push(self.chars[self.idx].to_lowercase().next().unwrap());
                             self.temp_buffer
-                                .push(self.chars[self.idx].to_lowercase().next().unwrap());
+                                .push(self.chars[self.idx].to_ascii_lowercase());
                             emit_token!(self, new_character_string(self.chars[self.idx]));
                         } else {
                             self.state = ScriptDataEscaped;
@@ -798,6 +1285,7 @@ impl Tokenizer {
                         emit_token!(self, new_character_string('<'));
                     }
                     '\u{0000}' => {
+                        self.error(ParseErrorKind::UnexpectedNullCharacter);
                         emit_token!(self, new_character_string('\u{FFFD}'));
                     }
                     _ => {
@@ -814,6 +1302,7 @@ impl Tokenizer {
                         emit_token!(self, new_character_string('<'));
                     }
                     '\u{0000}' => {
+                        self.error(ParseErrorKind::UnexpectedNullCharacter);
                         self.state = ScriptDataDoubleEscaped;
                         emit_token!(self, new_character_string('\u{FFFD}'));
                     }
@@ -835,6 +1324,7 @@ impl Tokenizer {
                         emit_token!(self, new_character_string('>'));
                     }
                     '\u{0000}' => {
+                        self.error(ParseErrorKind::UnexpectedNullCharacter);
                         self.state = ScriptDataDoubleEscaped;
                         emit_token!(self, new_character_string('\u{FFFD}'));
                     }
@@ -845,7 +1335,6 @@ impl Tokenizer {
                 },
                 ScriptDataDoubleEscapedLessThan => match self.chars[self.idx] {
                     '/' => {
-                        temp_buf = String::new();
                         self.temp_buffer = String::new();
                         self.state = ScriptDataDoubleEscapeEnd;
                         emit_token!(self, new_character_string('/'));
@@ -857,7 +1346,7 @@ impl Tokenizer {
                 },
                 ScriptDataDoubleEscapeEnd => match self.chars[self.idx] {
                     '\u{0009}' | '\u{000A}' | '\u{000C}' | '\u{0020}' | '/' | '>' => {
-                        if temp_buf == "script" {
+                        if self.temp_buffer == "script" {
                             self.state = ScriptDataEscaped;
                         } else {
                             self.state = ScriptDataDoubleEscaped;
@@ -866,9 +1355,8 @@ impl Tokenizer {
                     }
                     _ => {
                         if self.chars[self.idx].is_alphabetic() {
-                            temp_buf.push(self.chars[self.idx].to_lowercase().next().unwrap());
                             self.temp_buffer
-                                .push(self.chars[self.idx].to_lowercase().next().unwrap());
+                                .push(self.chars[self.idx].to_ascii_lowercase());
                             emit_token!(self, new_character_string(self.chars[self.idx]));
                         } else {
                             self.state = ScriptDataDoubleEscaped;
@@ -883,43 +1371,39 @@ impl Tokenizer {
                         reconsume!();
                     }
                     '=' => {
-                        self.cur_token.attributes.push(HtmlAttribute::new());
+                        self.error(ParseErrorKind::UnexpectedEqualsSignBeforeAttributeName);
+                        self.start_attribute();
                         self.cur_token
-                            .attributes
-                            .last_mut()
-                            .unwrap()
-                            .name
-                            .push(self.chars[self.idx]);
+                            .append_to_last_attribute_name(self.chars[self.idx]);
                         self.state = AttributeName;
                     }
                     _ => {
-                        self.cur_token.attributes.push(HtmlAttribute::new());
+                        self.start_attribute();
                         self.state = AttributeName;
                         reconsume!();
                     }
                 },
                 AttributeName => match self.chars[self.idx] {
                     '\u{0009}' | '\u{000A}' | '\u{000C}' | '\u{0020}' | '/' | '>' => {
+                        self.check_duplicate_attribute();
                         self.state = AfterAttributeName;
                         reconsume!();
                     }
-                    '=' => self.state = BeforeAttributeValue,
+                    '=' => {
+                        self.check_duplicate_attribute();
+                        self.state = BeforeAttributeValue;
+                    }
                     '\u{0000}' => {
-                        self.cur_token
-                            .attributes
-                            .last_mut()
-                            .unwrap()
-                            .name
-                            .push('\u{FFFD}');
+                        self.error(ParseErrorKind::UnexpectedNullCharacter);
+                        self.cur_token.append_to_last_attribute_name('\u{FFFD}');
                     }
-                    // '"' | '\u{0027}' | '<' => {} // Error but treated as the anything else category
                     _ => {
-                        self.cur_token
-                            .attributes
-                            .last_mut()
-                            .unwrap()
-                            .name
-                            .push(self.chars[self.idx].to_lowercase().next().unwrap());
+                        if matches!(self.chars[self.idx], '"' | '\u{0027}' | '<') {
+                            self.error(ParseErrorKind::UnexpectedCharacterInAttributeName);
+                        }
+                        self.cur_token.append_to_last_attribute_name(
+                            self.chars[self.idx].to_ascii_lowercase(),
+                        );
                     }
                 },
                 AfterAttributeName => match self.chars[self.idx] {
@@ -931,7 +1415,7 @@ impl Tokenizer {
                         emit_token!(self, self.cur_token.clone());
                     }
                     _ => {
-                        self.cur_token.attributes.push(HtmlAttribute::new());
+                        self.start_attribute();
                         self.state = AttributeName;
                         reconsume!();
                     }
@@ -941,6 +1425,7 @@ impl Tokenizer {
                     '"' => self.state = AttributeValueDoubleQuoted,
                     '\u{0027}' => self.state = AttributeValueSingleQuoted,
                     '>' => {
+                        self.error(ParseErrorKind::MissingAttributeValue);
                         self.state = Data;
                         emit_token!(self, self.cur_token.clone());
                     }
@@ -956,43 +1441,27 @@ impl Tokenizer {
                         self.state = CharacterReference;
                     }
                     '\u{0000}' => {
-                        self.cur_token
-                            .attributes
-                            .last_mut()
-                            .unwrap()
-                            .value
-                            .push('\u{FFFD}');
+                        self.error(ParseErrorKind::UnexpectedNullCharacter);
+                        self.cur_token.append_to_last_attribute('\u{FFFD}');
                     }
                     _ => {
                         self.cur_token
-                            .attributes
-                            .last_mut()
-                            .unwrap()
-                            .value
-                            .push(self.chars[self.idx].to_lowercase().next().unwrap());
+                            .append_to_last_attribute(self.chars[self.idx]);
                     }
                 },
                 AttributeValueSingleQuoted => match self.chars[self.idx] {
                     '\u{0027}' => self.state = AfterAttributeValueQuoted,
                     '&' => {
-                        self.return_state = AttributeValueDoubleQuoted;
+                        self.return_state = AttributeValueSingleQuoted;
                         self.state = CharacterReference;
                     }
                     '\u{0000}' => {
-                        self.cur_token
-                            .attributes
-                            .last_mut()
-                            .unwrap()
-                            .value
-                            .push('\u{FFFD}');
+                        self.error(ParseErrorKind::UnexpectedNullCharacter);
+                        self.cur_token.append_to_last_attribute('\u{FFFD}');
                     }
                     _ => {
                         self.cur_token
-                            .attributes
-                            .last_mut()
-                            .unwrap()
-                            .value
-                            .push(self.chars[self.idx].to_lowercase().next().unwrap());
+                            .append_to_last_attribute(self.chars[self.idx]);
                     }
                 },
                 AttributeValueUnquoted => match self.chars[self.idx] {
@@ -1008,21 +1477,15 @@ impl Tokenizer {
                         emit_token!(self, self.cur_token.clone());
                     }
                     '\u{0000}' => {
-                        self.cur_token
-                            .attributes
-                            .last_mut()
-                            .unwrap()
-                            .value
-                            .push('\u{FFFD}');
+                        self.error(ParseErrorKind::UnexpectedNullCharacter);
+                        self.cur_token.append_to_last_attribute('\u{FFFD}');
                     }
-                    // '"' | '\u{0027}' | '<' | '=' | '`' => {} // Error, treat as anything else
                     _ => {
+                        if matches!(self.chars[self.idx], '"' | '\u{0027}' | '<' | '=' | '`') {
+                            self.error(ParseErrorKind::UnexpectedCharacterInUnquotedAttributeValue);
+                        }
                         self.cur_token
-                            .attributes
-                            .last_mut()
-                            .unwrap()
-                            .value
-                            .push(self.chars[self.idx].to_lowercase().next().unwrap());
+                            .append_to_last_attribute(self.chars[self.idx]);
                     }
                 },
                 AfterAttributeValueQuoted => match self.chars[self.idx] {
@@ -1035,6 +1498,7 @@ impl Tokenizer {
                         emit_token!(self, self.cur_token.clone());
                     }
                     _ => {
+                        self.error(ParseErrorKind::MissingWhitespaceBetweenAttributes);
                         self.state = BeforeAttributeName;
                         reconsume!();
                     }
@@ -1047,6 +1511,7 @@ impl Tokenizer {
                         emit_token!(self, self.cur_token.clone());
                     }
                     _ => {
+                        self.error(ParseErrorKind::UnexpectedSolidusInTag);
                         self.state = BeforeAttributeName;
                         reconsume!();
                     }
@@ -1057,6 +1522,7 @@ impl Tokenizer {
                         emit_token!(self, self.cur_token.clone());
                     }
                     '\u{0000}' => {
+                        self.error(ParseErrorKind::UnexpectedNullCharacter);
                         self.cur_token.data.push('\u{FFFD}');
                     }
                     _ => {
@@ -1064,41 +1530,42 @@ impl Tokenizer {
                     }
                 },
                 MarkupDeclarationOpen => {
-                    if self.idx + 1 < self.chars.len()
-                        && compare_slices(&self.chars[self.idx..self.idx + 2], &['-', '-'], true)
-                    {
+                    if self.needs_more_input(7) {
+                        return;
+                    }
+                    // Matched characters are consumed here, so the next state starts without
+                    // advancing past another character
+                    if self.next_chars_match("--", true) {
                         self.idx += 2;
                         self.cur_token = HtmlToken::new(TokenTag::Comment);
                         self.state = CommentStart;
-                    } else if self.idx + 7 < self.chars.len()
-                        && compare_slices(
-                            &self.chars[self.idx..self.idx + 7],
-                            &['d', 'o', 'c', 't', 'y', 'p', 'e'],
-                            false,
-                        )
-                    {
+                        continue;
+                    } else if self.next_chars_match("doctype", false) {
                         self.idx += 7;
                         self.state = Doctype;
-                    } else if self.idx + 7 < self.chars.len()
-                        && compare_slices(
-                            &self.chars[self.idx..self.idx + 8],
-                            &['[', 'C', 'D', 'A', 'T', 'a', '['],
-                            true,
-                        )
-                    {
-                        self.idx += 8;
-                        // TODO: Check if there is an adjusted current node
-                        //       and it's not an element in HTML namespace.
-                        //       If not, create a comment instead
-                        self.state = CDataSection;
+                        continue;
+                    } else if self.next_chars_match("[CDATA[", true) {
+                        self.idx += 7;
+                        if self.allow_cdata {
+                            self.state = CDataSection;
+                        } else {
+                            self.error(ParseErrorKind::CdataInHtmlContent);
+                            self.cur_token = HtmlToken::new(TokenTag::Comment);
+                            self.cur_token.data = "[CDATA[".to_string();
+                            self.state = BogusComment;
+                        }
+                        continue;
                     } else {
+                        self.error(ParseErrorKind::IncorrectlyOpenedComment);
                         self.cur_token = HtmlToken::new(TokenTag::Comment);
                         self.state = BogusComment;
+                        continue;
                     }
                 }
                 CommentStart => match self.chars[self.idx] {
                     '-' => self.state = CommentStartDash,
                     '>' => {
+                        self.error(ParseErrorKind::AbruptClosingOfEmptyComment);
                         self.state = Data;
                         emit_token!(self, self.cur_token.clone());
                     }
@@ -1110,6 +1577,7 @@ impl Tokenizer {
                 CommentStartDash => match self.chars[self.idx] {
                     '-' => self.state = CommentEnd,
                     '>' => {
+                        self.error(ParseErrorKind::AbruptClosingOfEmptyComment);
                         self.state = Data;
                         emit_token!(self, self.cur_token.clone());
                     }
@@ -1125,7 +1593,10 @@ impl Tokenizer {
                         self.state = CommentLessThan;
                     }
                     '-' => self.state = CommentEndDash,
-                    '\u{0000}' => self.cur_token.data_append('\u{FFFD}'),
+                    '\u{0000}' => {
+                        self.error(ParseErrorKind::UnexpectedNullCharacter);
+                        self.cur_token.data_append('\u{FFFD}');
+                    }
                     _ => self.cur_token.data_append(self.chars[self.idx]),
                 },
                 CommentLessThan => match self.chars[self.idx] {
@@ -1154,6 +1625,9 @@ impl Tokenizer {
                     }
                 },
                 CommentLessThanBangDashDash => {
+                    if self.chars[self.idx] != '>' {
+                        self.error(ParseErrorKind::NestedComment);
+                    }
                     self.state = CommentEnd;
                     reconsume!();
                 }
@@ -1185,6 +1659,7 @@ impl Tokenizer {
                         self.state = CommentEndDash;
                     }
                     '>' => {
+                        self.error(ParseErrorKind::IncorrectlyClosedComment);
                         self.state = Data;
                         emit_token!(self, self.cur_token.clone());
                     }
@@ -1199,7 +1674,12 @@ impl Tokenizer {
                     '\u{0009}' | '\u{000A}' | '\u{000C}' | '\u{0020}' => {
                         self.state = BeforeDoctypeName
                     }
+                    '>' => {
+                        self.state = BeforeDoctypeName;
+                        reconsume!();
+                    }
                     _ => {
+                        self.error(ParseErrorKind::MissingWhitespaceBeforeDoctypeName);
                         self.state = BeforeDoctypeName;
                         reconsume!();
                     }
@@ -1207,11 +1687,13 @@ impl Tokenizer {
                 BeforeDoctypeName => match self.chars[self.idx] {
                     '\u{0009}' | '\u{000A}' | '\u{000C}' | '\u{0020}' => {}
                     '\u{0000}' => {
+                        self.error(ParseErrorKind::UnexpectedNullCharacter);
                         self.cur_token = HtmlToken::new(TokenTag::Doctype(DoctypeData::new()));
                         self.cur_token.data_append('\u{FFFD}');
                         self.state = DoctypeName;
                     }
                     '>' => {
+                        self.error(ParseErrorKind::MissingDoctypeName);
                         self.cur_token = HtmlToken::new(TokenTag::Doctype(DoctypeData::new()));
                         self.cur_token.flags.force_quirks = true;
                         self.state = Data;
@@ -1220,20 +1702,25 @@ impl Tokenizer {
                     _ => {
                         self.cur_token = HtmlToken::new(TokenTag::Doctype(DoctypeData::new()));
                         self.cur_token
-                            .data_append(self.chars[self.idx].to_lowercase().next().unwrap());
+                            .data_append(self.chars[self.idx].to_ascii_lowercase());
                         self.state = DoctypeName;
                     }
                 },
                 DoctypeName => match self.chars[self.idx] {
-                    '\u{0009}' | '\u{000A}' | '\u{000C}' | '\u{0020}' => self.state = AfterDoctype,
+                    '\u{0009}' | '\u{000A}' | '\u{000C}' | '\u{0020}' => {
+                        self.state = AfterDoctypeName
+                    }
                     '>' => {
                         self.state = Data;
                         emit_token!(self, self.cur_token.clone());
                     }
-                    '\u{0000}' => self.cur_token.data_append('\u{FFFD}'),
+                    '\u{0000}' => {
+                        self.error(ParseErrorKind::UnexpectedNullCharacter);
+                        self.cur_token.data_append('\u{FFFD}');
+                    }
                     _ => self
                         .cur_token
-                        .data_append(self.chars[self.idx].to_lowercase().next().unwrap()),
+                        .data_append(self.chars[self.idx].to_ascii_lowercase()),
                 },
                 AfterDoctypeName => match self.chars[self.idx] {
                     '\u{0009}' | '\u{000A}' | '\u{000C}' | '\u{0020}' => {}
@@ -1242,30 +1729,24 @@ impl Tokenizer {
                         emit_token!(self, self.cur_token.clone());
                     }
                     _ => {
-                        if self.idx + 6 < self.chars.len()
-                            && compare_slices(
-                                &self.chars[self.idx..self.idx + 7],
-                                &['p', 'u', 'b', 'l', 'i', 'c'],
-                                false,
-                            )
-                        {
-                            self.idx += 7;
+                        if self.needs_more_input(6) {
+                            return;
+                        }
+                        // Keywords are consumed here, so the next state starts on the following character
+                        if self.next_chars_match("public", false) {
+                            self.idx += 6;
                             self.state = AfterDoctypePublicKeyword;
                             reconsume!();
                         }
-                        if self.idx + 6 < self.chars.len()
-                            && compare_slices(
-                                &self.chars[self.idx..self.idx + 7],
-                                &['s', 'y', 's', 't', 'e', 'm'],
-                                false,
-                            )
-                        {
-                            self.idx += 7;
+                        if self.next_chars_match("system", false) {
+                            self.idx += 6;
+                            self.state = AfterDoctypeSystemKeyword;
                             reconsume!();
                         }
 
+                        self.error(ParseErrorKind::InvalidCharacterSequenceAfterDoctypeName);
                         self.cur_token.flags.force_quirks = true;
-                        self.state = BogusComment;
+                        self.state = BogusDoctype;
                         reconsume!();
                     }
                 },
@@ -1273,34 +1754,24 @@ impl Tokenizer {
                     '\u{0009}' | '\u{000A}' | '\u{000C}' | '\u{0020}' => {
                         self.state = BeforeDoctypePublicIndentifier;
                     }
-                    '"' => match self.cur_token.tag {
-                        TokenTag::Doctype(ref mut val) => {
-                            val.public_identifier = Some(String::new());
-                            self.state = DoctypePublicIdentifierDoubleQuote;
-                        }
-                        _ => {
-                            // TODO: This is fine as a panic for now,
-                            //       but we need better error handling
-                            panic!("HTML Tokenizer ")
-                        }
-                    },
-                    '\u{0027}' => match self.cur_token.tag {
-                        TokenTag::Doctype(ref mut val) => {
-                            val.public_identifier = Some(String::new());
-                            self.state = DoctypePublicIdentifierSingleQuote;
-                        }
-                        _ => {
-                            // TODO: This is fine as a panic for now,
-                            //       but we need better error handling
-                            panic!("HTML Tokenizer ")
-                        }
-                    },
+                    '"' => {
+                        self.error(ParseErrorKind::MissingWhitespaceAfterDoctypePublicKeyword);
+                        self.cur_token.start_public_identifier();
+                        self.state = DoctypePublicIdentifierDoubleQuote;
+                    }
+                    '\u{0027}' => {
+                        self.error(ParseErrorKind::MissingWhitespaceAfterDoctypePublicKeyword);
+                        self.cur_token.start_public_identifier();
+                        self.state = DoctypePublicIdentifierSingleQuote;
+                    }
                     '>' => {
+                        self.error(ParseErrorKind::MissingDoctypePublicIdentifier);
                         self.cur_token.flags.force_quirks = true;
                         self.state = Data;
                         emit_token!(self, self.cur_token.clone());
                     }
                     _ => {
+                        self.error(ParseErrorKind::MissingQuoteBeforeDoctypePublicIdentifier);
                         self.cur_token.flags.force_quirks = true;
                         self.state = BogusDoctype;
                         reconsume!();
@@ -1308,34 +1779,22 @@ impl Tokenizer {
                 },
                 BeforeDoctypePublicIndentifier => match self.chars[self.idx] {
                     '\u{0009}' | '\u{000A}' | '\u{000C}' | '\u{0020}' => {} // Ignore
-                    '"' => match self.cur_token.tag {
-                        TokenTag::Doctype(ref mut val) => {
-                            val.public_identifier = Some(String::new());
-                            self.state = DoctypePublicIdentifierDoubleQuote;
-                        }
-                        _ => {
-                            // TODO: This is fine as a panic for now,
-                            //       but we need better error handling
-                            panic!("HTML Tokenizer ")
-                        }
-                    },
-                    '\u{0027}' => match self.cur_token.tag {
-                        TokenTag::Doctype(ref mut val) => {
-                            val.public_identifier = Some(String::new());
-                            self.state = DoctypePublicIdentifierSingleQuote;
-                        }
-                        _ => {
-                            // TODO: This is fine as a panic for now,
-                            //       but we need better error handling
-                            panic!("HTML Tokenizer ")
-                        }
-                    },
+                    '"' => {
+                        self.cur_token.start_public_identifier();
+                        self.state = DoctypePublicIdentifierDoubleQuote;
+                    }
+                    '\u{0027}' => {
+                        self.cur_token.start_public_identifier();
+                        self.state = DoctypePublicIdentifierSingleQuote;
+                    }
                     '>' => {
+                        self.error(ParseErrorKind::MissingDoctypePublicIdentifier);
                         self.cur_token.flags.force_quirks = true;
                         self.state = Data;
                         emit_token!(self, self.cur_token.clone());
                     }
                     _ => {
+                        self.error(ParseErrorKind::MissingQuoteBeforeDoctypePublicIdentifier);
                         self.cur_token.flags.force_quirks = true;
                         self.state = BogusDoctype;
                         reconsume!();
@@ -1343,95 +1802,39 @@ impl Tokenizer {
                 },
                 DoctypePublicIdentifierDoubleQuote => match self.chars[self.idx] {
                     '"' => self.state = AfterDoctypePublicIdentifier,
-                    '\u{0000}' => match self.cur_token.tag {
-                        TokenTag::Doctype(ref mut val) => {
-                            match val.public_identifier {
-                                Some(ref mut s) => {
-                                    s.push('\u{FFFD}');
-                                }
-                                None => {
-                                    // TODO: Correct with error handling
-                                    panic!("In DoctypePublicIdentifierDoubleQuote without public identifier created");
-                                }
-                            }
-                            self.state = DoctypePublicIdentifierDoubleQuote;
-                        }
-                        _ => {
-                            // TODO: This is fine as a panic for now,
-                            //       but we need better error handling
-                            panic!("HTML Tokenizer ")
-                        }
-                    },
+                    '\u{0000}' => {
+                        self.error(ParseErrorKind::UnexpectedNullCharacter);
+                        self.cur_token.append_to_public_identifier('\u{FFFD}');
+                        self.state = DoctypePublicIdentifierDoubleQuote;
+                    }
                     '>' => {
+                        self.error(ParseErrorKind::AbruptDoctypePublicIdentifier);
                         self.cur_token.flags.force_quirks = true;
                         self.state = Data;
                         emit_token!(self, self.cur_token.clone());
                     }
-                    _ => match self.cur_token.tag {
-                        TokenTag::Doctype(ref mut val) => {
-                            match val.public_identifier {
-                                Some(ref mut s) => {
-                                    s.push(self.chars[self.idx]);
-                                }
-                                None => {
-                                    // TODO: Correct with error handling
-                                    panic!("In DoctypePublicIdentifierDoubleQuote without public identifier created");
-                                }
-                            }
-                            self.state = DoctypePublicIdentifierDoubleQuote;
-                        }
-                        _ => {
-                            // TODO: This is fine as a panic for now,
-                            //       but we need better error handling
-                            panic!("HTML Tokenizer ")
-                        }
-                    },
+                    _ => {
+                        self.cur_token
+                            .append_to_public_identifier(self.chars[self.idx]);
+                        self.state = DoctypePublicIdentifierDoubleQuote;
+                    }
                 },
                 DoctypePublicIdentifierSingleQuote => match self.chars[self.idx] {
                     '\u{0027}' => self.state = AfterDoctypePublicIdentifier,
-                    '\u{0000}' => match self.cur_token.tag {
-                        TokenTag::Doctype(ref mut val) => {
-                            match val.public_identifier {
-                                Some(ref mut s) => {
-                                    s.push('\u{FFFD}');
-                                }
-                                None => {
-                                    // TODO: Correct with error handling
-                                    panic!("In DoctypePublicIdentifierSingleQuote without public identifier created");
-                                }
-                            }
-                            self.state = DoctypePublicIdentifierDoubleQuote;
-                        }
-                        _ => {
-                            // TODO: This is fine as a panic for now,
-                            //       but we need better error handling
-                            panic!("HTML Tokenizer ")
-                        }
-                    },
+                    '\u{0000}' => {
+                        self.error(ParseErrorKind::UnexpectedNullCharacter);
+                        self.cur_token.append_to_public_identifier('\u{FFFD}');
+                    }
                     '>' => {
+                        self.error(ParseErrorKind::AbruptDoctypePublicIdentifier);
                         self.cur_token.flags.force_quirks = true;
                         self.state = Data;
                         emit_token!(self, self.cur_token.clone());
                     }
-                    _ => match self.cur_token.tag {
-                        TokenTag::Doctype(ref mut val) => {
-                            match val.public_identifier {
-                                Some(ref mut s) => {
-                                    s.push(self.chars[self.idx]);
-                                }
-                                None => {
-                                    // TODO: Correct with error handling
-                                    panic!("In DoctypePublicIdentifierSingleQuote without public identifier created");
-                                }
-                            }
-                            self.state = DoctypePublicIdentifierDoubleQuote;
-                        }
-                        _ => {
-                            // TODO: This is fine as a panic for now,
-                            //       but we need better error handling
-                            panic!("HTML Tokenizer ")
-                        }
-                    },
+                    _ => {
+                        self.cur_token
+                            .append_to_public_identifier(self.chars[self.idx]);
+                    }
                 },
                 AfterDoctypePublicIdentifier => match self.chars[self.idx] {
                     '\u{0009}' | '\u{000A}' | '\u{000C}' | '\u{0020}' => {
@@ -1441,29 +1844,18 @@ impl Tokenizer {
                         self.state = Data;
                         emit_token!(self, self.cur_token.clone());
                     }
-                    '"' => match self.cur_token.tag {
-                        TokenTag::Doctype(ref mut val) => {
-                            val.system = Some(String::new());
-                            self.state = DoctypeSystemIdentifierDoubleQuote;
-                        }
-                        _ => {
-                            // TODO: This is fine as a panic for now,
-                            //       but we need better error handling
-                            panic!("HTML Tokenizer ")
-                        }
-                    },
-                    '\u{0027}' => match self.cur_token.tag {
-                        TokenTag::Doctype(ref mut val) => {
-                            val.system = Some(String::new());
-                            self.state = DoctypeSystemIdentifierSingleQuote;
-                        }
-                        _ => {
-                            // TODO: This is fine as a panic for now,
-                            //       but we need better error handling
-                            panic!("HTML Tokenizer ")
-                        }
-                    },
+                    '"' => {
+                        self.error(ParseErrorKind::MissingWhitespaceBetweenDoctypePublicAndSystemIdentifiers);
+                        self.cur_token.start_system_identifier();
+                        self.state = DoctypeSystemIdentifierDoubleQuote;
+                    }
+                    '\u{0027}' => {
+                        self.error(ParseErrorKind::MissingWhitespaceBetweenDoctypePublicAndSystemIdentifiers);
+                        self.cur_token.start_system_identifier();
+                        self.state = DoctypeSystemIdentifierSingleQuote;
+                    }
                     _ => {
+                        self.error(ParseErrorKind::MissingQuoteBeforeDoctypeSystemIdentifier);
                         self.cur_token.flags.force_quirks = true;
                         self.state = BogusDoctype;
                         reconsume!();
@@ -1475,29 +1867,16 @@ impl Tokenizer {
                         self.state = Data;
                         emit_token!(self, self.cur_token.clone());
                     }
-                    '"' => match self.cur_token.tag {
-                        TokenTag::Doctype(ref mut val) => {
-                            val.system = Some(String::new());
-                            self.state = DoctypeSystemIdentifierDoubleQuote;
-                        }
-                        _ => {
-                            // TODO: This is fine as a panic for now,
-                            //       but we need better error handling
-                            panic!("HTML Tokenizer ")
-                        }
-                    },
-                    '\u{0027}' => match self.cur_token.tag {
-                        TokenTag::Doctype(ref mut val) => {
-                            val.system = Some(String::new());
-                            self.state = DoctypeSystemIdentifierSingleQuote;
-                        }
-                        _ => {
-                            // TODO: This is fine as a panic for now,
-                            //       but we need better error handling
-                            panic!("HTML Tokenizer ")
-                        }
-                    },
+                    '"' => {
+                        self.cur_token.start_system_identifier();
+                        self.state = DoctypeSystemIdentifierDoubleQuote;
+                    }
+                    '\u{0027}' => {
+                        self.cur_token.start_system_identifier();
+                        self.state = DoctypeSystemIdentifierSingleQuote;
+                    }
                     _ => {
+                        self.error(ParseErrorKind::MissingQuoteBeforeDoctypeSystemIdentifier);
                         self.cur_token.flags.force_quirks = true;
                         self.state = BogusDoctype;
                         reconsume!();
@@ -1507,34 +1886,24 @@ impl Tokenizer {
                     '\u{0009}' | '\u{000A}' | '\u{000C}' | '\u{0020}' => {
                         self.state = BeforeDoctypeSystemIdentifier;
                     }
-                    '"' => match self.cur_token.tag {
-                        TokenTag::Doctype(ref mut val) => {
-                            val.system = Some(String::new());
-                            self.state = DoctypeSystemIdentifierDoubleQuote;
-                        }
-                        _ => {
-                            // TODO: This is fine as a panic for now,
-                            //       but we need better error handling
-                            panic!("HTML Tokenizer ")
-                        }
-                    },
-                    '\u{0027}' => match self.cur_token.tag {
-                        TokenTag::Doctype(ref mut val) => {
-                            val.system = Some(String::new());
-                            self.state = DoctypeSystemIdentifierSingleQuote;
-                        }
-                        _ => {
-                            // TODO: This is fine as a panic for now,
-                            //       but we need better error handling
-                            panic!("HTML Tokenizer ")
-                        }
-                    },
+                    '"' => {
+                        self.error(ParseErrorKind::MissingWhitespaceAfterDoctypeSystemKeyword);
+                        self.cur_token.start_system_identifier();
+                        self.state = DoctypeSystemIdentifierDoubleQuote;
+                    }
+                    '\u{0027}' => {
+                        self.error(ParseErrorKind::MissingWhitespaceAfterDoctypeSystemKeyword);
+                        self.cur_token.start_system_identifier();
+                        self.state = DoctypeSystemIdentifierSingleQuote;
+                    }
                     '>' => {
+                        self.error(ParseErrorKind::MissingDoctypeSystemIdentifier);
                         self.cur_token.flags.force_quirks = true;
                         self.state = Data;
                         emit_token!(self, self.cur_token.clone());
                     }
                     _ => {
+                        self.error(ParseErrorKind::MissingQuoteBeforeDoctypeSystemIdentifier);
                         self.cur_token.flags.force_quirks = true;
                         self.state = BogusDoctype;
                         reconsume!();
@@ -1542,34 +1911,22 @@ impl Tokenizer {
                 },
                 BeforeDoctypeSystemIdentifier => match self.chars[self.idx] {
                     '\u{0009}' | '\u{000A}' | '\u{000C}' | '\u{0020}' => {}
-                    '"' => match self.cur_token.tag {
-                        TokenTag::Doctype(ref mut val) => {
-                            val.system = Some(String::new());
-                            self.state = DoctypeSystemIdentifierDoubleQuote;
-                        }
-                        _ => {
-                            // TODO: This is fine as a panic for now,
-                            //       but we need better error handling
-                            panic!("HTML Tokenizer ")
-                        }
-                    },
-                    '\u{0027}' => match self.cur_token.tag {
-                        TokenTag::Doctype(ref mut val) => {
-                            val.system = Some(String::new());
-                            self.state = DoctypeSystemIdentifierSingleQuote;
-                        }
-                        _ => {
-                            // TODO: This is fine as a panic for now,
-                            //       but we need better error handling
-                            panic!("HTML Tokenizer ")
-                        }
-                    },
+                    '"' => {
+                        self.cur_token.start_system_identifier();
+                        self.state = DoctypeSystemIdentifierDoubleQuote;
+                    }
+                    '\u{0027}' => {
+                        self.cur_token.start_system_identifier();
+                        self.state = DoctypeSystemIdentifierSingleQuote;
+                    }
                     '>' => {
+                        self.error(ParseErrorKind::MissingDoctypeSystemIdentifier);
                         self.cur_token.flags.force_quirks = true;
                         self.state = Data;
                         emit_token!(self, self.cur_token.clone());
                     }
                     _ => {
+                        self.error(ParseErrorKind::MissingQuoteBeforeDoctypeSystemIdentifier);
                         self.cur_token.flags.force_quirks = true;
                         self.state = BogusDoctype;
                         reconsume!();
@@ -1577,71 +1934,37 @@ impl Tokenizer {
                 },
                 DoctypeSystemIdentifierDoubleQuote => match self.chars[self.idx] {
                     '"' => self.state = AfterDoctypeSystemIdentifier,
-                    '\u{0000}' => match self.cur_token.tag {
-                        TokenTag::Doctype(ref mut val) => {
-                            match val.system {
-                                Some(ref mut s) => s.push('\u{FFFD}'),
-                                None => panic!("In DoctypeSystemIdentifier without system set"),
-                            };
-                        }
-                        _ => {
-                            // TODO: This is fine as a panic for now,
-                            //       but we need better error handling
-                            panic!("HTML Tokenizer ")
-                        }
-                    },
+                    '\u{0000}' => {
+                        self.error(ParseErrorKind::UnexpectedNullCharacter);
+                        self.cur_token.append_to_system_identifier('\u{FFFD}');
+                    }
                     '>' => {
+                        self.error(ParseErrorKind::AbruptDoctypeSystemIdentifier);
                         self.cur_token.flags.force_quirks = true;
                         self.state = Data;
                         emit_token!(self, self.cur_token.clone());
                     }
-                    _ => match self.cur_token.tag {
-                        TokenTag::Doctype(ref mut val) => {
-                            match val.system {
-                                Some(ref mut s) => s.push(self.chars[self.idx]),
-                                None => panic!("In DoctypeSystemIdentifier without system set"),
-                            };
-                        }
-                        _ => {
-                            // TODO: This is fine as a panic for now,
-                            //       but we need better error handling
-                            panic!("HTML Tokenizer ")
-                        }
-                    },
+                    _ => {
+                        self.cur_token
+                            .append_to_system_identifier(self.chars[self.idx]);
+                    }
                 },
                 DoctypeSystemIdentifierSingleQuote => match self.chars[self.idx] {
                     '\u{0027}' => self.state = AfterDoctypeSystemIdentifier,
-                    '\u{0000}' => match self.cur_token.tag {
-                        TokenTag::Doctype(ref mut val) => {
-                            match val.system {
-                                Some(ref mut s) => s.push('\u{FFFD}'),
-                                None => panic!("In DoctypeSystemIdentifier without system set"),
-                            };
-                        }
-                        _ => {
-                            // TODO: This is fine as a panic for now,
-                            //       but we need better error handling
-                            panic!("HTML Tokenizer ")
-                        }
-                    },
+                    '\u{0000}' => {
+                        self.error(ParseErrorKind::UnexpectedNullCharacter);
+                        self.cur_token.append_to_system_identifier('\u{FFFD}');
+                    }
                     '>' => {
+                        self.error(ParseErrorKind::AbruptDoctypeSystemIdentifier);
                         self.cur_token.flags.force_quirks = true;
                         self.state = Data;
                         emit_token!(self, self.cur_token.clone());
                     }
-                    _ => match self.cur_token.tag {
-                        TokenTag::Doctype(ref mut val) => {
-                            match val.system {
-                                Some(ref mut s) => s.push(self.chars[self.idx]),
-                                None => panic!("In DoctypeSystemIdentifier without system set"),
-                            };
-                        }
-                        _ => {
-                            // TODO: This is fine as a panic for now,
-                            //       but we need better error handling
-                            panic!("HTML Tokenizer ")
-                        }
-                    },
+                    _ => {
+                        self.cur_token
+                            .append_to_system_identifier(self.chars[self.idx]);
+                    }
                 },
                 AfterDoctypeSystemIdentifier => match self.chars[self.idx] {
                     '\u{0009}' | '\u{000A}' | '\u{000C}' | '\u{0020}' => {}
@@ -1650,6 +1973,7 @@ impl Tokenizer {
                         emit_token!(self, self.cur_token.clone());
                     }
                     _ => {
+                        self.error(ParseErrorKind::UnexpectedCharacterAfterDoctypeSystemIdentifier);
                         self.state = BogusDoctype;
                         reconsume!();
                     }
@@ -1659,6 +1983,7 @@ impl Tokenizer {
                         self.state = Data;
                         emit_token!(self, self.cur_token.clone());
                     }
+                    '\u{0000}' => self.error(ParseErrorKind::UnexpectedNullCharacter),
                     _ => {}
                 },
                 CDataSection => match self.chars[self.idx] {
@@ -1686,37 +2011,78 @@ impl Tokenizer {
                     }
                 },
                 CharacterReference => {
-                    temp_buf = "&".to_string();
+                    self.temp_buffer = "&".to_string();
                     match self.chars[self.idx] {
                         '#' => {
-                            temp_buf.push(self.chars[self.idx]);
                             self.temp_buffer.push(self.chars[self.idx]);
                             self.state = NumericCharacterReference;
                         }
                         _ => {
-                            if self.chars[self.idx].is_alphanumeric() {
+                            if self.chars[self.idx].is_ascii_alphanumeric() {
                                 self.state = NamedCharacterReference;
                                 reconsume!();
                             } else {
-                                if is_part_of_attribute(self.return_state) {
-                                    temp_buf
-                                        .chars()
-                                        .for_each(|c| self.cur_token.append_to_last_attribute(c));
-                                } else {
-                                    temp_buf.chars().for_each(|c| {
-                                        emit_token_no_return!(self, new_character_string(c));
-                                    });
-                                }
-
+                                self.flush_character_reference();
                                 self.state = self.return_state;
                                 reconsume!();
                             }
                         }
                     }
                 }
-                // NamedCharacterReference => {}
+                NamedCharacterReference => {
+                    // The longest match, and the character after it, have to be in the buffer
+                    if !self.input_finished
+                        && self.chars[self.idx..]
+                            .iter()
+                            .take(named_character_references::LONGEST_NAME_LENGTH + 1)
+                            .all(|c| c.is_ascii_alphanumeric())
+                        && self
+                            .needs_more_input(named_character_references::LONGEST_NAME_LENGTH + 1)
+                    {
+                        return;
+                    }
+                    match named_character_references::match_named_character_reference(
+                        &self.chars[self.idx..],
+                    ) {
+                        Some((len, replacement)) => {
+                            let name: String =
+                                self.chars[self.idx..self.idx + len].iter().collect();
+                            self.idx += len;
+                            self.temp_buffer.push_str(&name);
+
+                            // Legacy references without a semicolon are left alone in attributes
+                            // when followed by something that looks like more of a url parameter
+                            let ends_with_semicolon = name.ends_with(';');
+                            let next_is_ambiguous = match self.chars.get(self.idx) {
+                                Some(c) => *c == '=' || c.is_ascii_alphanumeric(),
+                                None => false,
+                            };
+                            if is_part_of_attribute(self.return_state)
+                                && !ends_with_semicolon
+                                && next_is_ambiguous
+                            {
+                                self.flush_character_reference();
+                            } else {
+                                if !ends_with_semicolon {
+                                    self.error(
+                                        ParseErrorKind::MissingSemicolonAfterCharacterReference,
+                                    );
+                                }
+                                self.temp_buffer = replacement.to_string();
+                                self.flush_character_reference();
+                            }
+                            self.state = self.return_state;
+                            reconsume!();
+                        }
+                        None => {
+                            self.flush_character_reference();
+                            self.state = AmbiguousAmpersand;
+                            reconsume!();
+                        }
+                    }
+                }
                 AmbiguousAmpersand => {
-                    if self.chars[self.idx].is_alphanumeric() {
+                    if self.chars[self.idx].is_ascii_alphanumeric() {
                         if is_part_of_attribute(self.return_state) {
                             self.cur_token
                                 .append_to_last_attribute(self.chars[self.idx]);
@@ -1724,6 +2090,9 @@ impl Tokenizer {
                             emit_token!(self, new_character_string(self.chars[self.idx]));
                         }
                     } else {
+                        if self.chars[self.idx] == ';' {
+                            self.error(ParseErrorKind::UnknownNamedCharacterReference);
+                        }
                         self.state = self.return_state;
                         reconsume!();
                     }
@@ -1732,7 +2101,6 @@ impl Tokenizer {
                     self.character_reference_code = 0;
                     match self.chars[self.idx] {
                         'x' | 'X' => {
-                            temp_buf.push(self.chars[self.idx]);
                             self.temp_buffer.push(self.chars[self.idx]);
                             self.state = HexadecimalCharacterReferenceStart;
                         }
@@ -1748,15 +2116,8 @@ impl Tokenizer {
                         reconsume!();
                     }
                     _ => {
-                        if is_part_of_attribute(self.return_state) {
-                            temp_buf
-                                .chars()
-                                .for_each(|c| self.cur_token.append_to_last_attribute(c));
-                        } else {
-                            temp_buf.chars().for_each(|c| {
-                                emit_token_no_return!(self, new_character_string(c));
-                            });
-                        }
+                        self.error(ParseErrorKind::AbsenceOfDigitsInNumericCharacterReference);
+                        self.flush_character_reference();
                         self.state = self.return_state;
                         reconsume!();
                     }
@@ -1767,107 +2128,45 @@ impl Tokenizer {
                         reconsume!();
                     }
                     _ => {
-                        if is_part_of_attribute(self.return_state) {
-                            temp_buf
-                                .chars()
-                                .for_each(|c| self.cur_token.append_to_last_attribute(c));
-                        } else {
-                            temp_buf.chars().for_each(|c| {
-                                emit_token_no_return!(self, new_character_string(c));
-                            });
-                        }
+                        self.error(ParseErrorKind::AbsenceOfDigitsInNumericCharacterReference);
+                        self.flush_character_reference();
                         self.state = self.return_state;
                         reconsume!();
                     }
                 },
                 HexadecimalCharacterReference => match self.chars[self.idx] {
                     '0'..='9' | 'A'..='F' | 'a'..='f' => {
-                        self.character_reference_code *= 16;
-                        self.character_reference_code += self.chars[self.idx].to_digit(16).unwrap();
+                        // Saturate so absurdly long references stay out of range instead of overflowing
+                        self.character_reference_code = self
+                            .character_reference_code
+                            .saturating_mul(16)
+                            .saturating_add(self.chars[self.idx].to_digit(16).unwrap_or(0));
                     }
                     ';' => self.state = NumericCharacterReferenceEnd,
                     _ => {
+                        self.error(ParseErrorKind::MissingSemicolonAfterCharacterReference);
                         self.state = NumericCharacterReferenceEnd;
                         reconsume!();
                     }
                 },
                 DecimalCharacterReference => match self.chars[self.idx] {
                     '0'..='9' => {
-                        self.character_reference_code *= 16;
-                        self.character_reference_code += self.chars[self.idx].to_digit(10).unwrap();
+                        self.character_reference_code = self
+                            .character_reference_code
+                            .saturating_mul(10)
+                            .saturating_add(self.chars[self.idx].to_digit(10).unwrap_or(0));
                     }
                     ';' => self.state = NumericCharacterReferenceEnd,
                     _ => {
+                        self.error(ParseErrorKind::MissingSemicolonAfterCharacterReference);
                         self.state = NumericCharacterReferenceEnd;
                         reconsume!();
                     }
                 },
                 NumericCharacterReferenceEnd => {
-                    match self.character_reference_code {
-                        // Null check
-                        0x00 |
-                        // Surrogate check
-                        0xD800..=0xDBFF | 0xDC00..=0xDFFF | 0x10FFFF.. => {
-                            self.character_reference_code = 0xFFFD;
-                        }
-                        // // NonCharacter Check
-                        // 0xFDD0..=0xFDEF
-                        // | 0xFFFE
-                        // | 0xFFFF
-                        // | 0x1FFFE
-                        // | 0x1FFFF
-                        // | 0x2FFFF
-                        // | 0x3FFFE
-                        // | 0x3FFFF
-                        // | 0x4FFFE
-                        // | 0x4FFFF
-                        // | 0x5FFFE
-                        // | 0x5FFFF
-                        // | 0x6FFFE
-                        // | 0x6FFFF
-                        // | 0x7FFFE
-                        // | 0x7FFFF
-                        // | 0x8FFFE
-                        // | 0x8FFFF
-                        // | 0x9FFFE
-                        // | 0x9FFFF
-                        // | 0xAFFFE
-                        // | 0xAFFFF
-                        // | 0xBFFFE
-                        // | 0xBFFFF
-                        // | 0xCFFFE
-                        // | 0xCFFFF
-                        // | 0xDFFFE
-                        // | 0xDFFFF
-                        // | 0xEFFFE
-                        // | 0xEFFFF
-                        // | 0xFFFFE
-                        // | 0xFFFFF
-                        // | 0x10FFFE => {}
-                        // // Control Character Check
-                        // 0x0001..0x0020 | 0x007f..=0x009f => {}
-                        _ => {
-                            self.character_reference_code =
-                                lookup_character_reference(self.character_reference_code);
-                        }
-                    };
-                    temp_buf = String::new();
-                    self.temp_buffer = String::new();
-                    // This cast should probably be error checked
-                    temp_buf.push(self.character_reference_code as u8 as char);
-                    self.temp_buffer
-                        .push(self.character_reference_code as u8 as char);
-                    if is_part_of_attribute(self.return_state) {
-                        temp_buf
-                            .chars()
-                            .for_each(|c| self.cur_token.append_to_last_attribute(c));
-                    } else {
-                        temp_buf.chars().for_each(|c| {
-                            emit_token!(self, new_character_string(c));
-                        });
-                    }
-
-                    self.state = self.return_state;
+                    // This state doesn't consume anything
+                    self.finish_numeric_character_reference();
+                    reconsume!();
                 }
                 _ => {
                     log::error!("Unsupported tag found");
@@ -1875,17 +2174,665 @@ impl Tokenizer {
             };
             self.idx += 1;
         }
+
+        // A character reference can still be pending when the input runs out
+        if self.input_finished {
+            self.finish_character_reference_at_eof();
+        }
+    }
+
+    fn flush_character_reference(&mut self) {
+        if is_part_of_attribute(self.return_state) {
+            for c in self.temp_buffer.chars() {
+                self.cur_token.append_to_last_attribute(c);
+            }
+        } else {
+            for c in self.temp_buffer.chars() {
+                self.token_buffer.push_back(new_character_string(c));
+            }
+        }
     }
 
+    fn finish_numeric_character_reference(&mut self) {
+        let code = match self.character_reference_code {
+            0x00 => {
+                self.error(ParseErrorKind::NullCharacterReference);
+                0xFFFD
+            }
+            0x110000.. => {
+                self.error(ParseErrorKind::CharacterReferenceOutsideUnicodeRange);
+                0xFFFD
+            }
+            0xD800..=0xDFFF => {
+                self.error(ParseErrorKind::SurrogateCharacterReference);
+                0xFFFD
+            }
+            // Noncharacters and control characters are still emitted.
+            // C1 controls get remapped to their windows-1252 equivalents.
+            code => {
+                match char::from_u32(code) {
+                    Some(c) if is_noncharacter(c) => {
+                        self.error(ParseErrorKind::NoncharacterCharacterReference)
+                    }
+                    Some(c) if c == '\u{000D}' || is_control_error(c) => {
+                        self.error(ParseErrorKind::ControlCharacterReference)
+                    }
+                    _ => {}
+                }
+                lookup_character_reference(code)
+            }
+        };
+
+        self.temp_buffer = char::from_u32(code).unwrap_or('\u{FFFD}').to_string();
+        self.flush_character_reference();
+        self.state = self.return_state;
+    }
+
+    fn finish_character_reference_at_eof(&mut self) {
+        use FsmState::*;
+        match self.state {
+            CharacterReference => {
+                self.temp_buffer = "&".to_string();
+                self.flush_character_reference();
+            }
+            NumericCharacterReference
+            | HexadecimalCharacterReferenceStart
+            | DecimalCharacterReferenceStart => {
+                self.error(ParseErrorKind::AbsenceOfDigitsInNumericCharacterReference);
+                self.flush_character_reference();
+            }
+            HexadecimalCharacterReference | DecimalCharacterReference => {
+                self.error(ParseErrorKind::MissingSemicolonAfterCharacterReference);
+                self.finish_numeric_character_reference();
+            }
+            // The semicolon was the last character
+            NumericCharacterReferenceEnd => self.finish_numeric_character_reference(),
+            NamedCharacterReference | AmbiguousAmpersand => {}
+            _ => return,
+        }
+        self.state = self.return_state;
+    }
+
+    // Flushes whatever the current state was in the middle of when the input ran out
     fn get_eof_token(&mut self) -> HtmlToken {
-        // TODO: Some states have specific EoF behaviour
-        //       This should eventually check the state
-        //       and behave correctly
+        use FsmState::*;
+        self.unspanned_tokens = self.token_buffer.len();
         match self.state {
-            _ => HtmlToken::new(TokenTag::EoF),
+            TagOpen => {
+                self.error(ParseErrorKind::EofBeforeTagName);
+                self.token_buffer.push_back(new_character_string('<'));
+            }
+            EndTagOpen => {
+                self.error(ParseErrorKind::EofBeforeTagName);
+                self.token_buffer.push_back(new_character_string('<'));
+                self.token_buffer.push_back(new_character_string('/'));
+            }
+            TagName
+            | BeforeAttributeName
+            | AttributeName
+            | AfterAttributeName
+            | BeforeAttributeValue
+            | AttributeValueDoubleQuoted
+            | AttributeValueSingleQuoted
+            | AttributeValueUnquoted
+            | AfterAttributeValueQuoted
+            | SelfClosingStartTag => self.error(ParseErrorKind::EofInTag),
+            RCDataLessThan | RawTextLessThan | ScriptDataLessThan | ScriptDataEscapedLessThan => {
+                self.token_buffer.push_back(new_character_string('<'));
+            }
+            RCDataEndTagOpen
+            | RawTextEndTagOpen
+            | ScriptDataEndTagOpen
+            | ScriptDataEscapedEndTagOpen
+            | RCDataEndTagName
+            | RawTextEndTagName
+            | ScriptDataEndTagName
+            | ScriptDataEscapedEndTagName => {
+                self.token_buffer.push_back(new_character_string('<'));
+                self.token_buffer.push_back(new_character_string('/'));
+                for c in self.temp_buffer.chars() {
+                    self.token_buffer.push_back(new_character_string(c));
+                }
+            }
+            ScriptDataEscaped
+            | ScriptDataEscapedDash
+            | ScriptDataEscapedDashDash
+            | ScriptDataDoubleEscapeStart
+            | ScriptDataDoubleEscaped
+            | ScriptDataDoubleEscapedDash
+            | ScriptDataDoubleEscapedDashDash
+            | ScriptDataDoubleEscapedLessThan
+            | ScriptDataDoubleEscapeEnd => {
+                self.error(ParseErrorKind::EofInScriptHtmlCommentLikeText)
+            }
+            MarkupDeclarationOpen => {
+                self.error(ParseErrorKind::IncorrectlyOpenedComment);
+                self.token_buffer
+                    .push_back(HtmlToken::new(TokenTag::Comment));
+            }
+            BogusComment | BogusDoctype => self.token_buffer.push_back(self.cur_token.clone()),
+            CommentStart
+            | CommentStartDash
+            | Comment
+            | CommentLessThan
+            | CommentLessThanBang
+            | CommentLessThanBangDash
+            | CommentLessThanBangDashDash
+            | CommentEndDash
+            | CommentEnd
+            | CommentEndBang => {
+                self.error(ParseErrorKind::EofInComment);
+                self.token_buffer.push_back(self.cur_token.clone());
+            }
+            Doctype | BeforeDoctypeName => {
+                self.error(ParseErrorKind::EofInDoctype);
+                let mut token = HtmlToken::new(TokenTag::Doctype(DoctypeData::new()));
+                token.flags.force_quirks = true;
+                self.token_buffer.push_back(token);
+            }
+            DoctypeName
+            | AfterDoctypeName
+            | AfterDoctypePublicKeyword
+            | BeforeDoctypePublicIndentifier
+            | DoctypePublicIdentifier
+            | DoctypePublicIdentifierDoubleQuote
+            | DoctypePublicIdentifierSingleQuote
+            | AfterDoctypePublicIdentifier
+            | BetweenDoctypePublicAndSystemIdentifiers
+            | AfterDoctypeSystemKeyword
+            | BeforeDoctypeSystemIdentifier
+            | DoctypeSystemIdentifierDoubleQuote
+            | DoctypeSystemIdentifierSingleQuote
+            | AfterDoctypeSystemIdentifier => {
+                self.error(ParseErrorKind::EofInDoctype);
+                self.cur_token.flags.force_quirks = true;
+                self.token_buffer.push_back(self.cur_token.clone());
+            }
+            CDataSection => self.error(ParseErrorKind::EofInCdata),
+            CDataSectionBracket => {
+                self.token_buffer.push_back(new_character_string(']'));
+                self.error(ParseErrorKind::EofInCdata);
+            }
+            CDataSectionEnd => {
+                self.token_buffer.push_back(new_character_string(']'));
+                self.token_buffer.push_back(new_character_string(']'));
+                self.error(ParseErrorKind::EofInCdata);
+            }
+            _ => {}
+        }
+        // Only flush once, every later call just gets the end of file
+        self.state = Data;
+        let end = self.discarded_chars + self.chars.len();
+        if self.unspanned_tokens < self.token_buffer.len() {
+            self.close_token_spans(end);
+        }
+
+        match self.token_buffer.pop_front() {
+            Some(token) => token,
+            None => {
+                let mut token = HtmlToken::new(TokenTag::EoF);
+                let position = self.source_position(end);
+                token.span = SourceSpan {
+                    start: position,
+                    end: position,
+                };
+                token
+            }
         }
     }
 }
 
 #[cfg(test)]
-mod test {}
+mod test {
+    use super::*;
+
+    fn tokenize_text(html: &str) -> String {
+        let mut tokenizer = Tokenizer::init(html);
+        let mut res = String::new();
+        loop {
+            let token = tokenizer.get_next_token();
+            match token.tag {
+                TokenTag::Character => res.push_str(&token.data),
+                TokenTag::EoF => break,
+                _ => {}
+            }
+        }
+        res
+    }
+
+    fn first_tag_attributes(html: &str) -> Vec<(String, String)> {
+        let mut tokenizer = Tokenizer::init(html);
+        loop {
+            let token = tokenizer.get_next_token();
+            match token.tag {
+                TokenTag::StartTag => {
+                    return token
+                        .attributes
+                        .into_iter()
+                        .map(|a| (a.name, a.value))
+                        .collect();
+                }
+                TokenTag::EoF => return Vec::new(),
+                _ => {}
+            }
+        }
+    }
+
+    #[test]
+    fn test_named_character_references() {
+        assert_eq!(
+            tokenize_text("&amp; &lt;&gt; &copy; &nbsp;"),
+            "& <> \u{A9} \u{A0}"
+        );
+        assert_eq!(
+            tokenize_text("&CounterClockwiseContourIntegral;"),
+            "\u{2233}"
+        );
+        assert_eq!(
+            tokenize_text("&NotNestedGreaterGreater;"),
+            "\u{2AA2}\u{338}"
+        );
+    }
+
+    #[test]
+    fn test_named_character_reference_longest_prefix() {
+        // Only the legacy "not" matches, the rest is left as text
+        assert_eq!(tokenize_text("&notit;"), "\u{AC}it;");
+        assert_eq!(tokenize_text("&notin;"), "\u{2209}");
+        assert_eq!(tokenize_text("&ampx &copy2"), "&x \u{A9}2");
+        assert_eq!(tokenize_text("&foo; & &;"), "&foo; & &;");
+    }
+
+    #[test]
+    fn test_named_character_reference_at_eof() {
+        assert_eq!(tokenize_text("a&"), "a&");
+        assert_eq!(tokenize_text("a&amp"), "a&");
+        assert_eq!(tokenize_text("a&#"), "a&#");
+        assert_eq!(tokenize_text("a&#x41"), "aA");
+    }
+
+    #[test]
+    fn test_character_references_in_attributes() {
+        let attributes =
+            first_tag_attributes("<a href=\"?x=1&copy=2&amp;y&notit\" title=\'&copy &ampX\'>");
+        assert_eq!(attributes[0].1, "?x=1&copy=2&y&notit");
+        assert_eq!(attributes[1].1, "\u{A9} &ampX");
+
+        let attributes = first_tag_attributes("<a title=&lt;b&gt;>");
+        assert_eq!(attributes[0].1, "<b>");
+    }
+
+    #[test]
+    fn test_numeric_character_references() {
+        assert_eq!(tokenize_text("&#65;&#x42;&#X43;&#128;"), "ABC\u{20AC}");
+        assert_eq!(
+            tokenize_text("&#0;&#xD800;&#x110000;"),
+            "\u{FFFD}\u{FFFD}\u{FFFD}"
+        );
+        assert_eq!(tokenize_text("&#99999999999999999999;"), "\u{FFFD}");
+        assert_eq!(tokenize_text("&#65x"), "Ax");
+        assert_eq!(tokenize_text("&#xZ"), "&#xZ");
+    }
+
+    #[test]
+    fn test_doctype_identifiers() {
+        let mut tokenizer = Tokenizer::init(
+            "<!DOCTYPE html PUBLIC \"-//W3C//DTD HTML 4.01//EN\" 'http://www.w3.org/TR/html4/strict.dtd'>",
+        );
+        let token = tokenizer.get_next_token();
+        match token.tag {
+            TokenTag::Doctype(data) => {
+                assert_eq!(token.data, "html");
+                assert_eq!(
+                    data.public_identifier.as_deref(),
+                    Some("-//W3C//DTD HTML 4.01//EN")
+                );
+                assert_eq!(
+                    data.system.as_deref(),
+                    Some("http://www.w3.org/TR/html4/strict.dtd")
+                );
+                assert!(!token.flags.force_quirks);
+            }
+            _ => panic!("Expected a doctype token"),
+        }
+
+        let mut tokenizer = Tokenizer::init("<!DOCTYPE html bogus>");
+        let token = tokenizer.get_next_token();
+        assert!(matches!(token.tag, TokenTag::Doctype(_)));
+        assert!(token.flags.force_quirks);
+        assert!(matches!(tokenizer.get_next_token().tag, TokenTag::EoF));
+    }
+
+    #[test]
+    fn test_input_stream_preprocessing() {
+        assert_eq!(tokenize_text("a\r\nb\rc\n\rd"), "a\nb\nc\n\nd");
+
+        let mut tokenizer = Tokenizer::init("\r\n\u{0001}a\u{FDD0}\u{7F}\u{1FFFF}\t\u{0000}");
+        let errors: Vec<(ParseErrorKind, usize, usize)> = tokenizer
+            .take_errors()
+            .into_iter()
+            .map(|e| (e.kind, e.line, e.column))
+            .collect();
+        assert_eq!(
+            errors,
+            vec![
+                (ParseErrorKind::ControlCharacterInInputStream, 2, 1),
+                (ParseErrorKind::NoncharacterInInputStream, 2, 3),
+                (ParseErrorKind::ControlCharacterInInputStream, 2, 4),
+                (ParseErrorKind::NoncharacterInInputStream, 2, 5),
+            ]
+        );
+    }
+
+    fn tokenizer_errors(html: &str) -> Vec<(ParseErrorKind, usize, usize)> {
+        let mut tokenizer = Tokenizer::init(html);
+        while !matches!(tokenizer.get_next_token().tag, TokenTag::EoF) {}
+        tokenizer
+            .take_errors()
+            .into_iter()
+            .map(|e| (e.kind, e.line, e.column))
+            .collect()
+    }
+
+    #[test]
+    fn test_tokenizer_errors() {
+        assert_eq!(
+            tokenizer_errors("<div a=1 a=2>"),
+            vec![(ParseErrorKind::DuplicateAttribute, 1, 11)]
+        );
+        assert_eq!(
+            tokenizer_errors("x\n&#0;"),
+            vec![(ParseErrorKind::NullCharacterReference, 2, 5)]
+        );
+        assert_eq!(
+            tokenizer_errors("</p class=a>"),
+            vec![(ParseErrorKind::EndTagWithAttributes, 1, 12)]
+        );
+
+        // The comment is still emitted when the input ends inside of it
+        let mut tokenizer = Tokenizer::init("<!--x");
+        let token = tokenizer.get_next_token();
+        assert!(matches!(token.tag, TokenTag::Comment));
+        assert_eq!(token.data, "x");
+        assert!(matches!(tokenizer.get_next_token().tag, TokenTag::EoF));
+        assert_eq!(
+            tokenizer.take_errors()[0].kind,
+            ParseErrorKind::EofInComment
+        );
+    }
+
+    fn tokenize_in_state(state: FsmState, last_start_tag: &str, html: &str) -> String {
+        let mut tokenizer = Tokenizer::init(html);
+        tokenizer.set_state(state);
+        tokenizer.set_last_start_tag(last_start_tag);
+        let mut res = String::new();
+        loop {
+            let token = tokenizer.get_next_token();
+            match token.tag {
+                TokenTag::Character => res.push_str(&token.data),
+                TokenTag::EndTag => res.push_str(&format!("[/{}]", token.data)),
+                TokenTag::EoF => break,
+                _ => res.push_str(&format!("[{:?}]", token.tag)),
+            }
+        }
+        res
+    }
+
+    #[test]
+    fn test_appropriate_end_tags() {
+        use FsmState::*;
+        assert_eq!(
+            tokenize_in_state(RCData, "textarea", "a</b>c</textarea>d"),
+            "a</b>c[/textarea]d"
+        );
+        assert_eq!(
+            tokenize_in_state(RawText, "style", "a</STYLE >b"),
+            "a[/style]b"
+        );
+        assert_eq!(
+            tokenize_in_state(ScriptData, "script", "a</scripts></script>b"),
+            "a</scripts>[/script]b"
+        );
+        // Comment-like text doesn't repeat the '!', and only a full "<!--" escapes
+        assert_eq!(
+            tokenize_in_state(ScriptData, "script", "<!--a</script>b"),
+            "<!--a[/script]b"
+        );
+        assert_eq!(
+            tokenize_in_state(ScriptData, "script", "<!-<script></script>b"),
+            "<!-<script>[/script]b"
+        );
+        assert_eq!(
+            tokenize_in_state(ScriptData, "script", "<!--<script></script>--></script>b"),
+            "<!--<script></script>-->[/script]b"
+        );
+    }
+
+    fn describe_tokens(tokenizer: &mut Tokenizer) -> Vec<String> {
+        let mut tokens = Vec::new();
+        while let Some(token) = tokenizer.next_token() {
+            let is_eof = matches!(token.tag, TokenTag::EoF);
+            let attributes: Vec<(String, String)> = token
+                .attributes
+                .iter()
+                .map(|a| (a.name.clone(), a.value.clone()))
+                .collect();
+            tokens.push(format!("{:?} {:?} {:?}", token.tag, token.data, attributes));
+            if is_eof {
+                break;
+            }
+        }
+        tokens
+    }
+
+    #[test]
+    fn test_chunked_input() {
+        let html = "<!DOCTYPE html PUBLIC \"-//W3C//DTD HTML 4.01//EN\">\r\n<p a='&notin;' b=&amp>\
+            x&notit;y\r\n<!--c--><title>&lt;</title><script><!--<script></script>--></script>\
+            <svg><![CDATA[z]]></svg>&#x41;";
+        let mut whole = Tokenizer::init(html);
+        let expected = describe_tokens(&mut whole);
+
+        // Every split point has to give the same tokens as the whole input
+        for split in 0..html.len() {
+            if !html.is_char_boundary(split) {
+                continue;
+            }
+            let mut tokenizer = Tokenizer::new();
+            tokenizer.feed(&html[..split]);
+            let mut tokens = describe_tokens(&mut tokenizer);
+            tokenizer.feed(&html[split..]);
+            tokenizer.finish();
+            tokens.extend(describe_tokens(&mut tokenizer));
+            assert_eq!(tokens, expected, "split at {}", split);
+        }
+
+        // Nothing is emitted until it's known how the input continues
+        let mut tokenizer = Tokenizer::new();
+        tokenizer.feed("<div");
+        assert!(tokenizer.next_token().is_none());
+        tokenizer.feed(">");
+        assert!(matches!(
+            tokenizer.next_token().map(|t| t.tag),
+            Some(TokenTag::StartTag)
+        ));
+
+        // Getting the tokens that are ready doesn't end the input
+        let mut tokenizer = Tokenizer::new();
+        tokenizer.feed("<a><b");
+        assert_eq!(tokenizer.get_next_token().data, "a");
+        tokenizer.feed("r>");
+        tokenizer.finish();
+        assert_eq!(tokenizer.get_next_token().data, "br");
+        assert!(matches!(tokenizer.get_next_token().tag, TokenTag::EoF));
+
+        // Nothing being ready isn't the end of the input, and feeding after finish is ignored
+        let mut tokenizer = Tokenizer::new();
+        tokenizer.feed("<a");
+        assert!(matches!(tokenizer.get_next_token().tag, TokenTag::EoF));
+        tokenizer.feed(">");
+        tokenizer.finish();
+        tokenizer.feed("<b>");
+        assert_eq!(tokenizer.get_next_token().data, "a");
+        assert!(matches!(tokenizer.get_next_token().tag, TokenTag::EoF));
+    }
+
+    #[test]
+    fn test_consumed_input_is_discarded() {
+        let mut tokenizer = Tokenizer::new();
+        for _ in 0..10000 {
+            tokenizer.feed("<p class=a>text</p>\n");
+            while tokenizer.next_token().is_some() {}
+            assert!(tokenizer.chars.len() < DISCARD_THRESHOLD * 2);
+        }
+
+        // Positions still count from the start of the input
+        tokenizer.feed("\u{0001}");
+        assert_eq!(
+            tokenizer.take_errors(),
+            vec![ParseError {
+                kind: ParseErrorKind::ControlCharacterInInputStream,
+                line: 10001,
+                column: 1,
+            }]
+        );
+    }
+
+    // The source text of every token up to the end of file
+    fn token_sources(tokenizer: &mut Tokenizer, html: &str) -> Vec<String> {
+        let mut res = Vec::new();
+        loop {
+            let token = tokenizer.get_next_token();
+            if let TokenTag::EoF = token.tag {
+                assert_eq!(token.span.range(), html.len()..html.len());
+                return res;
+            }
+            res.push(html[token.span.range()].to_string());
+        }
+    }
+
+    #[test]
+    fn test_token_spans() {
+        let html = "<p class=\"a\" id=b hidden>x&amp;y</p><!--c-->";
+        assert_eq!(
+            token_sources(&mut Tokenizer::init(html), html),
+            vec![
+                "<p class=\"a\" id=b hidden>",
+                "x",
+                "&amp;",
+                "y",
+                "</p>",
+                "<!--c-->"
+            ]
+        );
+
+        let mut tokenizer = Tokenizer::init(html);
+        let token = tokenizer.get_next_token();
+        let attributes: Vec<&str> = token
+            .attributes
+            .iter()
+            .map(|a| &html[a.span.range()])
+            .collect();
+        assert_eq!(attributes, vec!["class=\"a\"", "id=b", "hidden"]);
+
+        // Offsets are in bytes of the original text, lines and columns in characters
+        let html = "a\r\né<b>";
+        let mut tokenizer = Tokenizer::init(html);
+        let spans: Vec<SourceSpan> = (0..4).map(|_| tokenizer.get_next_token().span).collect();
+        assert_eq!(&html[spans[3].range()], "<b>");
+        assert_eq!(
+            spans[3].start,
+            SourcePosition {
+                offset: 5,
+                line: 2,
+                column: 2
+            }
+        );
+        assert_eq!(
+            spans[3].end,
+            SourcePosition {
+                offset: 8,
+                line: 2,
+                column: 5
+            }
+        );
+    }
+
+    #[test]
+    fn test_token_spans_in_chunks() {
+        let html = "<!DOCTYPE html>\r\n<p a='&notin;' b=&amp>x&notit;y\r\n<!--c-->\
+            <title>&lt;</title><script>a</script>é";
+        let expected = token_sources(&mut Tokenizer::init(html), html);
+
+        for split in 0..html.len() {
+            if !html.is_char_boundary(split) {
+                continue;
+            }
+            let mut tokenizer = Tokenizer::new();
+            tokenizer.feed(&html[..split]);
+            let mut sources = Vec::new();
+            while let Some(token) = tokenizer.next_token() {
+                sources.push(html[token.span.range()].to_string());
+            }
+            tokenizer.feed(&html[split..]);
+            tokenizer.finish();
+            sources.extend(token_sources(&mut tokenizer, html));
+            assert_eq!(sources, expected, "split at {}", split);
+        }
+    }
+
+    #[test]
+    fn test_token_iterator() {
+        let describe = |t: HtmlToken| format!("{:?} {}", t.tag, t.data);
+        let tokens: Vec<String> = Tokenizer::init("<a href=x>y</a><script><b></script>")
+            .map(describe)
+            .collect();
+        // Script contents stay text, and the end of the file ends the iteration
+        assert_eq!(
+            tokens,
+            vec![
+                "StartTag a",
+                "Character y",
+                "EndTag a",
+                "StartTag script",
+                "Character <",
+                "Character b",
+                "Character >",
+                "EndTag script"
+            ]
+        );
+
+        let mut tokenizer = Tokenizer::init("<script><b>");
+        tokenizer.set_switch_states(false);
+        assert_eq!(tokenizer.map(describe).last().unwrap(), "StartTag b");
+
+        // The caller can start in any state
+        let mut tokenizer = Tokenizer::init("<b></textarea>");
+        tokenizer.set_state(FsmState::RCData);
+        tokenizer.set_last_start_tag("textarea");
+        let tokens: Vec<String> = tokenizer.map(describe).collect();
+        assert_eq!(tokens.len(), 4);
+        assert_eq!(tokens[3], "EndTag textarea");
+
+        // Input that's still being fed is read up to where it's known how it continues
+        let mut tokenizer = Tokenizer::new();
+        tokenizer.feed("<script><b></scr");
+        let ready: Vec<String> = tokenizer.by_ref().map(describe).collect();
+        assert_eq!(
+            ready,
+            vec![
+                "StartTag script",
+                "Character <",
+                "Character b",
+                "Character >"
+            ]
+        );
+        assert!(!tokenizer.reached_eof());
+        tokenizer.feed("ipt>x");
+        tokenizer.finish();
+        let tokens: Vec<String> = tokenizer.by_ref().map(describe).collect();
+        assert_eq!(tokens, vec!["EndTag script", "Character x"]);
+        assert!(tokenizer.reached_eof());
+        assert!(tokenizer.next().is_none());
+    }
+}
@@ -0,0 +1,71 @@
+use crate::document::doctree::DoctreeNode;
+use crate::document::document::QuirksMode;
+use crate::document::node;
+use crate::html_elements::{HTMLElement, HTMLElementType, Namespace};
+
+use super::span::SourceSpan;
+
+// Receives the tree as the parser builds it. Nodes are referred to by the handles the sink
+// hands out, which only have to mean something to the sink itself.
+//
+// The parser looks elements back up while it works (the stack of open elements, the list
+// of active formatting elements, the form element pointer...), so a sink has to be able to
+// answer for every element it created. It doesn't have to keep the rest of the tree.
+pub trait TreeSink {
+    // Creates an element, comment, doctype or document fragment that isn't attached yet
+    fn create_node(&mut self, node: node::Node) -> DoctreeNode;
+
+    fn get_element(&self, node: &DoctreeNode) -> Option<&HTMLElement>;
+
+    fn get_mut_element(&mut self, node: &DoctreeNode) -> Option<&mut HTMLElement>;
+
+    // None for nodes that aren't attached, or are attached to the document itself
+    fn get_parent(&self, node: &DoctreeNode) -> Option<DoctreeNode>;
+
+    fn get_children(&self, node: &DoctreeNode) -> Vec<DoctreeNode>;
+
+    fn append_to_document(&mut self, child: DoctreeNode);
+
+    // Moves the child if it's already attached somewhere
+    fn append(&mut self, parent: &DoctreeNode, child: DoctreeNode);
+
+    // Falls back to appending when reference isn't a child of parent
+    fn insert_before(&mut self, parent: &DoctreeNode, child: DoctreeNode, reference: &DoctreeNode);
+
+    // Text goes into the text node right before the insertion point when there is one
+    fn insert_text(
+        &mut self,
+        parent: &DoctreeNode,
+        before: Option<&DoctreeNode>,
+        text: &str,
+        span: SourceSpan,
+    );
+
+    // Moves all children of one node to the end of another's
+    fn reparent_children(&mut self, from: &DoctreeNode, to: &DoctreeNode);
+
+    fn detach(&mut self, node: &DoctreeNode);
+
+    // The node is detached and won't be used by the parser again
+    fn remove(&mut self, node: &DoctreeNode);
+
+    fn set_quirks_mode(&mut self, _mode: QuirksMode) {}
+
+    // Called once the input has been parsed
+    fn finish(&mut self) {}
+
+    fn get_element_name(&self, node: &DoctreeNode) -> Option<String> {
+        self.get_element(node).map(|e| e.get_name().to_string())
+    }
+
+    fn get_element_namespace(&self, node: &DoctreeNode) -> Option<Namespace> {
+        self.get_element(node).map(|e| e.namespace)
+    }
+
+    fn get_template_contents(&self, node: &DoctreeNode) -> Option<DoctreeNode> {
+        match &self.get_element(node)?.element_type {
+            HTMLElementType::Template(template) => template.get_contents(),
+            _ => None,
+        }
+    }
+}